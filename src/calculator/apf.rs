@@ -1,9 +1,13 @@
 #![allow(dead_code)]
 use bevy::ecs::system::Resource;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
 use crate::octree::octree::Octree;
+use crate::octree::esdf::Esdf;
 use crate::data_reader::structor::Point3;
 
-#[derive(Debug, Resource)]
+#[derive(Debug, Clone, Resource)]
 pub struct ApfConfig {
     pub k_att: f32,   // Attractive force gain
     pub k_rep: f32,   // Repulsive force gain
@@ -11,6 +15,7 @@ pub struct ApfConfig {
     pub step_size: f32,   // Step size
     pub epsilon: f32,  // Goal radius
     pub max_steps: u32, // Maximum iteration steps
+    pub escape: ApfEscapeConfig, // What to do when stuck in a local minimum
 }
 
 impl Default for ApfConfig {
@@ -22,14 +27,114 @@ impl Default for ApfConfig {
             step_size: 0.1,
             epsilon: 0.1,
             max_steps: 1000,
+            escape: ApfEscapeConfig::default(),
         }
     }
 }
 
+impl ApfConfig {
+    /// Distance an `Esdf` with cells of `resolution` has to track for `apf_plan_esdf`,
+    /// one cell past the influence radius so the interpolation is exact up to `d0`
+    pub fn esdf_max_distance(&self, resolution: f32) -> f32 {
+        self.d0 + resolution
+    }
+}
+
+/// Ways to get out of a local minimum of the potential field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscapeStrategy {
+    RandomWalk,      // Random steps away from obstacles
+    VirtualObstacle, // Make the minimum repulsive so the field leads elsewhere
+    WallFollow,      // Slide along the obstacle surface towards the goal
+}
+
+#[derive(Debug, Clone)]
+pub struct ApfEscapeConfig {
+    pub strategies: Vec<EscapeStrategy>, // Tried in turn, one per escape
+    pub max_escapes: u32,          // Give up after this many escapes
+    pub stall_steps: u32,          // Steps without getting closer to the goal that count as oscillation
+    pub random_walk_steps: u32,    // Length of one random walk
+    pub wall_follow_steps: u32,    // Maximum length of one wall following
+    pub seed: u64,                 // Random walks are reproducible for a given seed
+}
+
+impl Default for ApfEscapeConfig {
+    fn default() -> Self {
+        Self {
+            strategies: vec![EscapeStrategy::WallFollow, EscapeStrategy::VirtualObstacle, EscapeStrategy::RandomWalk],
+            max_escapes: 6,
+            stall_steps: 20,
+            random_walk_steps: 10,
+            wall_follow_steps: 30,
+            seed: 0,
+        }
+    }
+}
+
+/// One escape the planner made
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApfEscape {
+    pub step: u32,
+    pub position: Point3,
+    pub strategy: EscapeStrategy,
+}
+
+/// How planning went, also for paths that did not reach the goal
+#[derive(Debug, Clone, Default)]
+pub struct ApfDiagnostic {
+    pub steps: u32,
+    pub remaining_distance: f32,       // Distance from the end of the path to the goal
+    pub local_minima: u32,             // Times the total force vanished
+    pub oscillations: u32,             // Times the planner stopped getting closer
+    pub escapes: Vec<ApfEscape>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApfPlan {
+    pub path: Vec<Point3>,
+    pub diagnostic: ApfDiagnostic,
+}
+
+/// Planning failed, the path up to where it got stuck is still returned
 #[derive(Debug)]
 pub enum ApfError {
-    LocalMinimum,
-    MaxStepsReached,
+    LocalMinimum(ApfPlan),
+    MaxStepsReached(ApfPlan),
+}
+
+impl ApfError {
+    /// The partial path and what happened on the way
+    pub fn plan(&self) -> &ApfPlan {
+        match self {
+            ApfError::LocalMinimum(plan) | ApfError::MaxStepsReached(plan) => plan,
+        }
+    }
+
+    pub fn into_plan(self) -> ApfPlan {
+        match self {
+            ApfError::LocalMinimum(plan) | ApfError::MaxStepsReached(plan) => plan,
+        }
+    }
+}
+
+impl fmt::Display for ApfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ApfError::LocalMinimum(_) => "stuck in a local minimum",
+            ApfError::MaxStepsReached(_) => "ran out of steps",
+        };
+        let diagnostic = &self.plan().diagnostic;
+        write!(
+            f,
+            "APF {} after {} steps, {:.2} m from the goal ({} minima, {} oscillations, {} escapes)",
+            reason,
+            diagnostic.steps,
+            diagnostic.remaining_distance,
+            diagnostic.local_minima,
+            diagnostic.oscillations,
+            diagnostic.escapes.len(),
+        )
+    }
 }
 
 fn distance(a: &Point3, b: &Point3) -> f32 {
@@ -73,6 +178,33 @@ fn compute_repulsive_force(
     f_rep
 }
 
+/// Repulsive force taken straight from the distance field gradient,
+/// with the same magnitude profile as `compute_repulsive_force`.
+/// Inside an obstacle, where the field is negative, it pushes out along the gradient
+/// as hard as half a cell from the surface.
+fn compute_repulsive_force_esdf(
+    current: &Point3,
+    esdf: &Esdf,
+    k_rep: f32,
+    d0: f32,
+) -> Point3 {
+    let (d, gradient) = esdf.distance_and_gradient([current.x, current.y, current.z]);
+    if d > d0 {
+        return Point3 { x: 0.0, y: 0.0, z: 0.0 };
+    }
+    let d = d.max((esdf.resolution() / 2.0).min(d0 / 2.0));
+    let direction = match Point3::new(gradient[0], gradient[1], gradient[2]).normalize() {
+        Some(direction) => direction,
+        None => return Point3 { x: 0.0, y: 0.0, z: 0.0 },
+    };
+    let term = (1.0/d - 1.0/d0) * k_rep / d;
+    Point3 {
+        x: direction.x * term,
+        y: direction.y * term,
+        z: direction.z * term,
+    }
+}
+
 fn add_forces(f_att: Point3, f_rep: Point3) -> Point3 {
     Point3 {
         x: f_att.x + f_rep.x,
@@ -81,56 +213,330 @@ fn add_forces(f_att: Point3, f_rep: Point3) -> Point3 {
     }
 }
 
+fn dot(a: &Point3, b: &Point3) -> f32 {
+    a.x * b.x + a.y * b.y + a.z * b.z
+}
+
+fn cross(a: &Point3, b: &Point3) -> Point3 {
+    Point3::new(a.y * b.z - a.z * b.y, a.z * b.x - a.x * b.z, a.x * b.y - a.y * b.x)
+}
+
+fn step(position: &Point3, direction: &Point3, step_size: f32) -> Point3 {
+    Point3 {
+        x: position.x + direction.x * step_size,
+        y: position.y + direction.y * step_size,
+        z: position.z + direction.z * step_size,
+    }
+}
+
+/// Uniformly distributed unit vector
+fn random_direction(rng: &mut StdRng) -> Point3 {
+    loop {
+        let candidate = Point3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+        let norm = candidate.norm();
+        if norm > 1e-3 && norm <= 1.0 {
+            return Point3::new(candidate.x / norm, candidate.y / norm, candidate.z / norm);
+        }
+    }
+}
+
 pub fn apf_plan(
     start: Point3,
     goal: Point3,
     octree: &Octree,
     config: ApfConfig,
-) -> Result<Vec<Point3>, ApfError> {
+) -> Result<ApfPlan, ApfError> {
+    apf_plan_internal(start, goal, &config, |current_pos| {
+        let obstacle_list = octree.radius_search(
+            [current_pos.x, current_pos.y, current_pos.z],
+            config.d0,
+        );
+        compute_repulsive_force(current_pos, obstacle_list, config.k_rep, config.d0)
+    })
+}
+
+/// APF planning against a precomputed distance field instead of raw octree leaves
+pub fn apf_plan_esdf(
+    start: Point3,
+    goal: Point3,
+    esdf: &Esdf,
+    config: ApfConfig,
+) -> Result<ApfPlan, ApfError> {
+    apf_plan_internal(start, goal, &config, |current_pos| {
+        compute_repulsive_force_esdf(current_pos, esdf, config.k_rep, config.d0)
+    })
+}
+
+fn apf_plan_internal<F>(
+    start: Point3,
+    goal: Point3,
+    config: &ApfConfig,
+    repulsive_force: F,
+) -> Result<ApfPlan, ApfError>
+where
+    F: Fn(&Point3) -> Point3,
+{
     let mut path = vec![start];
     let mut current_pos = start;
     let mut steps = 0;
-    let octree_map = octree.octree_to_map();
+    let mut diagnostic = ApfDiagnostic::default();
+    let mut rng = StdRng::seed_from_u64(config.escape.seed);
+    // Minima already escaped from, they repel like real obstacles
+    let mut virtual_obstacles: Vec<Point3> = Vec::new();
+    let mut best_distance = distance(&current_pos, &goal);
+    let mut stalled_steps = 0;
+    // Wall following keeps going the same way along the wall until it ends, and remembers
+    // how far from the goal it started
+    let mut wall_follow: Option<(u32, Point3, f32)> = None;
+    // A random walk keeps one direction, a new one every step barely leaves the minimum
+    let mut random_walk: Option<(u32, Point3)> = None;
+
+    let total_repulsion = |position: &Point3, virtual_obstacles: &[Point3]| {
+        let virtual_list = virtual_obstacles
+            .iter()
+            .map(|obstacle| (distance(position, obstacle), [obstacle.x, obstacle.y, obstacle.z]))
+            .collect();
+        add_forces(
+            repulsive_force(position),
+            compute_repulsive_force(position, virtual_list, config.k_rep, config.d0),
+        )
+    };
 
     while distance(&current_pos, &goal) > config.epsilon && steps < config.max_steps {
         let f_att = compute_attractive_force(&current_pos, &goal, config.k_att);
+        let f_rep = total_repulsion(&current_pos, &virtual_obstacles);
 
-        let mut obstacle_list: Vec<(f32, [f32; 3])> = Vec::new();
-
-        for (_, points) in &octree_map {
-            for point in points {
-                let x = point.x;
-                let y = point.y;
-                let z = point.z;
-                let distance = distance(&current_pos, &Point3::new(x, y, z));
-                if distance < config.d0 {
-                    obstacle_list.push((distance, [x, y, z]));
+        let direction = if let Some((remaining, heading)) = random_walk.filter(|(remaining, _)| *remaining > 0) {
+            random_walk = Some((remaining - 1, heading));
+            // Wander, but never into obstacles
+            let wander = add_forces(heading, f_rep.normalize().unwrap_or(Point3::new(0.0, 0.0, 0.0)));
+            wander.normalize()
+        } else if let Some((remaining, previous, start_distance)) = wall_follow {
+            // Follow the wall until it is left closer to the goal than where following started
+            let left = distance(&current_pos, &goal) < start_distance;
+            match f_rep.normalize() {
+                Some(normal) if remaining > 0 && (!left || dot(&f_att, &normal) < 0.0) => {
+                    let tangent = along_wall(&previous, &normal).unwrap_or_else(|| wall_tangent(&f_att, &normal, &previous));
+                    wall_follow = Some((remaining - 1, tangent, start_distance));
+                    Some(tangent)
+                }
+                _ => {
+                    wall_follow = None;
+                    add_forces(f_att, f_rep).normalize()
                 }
             }
-        }
+        } else {
+            let f_total = add_forces(f_att, f_rep);
+            if f_total.norm() < 1e-6 {
+                diagnostic.local_minima += 1;
+                None
+            } else if stalled_steps >= config.escape.stall_steps {
+                diagnostic.oscillations += 1;
+                None
+            } else {
+                f_total.normalize()
+            }
+        };
 
-        let f_rep = compute_repulsive_force(&current_pos, obstacle_list, config.k_rep, config.d0);
+        // 处理零向量（局部极小）
+        let Some(direction) = direction else {
+            if diagnostic.escapes.len() as u32 >= config.escape.max_escapes || config.escape.strategies.is_empty() {
+                return Err(ApfError::LocalMinimum(finish_plan(path, diagnostic, steps, &goal)));
+            }
+            let strategy = config.escape.strategies[diagnostic.escapes.len() % config.escape.strategies.len()];
+            diagnostic.escapes.push(ApfEscape { step: steps, position: current_pos, strategy });
+            match strategy {
+                EscapeStrategy::RandomWalk => {
+                    random_walk = Some((config.escape.random_walk_steps, random_direction(&mut rng)));
+                }
+                EscapeStrategy::VirtualObstacle => {
+                    // Half a step towards the goal, so it pushes the vehicle back out of the trap.
+                    // Right on the vehicle it would not push at all.
+                    let towards_goal = goal.sub(&current_pos).normalize().unwrap_or(Point3::new(1.0, 0.0, 0.0));
+                    virtual_obstacles.push(step(&current_pos, &towards_goal, config.step_size / 2.0));
+                }
+                EscapeStrategy::WallFollow => {
+                    // Start the way the goal pulls along the wall, or a random way if it pulls head-on
+                    let f_att = compute_attractive_force(&current_pos, &goal, config.k_att);
+                    let sideways = random_direction(&mut rng);
+                    let start = match total_repulsion(&current_pos, &virtual_obstacles).normalize() {
+                        Some(normal) => wall_tangent(&f_att, &normal, &sideways),
+                        None => sideways,
+                    };
+                    wall_follow = Some((config.escape.wall_follow_steps, start, distance(&current_pos, &goal)));
+                }
+            }
+            best_distance = distance(&current_pos, &goal);
+            stalled_steps = 0;
+            continue;
+        };
 
-        let f_total = add_forces(f_att, f_rep);
+        current_pos = step(&current_pos, &direction, config.step_size);
+        path.push(current_pos);
+        steps += 1;
 
-        // 处理零向量（局部极小）
-        if let Some(direction) = f_total.normalize() {
-            current_pos = Point3 {
-                x: current_pos.x + direction.x * config.step_size,
-                y: current_pos.y + direction.y * config.step_size,
-                z: current_pos.z + direction.z * config.step_size,
-            };
-            path.push(current_pos);
+        let goal_distance = distance(&current_pos, &goal);
+        if goal_distance < best_distance - config.step_size * 0.1 {
+            best_distance = goal_distance;
+            stalled_steps = 0;
         } else {
-            return Err(ApfError::LocalMinimum);
+            stalled_steps += 1;
         }
-
-        steps += 1;
     }
 
     if distance(&current_pos, &goal) <= config.epsilon {
-        Ok(path)
+        Ok(finish_plan(path, diagnostic, steps, &goal))
     } else {
-        Err(ApfError::MaxStepsReached)
+        Err(ApfError::MaxStepsReached(finish_plan(path, diagnostic, steps, &goal)))
+    }
+}
+
+/// Direction along a surface with outward `normal`: the attraction with its part into the
+/// wall removed. Head-on, where nothing is left, a sideways direction close to `previous`.
+fn wall_tangent(f_att: &Point3, normal: &Point3, previous: &Point3) -> Point3 {
+    along_wall(f_att, normal)
+        .or_else(|| along_wall(previous, normal))
+        .unwrap_or_else(|| cross(normal, &Point3::new(0.0, 0.0, 1.0)).normalize().unwrap_or(Point3::new(0.0, 1.0, 0.0)))
+}
+
+/// `direction` with its part along the outward `normal` removed, `None` if nothing is left
+fn along_wall(direction: &Point3, normal: &Point3) -> Option<Point3> {
+    let into_wall = dot(direction, normal);
+    let along = Point3::new(
+        direction.x - normal.x * into_wall,
+        direction.y - normal.y * into_wall,
+        direction.z - normal.z * into_wall,
+    );
+    along.normalize().filter(|_| along.norm() > 1e-4)
+}
+
+fn finish_plan(path: Vec<Point3>, mut diagnostic: ApfDiagnostic, steps: u32, goal: &Point3) -> ApfPlan {
+    diagnostic.steps = steps;
+    diagnostic.remaining_distance = path.last().map_or(0.0, |end| distance(end, goal));
+    ApfPlan { path, diagnostic }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn esdf_repulsion_pushes_out_of_obstacles() {
+        let mut octree = Octree::new([[0.0; 3], [4.0; 3]]);
+        for x in 6..12 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    octree.insert([x, y, z].map(|c| (c as f32 + 0.5) * 0.25), 4, 100).unwrap();
+                }
+            }
+        }
+        let mut esdf = Esdf::new([[0.0; 3], [4.0; 3]], 0.25, 1.0);
+        esdf.update_from_octree(&octree);
+
+        // Inside the slab, closer to its low x face
+        let inside = compute_repulsive_force_esdf(&Point3::new(1.9, 2.0, 2.0), &esdf, 1.0, 0.7);
+        assert!(inside.x < 0.0, "{:?}", inside);
+        // Outside, pushed away from the low x face
+        let outside = compute_repulsive_force_esdf(&Point3::new(1.2, 2.0, 2.0), &esdf, 1.0, 0.7);
+        assert!(outside.x < 0.0 && outside.norm() < inside.norm(), "{:?}", outside);
+        // Beyond the influence radius
+        let far = compute_repulsive_force_esdf(&Point3::new(0.3, 2.0, 2.0), &esdf, 1.0, 0.7);
+        assert_eq!(far.norm(), 0.0);
+    }
+
+    /// Box open towards -x with its bottom between the start at the origin and the goal,
+    /// so the field pulls the vehicle into it
+    fn u_trap() -> Octree {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        let n = 40;
+        for i in 0..=n {
+            for j in 0..=n {
+                let across = -1.0 + 2.0 * i as f32 / n as f32;
+                octree.insert([2.0, across, -1.0 + 2.0 * j as f32 / n as f32], 7, 100).unwrap();
+                let x = 0.5 + 1.5 * j as f32 / n as f32;
+                for side in [-1.0, 1.0] {
+                    octree.insert([x, side, across], 7, 100).unwrap();
+                    octree.insert([x, across, side], 7, 100).unwrap();
+                }
+            }
+        }
+        octree
+    }
+
+    const GOAL: Point3 = Point3 { x: 4.0, y: 0.0, z: 0.0 };
+
+    fn trap_config(strategies: Vec<EscapeStrategy>, seed: u64) -> ApfConfig {
+        ApfConfig {
+            k_att: 2.5,
+            k_rep: 2.5,
+            d0: 0.7,
+            epsilon: 0.1,
+            max_steps: 2000,
+            step_size: 0.1,
+            escape: ApfEscapeConfig { strategies, max_escapes: 20, random_walk_steps: 30, seed, ..ApfEscapeConfig::default() },
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn every_strategy_escapes_the_trap() {
+        let octree = u_trap();
+        for strategy in [EscapeStrategy::WallFollow, EscapeStrategy::VirtualObstacle, EscapeStrategy::RandomWalk] {
+            for seed in 0..3 {
+                let plan = apf_plan(Point3::new(0.0, 0.0, 0.0), GOAL, &octree, trap_config(vec![strategy], seed))
+                    .unwrap_or_else(|error| panic!("{:?} seed {}: {}", strategy, seed, error));
+                assert!(distance(plan.path.last().unwrap(), &GOAL) <= 0.1);
+                assert!(!plan.diagnostic.escapes.is_empty(), "{:?} never got stuck", strategy);
+                assert!(plan.diagnostic.escapes.iter().all(|escape| escape.strategy == strategy));
+                for point in &plan.path {
+                    let clearance = octree.nearest_obstacle_distance([point.x, point.y, point.z]).unwrap();
+                    assert!(clearance > 0.1, "{:?} seed {} at {:?}", strategy, seed, point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_path() {
+        let octree = u_trap();
+        let plan = |seed| apf_plan(Point3::new(0.0, 0.0, 0.0), GOAL, &octree, trap_config(vec![EscapeStrategy::RandomWalk], seed)).unwrap();
+        let (a, b) = (plan(4), plan(4));
+        assert_eq!(a.path, b.path);
+        assert_eq!(a.diagnostic.escapes, b.diagnostic.escapes);
+    }
+
+    #[test]
+    fn stuck_without_escapes_returns_the_partial_path() {
+        let octree = u_trap();
+        let error = apf_plan(Point3::new(0.0, 0.0, 0.0), GOAL, &octree, trap_config(Vec::new(), 0)).unwrap_err();
+        assert!(matches!(error, ApfError::LocalMinimum(_)));
+        assert!(error.to_string().contains("local minimum"));
+        let plan = error.into_plan();
+        assert_eq!(plan.path[0], Point3::new(0.0, 0.0, 0.0));
+        assert_eq!(plan.diagnostic.steps as usize, plan.path.len() - 1);
+        assert_eq!(plan.diagnostic.remaining_distance, distance(plan.path.last().unwrap(), &GOAL));
+        assert_eq!(plan.diagnostic.local_minima + plan.diagnostic.oscillations, 1);
+        assert!(plan.diagnostic.escapes.is_empty());
+        // Stuck inside the trap, in front of its bottom
+        let end = plan.path.last().unwrap();
+        assert!(end.x > 0.5 && end.x < 2.0 && end.y.abs() < 0.5 && end.z.abs() < 0.5, "{:?}", end);
+
+        // Every allowed escape is used and recorded before giving up
+        let mut config = trap_config(vec![EscapeStrategy::VirtualObstacle], 0);
+        config.escape.max_escapes = 2;
+        let plan = apf_plan(Point3::new(0.0, 0.0, 0.0), GOAL, &octree, config).unwrap_err().into_plan();
+        assert_eq!(plan.diagnostic.escapes.len(), 2);
+        assert_eq!(plan.diagnostic.local_minima + plan.diagnostic.oscillations, 3);
+        assert_eq!(plan.diagnostic.escapes[0].position, plan.path[plan.diagnostic.escapes[0].step as usize]);
+    }
+
+    #[test]
+    fn running_out_of_steps_returns_the_partial_path() {
+        let mut config = trap_config(Vec::new(), 0);
+        config.max_steps = 5;
+        let error = apf_plan(Point3::new(0.0, 0.0, 0.0), GOAL, &u_trap(), config).unwrap_err();
+        assert!(matches!(error, ApfError::MaxStepsReached(_)));
+        let plan = error.plan();
+        assert_eq!(plan.path.len(), 6);
+        assert!((plan.diagnostic.remaining_distance - (4.0 - 0.5)).abs() < 1e-4);
+    }
+}
+
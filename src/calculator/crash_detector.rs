@@ -1,7 +1,8 @@
 #![allow(dead_code)]
+use bevy::ecs::system::Resource;
 use crate::octree::octree;
 use crate::data_reader::structor::LaserPoint;
-use crate::calculator::mavlink_args::MavlinkArgs;
+use crate::calculator::mavlink_args::{MavFrame, MavlinkArgs};
 use crate::calculator::coordinate_switch::mid360_to_frd;
 
 fn distance_calculator(
@@ -33,102 +34,528 @@ pub fn crash_warn_for_octree(
     octree_input: &octree::Octree,
     warn_trigger_distance: f32,
 ) -> (bool, Vec<(f32, [f32; 3])>) {
-    // TODO: change input to map
-    let octree_map = octree_input.octree_to_map();
-    let mut result = false;
-    let mut obstacle_list: Vec<(f32, [f32; 3])> = Vec::new();
+    let obstacle_list = octree_input.radius_search([0.0, 0.0, 0.0], warn_trigger_distance * 3.0);
+    let result = !obstacle_list.is_empty();
+    return (result, obstacle_list);
+}
 
-    for (_, points)  in octree_map {
-        for point in points {
-            let x = point.x;
-            let y = point.y;
-            let z = point.z;
-            let distance = distance_calculator([x, y, z], [0.0, 0.0, 0.0]);
-            if distance < warn_trigger_distance * 3.0 {
-                result = true;
-                obstacle_list.push((distance, [x, y, z]));
-            }
+/// Radius of the vehicle around the sensor, shared by collision checks, planners and avoidance
+pub const VEHICLE_RADIUS: f32 = 0.35;
+
+/// Added to `VEHICLE_RADIUS` since leaves are only known by their center, half a leaf of the default map
+pub const LEAF_INFLATION: f32 = 0.04;
+
+/// Closest a leaf center may come to the sensor
+pub const VEHICLE_CLEARANCE: f32 = VEHICLE_RADIUS + LEAF_INFLATION;
+
+/// How urgent a predicted collision is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum CollisionSeverity {
+    #[default]
+    Clear,      // Nothing hit within the horizon
+    Caution,    // Hit within the horizon
+    Warning,    // Hit within `warning_time`
+    Critical,   // Hit within `critical_time`, or already touching
+}
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct CollisionConfig {
+    pub vehicle_radius: f32,      // Radius of the capsule around the vehicle
+    pub vehicle_half_height: f32, // Half length of the capsule axis, 0 for a sphere
+    pub inflation: f32,           // Added to the radius since leaves are only known by their center
+    pub horizon: f32,             // Seconds to look ahead
+    pub warning_time: f32,        // Time to collision that counts as a warning
+    pub critical_time: f32,       // Time to collision that counts as critical
+}
+
+impl Default for CollisionConfig {
+    fn default() -> Self {
+        CollisionConfig {
+            vehicle_radius: VEHICLE_RADIUS,
+            vehicle_half_height: 0.1,
+            inflation: LEAF_INFLATION,
+            horizon: 5.0,
+            warning_time: 2.5,
+            critical_time: 1.0,
         }
     }
-    return (result, obstacle_list);
 }
 
-// TODO: implement speed_factor
-/// Return velocity vector to avoid obstacles
+#[derive(Debug, Clone, Copy, PartialEq, Default, Resource)]
+pub struct CollisionPrediction {
+    pub time_to_collision: Option<f32>, // Seconds until first contact, `None` if clear
+    pub hit_voxel: Option<[f32; 3]>,    // Center of the first leaf hit, Mid-360 frame
+    pub severity: CollisionSeverity,
+}
+
+/// Sweep the vehicle capsule along `velocity` and find the first occupied leaf it touches.
+///
+/// The capsule axis is vertical and centered on the sensor. `velocity` is in the Mid-360
+/// frame in m/s. Unlike `crash_warn_for_octree`, obstacles the vehicle moves away from
+/// are never reported.
+pub fn predict_collision(
+    octree_input: &octree::Octree,
+    velocity: [f32; 3],
+    config: &CollisionConfig,
+) -> CollisionPrediction {
+    let radius = config.vehicle_radius + config.inflation;
+    let speed = distance_calculator(velocity, [0.0, 0.0, 0.0]);
+    let search_radius = speed * config.horizon + config.vehicle_half_height + radius;
+
+    let mut first: Option<(f32, [f32; 3])> = None;
+    for (_, center) in octree_input.radius_search([0.0, 0.0, 0.0], search_radius) {
+        let Some(time) = capsule_contact_time(center, velocity, config.vehicle_half_height, radius) else {
+            continue;
+        };
+        if time <= config.horizon && first.is_none_or(|(first_time, _)| time < first_time) {
+            first = Some((time, center));
+        }
+    }
+
+    let Some((time, center)) = first else {
+        return CollisionPrediction::default();
+    };
+    let severity = if time <= config.critical_time {
+        CollisionSeverity::Critical
+    } else if time <= config.warning_time {
+        CollisionSeverity::Warning
+    } else {
+        CollisionSeverity::Caution
+    };
+    CollisionPrediction { time_to_collision: Some(time), hit_voxel: Some(center), severity }
+}
+
+/// First time `t >= 0` at which `point` lies within `radius` of the vertical segment
+/// from `-half_height` to `half_height` moving with `velocity`, if ever
+fn capsule_contact_time(point: [f32; 3], velocity: [f32; 3], half_height: f32, radius: f32) -> Option<f32> {
+    // Relative to the vehicle the point moves with -velocity. The capsule is the union of
+    // two end spheres and a finite cylinder, the earliest entry into any of them wins.
+    let [px, py, pz] = point;
+    let [vx, vy, vz] = velocity;
+    let mut pieces = Vec::with_capacity(3);
+    for end in [-half_height, half_height] {
+        let dz = pz - end;
+        pieces.push(quadratic_interval(
+            vx * vx + vy * vy + vz * vz,
+            -2.0 * (px * vx + py * vy + dz * vz),
+            px * px + py * py + dz * dz - radius * radius,
+        ));
+    }
+    let side = quadratic_interval(vx * vx + vy * vy, -2.0 * (px * vx + py * vy), px * px + py * py - radius * radius);
+    let slab = if vz.abs() < 1e-9 {
+        (pz.abs() <= half_height).then_some((f32::NEG_INFINITY, f32::INFINITY))
+    } else {
+        let (a, b) = ((pz - half_height) / vz, (pz + half_height) / vz);
+        Some((a.min(b), a.max(b)))
+    };
+    pieces.push(match (side, slab) {
+        (Some(side), Some(slab)) => {
+            let interval = (side.0.max(slab.0), side.1.min(slab.1));
+            (interval.0 <= interval.1).then_some(interval)
+        }
+        _ => None,
+    });
+
+    pieces
+        .into_iter()
+        .flatten()
+        .filter(|(_, exit)| *exit >= 0.0)
+        .map(|(entry, _)| entry.max(0.0))
+        .min_by(|a, b| a.total_cmp(b))
+}
+
+/// Interval of `t` where `a t^2 + b t + c <= 0`, for `a >= 0`
+fn quadratic_interval(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    if a < 1e-9 {
+        // Not moving in these axes, either always or never inside
+        return (c <= 0.0).then_some((f32::NEG_INFINITY, f32::INFINITY));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    Some(((-b - root) / (2.0 * a), (-b + root) / (2.0 * a)))
+}
+
+/// Avoidance velocities are forward, right and down along the vehicle heading, i.e. FRD
+/// without roll and pitch. SET_POSITION_TARGET_LOCAL_NED only takes local frames, and
+/// BODY_OFFSET_NED is the one PX4 and ArduPilot read as exactly these axes.
+pub const AVOIDANCE_FRAME: MavFrame = MavFrame::BodyOffsetNed;
+
+/// Which local avoidance turns the map into velocity setpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AvoidanceMethod {
+    /// Inverse-cube repulsion in `obstacle_avoidance`, only acts near obstacles
+    #[default]
+    Repulsion,
+    /// VFH+ in `vfh::vfh_avoidance`, steers towards the goal through free directions
+    Vfh,
+}
+
+impl AvoidanceMethod {
+    pub fn parse(method: &str) -> Result<Self, String> {
+        match method {
+            "repulsion" => Ok(AvoidanceMethod::Repulsion),
+            "vfh" => Ok(AvoidanceMethod::Vfh),
+            _ => Err(format!("Unknown avoidance method {}, expected repulsion or vfh", method)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct AvoidanceConfig {
+    pub method: AvoidanceMethod,
+    pub warn_distance: f32,     // Avoidance takes over inside this clearance
+    pub stop_distance: f32,     // Clearance the vehicle must never get below
+    pub max_speed: f32,         // Escape speed at `stop_distance`, and overall limit
+    pub max_acceleration: f32,  // Limit for braking and for changes between commands
+    pub yaw_rate: f32,          // Turn rate while looking for a way out
+    pub yaw_search_timeout: f32, // Seconds of turning before giving up and holding
+}
+
+impl Default for AvoidanceConfig {
+    fn default() -> Self {
+        AvoidanceConfig {
+            method: AvoidanceMethod::Repulsion,
+            warn_distance: 0.7,
+            stop_distance: VEHICLE_CLEARANCE,
+            max_speed: 1.0,
+            max_acceleration: 2.0,
+            yaw_rate: 0.5,
+            yaw_search_timeout: 5.0,
+        }
+    }
+}
+
+/// What `obstacle_avoidance` remembers between two commands
+#[derive(Debug, Clone, Default, Resource)]
+pub struct AvoidanceState {
+    /// Last commanded velocity in `AVOIDANCE_FRAME` axes, the start point of the acceleration limit
+    pub last_velocity: Option<[f32; 3]>,
+    /// Seconds spent turning in place so far, `None` while not searching
+    pub yaw_search_time: Option<f32>,
+}
+
+/// Return a velocity setpoint that keeps the vehicle away from obstacles.
+///
+/// Inside `warn_distance` the vehicle keeps moving along obstacles, but its speed towards
+/// the nearest one is cut to what it can still brake from before `stop_distance`. On top
+/// of that it is pushed away, faster the closer it gets. `current_velocity` is the
+/// vehicle velocity in `AVOIDANCE_FRAME` axes if telemetry is available, `dt` the time
+/// since the last call.
 pub fn obstacle_avoidance(
-    obstacle_list: &Vec<(f32, [f32; 3])>,
-    warn_trigger_distance: f32,
-    //mavlink_args: &MavlinkArgs,
+    obstacle_list: &[(f32, [f32; 3])],
+    config: &AvoidanceConfig,
+    state: &mut AvoidanceState,
+    current_velocity: Option<[f32; 3]>,
+    dt: f32,
 ) -> MavlinkArgs {
     const EPSILON: f32 = 1e-6;
-    const MAX_SPEED: f32 = 1.0;
-    let mut result = MavlinkArgs::new(
-        0,
-        1,
-        1,
-        9,
-        0b0000001000000000,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-        0.0,
-    );
-    if obstacle_list.is_empty() {
-        return result;
-    }
-    let mut sorted_obstacle_list = obstacle_list.clone();
-    sorted_obstacle_list.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-    if sorted_obstacle_list[0].0 > warn_trigger_distance {
-        return result;
-    }
+    let nearest = obstacle_list
+        .iter()
+        .filter(|(distance, _)| *distance <= config.warn_distance)
+        .min_by(|a, b| a.0.total_cmp(&b.0));
+    let Some(&(clearance, [x, y, z])) = nearest else {
+        // Nothing close, slow down to a hold
+        state.yaw_search_time = None;
+        return velocity_command([0.0; 3], config, state, current_velocity, dt);
+    };
+
     let (mut sum_x, mut sum_y, mut sum_z) = (0.0, 0.0, 0.0);
-    for &(distance, [x, y, z]) in &sorted_obstacle_list {
+    for &(distance, [x, y, z]) in obstacle_list {
+        if distance > config.warn_distance {
+            continue;
+        }
         let weight = 1.0 / (distance.powi(3) + EPSILON);
         sum_x += -x * weight;
         sum_y += -y * weight;
-         sum_z += -z * weight;
+        sum_z += -z * weight;
     }
+    let mut magnitude = (sum_x.powi(2) + sum_y.powi(2) + sum_z.powi(2)).sqrt();
+    if magnitude < EPSILON {
+        // Surrounded evenly, back away from the nearest obstacle instead
+        (sum_x, sum_y, sum_z) = (-x, -y, -z);
+        magnitude = distance_calculator([x, y, z], [0.0, 0.0, 0.0]);
+    }
+    if magnitude < EPSILON {
+        // No direction to go, turn to get a different view, and hold once that takes too long
+        let search_time = state.yaw_search_time.unwrap_or(0.0) + dt;
+        state.yaw_search_time = Some(search_time);
+        if search_time <= config.yaw_search_timeout {
+            state.last_velocity = Some([0.0; 3]);
+            return MavlinkArgs::yaw_rate_only(AVOIDANCE_FRAME, config.yaw_rate);
+        }
+        return velocity_command([0.0; 3], config, state, current_velocity, dt);
+    }
+    state.yaw_search_time = None;
 
-    let _minimum_distance = sorted_obstacle_list[0].0;
-    let speed = MAX_SPEED;
+    // Change O-XYZ to the heading-aligned FRD axes of `AVOIDANCE_FRAME`. The sensor tilt
+    // is ignored, which holds for the small roll and pitch of a vehicle this close to obstacles.
+    let escape = mid360_to_frd(sum_x / magnitude, sum_y / magnitude, sum_z / magnitude);
+    let (to_x, to_y, to_z) = mid360_to_frd(x, y, z);
+    let to_obstacle = [to_x / clearance.max(EPSILON), to_y / clearance.max(EPSILON), to_z / clearance.max(EPSILON)];
 
-    let magnitude = (sum_x.powi(2) + sum_y.powi(2) + sum_z.powi(2)).sqrt();
-    if magnitude < EPSILON {
-        let (_, [x, y, z]) = sorted_obstacle_list[0];
-        let dir_mag = distance_calculator([x, y, z], [0.0, 0.0, 0.0]);
-        if dir_mag < EPSILON {
-            result.type_mask = 0b010111111111;
-            result.yaw_rate = 0.5; // TODO: stop after a while
-            return result;
+    // Brake the approach to what can still stop before `stop_distance`
+    let mut velocity = current_velocity.unwrap_or([0.0; 3]);
+    let approach = dot(velocity, to_obstacle);
+    let braking_speed = (2.0 * config.max_acceleration * (clearance - config.stop_distance).max(0.0)).sqrt();
+    if approach > braking_speed {
+        for i in 0..3 {
+            velocity[i] -= to_obstacle[i] * (approach - braking_speed);
+        }
+    }
+
+    // Push away, from nothing at the warning distance to full speed at the stop distance
+    let depth = (config.warn_distance - clearance) / (config.warn_distance - config.stop_distance).max(EPSILON);
+    let escape_speed = config.max_speed * depth.clamp(0.0, 1.0);
+    velocity[0] += escape.0 * escape_speed;
+    velocity[1] += escape.1 * escape_speed;
+    velocity[2] += escape.2 * escape_speed;
+    velocity_command(velocity, config, state, current_velocity, dt)
+}
+
+/// Limit `velocity` to `max_speed` and its change since the last command to
+/// `max_acceleration`, then build the setpoint
+fn velocity_command(
+    velocity: [f32; 3],
+    config: &AvoidanceConfig,
+    state: &mut AvoidanceState,
+    current_velocity: Option<[f32; 3]>,
+    dt: f32,
+) -> MavlinkArgs {
+    let mut velocity = scale_to(velocity, config.max_speed);
+    if let Some(reference) = state.last_velocity.or(current_velocity) {
+        let change = [velocity[0] - reference[0], velocity[1] - reference[1], velocity[2] - reference[2]];
+        let change = scale_to(change, config.max_acceleration * dt.max(0.0));
+        velocity = [reference[0] + change[0], reference[1] + change[1], reference[2] + change[2]];
+    }
+    state.last_velocity = Some(velocity);
+    MavlinkArgs::velocity_setpoint(AVOIDANCE_FRAME, velocity[0], velocity[1], velocity[2])
+}
+
+/// `vector` shortened to at most `max_length`
+fn scale_to(vector: [f32; 3], max_length: f32) -> [f32; 3] {
+    let length = dot(vector, vector).sqrt();
+    if length <= max_length || length == 0.0 {
+        return vector;
+    }
+    let scale = max_length / length;
+    [vector[0] * scale, vector[1] * scale, vector[2] * scale]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    fn assert_same_setpoint(a: &MavlinkArgs, b: &MavlinkArgs) {
+        assert_eq!((a.coordinate_frame, a.type_mask), (b.coordinate_frame, b.type_mask));
+        assert_eq!([a.vx, a.vy, a.vz, a.yaw_rate], [b.vx, b.vy, b.vz, b.yaw_rate]);
+    }
+
+    const MAX_DEPTH: u32 = 7;
+
+    /// Leaf size of the test octree
+    const VOXEL: f32 = 20.0 / (1 << MAX_DEPTH) as f32;
+
+    /// Octree from Mid-360 points (x forward, y left, z up)
+    fn map(points: &[[f32; 3]]) -> octree::Octree {
+        let mut octree = octree::Octree::new([[-10.0; 3], [10.0; 3]]);
+        for point in points {
+            octree.insert(*point, MAX_DEPTH, 100).unwrap();
+        }
+        octree
+    }
+
+    /// Wall across the x axis at `x`, 4 m wide and 2 m high
+    fn wall(x: f32) -> octree::Octree {
+        let points: Vec<[f32; 3]> = (0..=80)
+            .flat_map(|i| (0..=40).map(move |j| [x, -2.0 + i as f32 * 0.05, -1.0 + j as f32 * 0.05]))
+            .collect();
+        map(&points)
+    }
+
+    #[test]
+    fn wall_ahead_is_hit_with_the_contact_time() {
+        let config = CollisionConfig::default();
+        let radius = config.vehicle_radius + config.inflation;
+        let octree = wall(3.0);
+        for speed in [1.0, 2.0] {
+            let prediction = predict_collision(&octree, [speed, 0.0, 0.0], &config);
+            let hit = prediction.hit_voxel.unwrap();
+            assert!((hit[0] - 3.0).abs() < VOXEL && hit[1].abs() < VOXEL, "{:?}", hit);
+            assert!(hit[2].abs() <= config.vehicle_half_height, "{:?}", hit);
+            // The leaf center reaches the side of the capsule first
+            let expected = (hit[0] - (radius * radius - hit[1] * hit[1]).sqrt()) / speed;
+            assert_close(prediction.time_to_collision.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn wall_behind_is_not_hit() {
+        let config = CollisionConfig::default();
+        let prediction = predict_collision(&wall(-3.0), [2.0, 0.0, 0.0], &config);
+        assert_eq!(prediction, CollisionPrediction::default());
+        // Moving along the wall does not hit it either
+        let prediction = predict_collision(&wall(1.0), [0.0, 2.0, 0.0], &config);
+        assert_eq!(prediction.severity, CollisionSeverity::Clear);
+    }
+
+    #[test]
+    fn touching_without_moving_is_critical() {
+        let config = CollisionConfig::default();
+        let prediction = predict_collision(&map(&[[0.2, 0.0, 0.0]]), [0.0; 3], &config);
+        assert_eq!(prediction.severity, CollisionSeverity::Critical);
+        assert_eq!(prediction.time_to_collision, Some(0.0));
+
+        let prediction = predict_collision(&wall(3.0), [0.0; 3], &config);
+        assert_eq!(prediction.severity, CollisionSeverity::Clear);
+    }
+
+    #[test]
+    fn severity_follows_the_time_to_collision() {
+        let config = CollisionConfig::default();
+        let octree = wall(3.0);
+        // Contact after about 2.6 m
+        for (speed, severity) in [
+            (0.5, CollisionSeverity::Clear),
+            (1.0, CollisionSeverity::Caution),
+            (2.0, CollisionSeverity::Warning),
+            (4.0, CollisionSeverity::Critical),
+        ] {
+            let prediction = predict_collision(&octree, [speed, 0.0, 0.0], &config);
+            assert_eq!(prediction.severity, severity, "{} m/s: {:?}", speed, prediction);
+            if let Some(time) = prediction.time_to_collision {
+                assert!(time <= config.horizon);
+            }
+        }
+    }
+
+    #[test]
+    fn capsule_reaches_further_vertically() {
+        let config = CollisionConfig::default();
+        let octree = map(&[[0.0, 0.0, -1.0]]);
+        let prediction = predict_collision(&octree, [0.0, 0.0, -1.0], &config);
+        let hit = prediction.hit_voxel.unwrap();
+        // The lower end sphere touches first
+        let radius = config.vehicle_radius + config.inflation;
+        let horizontal = hit[0] * hit[0] + hit[1] * hit[1];
+        let expected = -hit[2] - config.vehicle_half_height - (radius * radius - horizontal).sqrt();
+        assert_close(prediction.time_to_collision.unwrap(), expected);
+        assert_eq!(prediction.severity, CollisionSeverity::Critical);
+    }
+
+    /// One obstacle straight ahead at `clearance`, given in the Mid-360 frame
+    fn ahead(clearance: f32) -> Vec<(f32, [f32; 3])> {
+        vec![(clearance, [clearance, 0.0, 0.0])]
+    }
+
+    #[test]
+    fn approach_is_braked_to_the_stopping_speed() {
+        let config = AvoidanceConfig::default();
+        let mut state = AvoidanceState::default();
+        // A long `dt` keeps the acceleration limit out of the way
+        let command = obstacle_avoidance(&ahead(0.5), &config, &mut state, Some([2.0, 0.5, 0.0]), 10.0);
+        let braking_speed = (2.0 * config.max_acceleration * (0.5 - config.stop_distance)).sqrt();
+        let depth = (config.warn_distance - 0.5) / (config.warn_distance - config.stop_distance);
+        let escape_speed = config.max_speed * depth;
+        let expected = [braking_speed - escape_speed, 0.5, 0.0];
+        let speed = dot(expected, expected).sqrt();
+        assert!(speed < config.max_speed);
+        assert_eq!(command.coordinate_frame, AVOIDANCE_FRAME as u8);
+        assert_close(command.vx, expected[0]);
+        assert_close(command.vy, expected[1]);
+        assert_close(command.vz, expected[2]);
+        assert_eq!(state.last_velocity, Some([command.vx, command.vy, command.vz]));
+    }
+
+    #[test]
+    fn slower_approach_is_not_braked() {
+        let config = AvoidanceConfig::default();
+        let mut state = AvoidanceState::default();
+        let command = obstacle_avoidance(&ahead(0.6), &config, &mut state, Some([0.5, 0.0, 0.0]), 10.0);
+        // Only the escape push is added
+        let depth = (config.warn_distance - 0.6) / (config.warn_distance - config.stop_distance);
+        assert_close(command.vx, 0.5 - depth * config.max_speed);
+    }
+
+    #[test]
+    fn commanded_speed_shrinks_with_clearance() {
+        let config = AvoidanceConfig::default();
+        let mut previous = f32::INFINITY;
+        for clearance in [0.7, 0.6, 0.5, 0.4, 0.3] {
+            let mut state = AvoidanceState::default();
+            let command = obstacle_avoidance(&ahead(clearance), &config, &mut state, Some([1.0, 0.0, 0.0]), 10.0);
+            assert!(command.vx < previous, "{} m: {} after {}", clearance, command.vx, previous);
+            previous = command.vx;
+        }
+        // At the stop distance the vehicle backs off at full speed
+        assert_close(previous, -config.max_speed);
+    }
+
+    #[test]
+    fn change_is_limited_by_the_acceleration() {
+        let config = AvoidanceConfig::default();
+        let mut state = AvoidanceState { last_velocity: Some([0.0; 3]), yaw_search_time: None };
+        let dt = 0.1;
+        let command = obstacle_avoidance(&ahead(0.3), &config, &mut state, None, dt);
+        assert_close(command.vx, -config.max_acceleration * dt);
+        assert_close(command.vy, 0.0);
+
+        // The last command is the reference, not the telemetry velocity
+        let command = obstacle_avoidance(&ahead(0.3), &config, &mut state, Some([5.0, 0.0, 0.0]), dt);
+        assert_close(command.vx, -2.0 * config.max_acceleration * dt);
+    }
+
+    #[test]
+    fn nothing_close_slows_down_to_a_hold() {
+        let config = AvoidanceConfig::default();
+        let mut state = AvoidanceState::default();
+        let far = [(2.0, [2.0, 0.0, 0.0])];
+        let command = obstacle_avoidance(&far, &config, &mut state, Some([1.0, 0.0, 0.0]), 0.1);
+        assert_close(command.vx, 1.0 - config.max_acceleration * 0.1);
+        for _ in 0..10 {
+            obstacle_avoidance(&far, &config, &mut state, None, 0.1);
         }
-        else {
-            result.type_mask = 0b0000001000000000;
-            let vx = speed * -x / dir_mag;
-            let vy = speed * -y / dir_mag; // Change O-XYZ to O-FRD
-            let vz = speed * -z / dir_mag;
-            let (vx, vy, vz) = mid360_to_frd(vx, vy, vz);
-            result.vx = vx;
-            result.vy = vy;
-            result.vz = vz;
-            return result;
+        assert_eq!(state.last_velocity, Some([0.0; 3]));
+    }
+
+    #[test]
+    fn yaw_search_holds_after_the_timeout() {
+        let config = AvoidanceConfig::default();
+        let mut state = AvoidanceState::default();
+        // An obstacle on the sensor itself gives no direction to escape in
+        let inside = [(0.0, [0.0; 3])];
+        let searching = (config.yaw_search_timeout / 0.5) as usize;
+        for _ in 0..searching {
+            let command = obstacle_avoidance(&inside, &config, &mut state, None, 0.5);
+            assert_same_setpoint(&command, &MavlinkArgs::yaw_rate_only(AVOIDANCE_FRAME, config.yaw_rate));
         }
+        let command = obstacle_avoidance(&inside, &config, &mut state, None, 0.5);
+        assert_same_setpoint(&command, &MavlinkArgs::velocity_setpoint(AVOIDANCE_FRAME, 0.0, 0.0, 0.0));
+        assert_close(state.yaw_search_time.unwrap(), config.yaw_search_timeout + 0.5);
+
+        // Any direction to go resets the search
+        obstacle_avoidance(&ahead(0.5), &config, &mut state, None, 0.5);
+        assert_eq!(state.yaw_search_time, None);
     }
-    else {
-        result.type_mask = 0b0000001000000000;
-        let vx = speed * sum_x / magnitude;
-        let vy = speed * sum_y / magnitude; // Change O-XYZ to O-FRD
-        let vz = speed * sum_z / magnitude;
-        let (vx, vy, vz) = mid360_to_frd(vx, vy, vz);
-        result.vx = vx;
-        result.vy = vy;
-        result.vz = vz;
-        return result;
+
+    #[test]
+    fn evenly_surrounded_backs_away_from_the_nearest() {
+        let config = AvoidanceConfig::default();
+        let mut state = AvoidanceState::default();
+        let sides = [(0.4, [0.0, 0.4, 0.0]), (0.4, [0.0, -0.4, 0.0])];
+        let command = obstacle_avoidance(&sides, &config, &mut state, None, 10.0);
+        // Obstacle left in the Mid-360 frame, so escape to the right in FRD
+        assert!(command.vy > 0.0);
+        assert_close(command.vx, 0.0);
     }
 }
@@ -0,0 +1,505 @@
+#![allow(dead_code)]
+use std::collections::VecDeque;
+use bevy::ecs::system::Resource;
+use bevy::math::Vec3;
+use crate::calculator::crash_detector::{CollisionSeverity, AVOIDANCE_FRAME};
+use crate::calculator::mavlink_args::{MavFrame, MavlinkArgs, PositionTargetTypeMask};
+
+/// What the vehicle is doing, from normal flight to the last resort
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum BehaviourState {
+    /// Follow the planned trajectory
+    #[default]
+    Nominal,
+    /// Only the local avoidance flies, capped to a slow speed
+    Caution,
+    /// Brake and hold where the vehicle is
+    Hover,
+    /// Retrace the breadcrumb trail to where the vehicle came from
+    BackOff,
+    /// Descend in place, only left through `Behaviour::reset`
+    Land,
+}
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct BehaviourConfig {
+    pub caution_speed: f32,     // Avoidance commands are capped to this speed in Caution
+    pub recovery_time: f32,     // Seconds without a cause before stepping down one state
+    pub hover_time: f32,        // Seconds a cause may last in Hover before backing off
+    pub planner_failures: u32,  // Consecutive failed plans that make the vehicle hover
+    pub back_off_distance: f32, // Trail length retraced by one back-off
+    pub max_back_offs: u32,     // Back-offs without getting back to Nominal before landing
+    pub sensor_timeout: f32,    // Seconds without a lidar frame until the sensor counts as lost
+    pub sensor_loss_time: f32,  // Seconds with the sensor lost before landing
+    pub crumb_acceptance: f32,  // A breadcrumb counts as reached this close
+    pub descent_rate: f32,      // Landing speed in m/s
+}
+
+impl Default for BehaviourConfig {
+    fn default() -> Self {
+        Self {
+            caution_speed: 0.5,
+            recovery_time: 1.0,
+            hover_time: 2.0,
+            planner_failures: 3,
+            back_off_distance: 2.0,
+            max_back_offs: 3,
+            sensor_timeout: 0.5,
+            sensor_loss_time: 3.0,
+            crumb_acceptance: 0.2,
+            descent_rate: 0.5,
+        }
+    }
+}
+
+/// Everything the transitions look at, gathered once per update
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BehaviourInputs {
+    pub severity: CollisionSeverity,
+    pub sensor_healthy: bool,
+    pub planner_failures: u32,  // Consecutive failed plans, 0 after a success
+    pub avoidance_stuck: bool,  // The local avoidance found no way out and turns in place
+    pub position: Option<Vec3>, // Local NED, `None` without telemetry
+}
+
+/// Positions the vehicle flew through in local NED, oldest first, at least `spacing` apart
+#[derive(Debug, Clone, Resource)]
+pub struct BreadcrumbTrail {
+    crumbs: VecDeque<Vec3>,
+    spacing: f32,
+    max_crumbs: usize,
+}
+
+impl BreadcrumbTrail {
+    pub fn new(spacing: f32, max_crumbs: usize) -> Self {
+        Self { crumbs: VecDeque::new(), spacing, max_crumbs }
+    }
+
+    /// Drop a crumb at `position` once it is `spacing` away from the last one.
+    /// The oldest crumb goes when the trail is full.
+    pub fn record(&mut self, position: Vec3) {
+        if self.crumbs.back().is_some_and(|last| last.distance(position) < self.spacing) {
+            return;
+        }
+        if self.crumbs.len() == self.max_crumbs {
+            self.crumbs.pop_front();
+        }
+        self.crumbs.push_back(position);
+    }
+
+    /// Take the newest crumb off the trail
+    pub fn pop(&mut self) -> Option<Vec3> {
+        self.crumbs.pop_back()
+    }
+
+    pub fn crumbs(&self) -> impl Iterator<Item = &Vec3> {
+        self.crumbs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.crumbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.crumbs.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.crumbs.clear();
+    }
+}
+
+impl Default for BreadcrumbTrail {
+    fn default() -> Self {
+        Self::new(0.5, 200)
+    }
+}
+
+/// The emergency state machine. Escalates straight to the state the inputs call for and
+/// steps back down one state at a time once the cause is gone for `recovery_time`.
+#[derive(Debug, Clone, Default, Resource)]
+pub struct Behaviour {
+    state: BehaviourState,
+    time_in_state: f32,
+    calm_time: f32,        // Seconds the inputs have not called for the current state
+    sensor_lost_time: f32,
+    back_offs: u32,        // Since the last time in Nominal
+    hold_position: Option<Vec3>,
+    back_off_target: Option<Vec3>,
+    back_off_left: f32,    // Trail length still to retrace
+}
+
+impl Behaviour {
+    pub fn state(&self) -> BehaviourState {
+        self.state
+    }
+
+    /// Back to Nominal, e.g. once the vehicle landed and disarmed
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Where the vehicle is heading while backing off, local NED
+    pub fn back_off_target(&self) -> Option<Vec3> {
+        self.back_off_target
+    }
+
+    /// Advance by `dt` seconds. Returns the new state when it changed.
+    pub fn update(
+        &mut self,
+        inputs: &BehaviourInputs,
+        trail: &mut BreadcrumbTrail,
+        config: &BehaviourConfig,
+        dt: f32,
+    ) -> Option<BehaviourState> {
+        let previous = self.state;
+        self.time_in_state += dt;
+        self.sensor_lost_time = if inputs.sensor_healthy { 0.0 } else { self.sensor_lost_time + dt };
+
+        let demand = if !inputs.sensor_healthy {
+            if self.sensor_lost_time >= config.sensor_loss_time {
+                BehaviourState::Land
+            } else {
+                BehaviourState::Hover
+            }
+        } else if inputs.severity == CollisionSeverity::Critical
+            || inputs.planner_failures >= config.planner_failures
+            || inputs.avoidance_stuck
+        {
+            BehaviourState::Hover
+        } else if inputs.severity >= CollisionSeverity::Warning || inputs.planner_failures > 0 {
+            BehaviourState::Caution
+        } else {
+            BehaviourState::Nominal
+        };
+
+        match self.state {
+            BehaviourState::Land => {}
+            _ if demand == BehaviourState::Land => self.enter(BehaviourState::Land, inputs),
+            BehaviourState::BackOff => self.back_off(inputs, trail, config),
+            BehaviourState::Hover => {
+                if demand < BehaviourState::Hover {
+                    self.calm_time += dt;
+                    if self.calm_time >= config.recovery_time {
+                        self.enter(BehaviourState::Caution, inputs);
+                    }
+                } else {
+                    self.calm_time = 0.0;
+                    // Backing off blind is not safer than waiting for the sensor
+                    if self.time_in_state >= config.hover_time && inputs.sensor_healthy {
+                        self.start_back_off(inputs, trail, config);
+                    }
+                }
+            }
+            BehaviourState::Nominal | BehaviourState::Caution => {
+                if demand > self.state {
+                    self.enter(demand, inputs);
+                } else if demand == self.state {
+                    self.calm_time = 0.0;
+                } else {
+                    self.calm_time += dt;
+                    if self.calm_time >= config.recovery_time {
+                        self.enter(BehaviourState::Nominal, inputs);
+                        self.back_offs = 0;
+                    }
+                }
+            }
+        }
+        (self.state != previous).then_some(self.state)
+    }
+
+    /// Setpoint for the current state. `nominal` is what Nominal flies, usually the planned
+    /// trajectory, `avoidance` the local avoidance command that also stands in for it.
+    pub fn command(&self, nominal: Option<MavlinkArgs>, avoidance: Option<MavlinkArgs>, config: &BehaviourConfig) -> MavlinkArgs {
+        let stop = MavlinkArgs::velocity_setpoint(AVOIDANCE_FRAME, 0.0, 0.0, 0.0);
+        match self.state {
+            BehaviourState::Nominal => nominal.or(avoidance).unwrap_or(stop),
+            BehaviourState::Caution => avoidance.map_or(stop, |command| capped_velocity(command, config.caution_speed)),
+            BehaviourState::Hover => match self.hold_position {
+                Some(position) => MavlinkArgs::position_setpoint(MavFrame::LocalNed, position.x, position.y, position.z),
+                None => stop,
+            },
+            BehaviourState::BackOff => match self.back_off_target {
+                Some(target) => MavlinkArgs::position_setpoint(MavFrame::LocalNed, target.x, target.y, target.z),
+                None => stop,
+            },
+            // NED, down is positive
+            BehaviourState::Land => MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 0.0, 0.0, config.descent_rate),
+        }
+    }
+
+    fn enter(&mut self, state: BehaviourState, inputs: &BehaviourInputs) {
+        self.state = state;
+        self.time_in_state = 0.0;
+        self.calm_time = 0.0;
+        if state == BehaviourState::Hover {
+            self.hold_position = inputs.position;
+        }
+        if state != BehaviourState::BackOff {
+            self.back_off_target = None;
+        }
+    }
+
+    /// Retrace the trail from Hover, or land if backing off did not help often enough.
+    /// Without a trail or a pose the vehicle keeps hovering.
+    fn start_back_off(&mut self, inputs: &BehaviourInputs, trail: &mut BreadcrumbTrail, config: &BehaviourConfig) {
+        if self.back_offs >= config.max_back_offs {
+            self.enter(BehaviourState::Land, inputs);
+            return;
+        }
+        let Some(position) = inputs.position else {
+            return;
+        };
+        // Crumbs the vehicle is still standing on are no way back
+        while trail.crumbs.back().is_some_and(|crumb| crumb.distance(position) < config.crumb_acceptance) {
+            trail.pop();
+        }
+        let Some(target) = trail.pop() else {
+            return;
+        };
+        self.enter(BehaviourState::BackOff, inputs);
+        self.back_offs += 1;
+        self.back_off_target = Some(target);
+        self.back_off_left = config.back_off_distance - position.distance(target);
+    }
+
+    /// Move on to the next crumb once the current one is reached, and hover at the end
+    fn back_off(&mut self, inputs: &BehaviourInputs, trail: &mut BreadcrumbTrail, config: &BehaviourConfig) {
+        let (Some(position), Some(target)) = (inputs.position, self.back_off_target) else {
+            self.enter(BehaviourState::Hover, inputs);
+            return;
+        };
+        if position.distance(target) > config.crumb_acceptance {
+            return;
+        }
+        let next = if self.back_off_left > 0.0 { trail.pop() } else { None };
+        match next {
+            Some(next) => {
+                self.back_off_left -= target.distance(next);
+                self.back_off_target = Some(next);
+            }
+            None => self.enter(BehaviourState::Hover, inputs),
+        }
+    }
+}
+
+/// `command` with its velocity shortened to at most `max_speed`, commands without a
+/// velocity are passed on as they are
+fn capped_velocity(mut command: MavlinkArgs, max_speed: f32) -> MavlinkArgs {
+    if command.type_mask_flags().contains(PositionTargetTypeMask::VELOCITY_IGNORE) {
+        return command;
+    }
+    let speed = (command.vx.powi(2) + command.vy.powi(2) + command.vz.powi(2)).sqrt();
+    if speed > max_speed {
+        let scale = max_speed / speed;
+        command.vx *= scale;
+        command.vy *= scale;
+        command.vz *= scale;
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exact in binary, so times add up to the thresholds without rounding
+    const DT: f32 = 0.25;
+
+    const POSITION_MASK: PositionTargetTypeMask = PositionTargetTypeMask::VELOCITY_IGNORE
+        .union(PositionTargetTypeMask::ACCELERATION_IGNORE)
+        .union(PositionTargetTypeMask::YAW_IGNORE)
+        .union(PositionTargetTypeMask::YAW_RATE_IGNORE);
+    const VELOCITY_MASK: PositionTargetTypeMask = PositionTargetTypeMask::POSITION_IGNORE
+        .union(PositionTargetTypeMask::ACCELERATION_IGNORE)
+        .union(PositionTargetTypeMask::YAW_IGNORE)
+        .union(PositionTargetTypeMask::YAW_RATE_IGNORE);
+
+    fn inputs(severity: CollisionSeverity, position: Vec3) -> BehaviourInputs {
+        BehaviourInputs { severity, sensor_healthy: true, position: Some(position), ..BehaviourInputs::default() }
+    }
+
+    /// Crumbs every 0.5 m along x from the origin to `length`
+    fn trail(length: f32) -> BreadcrumbTrail {
+        let mut trail = BreadcrumbTrail::default();
+        for i in 0..=(length / 0.5) as usize {
+            trail.record(Vec3::new(i as f32 * 0.5, 0.0, 0.0));
+        }
+        trail
+    }
+
+    /// Update `steps` times with the same inputs, returns the state changes
+    fn run(
+        behaviour: &mut Behaviour,
+        inputs: &BehaviourInputs,
+        trail: &mut BreadcrumbTrail,
+        config: &BehaviourConfig,
+        steps: usize,
+    ) -> Vec<BehaviourState> {
+        (0..steps).filter_map(|_| behaviour.update(inputs, trail, config, DT)).collect()
+    }
+
+    fn assert_position_setpoint(command: MavlinkArgs, position: Vec3) {
+        assert_eq!((command.coordinate_frame, command.type_mask), (MavFrame::LocalNed as u8, POSITION_MASK.bits()));
+        assert_eq!(Vec3::new(command.x, command.y, command.z), position);
+    }
+
+    fn assert_velocity_setpoint(command: MavlinkArgs, frame: MavFrame, velocity: Vec3) {
+        assert_eq!((command.coordinate_frame, command.type_mask), (frame as u8, VELOCITY_MASK.bits()));
+        assert!(Vec3::new(command.vx, command.vy, command.vz).distance(velocity) < 1e-5, "{:?}", command);
+    }
+
+    #[test]
+    fn critical_hovers_then_backs_off() {
+        let config = BehaviourConfig::default();
+        let mut trail = trail(3.0);
+        let mut behaviour = Behaviour::default();
+        let critical = inputs(CollisionSeverity::Critical, Vec3::new(3.0, 0.0, 0.0));
+
+        assert_eq!(behaviour.update(&critical, &mut trail, &config, DT), Some(BehaviourState::Hover));
+        assert_position_setpoint(behaviour.command(None, None, &config), Vec3::new(3.0, 0.0, 0.0));
+        let hover_steps = (config.hover_time / DT) as usize;
+        assert!(run(&mut behaviour, &critical, &mut trail, &config, hover_steps - 1).is_empty());
+        assert_eq!(behaviour.update(&critical, &mut trail, &config, DT), Some(BehaviourState::BackOff));
+
+        // The crumb under the vehicle is skipped
+        assert_eq!(behaviour.back_off_target(), Some(Vec3::new(2.5, 0.0, 0.0)));
+        assert_position_setpoint(behaviour.command(None, None, &config), Vec3::new(2.5, 0.0, 0.0));
+        assert_eq!(trail.len(), 5);
+    }
+
+    #[test]
+    fn back_off_retraces_the_trail_for_back_off_distance() {
+        let config = BehaviourConfig { back_off_distance: 1.5, ..BehaviourConfig::default() };
+        let mut trail = trail(3.0);
+        let mut behaviour = Behaviour::default();
+        let mut critical = inputs(CollisionSeverity::Critical, Vec3::new(3.0, 0.0, 0.0));
+        let hover_steps = (config.hover_time / DT) as usize;
+        assert_eq!(run(&mut behaviour, &critical, &mut trail, &config, hover_steps + 1), vec![BehaviourState::Hover, BehaviourState::BackOff]);
+
+        // Not there yet
+        critical.position = Some(Vec3::new(2.8, 0.0, 0.0));
+        assert_eq!(behaviour.update(&critical, &mut trail, &config, DT), None);
+        assert_eq!(behaviour.back_off_target(), Some(Vec3::new(2.5, 0.0, 0.0)));
+
+        let mut targets = Vec::new();
+        while behaviour.state() == BehaviourState::BackOff {
+            let target = behaviour.back_off_target().unwrap();
+            targets.push(target.x);
+            critical.position = Some(target + Vec3::new(0.0, 0.1, 0.0));
+            behaviour.update(&critical, &mut trail, &config, DT);
+        }
+        assert_eq!(targets, vec![2.5, 2.0, 1.5]);
+        assert_eq!(behaviour.state(), BehaviourState::Hover);
+        assert_position_setpoint(behaviour.command(None, None, &config), Vec3::new(1.5, 0.1, 0.0));
+        assert_eq!(trail.crumbs().map(|crumb| crumb.x).collect::<Vec<_>>(), vec![0.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn lands_after_max_back_offs() {
+        let config = BehaviourConfig::default();
+        let mut trail = trail(20.0);
+        let mut behaviour = Behaviour::default();
+        let mut critical = inputs(CollisionSeverity::Critical, Vec3::new(20.0, 0.0, 0.0));
+        let mut changes = Vec::new();
+        for _ in 0..200 {
+            if let Some(target) = behaviour.back_off_target() {
+                critical.position = Some(target);
+            }
+            changes.extend(behaviour.update(&critical, &mut trail, &config, DT));
+        }
+        let back_off = [BehaviourState::Hover, BehaviourState::BackOff];
+        let mut expected: Vec<BehaviourState> = back_off.repeat(config.max_back_offs as usize);
+        expected.extend([BehaviourState::Hover, BehaviourState::Land]);
+        assert_eq!(changes, expected);
+        assert_velocity_setpoint(behaviour.command(None, None, &config), MavFrame::LocalNed, Vec3::new(0.0, 0.0, config.descent_rate));
+
+        // Only a reset leaves Land
+        let clear = inputs(CollisionSeverity::Clear, Vec3::ZERO);
+        assert!(run(&mut behaviour, &clear, &mut trail, &config, 20).is_empty());
+        behaviour.reset();
+        assert_eq!(behaviour.state(), BehaviourState::Nominal);
+    }
+
+    #[test]
+    fn lands_after_sensor_loss_time() {
+        let config = BehaviourConfig::default();
+        let mut trail = trail(5.0);
+        let mut behaviour = Behaviour::default();
+        let lost = BehaviourInputs { sensor_healthy: false, ..inputs(CollisionSeverity::Clear, Vec3::new(5.0, 0.0, 0.0)) };
+        let loss_steps = (config.sensor_loss_time / DT) as usize;
+
+        // Back before the loss time, the timer starts over
+        assert_eq!(run(&mut behaviour, &lost, &mut trail, &config, loss_steps - 1), vec![BehaviourState::Hover]);
+        let healthy = inputs(CollisionSeverity::Clear, Vec3::new(5.0, 0.0, 0.0));
+        behaviour.update(&healthy, &mut trail, &config, DT);
+        assert!(run(&mut behaviour, &lost, &mut trail, &config, loss_steps - 1).is_empty());
+
+        // No back-off without the sensor, even past hover_time
+        assert_eq!(behaviour.state(), BehaviourState::Hover);
+        assert_eq!(behaviour.update(&lost, &mut trail, &config, DT), Some(BehaviourState::Land));
+        assert_eq!(trail.len(), 11);
+    }
+
+    #[test]
+    fn recovers_one_state_per_recovery_time() {
+        let config = BehaviourConfig::default();
+        let mut trail = trail(1.0);
+        let mut behaviour = Behaviour::default();
+        let position = Vec3::new(1.0, 0.0, 0.0);
+        let recovery_steps = (config.recovery_time / DT) as usize;
+
+        // Escalating skips states
+        assert_eq!(behaviour.update(&inputs(CollisionSeverity::Critical, position), &mut trail, &config, DT), Some(BehaviourState::Hover));
+        let clear = inputs(CollisionSeverity::Clear, position);
+        assert!(run(&mut behaviour, &clear, &mut trail, &config, recovery_steps - 1).is_empty());
+        assert_eq!(run(&mut behaviour, &clear, &mut trail, &config, 1), vec![BehaviourState::Caution]);
+
+        // The cause coming back starts the recovery over
+        let warning = inputs(CollisionSeverity::Warning, position);
+        assert!(run(&mut behaviour, &clear, &mut trail, &config, recovery_steps - 1).is_empty());
+        assert!(run(&mut behaviour, &warning, &mut trail, &config, 1).is_empty());
+        assert!(run(&mut behaviour, &clear, &mut trail, &config, recovery_steps - 1).is_empty());
+        assert_eq!(run(&mut behaviour, &clear, &mut trail, &config, 1), vec![BehaviourState::Nominal]);
+
+        let failing = BehaviourInputs { planner_failures: 1, ..clear };
+        assert_eq!(run(&mut behaviour, &failing, &mut trail, &config, 1), vec![BehaviourState::Caution]);
+        let stuck = BehaviourInputs { avoidance_stuck: true, ..clear };
+        assert_eq!(run(&mut behaviour, &stuck, &mut trail, &config, 1), vec![BehaviourState::Hover]);
+    }
+
+    #[test]
+    fn command_of_every_state() {
+        let config = BehaviourConfig::default();
+        let mut trail = trail(2.0);
+        let mut behaviour = Behaviour::default();
+        let position = Vec3::new(2.0, 0.0, -1.0);
+        let nominal = MavlinkArgs::trajectory_setpoint(MavFrame::LocalNed, [1.0, 2.0, 3.0], [0.5, 0.0, 0.0], [0.0; 3]);
+        let avoidance = MavlinkArgs::velocity_setpoint(AVOIDANCE_FRAME, 3.0, 4.0, 0.0);
+
+        assert_eq!(behaviour.command(Some(nominal), Some(avoidance), &config).type_mask, nominal.type_mask);
+        assert_velocity_setpoint(behaviour.command(None, Some(avoidance), &config), AVOIDANCE_FRAME, Vec3::new(3.0, 4.0, 0.0));
+        assert_velocity_setpoint(behaviour.command(None, None, &config), AVOIDANCE_FRAME, Vec3::ZERO);
+
+        behaviour.update(&inputs(CollisionSeverity::Warning, position), &mut trail, &config, DT);
+        assert_eq!(behaviour.state(), BehaviourState::Caution);
+        assert_velocity_setpoint(behaviour.command(Some(nominal), Some(avoidance), &config), AVOIDANCE_FRAME, Vec3::new(0.3, 0.4, 0.0));
+        assert_velocity_setpoint(behaviour.command(Some(nominal), None, &config), AVOIDANCE_FRAME, Vec3::ZERO);
+        let hold = MavlinkArgs::position_setpoint(MavFrame::LocalNed, 1.0, 0.0, -1.0);
+        assert_position_setpoint(behaviour.command(None, Some(hold), &config), Vec3::new(1.0, 0.0, -1.0));
+
+        behaviour.update(&inputs(CollisionSeverity::Critical, position), &mut trail, &config, DT);
+        assert_eq!(behaviour.state(), BehaviourState::Hover);
+        assert_position_setpoint(behaviour.command(Some(nominal), Some(avoidance), &config), position);
+        let mut blind = Behaviour::default();
+        let no_pose = BehaviourInputs { position: None, ..inputs(CollisionSeverity::Critical, position) };
+        blind.update(&no_pose, &mut trail, &config, DT);
+        assert_velocity_setpoint(blind.command(Some(nominal), Some(avoidance), &config), AVOIDANCE_FRAME, Vec3::ZERO);
+
+        let hover_steps = (config.hover_time / DT) as usize;
+        run(&mut behaviour, &inputs(CollisionSeverity::Critical, position), &mut trail, &config, hover_steps);
+        assert_eq!(behaviour.state(), BehaviourState::BackOff);
+        // 1 m above the newest crumb, so it is not skipped
+        assert_position_setpoint(behaviour.command(Some(nominal), Some(avoidance), &config), Vec3::new(2.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,464 @@
+#![allow(dead_code)]
+use std::collections::VecDeque;
+use std::fs;
+use bevy::ecs::system::Resource;
+use bevy::math::{Quat, Vec3};
+use crate::calculator::coordinate_switch::mid360_to_frd;
+use crate::data_reader::structor::Point3;
+
+/// Frame a goal position is given in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoalFrame {
+    /// Relative to the sensor when the goal is set. Pinned to local NED as soon as the
+    /// vehicle pose is known, until then it moves with the vehicle.
+    Mid360,
+    /// The autopilot's local NED frame, only reachable with telemetry
+    LocalNed,
+}
+
+impl GoalFrame {
+    pub fn parse(frame: &str) -> Result<Self, String> {
+        match frame {
+            "mid360" => Ok(GoalFrame::Mid360),
+            "ned" => Ok(GoalFrame::LocalNed),
+            _ => Err(format!("Unknown goal frame {}, expected mid360 or ned", frame)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavGoal {
+    pub position: Point3,
+    pub frame: GoalFrame,
+    pub acceptance_radius: Option<f32>, // `None` uses the default of the queue
+}
+
+impl NavGoal {
+    pub fn mid360(position: Point3) -> Self {
+        Self { position, frame: GoalFrame::Mid360, acceptance_radius: None }
+    }
+
+    pub fn local_ned(position: Point3) -> Self {
+        Self { position, frame: GoalFrame::LocalNed, acceptance_radius: None }
+    }
+
+    pub fn with_acceptance_radius(mut self, acceptance_radius: f32) -> Self {
+        self.acceptance_radius = Some(acceptance_radius);
+        self
+    }
+
+    /// Position in the current Mid-360 frame, given the NED position and FRD to NED
+    /// orientation of the vehicle. `None` for a local NED goal without a pose.
+    pub fn to_mid360(self, pose: Option<(Vec3, Quat)>) -> Option<Point3> {
+        match self.frame {
+            GoalFrame::Mid360 => Some(self.position),
+            GoalFrame::LocalNed => {
+                let (position, orientation) = pose?;
+                let ned = Vec3::new(self.position.x, self.position.y, self.position.z);
+                Some(local_ned_to_mid360(ned, position, orientation))
+            }
+        }
+    }
+
+    /// The same goal in local NED, a Mid-360 goal is taken relative to the given pose
+    pub fn anchored(&self, position: Vec3, orientation: Quat) -> Self {
+        match self.frame {
+            GoalFrame::LocalNed => *self,
+            GoalFrame::Mid360 => {
+                let ned = mid360_to_local_ned(self.position, position, orientation);
+                Self { position: Point3::new(ned.x, ned.y, ned.z), frame: GoalFrame::LocalNed, ..*self }
+            }
+        }
+    }
+}
+
+/// A Mid-360 point in local NED, for a vehicle at `position` with FRD to NED `orientation`
+pub fn mid360_to_local_ned(point: Point3, position: Vec3, orientation: Quat) -> Vec3 {
+    let (x, y, z) = mid360_to_frd(point.x, point.y, point.z);
+    position + orientation * Vec3::new(x, y, z)
+}
+
+/// Inverse of `mid360_to_local_ned`
+pub fn local_ned_to_mid360(point: Vec3, position: Vec3, orientation: Quat) -> Point3 {
+    let frd = orientation.inverse() * (point - position);
+    let (x, y, z) = mid360_to_frd(frd.x, frd.y, frd.z);
+    Point3::new(x, y, z)
+}
+
+/// Express a path planned at pose `from` in the Mid-360 frame of pose `to`
+pub fn reframe_path(path: &[Point3], from: (Vec3, Quat), to: (Vec3, Quat)) -> Vec<Point3> {
+    path.iter()
+        .map(|point| local_ned_to_mid360(mid360_to_local_ned(*point, from.0, from.1), to.0, to.1))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GoalProgress {
+    pub distance: f32,         // Straight-line distance left
+    pub initial_distance: f32, // Distance when the goal became active
+}
+
+impl GoalProgress {
+    /// 0 when the goal became active, 1 at the goal
+    pub fn fraction(&self) -> f32 {
+        if self.initial_distance <= 0.0 {
+            1.0
+        } else {
+            (1.0 - self.distance / self.initial_distance).clamp(0.0, 1.0)
+        }
+    }
+}
+
+/// The goal the planners head for and the waypoints after it
+#[derive(Debug, Clone, Resource)]
+pub struct GoalQueue {
+    active: Option<NavGoal>,
+    pending: VecDeque<NavGoal>,
+    progress: Option<GoalProgress>,
+    default_acceptance_radius: f32,
+    version: u64, // Bumped whenever the active goal is replaced
+    reached: usize,
+}
+
+impl GoalQueue {
+    /// Goals without their own acceptance radius are reached within `default_acceptance_radius`,
+    /// the APF `epsilon` so the planner and the queue agree on arriving
+    pub fn new(default_acceptance_radius: f32) -> Self {
+        Self {
+            active: None,
+            pending: VecDeque::new(),
+            progress: None,
+            default_acceptance_radius,
+            version: 0,
+            reached: 0,
+        }
+    }
+
+    /// Drop all waypoints and head for `goal` right away
+    pub fn set(&mut self, goal: NavGoal) {
+        self.pending.clear();
+        self.activate(Some(goal));
+    }
+
+    /// Append a waypoint, it becomes active if there is nothing else to do
+    pub fn push(&mut self, goal: NavGoal) {
+        if self.active.is_none() {
+            self.activate(Some(goal));
+        } else {
+            self.pending.push_back(goal);
+        }
+    }
+
+    pub fn extend(&mut self, goals: impl IntoIterator<Item = NavGoal>) {
+        for goal in goals {
+            self.push(goal);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.activate(None);
+    }
+
+    /// Give up on the active goal and head for the next waypoint
+    pub fn skip(&mut self) -> Option<NavGoal> {
+        let next = self.pending.pop_front();
+        self.activate(next);
+        next
+    }
+
+    pub fn active(&self) -> Option<&NavGoal> {
+        self.active.as_ref()
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &NavGoal> {
+        self.pending.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.iter().count() + self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_none()
+    }
+
+    pub fn progress(&self) -> Option<GoalProgress> {
+        self.progress
+    }
+
+    /// Changes whenever the active goal is replaced, compare to see if a path is stale
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Goals reached since the start
+    pub fn reached(&self) -> usize {
+        self.reached
+    }
+
+    pub fn acceptance_radius(&self, goal: &NavGoal) -> f32 {
+        goal.acceptance_radius.unwrap_or(self.default_acceptance_radius)
+    }
+
+    /// Pin every Mid-360 goal to local NED at the given pose. The goals stay where they
+    /// are, so the version does not change.
+    pub fn anchor(&mut self, position: Vec3, orientation: Quat) {
+        let unanchored = |goal: &NavGoal| goal.frame == GoalFrame::Mid360;
+        if !self.active.iter().chain(self.pending.iter()).any(unanchored) {
+            return;
+        }
+        if let Some(goal) = self.active.as_mut() {
+            *goal = goal.anchored(position, orientation);
+        }
+        for goal in self.pending.iter_mut() {
+            *goal = goal.anchored(position, orientation);
+        }
+    }
+
+    /// Track the distance to the active goal and move on to the next waypoint once it is
+    /// within the acceptance radius. Returns the goal that was reached.
+    pub fn update(&mut self, distance: f32) -> Option<NavGoal> {
+        let goal = self.active?;
+        let initial_distance = self.progress.map_or(distance, |progress| progress.initial_distance);
+        self.progress = Some(GoalProgress { distance, initial_distance });
+        if distance > self.acceptance_radius(&goal) {
+            return None;
+        }
+        self.reached += 1;
+        self.skip();
+        Some(goal)
+    }
+
+    fn activate(&mut self, goal: Option<NavGoal>) {
+        self.active = goal;
+        self.progress = None;
+        self.version += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct ReplanConfig {
+    pub moved_distance: f32, // Plan again once the vehicle is this far from where the path was planned
+}
+
+impl Default for ReplanConfig {
+    fn default() -> Self {
+        Self {
+            moved_distance: 1.0,
+        }
+    }
+}
+
+/// Why the path to the active goal is planned again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplanReason {
+    /// The active goal was replaced
+    GoalChanged,
+    /// There is no path to keep
+    NoPath,
+    /// No vehicle pose, so the last path cannot be moved into the new map frame
+    NoPose,
+    /// The vehicle is `ReplanConfig::moved_distance` away from where the path was planned
+    Moved,
+    /// The new map puts an obstacle on the path
+    PathBlocked,
+}
+
+/// Whether the path to the active goal has to be planned again. `moved` is how far the
+/// vehicle got since planning, `None` without a pose. `blocked` is whether the new map
+/// blocks the path, see `path_validation::first_blocked_segment`.
+pub fn replan_reason(
+    goal_changed: bool,
+    path: &[Point3],
+    moved: Option<f32>,
+    blocked: bool,
+    config: &ReplanConfig,
+) -> Option<ReplanReason> {
+    if goal_changed {
+        return Some(ReplanReason::GoalChanged);
+    }
+    if path.len() < 2 {
+        return Some(ReplanReason::NoPath);
+    }
+    if blocked {
+        return Some(ReplanReason::PathBlocked);
+    }
+    let moved = match moved {
+        Some(moved) => moved,
+        None => return Some(ReplanReason::NoPose),
+    };
+    (moved >= config.moved_distance).then_some(ReplanReason::Moved)
+}
+
+/// Read waypoints, one `<mid360|ned> x y z [acceptance radius]` per line. Empty lines and
+/// lines starting with `#` are skipped.
+pub fn load_goals(file_name: &str) -> Result<Vec<NavGoal>, String> {
+    let text = fs::read_to_string(file_name).map_err(|e| format!("Failed to read {}: {}", file_name, e))?;
+    parse_goals(&text).map_err(|e| format!("{}: {}", file_name, e))
+}
+
+pub fn parse_goals(text: &str) -> Result<Vec<NavGoal>, String> {
+    let mut goals = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 && fields.len() != 5 {
+            return Err(format!("line {}: expected frame x y z [acceptance radius]", index + 1));
+        }
+        let frame = GoalFrame::parse(fields[0]).map_err(|e| format!("line {}: {}", index + 1, e))?;
+        let mut values = Vec::new();
+        for field in &fields[1..] {
+            let value: f32 = field
+                .parse()
+                .map_err(|_| format!("line {}: {} is not a number", index + 1, field))?;
+            if !value.is_finite() {
+                return Err(format!("line {}: {} is not a finite number", index + 1, field));
+            }
+            values.push(value);
+        }
+        let mut goal = NavGoal { position: Point3::new(values[0], values[1], values[2]), frame, acceptance_radius: None };
+        if let Some(&radius) = values.get(3) {
+            if radius <= 0.0 {
+                return Err(format!("line {}: acceptance radius {} is not positive", index + 1, radius));
+            }
+            goal = goal.with_acceptance_radius(radius);
+        }
+        goals.push(goal);
+    }
+    Ok(goals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_goals_reads_frames_and_radii() {
+        let text = "# survey\n\nned 1 2 -3\n  mid360 4.5 0 0.5 0.8  \n";
+        let goals = parse_goals(text).unwrap();
+        assert_eq!(goals, vec![
+            NavGoal::local_ned(Point3::new(1.0, 2.0, -3.0)),
+            NavGoal::mid360(Point3::new(4.5, 0.0, 0.5)).with_acceptance_radius(0.8),
+        ]);
+        assert_eq!(parse_goals("# nothing\n"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn parse_goals_reports_the_bad_line() {
+        let error = |text: &str| parse_goals(text).unwrap_err();
+        assert!(error("ned 1 2 3\nned 1 2").starts_with("line 2: expected frame"));
+        assert!(error("ned 1 2 3 4 5").starts_with("line 1: expected frame"));
+        assert!(error("enu 1 2 3").contains("Unknown goal frame enu"));
+        assert_eq!(error("ned 1 two 3"), "line 1: two is not a number");
+        assert_eq!(error("\nned NaN 2 3"), "line 2: NaN is not a finite number");
+        assert_eq!(error("ned 1 inf 3"), "line 1: inf is not a finite number");
+        assert_eq!(error("mid360 1 2 3 -infinity"), "line 1: -infinity is not a finite number");
+        assert_eq!(error("ned 1 2 3 0"), "line 1: acceptance radius 0 is not positive");
+    }
+
+    #[test]
+    fn update_moves_on_within_the_acceptance_radius() {
+        let mut queue = GoalQueue::new(0.5);
+        let first = NavGoal::local_ned(Point3::new(4.0, 0.0, 0.0));
+        let second = NavGoal::local_ned(Point3::new(8.0, 0.0, 0.0)).with_acceptance_radius(1.5);
+        queue.extend([first, second]);
+        assert_eq!(queue.len(), 2);
+        let version = queue.version();
+
+        assert_eq!(queue.update(4.0), None);
+        assert_eq!(queue.update(1.0), None);
+        assert_eq!(queue.progress().unwrap().fraction(), 0.75);
+        assert_eq!(queue.update(0.6), None);
+        assert_eq!(queue.update(0.5), Some(first));
+        assert_eq!((queue.reached(), queue.active()), (1, Some(&second)));
+        assert_eq!(queue.version(), version + 1);
+        assert_eq!(queue.progress(), None);
+
+        // The second goal has its own, wider radius
+        assert_eq!(queue.update(3.0), None);
+        assert_eq!(queue.update(1.4), Some(second));
+        assert_eq!(queue.reached(), 2);
+        assert!(queue.is_empty());
+        assert_eq!(queue.update(0.0), None);
+        assert_eq!(queue.reached(), 2);
+    }
+
+    #[test]
+    fn skipped_goals_are_not_reached() {
+        let mut queue = GoalQueue::new(0.5);
+        queue.extend([NavGoal::mid360(Point3::new(1.0, 0.0, 0.0)), NavGoal::mid360(Point3::new(2.0, 0.0, 0.0))]);
+        assert_eq!(queue.skip(), Some(NavGoal::mid360(Point3::new(2.0, 0.0, 0.0))));
+        assert_eq!(queue.pending().count(), 0);
+        assert_eq!(queue.reached(), 0);
+    }
+
+    fn assert_close(a: Point3, b: Point3) {
+        assert!((a.x - b.x).abs() < 1e-4 && (a.y - b.y).abs() < 1e-4 && (a.z - b.z).abs() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    fn tilted_pose() -> (Vec3, Quat) {
+        (Vec3::new(3.0, -2.0, -5.0), Quat::from_rotation_z(0.7) * Quat::from_rotation_y(-0.2) * Quat::from_rotation_x(0.1))
+    }
+
+    #[test]
+    fn frame_conversion_round_trips() {
+        let (position, orientation) = tilted_pose();
+        let point = Point3::new(1.5, -0.5, 2.0);
+        let ned = mid360_to_local_ned(point, position, orientation);
+        assert!((ned - position).length() > 1.0);
+        assert_close(local_ned_to_mid360(ned, position, orientation), point);
+
+        // Straight ahead of a vehicle yawed to the east is east of it
+        let east = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let ahead = mid360_to_local_ned(Point3::new(2.0, 0.0, 0.0), position, east);
+        assert!((ahead - (position + Vec3::new(0.0, 2.0, 0.0))).length() < 1e-4);
+    }
+
+    #[test]
+    fn local_ned_goal_needs_a_pose() {
+        let (position, orientation) = tilted_pose();
+        let goal = NavGoal::local_ned(Point3::new(10.0, 4.0, -6.0));
+        assert_eq!(goal.to_mid360(None), None);
+        assert!(goal.to_mid360(Some((position, orientation))).is_some());
+
+        let relative = NavGoal::mid360(Point3::new(4.0, 1.0, 0.5));
+        assert_eq!(relative.to_mid360(None), Some(relative.position));
+        let anchored = relative.anchored(position, orientation);
+        assert_eq!(anchored.frame, GoalFrame::LocalNed);
+        assert_eq!(anchored.to_mid360(None), None);
+        assert_close(anchored.to_mid360(Some((position, orientation))).unwrap(), relative.position);
+    }
+
+    #[test]
+    fn reframed_path_keeps_world_positions() {
+        let from = tilted_pose();
+        let to = (from.0 + Vec3::new(1.0, 0.5, 0.2), Quat::from_rotation_z(-0.4) * from.1);
+        let path = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 1.0, 0.0), Point3::new(4.0, 1.0, -0.5)];
+        let reframed = reframe_path(&path, from, to);
+        assert_eq!(reframed.len(), path.len());
+        for (before, after) in path.iter().zip(&reframed) {
+            let world_before = mid360_to_local_ned(*before, from.0, from.1);
+            let world_after = mid360_to_local_ned(*after, to.0, to.1);
+            assert!((world_before - world_after).length() < 1e-4);
+        }
+        // The vehicle moved, so the Mid-360 coordinates did change
+        assert!((reframed[0].x - path[0].x).abs() + (reframed[0].y - path[0].y).abs() > 0.5);
+    }
+
+    #[test]
+    fn replan_reason_covers_every_case() {
+        let config = ReplanConfig::default();
+        let path = [Point3::new(0.0, 0.0, 0.0), Point3::new(3.0, 0.0, 0.0)];
+        assert_eq!(replan_reason(true, &path, Some(0.0), false, &config), Some(ReplanReason::GoalChanged));
+        assert_eq!(replan_reason(false, &path[..1], Some(0.0), false, &config), Some(ReplanReason::NoPath));
+        assert_eq!(replan_reason(false, &[], Some(0.0), false, &config), Some(ReplanReason::NoPath));
+        assert_eq!(replan_reason(false, &path, Some(0.0), true, &config), Some(ReplanReason::PathBlocked));
+        assert_eq!(replan_reason(false, &path, None, false, &config), Some(ReplanReason::NoPose));
+        assert_eq!(replan_reason(false, &path, Some(config.moved_distance), false, &config), Some(ReplanReason::Moved));
+        assert_eq!(replan_reason(false, &path, Some(config.moved_distance * 0.5), false, &config), None);
+    }
+}
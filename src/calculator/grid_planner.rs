@@ -0,0 +1,437 @@
+#![allow(dead_code)]
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use bevy::ecs::system::Resource;
+use crate::calculator::crash_detector::{LEAF_INFLATION, VEHICLE_RADIUS};
+use crate::octree::octree::Octree;
+use crate::data_reader::structor::Point3;
+
+/// Which graph search runs over the grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GridSearch {
+    /// Paths along the 26 grid directions
+    #[default]
+    AStar,
+    /// Any-angle paths, a node may connect to its grandparent when it can see it
+    ThetaStar,
+}
+
+/// How to treat cells the map knows nothing about. The octree only stores lidar hits,
+/// so everything outside its bounds is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownSpace {
+    /// Plan through unknown space, e.g. towards a goal beyond sensor range
+    Free,
+    /// Stay inside the mapped volume
+    #[default]
+    Occupied,
+}
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct GridPlannerConfig {
+    pub search: GridSearch,
+    pub resolution: f32,      // Edge length of a grid cell
+    pub vehicle_radius: f32,  // Cells closer than this to an obstacle are blocked
+    pub inflation: f32,       // Added to the vehicle radius, half a voxel since leaves are only known by their center
+    pub unknown: UnknownSpace,
+    pub max_expansions: usize, // Give up after expanding this many cells
+}
+
+impl Default for GridPlannerConfig {
+    fn default() -> Self {
+        Self {
+            search: GridSearch::AStar,
+            resolution: 0.2,
+            vehicle_radius: VEHICLE_RADIUS,
+            inflation: LEAF_INFLATION,
+            unknown: UnknownSpace::Occupied,
+            max_expansions: 200_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridPlanError {
+    /// The goal is inside an inflated obstacle or in unknown space
+    GoalBlocked,
+    /// Every reachable cell was expanded without getting to the goal
+    NoPath,
+    MaxExpansionsReached,
+}
+
+impl fmt::Display for GridPlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GridPlanError::GoalBlocked => write!(f, "goal is blocked"),
+            GridPlanError::NoPath => write!(f, "no path to the goal"),
+            GridPlanError::MaxExpansionsReached => write!(f, "gave up after the maximum number of expansions"),
+        }
+    }
+}
+
+pub fn astar_plan(start: Point3, goal: Point3, octree: &Octree, config: &GridPlannerConfig) -> Result<Vec<Point3>, GridPlanError> {
+    grid_plan(start, goal, octree, &GridPlannerConfig { search: GridSearch::AStar, ..*config })
+}
+
+pub fn theta_star_plan(start: Point3, goal: Point3, octree: &Octree, config: &GridPlannerConfig) -> Result<Vec<Point3>, GridPlanError> {
+    grid_plan(start, goal, octree, &GridPlannerConfig { search: GridSearch::ThetaStar, ..*config })
+}
+
+/// Shortest path from `start` to `goal` over the free cells of a grid laid over the octree.
+/// The path begins at `start` exactly and ends at `goal` exactly, with cell centers in between.
+pub fn grid_plan(start: Point3, goal: Point3, octree: &Octree, config: &GridPlannerConfig) -> Result<Vec<Point3>, GridPlanError> {
+    let mut grid = Grid::new(octree, config);
+    let start_cell = grid.cell_of(&start);
+    let goal_cell = grid.cell_of(&goal);
+    if !grid.is_free(goal_cell) {
+        return Err(GridPlanError::GoalBlocked);
+    }
+
+    let mut open = BinaryHeap::new();
+    let mut g_score: HashMap<Cell, f32> = HashMap::new();
+    let mut parent: HashMap<Cell, Cell> = HashMap::new();
+    let mut closed: HashSet<Cell> = HashSet::new();
+    g_score.insert(start_cell, 0.0);
+    parent.insert(start_cell, start_cell);
+    open.push(OpenNode { f: cell_distance(start_cell, goal_cell), g: 0.0, cell: start_cell });
+
+    let mut expansions = 0;
+    while let Some(OpenNode { g, cell, .. }) = open.pop() {
+        if closed.contains(&cell) || g > g_score[&cell] {
+            continue;
+        }
+        if cell == goal_cell {
+            return Ok(grid.reconstruct(&parent, start, goal, goal_cell));
+        }
+        closed.insert(cell);
+        expansions += 1;
+        if expansions > config.max_expansions {
+            return Err(GridPlanError::MaxExpansionsReached);
+        }
+
+        let cell_parent = parent[&cell];
+        for neighbor in neighbors(cell) {
+            if closed.contains(&neighbor) || !grid.can_move(cell, neighbor) {
+                continue;
+            }
+            // Theta*: skip the current cell when its parent sees the neighbor directly
+            let (from, g_from) = if config.search == GridSearch::ThetaStar
+                && cell_parent != cell
+                && grid.line_of_sight(cell_parent, neighbor)
+            {
+                (cell_parent, g_score[&cell_parent])
+            } else {
+                (cell, g)
+            };
+            let tentative = g_from + cell_distance(from, neighbor);
+            if g_score.get(&neighbor).is_none_or(|&known| tentative < known) {
+                g_score.insert(neighbor, tentative);
+                parent.insert(neighbor, from);
+                open.push(OpenNode { f: tentative + cell_distance(neighbor, goal_cell), g: tentative, cell: neighbor });
+            }
+        }
+    }
+    Err(GridPlanError::NoPath)
+}
+
+type Cell = [i32; 3];
+
+/// Grid cells over the octree bounds, with obstacle clearance looked up lazily
+/// so only the cells the search touches are ever queried
+struct Grid<'a> {
+    octree: &'a Octree,
+    origin: [f32; 3],
+    cells: [i32; 3],
+    config: GridPlannerConfig,
+    clearance: HashMap<Cell, f32>,
+}
+
+impl<'a> Grid<'a> {
+    fn new(octree: &'a Octree, config: &GridPlannerConfig) -> Self {
+        let bounds = octree.get_root().bounds();
+        let cells = [0, 1, 2].map(|i| ((bounds[1][i] - bounds[0][i]) / config.resolution).ceil() as i32);
+        Grid {
+            octree,
+            origin: bounds[0],
+            cells,
+            config: *config,
+            clearance: HashMap::new(),
+        }
+    }
+
+    fn cell_of(&self, point: &Point3) -> Cell {
+        let point = [point.x, point.y, point.z];
+        [0, 1, 2].map(|i| ((point[i] - self.origin[i]) / self.config.resolution).floor() as i32)
+    }
+
+    fn center(&self, cell: Cell) -> Point3 {
+        let [x, y, z] = [0, 1, 2].map(|i| self.origin[i] + (cell[i] as f32 + 0.5) * self.config.resolution);
+        Point3::new(x, y, z)
+    }
+
+    fn in_bounds(&self, cell: Cell) -> bool {
+        (0..3).all(|i| cell[i] >= 0 && cell[i] < self.cells[i])
+    }
+
+    /// Distance from the cell center to the closest occupied leaf, zero for unknown
+    /// cells that count as occupied
+    fn clearance(&mut self, cell: Cell) -> f32 {
+        if let Some(&clearance) = self.clearance.get(&cell) {
+            return clearance;
+        }
+        let clearance = if !self.in_bounds(cell) && self.config.unknown == UnknownSpace::Occupied {
+            0.0
+        } else {
+            let center = self.center(cell);
+            self.octree
+                .nearest_obstacle_distance([center.x, center.y, center.z])
+                .unwrap_or(f32::INFINITY)
+        };
+        self.clearance.insert(cell, clearance);
+        clearance
+    }
+
+    fn is_free(&mut self, cell: Cell) -> bool {
+        self.clearance(cell) >= self.config.vehicle_radius + self.config.inflation
+    }
+
+    /// Free cells can always be entered. A vehicle that already is too close to an obstacle
+    /// may still move away from it, otherwise it could never leave its start cell.
+    fn can_move(&mut self, from: Cell, to: Cell) -> bool {
+        if self.is_free(to) {
+            return true;
+        }
+        let from_clearance = self.clearance(from);
+        !self.is_free(from) && self.clearance(to) > from_clearance
+    }
+
+    /// Whether every cell the segment between two cell centers passes through is free
+    fn line_of_sight(&mut self, from: Cell, to: Cell) -> bool {
+        let (a, b) = (self.center(from), self.center(to));
+        let length = b.sub(&a).norm();
+        let samples = (length / (self.config.resolution * 0.5)).ceil().max(1.0) as i32;
+        (0..=samples).all(|i| {
+            let t = i as f32 / samples as f32;
+            let point = Point3::new(a.x + (b.x - a.x) * t, a.y + (b.y - a.y) * t, a.z + (b.z - a.z) * t);
+            let cell = self.cell_of(&point);
+            self.is_free(cell)
+        })
+    }
+
+    fn reconstruct(&self, parent: &HashMap<Cell, Cell>, start: Point3, goal: Point3, goal_cell: Cell) -> Vec<Point3> {
+        let mut cells = vec![goal_cell];
+        let mut cell = goal_cell;
+        while parent[&cell] != cell {
+            cell = parent[&cell];
+            cells.push(cell);
+        }
+        cells.reverse();
+
+        let mut path = vec![start];
+        // The start and goal cells are replaced by the exact points
+        for &cell in cells.iter().skip(1).take(cells.len().saturating_sub(2)) {
+            path.push(self.center(cell));
+        }
+        path.push(goal);
+        path
+    }
+}
+
+fn neighbors(cell: Cell) -> impl Iterator<Item = Cell> {
+    (-1..=1).flat_map(move |dx| {
+        (-1..=1).flat_map(move |dy| {
+            (-1..=1)
+                .filter(move |&dz| (dx, dy, dz) != (0, 0, 0))
+                .map(move |dz| [cell[0] + dx, cell[1] + dy, cell[2] + dz])
+        })
+    })
+}
+
+/// Euclidean distance in cells
+fn cell_distance(a: Cell, b: Cell) -> f32 {
+    let dx = (a[0] - b[0]) as f32;
+    let dy = (a[1] - b[1]) as f32;
+    let dz = (a[2] - b[2]) as f32;
+    (dx * dx + dy * dy + dz * dz).sqrt()
+}
+
+/// Entry of the open list, ordered so the heap pops the lowest f first
+#[derive(Debug, Clone, Copy)]
+struct OpenNode {
+    f: f32,
+    g: f32,
+    cell: Cell,
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OpenNode {}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed for the max-heap, ties go to the node closer to the goal
+        other.f.total_cmp(&self.f).then(self.g.total_cmp(&other.g))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::apf::{apf_plan, ApfConfig, ApfEscapeConfig};
+
+    const GOAL: Point3 = Point3 { x: 4.0, y: 0.0, z: 0.0 };
+
+    /// A box open towards -x around x = 0.5..2, with the goal behind its back wall
+    fn u_trap() -> Octree {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        let n = 40;
+        for i in 0..=n {
+            for j in 0..=n {
+                let across = -1.0 + 2.0 * i as f32 / n as f32;
+                octree.insert([2.0, across, -1.0 + 2.0 * j as f32 / n as f32], 7, 100).unwrap();
+                let x = 0.5 + 1.5 * j as f32 / n as f32;
+                for side in [-1.0, 1.0] {
+                    octree.insert([x, side, across], 7, 100).unwrap();
+                    octree.insert([x, across, side], 7, 100).unwrap();
+                }
+            }
+        }
+        octree
+    }
+
+    fn config(search: GridSearch) -> GridPlannerConfig {
+        GridPlannerConfig { search, ..GridPlannerConfig::default() }
+    }
+
+    fn assert_clear(path: &[Point3], octree: &Octree, config: &GridPlannerConfig) {
+        let clearance = config.vehicle_radius + config.inflation;
+        for point in &path[1..path.len() - 1] {
+            let distance = octree.nearest_obstacle_distance([point.x, point.y, point.z]).unwrap();
+            assert!(distance >= clearance, "{:?} is {} from an obstacle", point, distance);
+        }
+        for segment in path.windows(2) {
+            let (from, to) = ([segment[0].x, segment[0].y, segment[0].z], [segment[1].x, segment[1].y, segment[1].z]);
+            assert!(octree.segment_clear(from, to, clearance), "{:?} -> {:?}", segment[0], segment[1]);
+        }
+    }
+
+    fn length(path: &[Point3]) -> f32 {
+        path.windows(2).map(|segment| segment[1].sub(&segment[0]).norm()).sum()
+    }
+
+    #[test]
+    fn astar_leaves_the_trap_apf_is_stuck_in() {
+        let octree = u_trap();
+        let start = Point3::new(0.0, 0.0, 0.0);
+        let apf = ApfConfig {
+            k_att: 2.5,
+            k_rep: 2.5,
+            d0: 0.7,
+            epsilon: 0.1,
+            max_steps: 500,
+            step_size: 0.1,
+            escape: ApfEscapeConfig { strategies: Vec::new(), ..ApfEscapeConfig::default() },
+        };
+        assert!(apf_plan(start, GOAL, &octree, apf).is_err());
+
+        let path = astar_plan(start, GOAL, &octree, &config(GridSearch::AStar)).unwrap();
+        assert_eq!((path[0], *path.last().unwrap()), (start, GOAL));
+        assert!(path.iter().any(|point| point.y.abs() > 1.0 || point.z.abs() > 1.0), "path goes around the box");
+    }
+
+    #[test]
+    fn paths_keep_the_clearance() {
+        let octree = u_trap();
+        for search in [GridSearch::AStar, GridSearch::ThetaStar] {
+            let config = config(search);
+            let path = grid_plan(Point3::new(0.0, 0.0, 0.0), GOAL, &octree, &config).unwrap();
+            assert_clear(&path, &octree, &config);
+        }
+    }
+
+    #[test]
+    fn theta_star_needs_fewer_waypoints() {
+        let octree = u_trap();
+        let start = Point3::new(0.0, 0.0, 0.0);
+        let astar = astar_plan(start, GOAL, &octree, &config(GridSearch::AStar)).unwrap();
+        let theta_star = theta_star_plan(start, GOAL, &octree, &config(GridSearch::ThetaStar)).unwrap();
+        assert!(theta_star.len() < astar.len() / 2, "{} vs {} waypoints", theta_star.len(), astar.len());
+        assert!(length(&theta_star) <= length(&astar) + 1e-3);
+        // Any angle, not only the 26 grid directions
+        let off_grid = theta_star.windows(2).any(|segment| {
+            let offset = segment[1].sub(&segment[0]);
+            let steps = [offset.x, offset.y, offset.z].map(|c| (c / 0.2).abs());
+            let longest = steps.iter().cloned().fold(0.0, f32::max);
+            steps.iter().any(|&step| step > 0.5 && (step - longest).abs() > 0.5)
+        });
+        assert!(off_grid, "{:?}", theta_star);
+        assert_clear(&theta_star, &octree, &config(GridSearch::ThetaStar));
+    }
+
+    #[test]
+    fn unknown_space_outside_the_bounds() {
+        let mut octree = Octree::new([[-5.0; 3], [5.0; 3]]);
+        octree.insert([2.0, 0.0, 0.0], 6, 100).unwrap();
+        let (start, goal) = (Point3::new(0.0, 0.0, 0.0), Point3::new(7.0, 0.5, 0.0));
+
+        let occupied = GridPlannerConfig { unknown: UnknownSpace::Occupied, ..GridPlannerConfig::default() };
+        assert_eq!(astar_plan(start, goal, &octree, &occupied), Err(GridPlanError::GoalBlocked));
+        let inside = Point3::new(4.5, 0.5, 0.0);
+        assert!(astar_plan(start, inside, &octree, &occupied).is_ok());
+
+        let free = GridPlannerConfig { unknown: UnknownSpace::Free, ..GridPlannerConfig::default() };
+        let path = astar_plan(start, goal, &octree, &free).unwrap();
+        assert_eq!(*path.last().unwrap(), goal);
+        assert!(path.iter().any(|point| point.x > 5.0));
+    }
+
+    #[test]
+    fn blocked_goal_and_exhausted_search() {
+        let octree = u_trap();
+        let start = Point3::new(0.0, 0.0, 0.0);
+        let astar = config(GridSearch::AStar);
+        assert_eq!(astar_plan(start, Point3::new(2.0, 0.0, 0.0), &octree, &astar), Err(GridPlanError::GoalBlocked));
+        // Beside the wall, inside the inflation
+        assert_eq!(astar_plan(start, Point3::new(2.3, 0.0, 0.0), &octree, &astar), Err(GridPlanError::GoalBlocked));
+
+        let limited = GridPlannerConfig { max_expansions: 10, ..astar };
+        assert_eq!(astar_plan(start, GOAL, &octree, &limited), Err(GridPlanError::MaxExpansionsReached));
+        assert_eq!(theta_star_plan(start, GOAL, &octree, &limited), Err(GridPlanError::MaxExpansionsReached));
+    }
+
+    #[test]
+    fn start_inside_the_inflation_may_only_move_away() {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        octree.insert([0.05, 0.05, 0.05], 7, 100).unwrap();
+        let config = GridPlannerConfig::default();
+        let mut grid = Grid::new(&octree, &config);
+        let start = grid.cell_of(&Point3::new(0.3, 0.1, 0.1));
+        assert!(!grid.is_free(start));
+
+        let away = [start[0] + 1, start[1], start[2]];
+        let deeper = [start[0] - 1, start[1], start[2]];
+        assert!(grid.clearance(away) > grid.clearance(start));
+        assert!(grid.clearance(deeper) < grid.clearance(start));
+        assert!(grid.can_move(start, away));
+        assert!(!grid.can_move(start, deeper));
+
+        // A free cell never moves into the inflation
+        let free = [start[0] + 2, start[1], start[2]];
+        assert!(grid.is_free(free));
+        assert!(!grid.can_move(free, start));
+
+        let path = astar_plan(Point3::new(0.3, 0.1, 0.1), Point3::new(2.0, 0.1, 0.1), &octree, &config).unwrap();
+        assert_eq!(*path.last().unwrap(), Point3::new(2.0, 0.1, 0.1));
+    }
+}
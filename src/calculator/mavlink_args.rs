@@ -1,5 +1,92 @@
 #![allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
+bitflags! {
+    /// POSITION_TARGET_TYPEMASK. A set bit tells the autopilot to ignore that field.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PositionTargetTypeMask: u16 {
+        const X_IGNORE = 1 << 0;
+        const Y_IGNORE = 1 << 1;
+        const Z_IGNORE = 1 << 2;
+        const VX_IGNORE = 1 << 3;
+        const VY_IGNORE = 1 << 4;
+        const VZ_IGNORE = 1 << 5;
+        const AX_IGNORE = 1 << 6;
+        const AY_IGNORE = 1 << 7;
+        const AZ_IGNORE = 1 << 8;
+        /// afx, afy and afz are a force instead of an acceleration
+        const FORCE_SET = 1 << 9;
+        const YAW_IGNORE = 1 << 10;
+        const YAW_RATE_IGNORE = 1 << 11;
+
+        const POSITION_IGNORE = Self::X_IGNORE.bits() | Self::Y_IGNORE.bits() | Self::Z_IGNORE.bits();
+        const VELOCITY_IGNORE = Self::VX_IGNORE.bits() | Self::VY_IGNORE.bits() | Self::VZ_IGNORE.bits();
+        const ACCELERATION_IGNORE = Self::AX_IGNORE.bits() | Self::AY_IGNORE.bits() | Self::AZ_IGNORE.bits();
+    }
+}
+
+/// MAV_FRAME
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MavFrame {
+    Global = 0,
+    LocalNed = 1,
+    Mission = 2,
+    GlobalRelativeAlt = 3,
+    LocalEnu = 4,
+    GlobalInt = 5,
+    GlobalRelativeAltInt = 6,
+    /// NED offsets from the current position
+    LocalOffsetNed = 7,
+    /// NED axes rotated with the vehicle heading, origin at the local origin
+    BodyNed = 8,
+    /// NED axes rotated with the vehicle heading, origin at the vehicle
+    BodyOffsetNed = 9,
+    GlobalTerrainAlt = 10,
+    GlobalTerrainAltInt = 11,
+    BodyFrd = 12,
+    LocalFrd = 20,
+    LocalFlu = 21,
+}
+
+impl MavFrame {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        let frame = match value {
+            0 => MavFrame::Global,
+            1 => MavFrame::LocalNed,
+            2 => MavFrame::Mission,
+            3 => MavFrame::GlobalRelativeAlt,
+            4 => MavFrame::LocalEnu,
+            5 => MavFrame::GlobalInt,
+            6 => MavFrame::GlobalRelativeAltInt,
+            7 => MavFrame::LocalOffsetNed,
+            8 => MavFrame::BodyNed,
+            9 => MavFrame::BodyOffsetNed,
+            10 => MavFrame::GlobalTerrainAlt,
+            11 => MavFrame::GlobalTerrainAltInt,
+            12 => MavFrame::BodyFrd,
+            20 => MavFrame::LocalFrd,
+            21 => MavFrame::LocalFlu,
+            _ => return None,
+        };
+        Some(frame)
+    }
+
+    /// Frames SET_POSITION_TARGET_LOCAL_NED is defined for, PX4 and ArduPilot reject the rest
+    pub fn is_local(self) -> bool {
+        matches!(
+            self,
+            MavFrame::LocalNed | MavFrame::LocalOffsetNed | MavFrame::BodyNed | MavFrame::BodyOffsetNed
+        )
+    }
+}
+
+/// Target of setpoints made by the builders, the first autopilot of system 1
+pub const DEFAULT_TARGET_SYSTEM: u8 = 1;
+pub const DEFAULT_TARGET_COMPONENT: u8 = 1;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct MavlinkArgs {
     pub time_boot_ms: u32,
     pub target_system: u8,
@@ -57,4 +144,250 @@ impl MavlinkArgs {
             yaw_rate,
         }
     }
+
+    /// Fly with velocity `vx`, `vy`, `vz` in m/s, everything else ignored
+    pub fn velocity_setpoint(frame: MavFrame, vx: f32, vy: f32, vz: f32) -> Self {
+        let mut args = Self::with_mask(
+            frame,
+            PositionTargetTypeMask::POSITION_IGNORE
+                | PositionTargetTypeMask::ACCELERATION_IGNORE
+                | PositionTargetTypeMask::YAW_IGNORE
+                | PositionTargetTypeMask::YAW_RATE_IGNORE,
+        );
+        args.vx = vx;
+        args.vy = vy;
+        args.vz = vz;
+        args
+    }
+
+    /// Fly to `x`, `y`, `z` in metres, everything else ignored
+    pub fn position_setpoint(frame: MavFrame, x: f32, y: f32, z: f32) -> Self {
+        let mut args = Self::with_mask(
+            frame,
+            PositionTargetTypeMask::VELOCITY_IGNORE
+                | PositionTargetTypeMask::ACCELERATION_IGNORE
+                | PositionTargetTypeMask::YAW_IGNORE
+                | PositionTargetTypeMask::YAW_RATE_IGNORE,
+        );
+        args.x = x;
+        args.y = y;
+        args.z = z;
+        args
+    }
+
+    /// Follow a trajectory: be at `position` in metres, moving with `velocity` in m/s and
+    /// accelerating with `acceleration` in m/s², yaw ignored
+    pub fn trajectory_setpoint(frame: MavFrame, position: [f32; 3], velocity: [f32; 3], acceleration: [f32; 3]) -> Self {
+        let mut args = Self::with_mask(
+            frame,
+            PositionTargetTypeMask::YAW_IGNORE | PositionTargetTypeMask::YAW_RATE_IGNORE,
+        );
+        [args.x, args.y, args.z] = position;
+        [args.vx, args.vy, args.vz] = velocity;
+        [args.afx, args.afy, args.afz] = acceleration;
+        args
+    }
+
+    /// Turn in place at `yaw_rate` rad/s, everything else ignored
+    pub fn yaw_rate_only(frame: MavFrame, yaw_rate: f32) -> Self {
+        let mut args = Self::with_mask(
+            frame,
+            PositionTargetTypeMask::POSITION_IGNORE
+                | PositionTargetTypeMask::VELOCITY_IGNORE
+                | PositionTargetTypeMask::ACCELERATION_IGNORE
+                | PositionTargetTypeMask::YAW_IGNORE,
+        );
+        args.yaw_rate = yaw_rate;
+        args
+    }
+
+    /// Also command `yaw_rate` rad/s, replacing any yaw angle
+    pub fn with_yaw_rate(mut self, yaw_rate: f32) -> Self {
+        let mask = (self.type_mask_flags() | PositionTargetTypeMask::YAW_IGNORE) - PositionTargetTypeMask::YAW_RATE_IGNORE;
+        self.type_mask = mask.bits();
+        self.yaw = 0.0;
+        self.yaw_rate = yaw_rate;
+        self
+    }
+
+    pub fn with_target(mut self, target_system: u8, target_component: u8) -> Self {
+        self.target_system = target_system;
+        self.target_component = target_component;
+        self
+    }
+
+    fn with_mask(frame: MavFrame, mask: PositionTargetTypeMask) -> Self {
+        Self::new(
+            0,
+            DEFAULT_TARGET_SYSTEM,
+            DEFAULT_TARGET_COMPONENT,
+            frame as u8,
+            mask.bits(),
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+        )
+    }
+
+    /// `type_mask` with bits outside POSITION_TARGET_TYPEMASK dropped
+    pub fn type_mask_flags(&self) -> PositionTargetTypeMask {
+        PositionTargetTypeMask::from_bits_truncate(self.type_mask)
+    }
+
+    /// `coordinate_frame`, `None` if it is not a known MAV_FRAME
+    pub fn mav_frame(&self) -> Option<MavFrame> {
+        MavFrame::from_u8(self.coordinate_frame)
+    }
+
+    /// Check the setpoint would be accepted and actually command something
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(mask) = PositionTargetTypeMask::from_bits(self.type_mask) else {
+            return Err(format!("type_mask {:#06x} has unknown bits", self.type_mask));
+        };
+        match self.mav_frame() {
+            Some(frame) if frame.is_local() => {}
+            Some(frame) => return Err(format!("{:?} is not a local frame", frame)),
+            None => return Err(format!("unknown coordinate_frame {}", self.coordinate_frame)),
+        }
+        let all_ignored = PositionTargetTypeMask::POSITION_IGNORE
+            | PositionTargetTypeMask::VELOCITY_IGNORE
+            | PositionTargetTypeMask::ACCELERATION_IGNORE
+            | PositionTargetTypeMask::YAW_IGNORE
+            | PositionTargetTypeMask::YAW_RATE_IGNORE;
+        if mask.contains(all_ignored) {
+            return Err(format!("type_mask {:#06x} ignores every field", self.type_mask));
+        }
+        let fields = [
+            (PositionTargetTypeMask::X_IGNORE, self.x),
+            (PositionTargetTypeMask::Y_IGNORE, self.y),
+            (PositionTargetTypeMask::Z_IGNORE, self.z),
+            (PositionTargetTypeMask::VX_IGNORE, self.vx),
+            (PositionTargetTypeMask::VY_IGNORE, self.vy),
+            (PositionTargetTypeMask::VZ_IGNORE, self.vz),
+            (PositionTargetTypeMask::AX_IGNORE, self.afx),
+            (PositionTargetTypeMask::AY_IGNORE, self.afy),
+            (PositionTargetTypeMask::AZ_IGNORE, self.afz),
+            (PositionTargetTypeMask::YAW_IGNORE, self.yaw),
+            (PositionTargetTypeMask::YAW_RATE_IGNORE, self.yaw_rate),
+        ];
+        for (ignore, value) in fields {
+            if !mask.contains(ignore) && !value.is_finite() {
+                return Err(format!("field of {:?} is used but not finite", ignore));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL_FRAMES: [MavFrame; 4] =
+        [MavFrame::LocalNed, MavFrame::LocalOffsetNed, MavFrame::BodyNed, MavFrame::BodyOffsetNed];
+
+    fn ignored(args: &MavlinkArgs, flags: PositionTargetTypeMask) -> bool {
+        args.type_mask_flags().contains(flags)
+    }
+
+    #[test]
+    fn builders_make_valid_setpoints() {
+        for frame in LOCAL_FRAMES {
+            let velocity = MavlinkArgs::velocity_setpoint(frame, 1.0, -0.5, 0.2);
+            assert_eq!(velocity.validate(), Ok(()));
+            assert!(!ignored(&velocity, PositionTargetTypeMask::VELOCITY_IGNORE));
+            assert!(ignored(&velocity, PositionTargetTypeMask::POSITION_IGNORE | PositionTargetTypeMask::YAW_RATE_IGNORE));
+
+            let position = MavlinkArgs::position_setpoint(frame, 3.0, 4.0, -2.0);
+            assert_eq!(position.validate(), Ok(()));
+            assert!(!ignored(&position, PositionTargetTypeMask::X_IGNORE));
+            assert!(ignored(&position, PositionTargetTypeMask::VELOCITY_IGNORE));
+
+            let trajectory = MavlinkArgs::trajectory_setpoint(frame, [1.0, 2.0, -3.0], [0.5, 0.0, 0.0], [0.1, 0.0, 0.0]);
+            assert_eq!(trajectory.validate(), Ok(()));
+
+            let turn = MavlinkArgs::yaw_rate_only(frame, 0.5);
+            assert_eq!(turn.validate(), Ok(()));
+            assert_eq!(turn.yaw_rate, 0.5);
+            assert!(!ignored(&turn, PositionTargetTypeMask::YAW_RATE_IGNORE));
+
+            let turning = velocity.with_yaw_rate(-0.3);
+            assert_eq!(turning.validate(), Ok(()));
+            assert!(!ignored(&turning, PositionTargetTypeMask::YAW_RATE_IGNORE));
+            assert!(ignored(&turning, PositionTargetTypeMask::YAW_IGNORE));
+            assert!(!ignored(&turning, PositionTargetTypeMask::VELOCITY_IGNORE));
+            assert_eq!(turning.coordinate_frame, frame as u8);
+        }
+    }
+
+    #[test]
+    fn builders_target_the_first_autopilot() {
+        let args = MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 0.0, 0.0, 0.0);
+        assert_eq!((args.target_system, args.target_component), (DEFAULT_TARGET_SYSTEM, DEFAULT_TARGET_COMPONENT));
+        let args = args.with_target(2, 191);
+        assert_eq!((args.target_system, args.target_component), (2, 191));
+    }
+
+    #[test]
+    fn rejects_a_mask_that_ignores_everything() {
+        let mut args = MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 1.0, 0.0, 0.0);
+        args.type_mask |= PositionTargetTypeMask::VELOCITY_IGNORE.bits();
+        assert!(args.validate().unwrap_err().contains("ignores every field"));
+    }
+
+    #[test]
+    fn rejects_unknown_mask_bits() {
+        let mut args = MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 1.0, 0.0, 0.0);
+        args.type_mask |= 1 << 12;
+        assert!(args.validate().unwrap_err().contains("unknown bits"));
+        assert_eq!(args.type_mask_flags(), MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 1.0, 0.0, 0.0).type_mask_flags());
+    }
+
+    #[test]
+    fn rejects_frames_that_are_not_local() {
+        for frame in [MavFrame::Global, MavFrame::GlobalRelativeAltInt, MavFrame::LocalEnu, MavFrame::BodyFrd, MavFrame::LocalFrd] {
+            let args = MavlinkArgs::velocity_setpoint(frame, 1.0, 0.0, 0.0);
+            assert!(args.validate().unwrap_err().contains("not a local frame"), "{:?}", frame);
+        }
+        let mut args = MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 1.0, 0.0, 0.0);
+        args.coordinate_frame = 99;
+        assert!(args.validate().unwrap_err().contains("unknown coordinate_frame"));
+    }
+
+    #[test]
+    fn rejects_non_finite_fields_only_where_used() {
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert!(MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, value, 0.0, 0.0).validate().is_err());
+            assert!(MavlinkArgs::position_setpoint(MavFrame::LocalNed, 0.0, 0.0, value).validate().is_err());
+            assert!(MavlinkArgs::yaw_rate_only(MavFrame::BodyNed, value).validate().is_err());
+
+            // PX4 and ArduPilot send NaN in fields they ignore, so those are fine
+            let mut args = MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 1.0, 0.0, 0.0);
+            args.x = value;
+            args.yaw = value;
+            args.afz = value;
+            assert_eq!(args.validate(), Ok(()));
+
+            // Only vx is used, vy and vz are ignored
+            let mut args = MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, 1.0, value, value);
+            args.type_mask |= (PositionTargetTypeMask::VY_IGNORE | PositionTargetTypeMask::VZ_IGNORE).bits();
+            assert_eq!(args.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn frames_convert_from_their_mavlink_ids() {
+        for frame in LOCAL_FRAMES.into_iter().chain([MavFrame::Global, MavFrame::BodyFrd, MavFrame::LocalFlu]) {
+            assert_eq!(MavFrame::from_u8(frame as u8), Some(frame));
+        }
+        assert_eq!(MavFrame::from_u8(13), None);
+    }
 }
\ No newline at end of file
@@ -3,4 +3,13 @@ pub mod voxel_grid;
 pub mod mavlink_args;
 pub mod coordinate_switch;
 pub mod apf;
-pub mod point_divider;
\ No newline at end of file
+pub mod point_divider;
+pub mod vfh;
+pub mod grid_planner;
+pub mod planner;
+pub mod planner_benchmark;
+pub mod rrt_star;
+pub mod trajectory;
+pub mod goal;
+pub mod path_validation;
+pub mod emergency;
\ No newline at end of file
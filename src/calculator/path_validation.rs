@@ -0,0 +1,116 @@
+#![allow(dead_code)]
+use crate::data_reader::structor::Point3;
+use crate::octree::octree::Octree;
+
+/// Part of a path that the map blocks, `index` is the segment from `path[index]` to `path[index + 1]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockedSegment {
+    pub index: usize,
+    pub from: Point3,
+    pub to: Point3,
+}
+
+/// First segment of `path` that the vehicle cannot fly. The center line of every segment
+/// must be free and so must the rays `radius` around it, see `Octree::segment_clear`.
+/// The vehicle at the start and the final goal may be closer than `radius` to an obstacle,
+/// so those rays leave out the first `radius` of the first segment and the last `radius`
+/// of the last one.
+pub fn first_blocked_segment(path: &[Point3], octree: &Octree, radius: f32) -> Option<BlockedSegment> {
+    let last = path.len().saturating_sub(2);
+    path.windows(2).enumerate().find_map(|(index, segment)| {
+        let from = [segment[0].x, segment[0].y, segment[0].z];
+        let to = [segment[1].x, segment[1].y, segment[1].z];
+        let length = segment[1].sub(&segment[0]).norm();
+        let along = |distance: f32| [0, 1, 2].map(|i| from[i] + (to[i] - from[i]) * distance / length);
+        let start = if index == 0 { radius } else { 0.0 };
+        let end = if index == last { length - radius } else { length };
+        let clear = octree.segment_clear(from, to, 0.0)
+            && (radius <= 0.0 || end <= start || octree.segment_clear(along(start), along(end), radius));
+        (!clear).then_some(BlockedSegment { index, from: segment[0], to: segment[1] })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::crash_detector::VEHICLE_RADIUS;
+    use crate::calculator::planner::{GlobalPlanner, PlannerConfigs, PlanningMap};
+    use crate::octree::esdf::Esdf;
+
+    const BOUNDS: [[f32; 3]; 2] = [[-5.0; 3], [5.0; 3]];
+    const VOXEL: f32 = 10.0 / (1 << 6) as f32;
+
+    fn map(points: impl IntoIterator<Item = [f32; 3]>) -> Octree {
+        let mut octree = Octree::new(BOUNDS);
+        for point in points {
+            octree.insert(point, 6, 100).unwrap();
+        }
+        octree
+    }
+
+    /// 2 m square wall across the x axis at `x`
+    fn wall(x: f32) -> Vec<[f32; 3]> {
+        (-20..=20).flat_map(|i| (-20..=20).map(move |j| [x, i as f32 * 0.05, j as f32 * 0.05])).collect()
+    }
+
+    fn path(points: &[[f32; 3]]) -> Vec<Point3> {
+        points.iter().map(|p| Point3::new(p[0], p[1], p[2])).collect()
+    }
+
+    #[test]
+    fn planned_paths_stay_valid_on_the_same_map() {
+        let octree = map(wall(0.0).into_iter().chain(wall(2.5)));
+        let configs = PlannerConfigs::new(VOXEL);
+        let mut esdf = Esdf::new(BOUNDS, 0.15, configs.apf.esdf_max_distance(0.15));
+        esdf.update_from_octree(&octree);
+        let planning_map = PlanningMap { octree: &octree, esdf: &esdf };
+        for (start, goal) in [
+            (Point3::new(-2.0, 0.3, 0.2), Point3::new(1.2, -0.2, 0.1)),
+            (Point3::new(-2.0, -0.5, 0.0), Point3::new(4.0, 0.4, -0.3)),
+        ] {
+            for kind in GlobalPlanner::ALL {
+                let mut planner = kind.build(&configs);
+                let planned = planner.plan(start, goal, &planning_map).unwrap_or_else(|e| panic!("{}: {}", planner.name(), e));
+                assert_eq!(first_blocked_segment(&planned, &octree, planner.free_radius()), None, "{}", planner.name());
+            }
+        }
+    }
+
+    #[test]
+    fn new_obstacles_block_the_segment_they_are_on() {
+        let route = path(&[[-2.0, -1.0, 0.05], [-1.0, 1.0, 0.05], [1.0, 1.0, 0.05], [2.0, -1.0, 0.05]]);
+        assert_eq!(first_blocked_segment(&route, &map([]), VEHICLE_RADIUS), None);
+        // Clear of the center line and the radius around it
+        assert_eq!(first_blocked_segment(&route, &map([[0.0, 1.6, 0.05], [0.0, -1.0, 0.05]]), VEHICLE_RADIUS), None);
+
+        let on_the_line = map([[0.0, 1.0, 0.05]]);
+        let blocked = first_blocked_segment(&route, &on_the_line, VEHICLE_RADIUS);
+        assert_eq!(blocked, Some(BlockedSegment { index: 1, from: route[1], to: route[2] }));
+        assert_eq!(first_blocked_segment(&route, &on_the_line, 0.0).map(|segment| segment.index), Some(1));
+
+        let beside_the_line = map([[0.0, 1.2, 0.05]]);
+        assert_eq!(first_blocked_segment(&route, &beside_the_line, VEHICLE_RADIUS).map(|segment| segment.index), Some(1));
+        assert_eq!(first_blocked_segment(&route, &beside_the_line, 0.0), None);
+
+        let on_the_last = map([[1.5, 0.0, 0.05]]);
+        assert_eq!(first_blocked_segment(&route, &on_the_last, VEHICLE_RADIUS).map(|segment| segment.index), Some(2));
+    }
+
+    #[test]
+    fn start_and_goal_may_be_close_to_obstacles() {
+        let octree = map(wall(0.0));
+        let to_the_wall = path(&[[-2.0, 0.0, 0.0], [-1.0, 0.5, 0.0], [-0.3, 0.0, 0.0]]);
+        assert_eq!(first_blocked_segment(&to_the_wall, &octree, VEHICLE_RADIUS), None);
+        let from_the_wall = path(&[[-0.3, 0.0, 0.0], [-1.0, 0.5, 0.0], [-2.0, 0.0, 0.0]]);
+        assert_eq!(first_blocked_segment(&from_the_wall, &octree, VEHICLE_RADIUS), None);
+        let single = path(&[[-0.3, 0.0, 0.0], [-0.3, 0.5, 0.0]]);
+        assert_eq!(first_blocked_segment(&single, &octree, VEHICLE_RADIUS), None);
+
+        // Along the wall in between is too close
+        let along_the_wall = path(&[[-2.0, 0.0, 0.0], [-0.3, 0.0, 0.0], [-0.3, 0.8, 0.0], [-2.0, 0.8, 0.0]]);
+        assert_eq!(first_blocked_segment(&along_the_wall, &octree, VEHICLE_RADIUS).map(|segment| segment.index), Some(1));
+        // Through it is blocked even at the goal
+        let through_the_wall = path(&[[-2.0, 0.0, 0.0], [-1.0, 0.0, 0.0], [0.3, 0.0, 0.0]]);
+        assert_eq!(first_blocked_segment(&through_the_wall, &octree, VEHICLE_RADIUS).map(|segment| segment.index), Some(1));
+    }
+}
@@ -0,0 +1,396 @@
+#![allow(dead_code)]
+use std::fmt;
+use bevy::ecs::system::Resource;
+use crate::calculator::apf::{self, ApfConfig, ApfError, ApfEscapeConfig};
+use crate::calculator::grid_planner::{self, GridPlanError, GridPlannerConfig, GridSearch};
+use crate::calculator::rrt_star::{self, RrtConfig, RrtError};
+use crate::data_reader::structor::Point3;
+use crate::octree::esdf::Esdf;
+use crate::octree::octree::Octree;
+
+/// Which planner fills the `Path` resource, can be changed while the app runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Resource)]
+pub enum GlobalPlanner {
+    /// Artificial potential field in `apf::apf_plan_esdf`
+    #[default]
+    Apf,
+    /// 26-connected A* in `grid_planner::astar_plan`
+    AStar,
+    /// Any-angle Theta* in `grid_planner::theta_star_plan`
+    ThetaStar,
+    /// Sampling-based RRT* in `rrt_star::rrt_star_plan`, for large open maps
+    RrtStar,
+}
+
+impl GlobalPlanner {
+    pub const ALL: [GlobalPlanner; 4] = [GlobalPlanner::Apf, GlobalPlanner::AStar, GlobalPlanner::ThetaStar, GlobalPlanner::RrtStar];
+
+    pub fn parse(planner: &str) -> Result<Self, String> {
+        match planner {
+            "apf" => Ok(GlobalPlanner::Apf),
+            "astar" => Ok(GlobalPlanner::AStar),
+            "theta" => Ok(GlobalPlanner::ThetaStar),
+            "rrt" => Ok(GlobalPlanner::RrtStar),
+            _ => Err(format!("Unknown planner {}, expected apf, astar, theta or rrt", planner)),
+        }
+    }
+
+    /// The planner after this one, wrapping around
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|planner| *planner == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    pub fn build(self, configs: &PlannerConfigs) -> Box<dyn Planner> {
+        match self {
+            GlobalPlanner::Apf => Box::new(ApfPlanner::new(configs.apf.clone())),
+            GlobalPlanner::AStar => Box::new(GridPlanner::new(GridPlannerConfig { search: GridSearch::AStar, ..configs.grid })),
+            GlobalPlanner::ThetaStar => Box::new(GridPlanner::new(GridPlannerConfig { search: GridSearch::ThetaStar, ..configs.grid })),
+            GlobalPlanner::RrtStar => Box::new(RrtStarPlanner::new(configs.rrt)),
+        }
+    }
+}
+
+/// Settings of every planner, so switching planners keeps their tuning
+#[derive(Debug, Clone, Resource)]
+pub struct PlannerConfigs {
+    pub apf: ApfConfig,
+    pub grid: GridPlannerConfig,
+    pub rrt: RrtConfig,
+}
+
+impl PlannerConfigs {
+    /// Settings tuned for the Mid-360 map, obstacles are inflated by half a voxel
+    pub fn new(voxel_size: f32) -> Self {
+        Self {
+            apf: ApfConfig {
+                k_att: 2.5,
+                k_rep: 2.5,
+                d0: 0.7,
+                epsilon: 0.1,
+                max_steps: 500,
+                step_size: 0.1,
+                escape: ApfEscapeConfig::default(),
+            },
+            grid: GridPlannerConfig {
+                inflation: voxel_size / 2.0,
+                ..GridPlannerConfig::default()
+            },
+            rrt: RrtConfig {
+                inflation: voxel_size / 2.0,
+                ..RrtConfig::default()
+            },
+        }
+    }
+
+    /// Closest the grid and RRT* paths come to a leaf center
+    pub fn clearance(&self) -> f32 {
+        self.grid.vehicle_radius + self.grid.inflation
+    }
+}
+
+/// What a planner may look at
+pub struct PlanningMap<'a> {
+    pub octree: &'a Octree,
+    pub esdf: &'a Esdf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlanError {
+    /// The goal is inside an obstacle or in space the planner may not enter
+    GoalBlocked,
+    /// The planner searched everything it could reach
+    NoPath,
+    /// Stuck without a way out, with the path up to there
+    LocalMinimum { partial: Vec<Point3> },
+    /// Ran out of steps, expansions or time, with the path up to there if there is one
+    BudgetExhausted { partial: Vec<Point3> },
+    /// `replan` without a goal, either never set or cancelled
+    NoGoal,
+}
+
+impl PlanError {
+    /// Best path found before failing, empty if there is none
+    pub fn partial_path(&self) -> &[Point3] {
+        match self {
+            PlanError::LocalMinimum { partial } | PlanError::BudgetExhausted { partial } => partial,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::GoalBlocked => write!(f, "goal is blocked"),
+            PlanError::NoPath => write!(f, "no path to the goal"),
+            PlanError::LocalMinimum { partial } => write!(f, "stuck in a local minimum after {} waypoints", partial.len()),
+            PlanError::BudgetExhausted { partial } => write!(f, "budget exhausted after {} waypoints", partial.len()),
+            PlanError::NoGoal => write!(f, "no goal to plan to"),
+        }
+    }
+}
+
+impl From<ApfError> for PlanError {
+    fn from(error: ApfError) -> Self {
+        match error {
+            ApfError::LocalMinimum(plan) => PlanError::LocalMinimum { partial: plan.path },
+            ApfError::MaxStepsReached(plan) => PlanError::BudgetExhausted { partial: plan.path },
+        }
+    }
+}
+
+impl From<GridPlanError> for PlanError {
+    fn from(error: GridPlanError) -> Self {
+        match error {
+            GridPlanError::GoalBlocked => PlanError::GoalBlocked,
+            GridPlanError::NoPath => PlanError::NoPath,
+            GridPlanError::MaxExpansionsReached => PlanError::BudgetExhausted { partial: Vec::new() },
+        }
+    }
+}
+
+impl From<RrtError> for PlanError {
+    fn from(error: RrtError) -> Self {
+        match error {
+            RrtError::GoalBlocked => PlanError::GoalBlocked,
+            RrtError::NoPath => PlanError::BudgetExhausted { partial: Vec::new() },
+        }
+    }
+}
+
+/// A global planner. Paths start at `start` and end at the goal, in the map frame.
+pub trait Planner: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Plan to a new goal, which is kept for `replan`
+    fn plan(&mut self, start: Point3, goal: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError>;
+
+    /// Plan again to the last goal, e.g. after the vehicle moved or the map changed
+    fn replan(&mut self, start: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError>;
+
+    /// Forget the goal, `replan` fails with `NoGoal` until the next `plan`
+    fn cancel(&mut self);
+
+    fn goal(&self) -> Option<Point3>;
+
+    /// Radius around the center line of its paths that the planner keeps free, what a kept
+    /// path is checked with in `path_validation::first_blocked_segment`. APF paths only keep
+    /// the center line itself free.
+    fn free_radius(&self) -> f32 {
+        0.0
+    }
+}
+
+pub struct ApfPlanner {
+    pub config: ApfConfig,
+    goal: Option<Point3>,
+}
+
+impl ApfPlanner {
+    pub fn new(config: ApfConfig) -> Self {
+        Self { config, goal: None }
+    }
+}
+
+impl Planner for ApfPlanner {
+    fn name(&self) -> &'static str {
+        "APF"
+    }
+
+    fn plan(&mut self, start: Point3, goal: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+        self.goal = Some(goal);
+        apf::apf_plan_esdf(start, goal, map.esdf, self.config.clone())
+            .map(|plan| plan.path)
+            .map_err(PlanError::from)
+    }
+
+    fn replan(&mut self, start: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+        let goal = self.goal.ok_or(PlanError::NoGoal)?;
+        self.plan(start, goal, map)
+    }
+
+    fn cancel(&mut self) {
+        self.goal = None;
+    }
+
+    fn goal(&self) -> Option<Point3> {
+        self.goal
+    }
+}
+
+pub struct GridPlanner {
+    pub config: GridPlannerConfig,
+    goal: Option<Point3>,
+}
+
+impl GridPlanner {
+    pub fn new(config: GridPlannerConfig) -> Self {
+        Self { config, goal: None }
+    }
+}
+
+impl Planner for GridPlanner {
+    fn name(&self) -> &'static str {
+        match self.config.search {
+            GridSearch::AStar => "A*",
+            GridSearch::ThetaStar => "Theta*",
+        }
+    }
+
+    fn plan(&mut self, start: Point3, goal: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+        self.goal = Some(goal);
+        grid_planner::grid_plan(start, goal, map.octree, &self.config).map_err(PlanError::from)
+    }
+
+    fn replan(&mut self, start: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+        let goal = self.goal.ok_or(PlanError::NoGoal)?;
+        self.plan(start, goal, map)
+    }
+
+    fn cancel(&mut self) {
+        self.goal = None;
+    }
+
+    fn goal(&self) -> Option<Point3> {
+        self.goal
+    }
+
+    fn free_radius(&self) -> f32 {
+        self.config.vehicle_radius
+    }
+}
+
+pub struct RrtStarPlanner {
+    pub config: RrtConfig,
+    goal: Option<Point3>,
+}
+
+impl RrtStarPlanner {
+    pub fn new(config: RrtConfig) -> Self {
+        Self { config, goal: None }
+    }
+}
+
+impl Planner for RrtStarPlanner {
+    fn name(&self) -> &'static str {
+        "RRT*"
+    }
+
+    fn plan(&mut self, start: Point3, goal: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+        self.goal = Some(goal);
+        rrt_star::rrt_star_plan(start, goal, map.octree, &self.config).map_err(PlanError::from)
+    }
+
+    fn replan(&mut self, start: Point3, map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+        let goal = self.goal.ok_or(PlanError::NoGoal)?;
+        self.plan(start, goal, map)
+    }
+
+    fn cancel(&mut self) {
+        self.goal = None;
+    }
+
+    fn goal(&self) -> Option<Point3> {
+        self.goal
+    }
+
+    fn free_radius(&self) -> f32 {
+        self.config.vehicle_radius
+    }
+}
+
+/// The planner currently filling the `Path` resource
+#[derive(Resource)]
+pub struct ActivePlanner(pub Box<dyn Planner>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOXEL: f32 = 10.0 / (1 << 6) as f32;
+
+    /// 2 m square wall across the x axis, between the start and goal of the tests
+    fn map() -> (Octree, Esdf) {
+        let bounds = [[-5.0; 3], [5.0; 3]];
+        let mut octree = Octree::new(bounds);
+        for i in -20..=20 {
+            for j in -20..=20 {
+                octree.insert([0.0, i as f32 * 0.05, j as f32 * 0.05], 6, 100).unwrap();
+            }
+        }
+        let configs = PlannerConfigs::new(VOXEL);
+        let mut esdf = Esdf::new(bounds, 0.15, configs.apf.esdf_max_distance(0.15));
+        esdf.update_from_octree(&octree);
+        (octree, esdf)
+    }
+
+    fn assert_near(a: Point3, b: Point3, tolerance: f32, what: &str) {
+        assert!(a.sub(&b).norm() <= tolerance, "{}: {:?} != {:?}", what, a, b);
+    }
+
+    #[test]
+    fn every_planner_plans_replans_and_cancels() {
+        let (octree, esdf) = map();
+        let planning_map = PlanningMap { octree: &octree, esdf: &esdf };
+        let configs = PlannerConfigs::new(VOXEL);
+        let (start, goal) = (Point3::new(-2.0, 0.3, 0.2), Point3::new(2.0, -0.2, 0.1));
+        for kind in GlobalPlanner::ALL {
+            let mut planner = kind.build(&configs);
+            assert_eq!(planner.goal(), None);
+            assert_eq!(planner.replan(start, &planning_map), Err(PlanError::NoGoal));
+
+            let path = planner.plan(start, goal, &planning_map).unwrap_or_else(|e| panic!("{}: {}", planner.name(), e));
+            assert_eq!(planner.goal(), Some(goal));
+            assert_near(path[0], start, 1e-4, planner.name());
+            assert_near(*path.last().unwrap(), goal, configs.apf.epsilon, planner.name());
+
+            // From further along, to the same goal
+            let moved = Point3::new(-1.5, 1.8, 0.0);
+            let path = planner.replan(moved, &planning_map).unwrap_or_else(|e| panic!("{}: {}", planner.name(), e));
+            assert_near(path[0], moved, 1e-4, planner.name());
+            assert_near(*path.last().unwrap(), goal, configs.apf.epsilon, planner.name());
+            assert_eq!(planner.goal(), Some(goal));
+
+            planner.cancel();
+            assert_eq!(planner.goal(), None);
+            assert_eq!(planner.replan(moved, &planning_map), Err(PlanError::NoGoal));
+        }
+    }
+
+    #[test]
+    fn goal_in_the_wall_is_blocked() {
+        let (octree, esdf) = map();
+        let planning_map = PlanningMap { octree: &octree, esdf: &esdf };
+        let configs = PlannerConfigs::new(VOXEL);
+        for kind in [GlobalPlanner::AStar, GlobalPlanner::ThetaStar, GlobalPlanner::RrtStar] {
+            let mut planner = kind.build(&configs);
+            let result = planner.plan(Point3::new(-2.0, 0.0, 0.0), Point3::new(0.0, 0.0, 0.0), &planning_map);
+            assert_eq!(result, Err(PlanError::GoalBlocked), "{}", planner.name());
+            // The goal is kept for `replan` even if planning failed
+            assert_eq!(planner.goal(), Some(Point3::new(0.0, 0.0, 0.0)));
+        }
+    }
+
+    #[test]
+    fn planners_cycle_and_parse() {
+        let mut planner = GlobalPlanner::Apf;
+        for _ in 0..GlobalPlanner::ALL.len() {
+            planner = planner.next();
+        }
+        assert_eq!(planner, GlobalPlanner::Apf);
+        assert_eq!(GlobalPlanner::Apf.next(), GlobalPlanner::AStar);
+        assert_eq!(GlobalPlanner::parse("theta"), Ok(GlobalPlanner::ThetaStar));
+        assert!(GlobalPlanner::parse("dijkstra").is_err());
+    }
+
+    #[test]
+    fn planner_errors_keep_the_partial_path() {
+        let partial = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(0.1, 0.0, 0.0)];
+        assert_eq!(PlanError::LocalMinimum { partial: partial.clone() }.partial_path(), &partial[..]);
+        assert_eq!(PlanError::BudgetExhausted { partial: partial.clone() }.partial_path(), &partial[..]);
+        assert!(PlanError::NoPath.partial_path().is_empty());
+        assert_eq!(PlanError::from(GridPlanError::MaxExpansionsReached), PlanError::BudgetExhausted { partial: Vec::new() });
+        assert_eq!(PlanError::from(RrtError::NoPath), PlanError::BudgetExhausted { partial: Vec::new() });
+    }
+}
@@ -0,0 +1,320 @@
+#![allow(dead_code)]
+use std::fmt;
+use std::path::Path;
+use std::time::Instant;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::calculator::crash_detector::VEHICLE_RADIUS;
+use crate::calculator::planner::{GlobalPlanner, Planner, PlannerConfigs, PlanningMap};
+use crate::data_reader::structor::Point3;
+use crate::octree::esdf::Esdf;
+use crate::octree::octree::Octree;
+use crate::octree::octree_file;
+
+#[derive(Debug, Clone)]
+pub struct BenchmarkConfig {
+    pub queries_per_map: usize,  // Start and goal pairs planned on every map
+    pub min_query_distance: f32, // Start and goal are at least this far apart
+    pub query_clearance: f32,    // Start and goal are at least this far from obstacles
+    pub collision_radius: f32,   // A returned path only counts as a success if a body this wide clears every leaf along it
+    pub esdf_resolution: f32,
+    pub voxel_size: f32,         // Passed to `PlannerConfigs::new`
+    pub seed: u64,               // Same seed and maps give the same queries
+}
+
+impl Default for BenchmarkConfig {
+    fn default() -> Self {
+        Self {
+            queries_per_map: 20,
+            min_query_distance: 3.0,
+            query_clearance: 0.5,
+            collision_radius: VEHICLE_RADIUS,
+            esdf_resolution: 0.15,
+            voxel_size: 0.08,
+            seed: 0,
+        }
+    }
+}
+
+/// One map of the suite
+pub struct BenchmarkMap {
+    pub name: String,
+    pub octree: Octree,
+}
+
+impl BenchmarkMap {
+    /// Load a map saved with `octree_file::save_octree`
+    pub fn load(file_name: &str) -> std::io::Result<Self> {
+        let name = Path::new(file_name)
+            .file_name()
+            .map_or_else(|| file_name.to_string(), |name| name.to_string_lossy().into_owned());
+        Ok(Self { name, octree: octree_file::load_octree(file_name)? })
+    }
+}
+
+/// Results of one planner on one map, lengths and clearances over the successful runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkRow {
+    pub planner: &'static str,
+    pub map: String,
+    pub runs: usize,
+    pub successes: usize,
+    pub collisions: usize,           // Paths returned that cut through obstacles, not counted as successes
+    pub mean_length: Option<f32>,
+    pub min_clearance: Option<f32>,  // Closest any path came to an occupied leaf center
+    pub mean_clearance: Option<f32>, // Mean over paths of each path's closest approach
+    pub mean_runtime_ms: f32,        // Over all runs, failed ones included
+}
+
+impl BenchmarkRow {
+    pub fn success_rate(&self) -> f32 {
+        if self.runs == 0 {
+            0.0
+        } else {
+            self.successes as f32 / self.runs as f32
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BenchmarkReport {
+    pub rows: Vec<BenchmarkRow>,
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let optional = |value: Option<f32>| value.map_or_else(|| "-".to_string(), |value| format!("{:.2}", value));
+        writeln!(
+            f,
+            "{:<8} {:<24} {:>8} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            "planner", "map", "success", "collisions", "length m", "min clr m", "mean clr m", "runtime ms"
+        )?;
+        for row in &self.rows {
+            writeln!(
+                f,
+                "{:<8} {:<24} {:>7.0}% {:>10} {:>10} {:>10} {:>10} {:>12.1}",
+                row.planner,
+                row.map,
+                row.success_rate() * 100.0,
+                row.collisions,
+                optional(row.mean_length),
+                optional(row.min_clearance),
+                optional(row.mean_clearance),
+                row.mean_runtime_ms,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every planner on the same start and goal pairs of every map
+pub fn run_benchmark(maps: &[BenchmarkMap], planners: &[GlobalPlanner], config: &BenchmarkConfig) -> BenchmarkReport {
+    let configs = PlannerConfigs::new(config.voxel_size);
+    let mut report = BenchmarkReport::default();
+    for map in maps {
+        let mut esdf = Esdf::new(
+            *map.octree.get_root().bounds(),
+            config.esdf_resolution,
+            configs.apf.esdf_max_distance(config.esdf_resolution),
+        );
+        esdf.update_from_octree(&map.octree);
+        let planning_map = PlanningMap { octree: &map.octree, esdf: &esdf };
+        let queries = sample_queries(&map.octree, config);
+
+        for planner_kind in planners {
+            let mut planner = planner_kind.build(&configs);
+            report.rows.push(benchmark_planner(planner.as_mut(), map, &planning_map, &queries, config));
+        }
+    }
+    report
+}
+
+/// Plan every query with one planner. A path only counts if it clears the map.
+fn benchmark_planner(
+    planner: &mut dyn Planner,
+    map: &BenchmarkMap,
+    planning_map: &PlanningMap,
+    queries: &[(Point3, Point3)],
+    config: &BenchmarkConfig,
+) -> BenchmarkRow {
+    let mut runtime_ms = 0.0;
+    let mut collisions = 0;
+    let mut lengths = Vec::new();
+    let mut clearances = Vec::new();
+    for (start, goal) in queries {
+        let started = Instant::now();
+        let result = planner.plan(*start, *goal, planning_map);
+        runtime_ms += started.elapsed().as_secs_f32() * 1000.0;
+        let Ok(path) = result else {
+            continue;
+        };
+        if !path_clear(&map.octree, &path, config.collision_radius) {
+            collisions += 1;
+            continue;
+        }
+        lengths.push(path_length(&path));
+        clearances.push(path_clearance(&map.octree, &path, config.voxel_size / 2.0));
+    }
+    BenchmarkRow {
+        planner: planner.name(),
+        map: map.name.clone(),
+        runs: queries.len(),
+        successes: lengths.len(),
+        collisions,
+        mean_length: mean(&lengths),
+        min_clearance: clearances.iter().copied().reduce(f32::min),
+        mean_clearance: mean(&clearances),
+        mean_runtime_ms: if queries.is_empty() { 0.0 } else { runtime_ms / queries.len() as f32 },
+    }
+}
+
+/// Free start and goal pairs inside the map bounds, fewer than asked for if the map
+/// has too little free space
+fn sample_queries(octree: &Octree, config: &BenchmarkConfig) -> Vec<(Point3, Point3)> {
+    let bounds = *octree.get_root().bounds();
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let free_point = |rng: &mut StdRng| {
+        for _ in 0..1000 {
+            let point = [0, 1, 2].map(|i| rng.gen_range(bounds[0][i]..bounds[1][i]));
+            if octree
+                .nearest_obstacle_distance(point)
+                .is_none_or(|distance| distance >= config.query_clearance)
+            {
+                return Some(Point3::new(point[0], point[1], point[2]));
+            }
+        }
+        None
+    };
+    let mut queries = Vec::new();
+    for _ in 0..config.queries_per_map * 10 {
+        if queries.len() == config.queries_per_map {
+            break;
+        }
+        let (Some(start), Some(goal)) = (free_point(&mut rng), free_point(&mut rng)) else {
+            break;
+        };
+        if goal.sub(&start).norm() >= config.min_query_distance {
+            queries.push((start, goal));
+        }
+    }
+    queries
+}
+
+/// Whether a body of `radius` can fly every segment of the path
+pub fn path_clear(octree: &Octree, path: &[Point3], radius: f32) -> bool {
+    path.windows(2)
+        .all(|segment| octree.segment_clear([segment[0].x, segment[0].y, segment[0].z], [segment[1].x, segment[1].y, segment[1].z], radius))
+}
+
+pub fn path_length(path: &[Point3]) -> f32 {
+    path.windows(2).map(|segment| segment[1].sub(&segment[0]).norm()).sum()
+}
+
+/// Smallest distance from the path to an occupied leaf center, checked every `spacing`
+/// along each segment
+pub fn path_clearance(octree: &Octree, path: &[Point3], spacing: f32) -> f32 {
+    let mut clearance = f32::INFINITY;
+    for segment in path.windows(2) {
+        let offset = segment[1].sub(&segment[0]);
+        let samples = (offset.norm() / spacing.max(1e-3)).ceil().max(1.0) as usize;
+        for i in 0..=samples {
+            let t = i as f32 / samples as f32;
+            let point = [segment[0].x + offset.x * t, segment[0].y + offset.y * t, segment[0].z + offset.z * t];
+            if let Some(distance) = octree.nearest_obstacle_distance(point) {
+                clearance = clearance.min(distance);
+            }
+        }
+    }
+    clearance
+}
+
+fn mean(values: &[f32]) -> Option<f32> {
+    (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::planner::PlanError;
+
+    /// 2 m square wall across the x axis
+    fn wall_map() -> BenchmarkMap {
+        let mut octree = Octree::new([[-5.0; 3], [5.0; 3]]);
+        for i in -20..=20 {
+            for j in -20..=20 {
+                octree.insert([0.0, i as f32 * 0.05, j as f32 * 0.05], 6, 100).unwrap();
+            }
+        }
+        BenchmarkMap { name: "wall".to_string(), octree }
+    }
+
+    /// Flies straight to the goal whatever is in the way
+    struct StraightLine;
+
+    impl Planner for StraightLine {
+        fn name(&self) -> &'static str {
+            "line"
+        }
+
+        fn plan(&mut self, start: Point3, goal: Point3, _map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+            Ok(vec![start, goal])
+        }
+
+        fn replan(&mut self, _start: Point3, _map: &PlanningMap) -> Result<Vec<Point3>, PlanError> {
+            Err(PlanError::NoGoal)
+        }
+
+        fn cancel(&mut self) {}
+
+        fn goal(&self) -> Option<Point3> {
+            None
+        }
+    }
+
+    #[test]
+    fn queries_are_free_far_apart_and_seeded() {
+        let map = wall_map();
+        let config = BenchmarkConfig { queries_per_map: 10, ..BenchmarkConfig::default() };
+        let queries = sample_queries(&map.octree, &config);
+        assert_eq!(queries.len(), 10);
+        assert_eq!(queries, sample_queries(&map.octree, &config));
+        assert_ne!(queries, sample_queries(&map.octree, &BenchmarkConfig { seed: 1, ..config.clone() }));
+        for (start, goal) in &queries {
+            assert!(goal.sub(start).norm() >= config.min_query_distance);
+            for point in [start, goal] {
+                assert!(map.octree.nearest_obstacle_distance([point.x, point.y, point.z]).unwrap() >= config.query_clearance);
+                assert!([point.x, point.y, point.z].iter().all(|c| c.abs() <= 5.0));
+            }
+        }
+    }
+
+    #[test]
+    fn paths_through_obstacles_are_not_successes() {
+        let map = wall_map();
+        let esdf = Esdf::new([[-5.0; 3], [5.0; 3]], 0.5, 1.0);
+        let planning_map = PlanningMap { octree: &map.octree, esdf: &esdf };
+        let config = BenchmarkConfig::default();
+        let through = (Point3::new(-2.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0));
+        let past = (Point3::new(-2.0, 3.0, 0.0), Point3::new(2.0, 3.0, 0.0));
+        let grazing = (Point3::new(-2.0, 1.2, 0.0), Point3::new(2.0, 1.2, 0.0));
+
+        let row = benchmark_planner(&mut StraightLine, &map, &planning_map, &[through, past, grazing], &config);
+        assert_eq!((row.runs, row.successes, row.collisions), (3, 1, 2));
+        assert_eq!(row.mean_length, Some(4.0));
+        assert!(row.min_clearance.unwrap() > 1.9);
+    }
+
+    #[test]
+    fn grid_planners_clear_the_map() {
+        let config = BenchmarkConfig { queries_per_map: 4, voxel_size: 10.0 / 64.0, ..BenchmarkConfig::default() };
+        let report = run_benchmark(&[wall_map()], &[GlobalPlanner::AStar, GlobalPlanner::ThetaStar], &config);
+        assert_eq!(report.rows.len(), 2);
+        for row in &report.rows {
+            assert_eq!((row.map.as_str(), row.runs), ("wall", 4));
+            assert_eq!((row.successes, row.collisions), (4, 0), "{}", row.planner);
+            assert!(row.min_clearance.unwrap() >= config.collision_radius);
+        }
+        let table = report.to_string();
+        assert!(table.contains("A*") && table.contains("Theta*") && table.contains("100%"));
+    }
+}
@@ -0,0 +1,359 @@
+#![allow(dead_code)]
+use std::fmt;
+use std::time::{Duration, Instant};
+use bevy::ecs::system::Resource;
+use bevy::math::{Quat, Vec3};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::calculator::crash_detector::{LEAF_INFLATION, VEHICLE_RADIUS};
+use crate::octree::octree::Octree;
+use crate::data_reader::structor::Point3;
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct RrtConfig {
+    pub max_iterations: u32,           // Samples drawn at most
+    pub time_budget: Option<Duration>, // Stop sampling after this long, `None` to only count iterations
+    pub step_size: f32,                // Longest edge added to the tree
+    pub rewire_radius: f32,            // Nodes this close to a new node are considered as parents and rewired
+    pub goal_bias: f32,                // Fraction of samples taken at the goal before the first solution
+    pub informed: bool,                // Sample the ellipsoid that can still improve the solution once there is one
+    pub vehicle_radius: f32,           // Edges and nodes keep this far from obstacles
+    pub inflation: f32,                // Added to the vehicle radius, half a voxel since leaves are only known by their center
+    pub seed: u64,                     // Same seed and map give the same path when the iterations run out first
+}
+
+impl Default for RrtConfig {
+    fn default() -> Self {
+        Self {
+            max_iterations: 5000,
+            time_budget: Some(Duration::from_millis(100)),
+            step_size: 0.5,
+            rewire_radius: 1.0,
+            goal_bias: 0.1,
+            informed: true,
+            vehicle_radius: VEHICLE_RADIUS,
+            inflation: LEAF_INFLATION,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RrtError {
+    /// The goal is outside the octree or too close to an obstacle
+    GoalBlocked,
+    /// The budget ran out before the tree reached the goal
+    NoPath,
+}
+
+impl fmt::Display for RrtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RrtError::GoalBlocked => write!(f, "goal is blocked"),
+            RrtError::NoPath => write!(f, "no path to the goal within the budget"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    position: Vec3,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    cost: f32, // Path length from the start
+}
+
+/// RRT* from `start` to `goal`, sampling inside the octree bounds. Keeps improving the
+/// first solution until the iterations or the time budget run out.
+pub fn rrt_star_plan(start: Point3, goal: Point3, octree: &Octree, config: &RrtConfig) -> Result<Vec<Point3>, RrtError> {
+    let started = Instant::now();
+    let bounds = *octree.get_root().bounds();
+    let start = Vec3::new(start.x, start.y, start.z);
+    let goal = Vec3::new(goal.x, goal.y, goal.z);
+    let clearance = config.vehicle_radius + config.inflation;
+    if !inside(&bounds, goal) || !node_free(octree, goal, clearance) {
+        return Err(RrtError::GoalBlocked);
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut nodes = vec![Node { position: start, parent: None, children: Vec::new(), cost: 0.0 }];
+    // Nodes with a free edge to the goal
+    let mut goal_parents: Vec<usize> = Vec::new();
+    let direct_distance = start.distance(goal);
+    let ellipsoid_rotation = Quat::from_rotation_arc(Vec3::X, (goal - start).normalize_or(Vec3::X));
+
+    for _ in 0..config.max_iterations {
+        if config.time_budget.is_some_and(|budget| started.elapsed() >= budget) {
+            break;
+        }
+        let best = best_goal_parent(&nodes, &goal_parents, goal);
+        let sample = match best {
+            Some((_, best_cost)) if config.informed => {
+                // Nothing can beat the straight line
+                if best_cost <= direct_distance * 1.0001 {
+                    break;
+                }
+                informed_sample(&mut rng, start, goal, best_cost, ellipsoid_rotation)
+            }
+            None if rng.gen::<f32>() < config.goal_bias => goal,
+            _ => uniform_sample(&mut rng, &bounds),
+        };
+        if !inside(&bounds, sample) {
+            continue;
+        }
+
+        let nearest = nearest_node(&nodes, sample);
+        let new_position = steer(nodes[nearest].position, sample, config.step_size);
+        if !node_free(octree, new_position, clearance) || !edge_free(octree, nodes[nearest].position, new_position, clearance) {
+            continue;
+        }
+
+        // Cheapest parent among the neighbours
+        let near: Vec<usize> = (0..nodes.len())
+            .filter(|&i| nodes[i].position.distance(new_position) <= config.rewire_radius)
+            .collect();
+        let mut parent = nearest;
+        let mut cost = nodes[nearest].cost + nodes[nearest].position.distance(new_position);
+        for &candidate in &near {
+            let candidate_cost = nodes[candidate].cost + nodes[candidate].position.distance(new_position);
+            if candidate_cost < cost && edge_free(octree, nodes[candidate].position, new_position, clearance) {
+                parent = candidate;
+                cost = candidate_cost;
+            }
+        }
+        let new = nodes.len();
+        nodes.push(Node { position: new_position, parent: Some(parent), children: Vec::new(), cost });
+        nodes[parent].children.push(new);
+
+        // Rewire the neighbours through the new node where that is shorter
+        for &neighbor in &near {
+            let rewired_cost = cost + new_position.distance(nodes[neighbor].position);
+            if rewired_cost < nodes[neighbor].cost && edge_free(octree, new_position, nodes[neighbor].position, clearance) {
+                if let Some(old_parent) = nodes[neighbor].parent {
+                    nodes[old_parent].children.retain(|&child| child != neighbor);
+                }
+                nodes[neighbor].parent = Some(new);
+                nodes[new].children.push(neighbor);
+                propagate_cost(&mut nodes, neighbor, rewired_cost);
+            }
+        }
+
+        if new_position.distance(goal) <= config.step_size && edge_free(octree, new_position, goal, clearance) {
+            goal_parents.push(new);
+        }
+    }
+
+    let (last, _) = best_goal_parent(&nodes, &goal_parents, goal).ok_or(RrtError::NoPath)?;
+    let mut path = vec![Point3::new(goal.x, goal.y, goal.z)];
+    let mut node = Some(last);
+    while let Some(index) = node {
+        let position = nodes[index].position;
+        path.push(Point3::new(position.x, position.y, position.z));
+        node = nodes[index].parent;
+    }
+    path.reverse();
+    Ok(path)
+}
+
+/// Node to connect the goal to and the resulting path length
+fn best_goal_parent(nodes: &[Node], goal_parents: &[usize], goal: Vec3) -> Option<(usize, f32)> {
+    goal_parents
+        .iter()
+        .map(|&index| (index, nodes[index].cost + nodes[index].position.distance(goal)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// Set a node's cost and shift its whole subtree by the same amount
+fn propagate_cost(nodes: &mut [Node], root: usize, cost: f32) {
+    let delta = cost - nodes[root].cost;
+    let mut stack = vec![root];
+    while let Some(index) = stack.pop() {
+        nodes[index].cost += delta;
+        stack.extend(nodes[index].children.iter().copied());
+    }
+}
+
+fn nearest_node(nodes: &[Node], point: Vec3) -> usize {
+    nodes
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.position.distance_squared(point).total_cmp(&b.1.position.distance_squared(point)))
+        .map_or(0, |(index, _)| index)
+}
+
+fn steer(from: Vec3, to: Vec3, step_size: f32) -> Vec3 {
+    let offset = to - from;
+    if offset.length() <= step_size {
+        to
+    } else {
+        from + offset.normalize() * step_size
+    }
+}
+
+fn inside(bounds: &[[f32; 3]; 2], point: Vec3) -> bool {
+    let point = point.to_array();
+    (0..3).all(|i| point[i] >= bounds[0][i] && point[i] <= bounds[1][i])
+}
+
+fn uniform_sample(rng: &mut StdRng, bounds: &[[f32; 3]; 2]) -> Vec3 {
+    Vec3::new(
+        rng.gen_range(bounds[0][0]..=bounds[1][0]),
+        rng.gen_range(bounds[0][1]..=bounds[1][1]),
+        rng.gen_range(bounds[0][2]..=bounds[1][2]),
+    )
+}
+
+/// Uniform sample of the prolate ellipsoid with foci `start` and `goal` that holds every
+/// point through which a path shorter than `best_cost` could pass
+fn informed_sample(rng: &mut StdRng, start: Vec3, goal: Vec3, best_cost: f32, rotation: Quat) -> Vec3 {
+    let direct_distance = start.distance(goal);
+    let major = best_cost / 2.0;
+    let minor = (best_cost * best_cost - direct_distance * direct_distance).max(0.0).sqrt() / 2.0;
+    let ball = loop {
+        let candidate = Vec3::new(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0));
+        if candidate.length_squared() <= 1.0 {
+            break candidate;
+        }
+    };
+    (start + goal) / 2.0 + rotation * Vec3::new(ball.x * major, ball.y * minor, ball.z * minor)
+}
+
+fn node_free(octree: &Octree, point: Vec3, clearance: f32) -> bool {
+    octree
+        .nearest_obstacle_distance(point.to_array())
+        .is_none_or(|distance| distance >= clearance)
+}
+
+fn edge_free(octree: &Octree, from: Vec3, to: Vec3, clearance: f32) -> bool {
+    octree.segment_clear(from.to_array(), to.to_array(), clearance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A wall at x = 2 with a gap above y = 1
+    fn wall() -> Octree {
+        let mut octree = Octree::new([[-5.0; 3], [5.0; 3]]);
+        for i in -40..=8 {
+            for k in -10..=10 {
+                octree.insert([2.0, i as f32 * 0.1, k as f32 * 0.1], 6, 100).unwrap();
+            }
+        }
+        octree
+    }
+
+    fn config(seed: u64) -> RrtConfig {
+        RrtConfig { max_iterations: 1500, time_budget: None, seed, ..RrtConfig::default() }
+    }
+
+    #[test]
+    fn same_seed_gives_same_path() {
+        let octree = wall();
+        let start = Point3::new(0.0, 0.0, 0.0);
+        let goal = Point3::new(4.0, 0.0, 0.0);
+        let first = rrt_star_plan(start, goal, &octree, &config(7)).unwrap();
+        let second = rrt_star_plan(start, goal, &octree, &config(7)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.first(), Some(&start));
+        assert_eq!(first.last(), Some(&goal));
+
+        let clearance = config(7).vehicle_radius + config(7).inflation;
+        for edge in first.windows(2) {
+            let from = Vec3::new(edge[0].x, edge[0].y, edge[0].z);
+            let to = Vec3::new(edge[1].x, edge[1].y, edge[1].z);
+            assert!(edge_free(&octree, from, to, clearance));
+        }
+    }
+
+    #[test]
+    fn blocked_goal_is_rejected() {
+        let octree = wall();
+        let result = rrt_star_plan(Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0), &octree, &config(0));
+        assert_eq!(result, Err(RrtError::GoalBlocked));
+    }
+
+    fn length(path: &[Point3]) -> f32 {
+        path.windows(2).map(|edge| edge[1].sub(&edge[0]).norm()).sum()
+    }
+
+    #[test]
+    fn zero_time_budget_stops_before_sampling() {
+        let open = Octree::new([[-5.0; 3], [5.0; 3]]);
+        let budget = RrtConfig { time_budget: Some(Duration::ZERO), ..config(0) };
+        let result = rrt_star_plan(Point3::new(0.0, 0.0, 0.0), Point3::new(4.0, 0.0, 0.0), &open, &budget);
+        assert_eq!(result, Err(RrtError::NoPath));
+
+        // Far more iterations than the budget allows
+        let started = Instant::now();
+        let budget = RrtConfig { max_iterations: u32::MAX, time_budget: Some(Duration::from_millis(50)), informed: false, ..config(0) };
+        assert!(rrt_star_plan(Point3::new(0.0, 0.0, 0.0), Point3::new(4.0, 0.0, 0.0), &wall(), &budget).is_ok());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn informed_samples_can_improve_the_solution() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let (start, goal) = (Vec3::new(-1.0, 0.5, 0.0), Vec3::new(2.0, -1.0, 1.0));
+        let best_cost = start.distance(goal) * 1.3;
+        let rotation = Quat::from_rotation_arc(Vec3::X, (goal - start).normalize());
+        let mut widest: f32 = 0.0;
+        for _ in 0..2000 {
+            let sample = informed_sample(&mut rng, start, goal, best_cost, rotation);
+            assert!(sample.distance(start) + sample.distance(goal) <= best_cost + 1e-4, "{:?}", sample);
+            let along = (sample - start).dot((goal - start).normalize());
+            widest = widest.max((sample - start - (goal - start).normalize() * along).length());
+        }
+        // Not only the line between the foci
+        assert!(widest > 0.5, "{}", widest);
+    }
+
+    #[test]
+    fn rewiring_shortens_the_path() {
+        let open = Octree::new([[-2.0; 3], [2.0; 3]]);
+        let (start, goal) = (Point3::new(-1.5, -0.5, 0.0), Point3::new(1.5, 0.5, 0.3));
+        let direct = goal.sub(&start).norm();
+        // Without informed sampling only rewiring makes the path shorter
+        let uninformed = |max_iterations| RrtConfig { max_iterations, informed: false, ..config(5) };
+        let first = length(&rrt_star_plan(start, goal, &open, &uninformed(200)).unwrap());
+        let mut previous = first;
+        for max_iterations in [1000, 3000] {
+            let length = length(&rrt_star_plan(start, goal, &open, &uninformed(max_iterations)).unwrap());
+            assert!(length <= previous + 1e-4, "{} iterations: {} after {}", max_iterations, length, previous);
+            previous = length;
+        }
+        assert!(previous < first - 0.1, "{} after {}", previous, first);
+        assert!(previous < direct * 1.05, "{} for a straight line of {}", previous, direct);
+    }
+
+    #[test]
+    fn full_goal_bias_goes_straight() {
+        let open = Octree::new([[-5.0; 3], [5.0; 3]]);
+        let (start, goal) = (Point3::new(0.0, 0.0, 0.0), Point3::new(3.2, 2.4, 0.0));
+        let biased = |max_iterations| RrtConfig { max_iterations, goal_bias: 1.0, ..config(0) };
+        let iterations = (goal.sub(&start).norm() / biased(0).step_size).ceil() as u32;
+
+        let path = rrt_star_plan(start, goal, &open, &biased(iterations)).unwrap();
+        assert!((length(&path) - 4.0).abs() < 1e-4);
+        assert!(path.iter().all(|point| (point.x * 0.75 - point.y).abs() < 1e-4 && point.z == 0.0));
+        assert_eq!(rrt_star_plan(start, goal, &open, &biased(iterations - 2)), Err(RrtError::NoPath));
+    }
+
+    #[test]
+    fn walled_off_goal_has_no_path() {
+        // A closed box around a free goal
+        let mut octree = Octree::new([[-5.0; 3], [5.0; 3]]);
+        for i in -12..=12 {
+            for j in -12..=12 {
+                let (a, b) = (i as f32 * 0.1, j as f32 * 0.1);
+                for side in [-1.2, 1.2] {
+                    octree.insert([3.0 + side, a, b], 6, 100).unwrap();
+                    octree.insert([3.0 + a, side, b], 6, 100).unwrap();
+                    octree.insert([3.0 + a, b, side], 6, 100).unwrap();
+                }
+            }
+        }
+        let result = rrt_star_plan(Point3::new(0.0, 0.0, 0.0), Point3::new(3.0, 0.0, 0.0), &octree, &config(1));
+        assert_eq!(result, Err(RrtError::NoPath));
+    }
+}
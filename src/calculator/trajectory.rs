@@ -0,0 +1,581 @@
+#![allow(dead_code)]
+use std::fmt;
+use bevy::ecs::system::Resource;
+use bevy::math::{Quat, Vec3};
+use crate::calculator::coordinate_switch::mid360_to_frd;
+use crate::calculator::crash_detector::VEHICLE_CLEARANCE;
+use crate::calculator::mavlink_args::{MavFrame, MavlinkArgs};
+use crate::data_reader::structor::Point3;
+use crate::octree::octree::Octree;
+
+/// How the shortcut path is turned into a smooth curve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SmoothingMethod {
+    /// 7th order polynomials through every waypoint, minimising snap
+    #[default]
+    MinimumSnap,
+    /// Uniform cubic B-spline with the waypoints as control points, stays close to the path
+    BSpline,
+}
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct TrajectoryConfig {
+    pub method: SmoothingMethod,
+    pub max_velocity: f32,     // m/s, never exceeded anywhere on the trajectory
+    pub max_acceleration: f32, // m/s², never exceeded anywhere on the trajectory
+    pub clearance: f32,        // Vehicle radius plus margin for line-of-sight and collision checks
+    pub control_spacing: f32,  // B-spline control points are at most this far apart
+    pub max_refinements: u32,  // Refits closer to the path where the curve cuts into obstacles
+}
+
+impl Default for TrajectoryConfig {
+    fn default() -> Self {
+        Self {
+            method: SmoothingMethod::MinimumSnap,
+            max_velocity: 1.0,
+            max_acceleration: 1.0,
+            clearance: VEHICLE_CLEARANCE,
+            control_spacing: 0.5,
+            max_refinements: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrajectoryError {
+    /// Fewer than two distinct points
+    EmptyPath,
+    /// The curve still hits an obstacle at this time after every refinement
+    Collision { time: f32 },
+    /// The minimum-snap system could not be solved
+    Singular,
+}
+
+impl fmt::Display for TrajectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrajectoryError::EmptyPath => write!(f, "path is too short for a trajectory"),
+            TrajectoryError::Collision { time } => write!(f, "trajectory collides at {:.2} s", time),
+            TrajectoryError::Singular => write!(f, "minimum-snap system is singular"),
+        }
+    }
+}
+
+/// One polynomial piece, `coefficients[axis][k]` multiplies t^k with t from 0 to `duration`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Piece {
+    pub duration: f32,
+    pub coefficients: [[f32; 8]; 3],
+}
+
+/// State on the trajectory, in the frame of the path it was made from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrajectorySample {
+    pub position: [f32; 3],
+    pub velocity: [f32; 3],
+    pub acceleration: [f32; 3],
+}
+
+impl TrajectorySample {
+    /// Setpoint relative to the vehicle for a Mid-360 sample. Only right while the vehicle
+    /// still is where the path was planned from.
+    pub fn to_body_offset(self) -> MavlinkArgs {
+        let frd = |v: [f32; 3]| {
+            let (x, y, z) = mid360_to_frd(v[0], v[1], v[2]);
+            [x, y, z]
+        };
+        MavlinkArgs::trajectory_setpoint(
+            MavFrame::BodyOffsetNed,
+            frd(self.position),
+            frd(self.velocity),
+            frd(self.acceleration),
+        )
+    }
+
+    /// Setpoint in MAV_FRAME_LOCAL_NED for a Mid-360 sample, given the NED position and the
+    /// FRD to NED orientation the vehicle had when the path was planned
+    pub fn to_local_ned(self, origin_position: Vec3, origin_orientation: Quat) -> MavlinkArgs {
+        let ned = |v: [f32; 3]| {
+            let (x, y, z) = mid360_to_frd(v[0], v[1], v[2]);
+            origin_orientation * Vec3::new(x, y, z)
+        };
+        MavlinkArgs::trajectory_setpoint(
+            MavFrame::LocalNed,
+            (origin_position + ned(self.position)).to_array(),
+            ned(self.velocity).to_array(),
+            ned(self.acceleration).to_array(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Trajectory {
+    pub pieces: Vec<Piece>,
+}
+
+impl Trajectory {
+    pub fn duration(&self) -> f32 {
+        self.pieces.iter().map(|piece| piece.duration).sum()
+    }
+
+    /// State at `time` seconds from the start, held at the ends outside the trajectory
+    pub fn sample(&self, time: f32) -> TrajectorySample {
+        let mut remaining = time.max(0.0);
+        let mut index = 0;
+        while index + 1 < self.pieces.len() && remaining > self.pieces[index].duration {
+            remaining -= self.pieces[index].duration;
+            index += 1;
+        }
+        let Some(piece) = self.pieces.get(index) else {
+            return TrajectorySample { position: [0.0; 3], velocity: [0.0; 3], acceleration: [0.0; 3] };
+        };
+        let t = remaining.min(piece.duration);
+        let evaluate = |derivative: usize| [0, 1, 2].map(|axis| polynomial_derivative(&piece.coefficients[axis], derivative, t));
+        TrajectorySample { position: evaluate(0), velocity: evaluate(1), acceleration: evaluate(2) }
+    }
+
+    /// Samples every `period` seconds from the start to the end, both included
+    pub fn samples(&self, period: f32) -> Vec<(f32, TrajectorySample)> {
+        let duration = self.duration();
+        let count = (duration / period.max(1e-3)).ceil() as usize;
+        (0..=count)
+            .map(|i| {
+                let time = (i as f32 * period).min(duration);
+                (time, self.sample(time))
+            })
+            .collect()
+    }
+
+    /// Largest speed and acceleration magnitude, checked on a fine grid
+    pub fn max_velocity_acceleration(&self) -> (f32, f32) {
+        let mut max_velocity: f32 = 0.0;
+        let mut max_acceleration: f32 = 0.0;
+        for piece in &self.pieces {
+            for i in 0..=32 {
+                let t = piece.duration * i as f32 / 32.0;
+                let norm = |derivative: usize| {
+                    [0, 1, 2]
+                        .map(|axis| polynomial_derivative(&piece.coefficients[axis], derivative, t))
+                        .iter()
+                        .map(|v| v * v)
+                        .sum::<f32>()
+                        .sqrt()
+                };
+                max_velocity = max_velocity.max(norm(1));
+                max_acceleration = max_acceleration.max(norm(2));
+            }
+        }
+        (max_velocity, max_acceleration)
+    }
+
+    /// Stretch time by `factor`, dividing velocities by it and accelerations by its square
+    fn scale_time(&mut self, factor: f32) {
+        for piece in &mut self.pieces {
+            piece.duration *= factor;
+            for axis in &mut piece.coefficients {
+                for (k, coefficient) in axis.iter_mut().enumerate() {
+                    *coefficient /= factor.powi(k as i32);
+                }
+            }
+        }
+    }
+
+    /// First time the curve comes closer than `clearance` to an obstacle, and the piece
+    fn first_collision(&self, octree: &Octree, clearance: f32) -> Option<(f32, usize)> {
+        let mut start_time = 0.0;
+        for (index, piece) in self.pieces.iter().enumerate() {
+            let steps = 16;
+            let position = |t: f32| [0, 1, 2].map(|axis| polynomial_derivative(&piece.coefficients[axis], 0, t));
+            for i in 0..steps {
+                let (t0, t1) = (piece.duration * i as f32 / steps as f32, piece.duration * (i + 1) as f32 / steps as f32);
+                if !octree.segment_clear(position(t0), position(t1), clearance) {
+                    return Some((start_time + t0, index));
+                }
+            }
+            start_time += piece.duration;
+        }
+        None
+    }
+}
+
+/// Drop every waypoint the vehicle can skip by flying straight, keeping `clearance`
+/// from the octree
+pub fn shortcut_path(path: &[Point3], octree: &Octree, clearance: f32) -> Vec<Point3> {
+    if path.len() <= 2 {
+        return path.to_vec();
+    }
+    let to_array = |point: &Point3| [point.x, point.y, point.z];
+    let mut result = vec![path[0]];
+    let mut anchor = 0;
+    while anchor < path.len() - 1 {
+        // Furthest waypoint still in sight, the next one is always kept
+        let mut next = anchor + 1;
+        while next + 1 < path.len() && octree.segment_clear(to_array(&path[anchor]), to_array(&path[next + 1]), clearance) {
+            next += 1;
+        }
+        result.push(path[next]);
+        anchor = next;
+    }
+    result
+}
+
+/// Shortcut `path`, fit the configured curve and time it so the limits hold. Where the curve
+/// cuts into an obstacle it is fitted again closer to the path: minimum snap gets a midpoint
+/// on the offending segment, the B-spline denser control points.
+pub fn generate_trajectory(path: &[Point3], octree: &Octree, config: &TrajectoryConfig) -> Result<Trajectory, TrajectoryError> {
+    let mut waypoints: Vec<Vec3> = Vec::new();
+    for point in shortcut_path(path, octree, config.clearance) {
+        let point = Vec3::new(point.x, point.y, point.z);
+        if waypoints.last().is_none_or(|last| last.distance(point) > 1e-4) {
+            waypoints.push(point);
+        }
+    }
+    if waypoints.len() < 2 {
+        return Err(TrajectoryError::EmptyPath);
+    }
+
+    let mut refinements = 0;
+    let mut control_spacing = config.control_spacing;
+    loop {
+        let trajectory = match config.method {
+            SmoothingMethod::MinimumSnap => minimum_snap(&waypoints, config)?,
+            SmoothingMethod::BSpline => b_spline(&waypoints, control_spacing, config),
+        };
+        let Some((time, piece)) = trajectory.first_collision(octree, config.clearance) else {
+            return Ok(trajectory);
+        };
+        if refinements >= config.max_refinements {
+            return Err(TrajectoryError::Collision { time });
+        }
+        refinements += 1;
+        match config.method {
+            SmoothingMethod::MinimumSnap => {
+                let segment = piece.min(waypoints.len() - 2);
+                let midpoint = (waypoints[segment] + waypoints[segment + 1]) / 2.0;
+                waypoints.insert(segment + 1, midpoint);
+            }
+            SmoothingMethod::BSpline => control_spacing /= 2.0,
+        }
+    }
+}
+
+/// Time for a rest-to-rest move of `distance` under the limits
+fn segment_time(distance: f32, max_velocity: f32, max_acceleration: f32) -> f32 {
+    if distance >= max_velocity * max_velocity / max_acceleration {
+        distance / max_velocity + max_velocity / max_acceleration
+    } else {
+        2.0 * (distance / max_acceleration).sqrt()
+    }
+}
+
+/// Minimum-snap polynomials through `waypoints`, at rest at both ends. With fixed segment
+/// times the optimum is a 7th order polynomial per segment, continuous up to the 6th
+/// derivative at interior waypoints, so the optimum is the solution of one linear system.
+fn minimum_snap(waypoints: &[Vec3], config: &TrajectoryConfig) -> Result<Trajectory, TrajectoryError> {
+    let durations: Vec<f32> = waypoints
+        .windows(2)
+        .map(|segment| segment_time(segment[0].distance(segment[1]), config.max_velocity, config.max_acceleration).max(0.05))
+        .collect();
+    let mut pieces: Vec<Piece> = durations
+        .iter()
+        .map(|&duration| Piece { duration, coefficients: [[0.0; 8]; 3] })
+        .collect();
+    for axis in 0..3 {
+        let values: Vec<f32> = waypoints.iter().map(|point| point.to_array()[axis]).collect();
+        let coefficients = minimum_snap_axis(&values, &durations).ok_or(TrajectoryError::Singular)?;
+        for (piece, coefficients) in pieces.iter_mut().zip(coefficients) {
+            piece.coefficients[axis] = coefficients;
+        }
+    }
+    let mut trajectory = Trajectory { pieces };
+    fit_limits(&mut trajectory, config);
+    Ok(trajectory)
+}
+
+fn minimum_snap_axis(values: &[f32], durations: &[f32]) -> Option<Vec<[f32; 8]>> {
+    let segments = durations.len();
+    let size = 8 * segments;
+    let mut matrix = vec![vec![0.0f64; size]; size];
+    let mut rhs = vec![0.0f64; size];
+    let mut row = 0;
+    // Row of derivative `derivative` of segment `segment` at time `t`, times `sign`
+    let set = |matrix: &mut Vec<Vec<f64>>, row: usize, segment: usize, derivative: usize, t: f64, sign: f64| {
+        for k in derivative..8 {
+            matrix[row][8 * segment + k] += sign * falling_factorial(k, derivative) * t.powi((k - derivative) as i32);
+        }
+    };
+
+    // At rest at both ends
+    for derivative in 0..4 {
+        set(&mut matrix, row, 0, derivative, 0.0, 1.0);
+        rhs[row] = if derivative == 0 { values[0] as f64 } else { 0.0 };
+        row += 1;
+        set(&mut matrix, row, segments - 1, derivative, durations[segments - 1] as f64, 1.0);
+        rhs[row] = if derivative == 0 { values[segments] as f64 } else { 0.0 };
+        row += 1;
+    }
+    // Through every interior waypoint and smooth across it
+    for segment in 1..segments {
+        let end = durations[segment - 1] as f64;
+        set(&mut matrix, row, segment - 1, 0, end, 1.0);
+        rhs[row] = values[segment] as f64;
+        row += 1;
+        set(&mut matrix, row, segment, 0, 0.0, 1.0);
+        rhs[row] = values[segment] as f64;
+        row += 1;
+        for derivative in 1..7 {
+            set(&mut matrix, row, segment - 1, derivative, end, 1.0);
+            set(&mut matrix, row, segment, derivative, 0.0, -1.0);
+            row += 1;
+        }
+    }
+
+    let solution = solve(matrix, rhs)?;
+    Some(
+        (0..segments)
+            .map(|segment| std::array::from_fn(|k| solution[8 * segment + k] as f32))
+            .collect(),
+    )
+}
+
+fn falling_factorial(k: usize, derivative: usize) -> f64 {
+    ((k - derivative + 1)..=k).map(|i| i as f64).product()
+}
+
+/// Gaussian elimination with partial pivoting, `None` for a singular system
+fn solve(mut matrix: Vec<Vec<f64>>, mut rhs: Vec<f64>) -> Option<Vec<f64>> {
+    let size = rhs.len();
+    for column in 0..size {
+        let pivot = (column..size).max_by(|&a, &b| matrix[a][column].abs().total_cmp(&matrix[b][column].abs()))?;
+        if matrix[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        rhs.swap(column, pivot);
+        let (above, below) = matrix.split_at_mut(column + 1);
+        let pivot_row = &above[column];
+        for (offset, row) in below.iter_mut().enumerate() {
+            let factor = row[column] / pivot_row[column];
+            if factor == 0.0 {
+                continue;
+            }
+            for (value, pivot) in row[column..].iter_mut().zip(&pivot_row[column..]) {
+                *value -= factor * pivot;
+            }
+            rhs[column + 1 + offset] -= factor * rhs[column];
+        }
+    }
+    let mut solution = vec![0.0; size];
+    for row in (0..size).rev() {
+        let sum: f64 = (row + 1..size).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (rhs[row] - sum) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+/// Uniform cubic B-spline over `waypoints` resampled every `spacing`, with the end
+/// points tripled so it starts and stops at rest exactly on them. The knot interval comes
+/// from the control point differences, which bound velocity and acceleration everywhere.
+fn b_spline(waypoints: &[Vec3], spacing: f32, config: &TrajectoryConfig) -> Trajectory {
+    let control = control_points(waypoints, spacing);
+    let max_step = control.windows(2).map(|pair| pair[0].distance(pair[1])).fold(0.0, f32::max);
+    let max_second = control
+        .windows(3)
+        .map(|triple| (triple[2] - 2.0 * triple[1] + triple[0]).length())
+        .fold(0.0, f32::max);
+    let interval = (max_step / config.max_velocity)
+        .max((max_second / config.max_acceleration).sqrt())
+        .max(0.01);
+
+    // Basis matrix rows give the coefficients of u^0..u^3, divided by 6
+    const BASIS: [[f32; 4]; 4] = [[1.0, 4.0, 1.0, 0.0], [-3.0, 0.0, 3.0, 0.0], [3.0, -6.0, 3.0, 0.0], [-1.0, 3.0, -3.0, 1.0]];
+    let pieces = control
+        .windows(4)
+        .map(|points| {
+            let mut coefficients = [[0.0; 8]; 3];
+            for (axis, axis_coefficients) in coefficients.iter_mut().enumerate() {
+                for (k, basis) in BASIS.iter().enumerate() {
+                    let value: f32 = (0..4).map(|i| basis[i] * points[i].to_array()[axis]).sum::<f32>() / 6.0;
+                    axis_coefficients[k] = value / interval.powi(k as i32);
+                }
+            }
+            Piece { duration: interval, coefficients }
+        })
+        .collect();
+    Trajectory { pieces }
+}
+
+/// Waypoints with extra points so no two are further apart than `spacing`, ends tripled
+fn control_points(waypoints: &[Vec3], spacing: f32) -> Vec<Vec3> {
+    let mut control = vec![waypoints[0], waypoints[0]];
+    for segment in waypoints.windows(2) {
+        let parts = (segment[0].distance(segment[1]) / spacing.max(1e-3)).ceil().max(1.0) as usize;
+        for part in 0..parts {
+            control.push(segment[0].lerp(segment[1], part as f32 / parts as f32));
+        }
+    }
+    let last = *waypoints.last().unwrap();
+    control.extend([last, last, last]);
+    control
+}
+
+/// Stretch or compress time so the tighter of the two limits is just reached
+fn fit_limits(trajectory: &mut Trajectory, config: &TrajectoryConfig) {
+    let (max_velocity, max_acceleration) = trajectory.max_velocity_acceleration();
+    let factor = (max_velocity / config.max_velocity).max((max_acceleration / config.max_acceleration).sqrt());
+    if factor > 0.0 {
+        trajectory.scale_time(factor);
+    }
+}
+
+fn polynomial_derivative(coefficients: &[f32; 8], derivative: usize, t: f32) -> f32 {
+    (derivative..8)
+        .map(|k| coefficients[k] * falling_factorial(k, derivative) as f32 * t.powi((k - derivative) as i32))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Octree from Mid-360 points at leaves of 10 / 64 m
+    fn map(points: &[[f32; 3]]) -> Octree {
+        let mut octree = Octree::new([[-5.0; 3], [5.0; 3]]);
+        for point in points {
+            octree.insert(*point, 6, 100).unwrap();
+        }
+        octree
+    }
+
+    /// Boxes filling x from `x0` to `x1` and y from `y0` to `y1`, 1 m high
+    fn blocks(boxes: &[[f32; 4]]) -> Octree {
+        let mut points = Vec::new();
+        let steps = |from: f32, to: f32| ((to - from) / 0.05).round() as i32;
+        for [x0, x1, y0, y1] in boxes {
+            for i in 0..=steps(*x0, *x1) {
+                for j in 0..=steps(*y0, *y1) {
+                    for k in -10..=10 {
+                        points.push([x0 + i as f32 * 0.05, y0 + j as f32 * 0.05, k as f32 * 0.05]);
+                    }
+                }
+            }
+        }
+        map(&points)
+    }
+
+    fn points(coordinates: &[[f32; 3]]) -> Vec<Point3> {
+        coordinates.iter().map(|c| Point3::new(c[0], c[1], c[2])).collect()
+    }
+
+    fn assert_near(a: [f32; 3], b: [f32; 3], tolerance: f32, what: &str) {
+        let distance = Vec3::from_array(a).distance(Vec3::from_array(b));
+        assert!(distance <= tolerance, "{}: {:?} != {:?}", what, a, b);
+    }
+
+    fn waypoints(coordinates: &[[f32; 3]]) -> Vec<Vec3> {
+        coordinates.iter().map(|c| Vec3::from_array(*c)).collect()
+    }
+
+    const CORNERS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [2.0, 2.0, 0.5], [0.0, 2.5, 1.0]];
+
+    #[test]
+    fn minimum_snap_passes_every_waypoint_and_rests_at_the_ends() {
+        let trajectory = minimum_snap(&waypoints(&CORNERS), &TrajectoryConfig::default()).unwrap();
+        assert_eq!(trajectory.pieces.len(), 3);
+        let mut time = 0.0;
+        for (index, corner) in CORNERS.iter().enumerate() {
+            assert_near(trajectory.sample(time).position, *corner, 1e-3, "waypoint");
+            if let Some(piece) = trajectory.pieces.get(index) {
+                time += piece.duration;
+            }
+        }
+        for end in [0.0, trajectory.duration()] {
+            let sample = trajectory.sample(end);
+            assert_near(sample.velocity, [0.0; 3], 1e-3, "velocity at the end");
+            assert_near(sample.acceleration, [0.0; 3], 1e-3, "acceleration at the end");
+        }
+        // Moving through the interior waypoints, not stopping at them
+        let through = trajectory.sample(trajectory.pieces[0].duration);
+        assert!(Vec3::from_array(through.velocity).length() > 0.1);
+    }
+
+    #[test]
+    fn b_spline_starts_and_stops_at_rest_on_the_ends() {
+        let config = TrajectoryConfig::default();
+        let trajectory = b_spline(&waypoints(&CORNERS), config.control_spacing, &config);
+        for (time, corner) in [(0.0, CORNERS[0]), (trajectory.duration(), CORNERS[3])] {
+            let sample = trajectory.sample(time);
+            assert_near(sample.position, corner, 1e-3, "end");
+            assert_near(sample.velocity, [0.0; 3], 1e-3, "velocity at the end");
+        }
+        // Close to the interior waypoints it only approximates
+        let closest = |corner: [f32; 3]| {
+            trajectory
+                .samples(0.01)
+                .iter()
+                .map(|(_, sample)| Vec3::from_array(sample.position).distance(Vec3::from_array(corner)))
+                .fold(f32::INFINITY, f32::min)
+        };
+        assert!(closest(CORNERS[1]) < config.control_spacing / 2.0);
+        assert!(closest(CORNERS[2]) < config.control_spacing / 2.0);
+    }
+
+    #[test]
+    fn fitted_trajectories_keep_the_limits() {
+        for (max_velocity, max_acceleration) in [(1.0, 1.0), (2.0, 0.5), (0.5, 3.0)] {
+            let config = TrajectoryConfig { max_velocity, max_acceleration, ..TrajectoryConfig::default() };
+            let snap = minimum_snap(&waypoints(&CORNERS), &config).unwrap();
+            let (velocity, acceleration) = snap.max_velocity_acceleration();
+            assert!(velocity <= max_velocity * 1.001 && acceleration <= max_acceleration * 1.001);
+            // `fit_limits` makes the tighter limit just reached
+            let reached = (velocity / max_velocity).max(acceleration / max_acceleration);
+            assert!((reached - 1.0).abs() < 1e-3, "{} {} reached {}", max_velocity, max_acceleration, reached);
+
+            let spline = b_spline(&waypoints(&CORNERS), config.control_spacing, &config);
+            let (velocity, acceleration) = spline.max_velocity_acceleration();
+            assert!(velocity <= max_velocity * 1.001 && acceleration <= max_acceleration * 1.001);
+        }
+    }
+
+    #[test]
+    fn shortcut_drops_points_in_free_space() {
+        let octree = map(&[[0.0, 3.0, 0.0]]);
+        let line = points(&[[0.0, 0.0, 0.0], [0.5, 0.0, 0.0], [1.0, 0.0, 0.0], [1.5, 0.0, 0.0], [2.0, 0.0, 0.0]]);
+        assert_eq!(shortcut_path(&line, &octree, VEHICLE_CLEARANCE), points(&[[0.0, 0.0, 0.0], [2.0, 0.0, 0.0]]));
+        let zigzag = points(&[[0.0, 0.0, 0.0], [0.5, 0.3, 0.0], [1.0, -0.3, 0.0], [2.0, 0.0, 0.0]]);
+        assert_eq!(shortcut_path(&zigzag, &octree, VEHICLE_CLEARANCE).len(), 2);
+
+        // A block in the inner corner keeps the corner
+        let corner = points(&[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [2.0, 0.0, 0.0], [2.0, 1.0, 0.0], [2.0, 2.0, 0.0]]);
+        let octree = blocks(&[[-1.0, 1.4, 0.6, 3.0]]);
+        let shortcut = shortcut_path(&corner, &octree, VEHICLE_CLEARANCE);
+        assert_eq!(shortcut, points(&[[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [2.0, 2.0, 0.0]]));
+    }
+
+    #[test]
+    fn curve_near_obstacles_is_refined_instead_of_cutting_in() {
+        // Corridor around a corner, the first fit swings wide into the outer walls
+        let path = points(&[[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [2.0, 2.0, 0.0]]);
+        let octree = blocks(&[[-1.0, 1.4, 0.6, 3.0], [2.6, 3.0, -1.0, 3.0], [-1.0, 3.0, -1.0, -0.6]]);
+        let config = TrajectoryConfig::default();
+        let first_fit = minimum_snap(&waypoints(&[[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [2.0, 2.0, 0.0]]), &config).unwrap();
+        assert!(first_fit.first_collision(&octree, config.clearance).is_some());
+
+        let trajectory = generate_trajectory(&path, &octree, &config).unwrap();
+        assert!(trajectory.pieces.len() > 2);
+        assert_eq!(trajectory.first_collision(&octree, config.clearance), None);
+        assert_near(trajectory.sample(trajectory.duration()).position, [2.0, 2.0, 0.0], 1e-3, "goal");
+
+        let unrefined = TrajectoryConfig { max_refinements: 0, ..config };
+        assert!(matches!(generate_trajectory(&path, &octree, &unrefined), Err(TrajectoryError::Collision { .. })));
+    }
+
+    #[test]
+    fn too_short_paths_are_rejected() {
+        let octree = map(&[]);
+        let config = TrajectoryConfig::default();
+        assert_eq!(generate_trajectory(&[], &octree, &config), Err(TrajectoryError::EmptyPath));
+        let repeated = points(&[[1.0, 0.0, 0.0], [1.0, 0.0, 0.0]]);
+        assert_eq!(generate_trajectory(&repeated, &octree, &config), Err(TrajectoryError::EmptyPath));
+    }
+}
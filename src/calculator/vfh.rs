@@ -0,0 +1,403 @@
+#![allow(dead_code)]
+use bevy::ecs::system::Resource;
+use crate::octree::octree::Octree;
+use crate::calculator::mavlink_args::MavlinkArgs;
+use crate::calculator::coordinate_switch::mid360_to_frd;
+use crate::calculator::crash_detector::{AVOIDANCE_FRAME, VEHICLE_CLEARANCE};
+
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct VfhConfig {
+    pub window_radius: f32,   // Only leaves this close go into the histogram
+    pub vehicle_radius: f32,  // Vehicle radius plus leaf inflation, obstacles are enlarged by it
+    pub bin_size: f32,        // Bin width in degrees, for azimuth and elevation
+    pub max_elevation: f32,   // Candidate directions stay within this many degrees of level
+    pub threshold_low: f32,   // Bins below this become free
+    pub threshold_high: f32,  // Bins above this become blocked, in between they keep their state
+    pub valley_width: f32,    // Degrees of free bins needed around a candidate direction
+    pub goal_weight: f32,     // Cost of turning away from the goal
+    pub heading_weight: f32,  // Cost of turning away from the nose
+    pub previous_weight: f32, // Cost of turning away from the last chosen direction
+    pub max_speed: f32,       // Speed in a completely free direction
+}
+
+impl Default for VfhConfig {
+    fn default() -> Self {
+        VfhConfig {
+            window_radius: 3.0,
+            vehicle_radius: VEHICLE_CLEARANCE,
+            bin_size: 5.0,
+            max_elevation: 30.0,
+            threshold_low: 0.5,
+            threshold_high: 1.0,
+            valley_width: 10.0,
+            goal_weight: 5.0,
+            heading_weight: 2.0,
+            previous_weight: 2.0,
+            max_speed: 1.0,
+        }
+    }
+}
+
+/// What VFH+ remembers between two commands
+#[derive(Debug, Clone, Default, Resource)]
+pub struct VfhState {
+    /// Binary histogram of the last update, for the hysteresis
+    blocked: Vec<bool>,
+    /// Last chosen direction, unit vector in the Mid-360 frame
+    previous_direction: Option<[f32; 3]>,
+}
+
+/// Obstacle density over azimuth and elevation around the sensor, Mid-360 frame.
+/// Azimuth bins start behind the vehicle at -180°, elevation bins at -90°.
+#[derive(Debug, Clone)]
+pub struct PolarHistogram {
+    pub azimuth_bins: usize,
+    pub elevation_bins: usize,
+    pub bin_size: f32,
+    pub values: Vec<f32>,
+}
+
+impl PolarHistogram {
+    pub fn new(bin_size: f32) -> Self {
+        let azimuth_bins = (360.0 / bin_size).round().max(1.0) as usize;
+        let elevation_bins = (180.0 / bin_size).round().max(1.0) as usize;
+        PolarHistogram {
+            azimuth_bins,
+            elevation_bins,
+            bin_size,
+            values: vec![0.0; azimuth_bins * elevation_bins],
+        }
+    }
+
+    /// Add every occupied leaf within `window_radius`, enlarged by `vehicle_radius`
+    pub fn from_octree(octree: &Octree, config: &VfhConfig) -> Self {
+        let mut histogram = PolarHistogram::new(config.bin_size);
+        for (distance, center) in octree.radius_search([0.0, 0.0, 0.0], config.window_radius) {
+            if distance <= f32::EPSILON {
+                continue;
+            }
+            let direction = [center[0] / distance, center[1] / distance, center[2] / distance];
+            // Closer leaves weigh more and cover a wider angle
+            let magnitude = 1.0 - (distance / config.window_radius).powi(2);
+            let enlargement = (config.vehicle_radius / distance).min(1.0).asin().to_degrees();
+            histogram.add(direction, enlargement, magnitude);
+        }
+        histogram
+    }
+
+    /// Add `magnitude` to every bin whose center is within `half_angle` degrees of `direction`
+    pub fn add(&mut self, direction: [f32; 3], half_angle: f32, magnitude: f32) {
+        let (_, elevation) = azimuth_elevation(direction);
+        let reach = half_angle + self.bin_size / 2.0;
+        let cos_reach = reach.min(180.0).to_radians().cos();
+        for row in 0..self.elevation_bins {
+            let row_elevation = -90.0 + (row as f32 + 0.5) * self.bin_size;
+            if (row_elevation - elevation).abs() > reach {
+                continue;
+            }
+            for column in 0..self.azimuth_bins {
+                if dot(self.bin_direction(row, column), direction) >= cos_reach {
+                    self.values[row * self.azimuth_bins + column] += magnitude;
+                }
+            }
+        }
+    }
+
+    /// Unit vector through the center of a bin
+    pub fn bin_direction(&self, row: usize, column: usize) -> [f32; 3] {
+        let azimuth = (-180.0 + (column as f32 + 0.5) * self.bin_size).to_radians();
+        let elevation = (-90.0 + (row as f32 + 0.5) * self.bin_size).to_radians();
+        direction_from(azimuth, elevation)
+    }
+
+    /// Row and column of the bin containing `direction`
+    pub fn bin_of(&self, direction: [f32; 3]) -> (usize, usize) {
+        let (azimuth, elevation) = azimuth_elevation(direction);
+        let column = ((azimuth + 180.0) / self.bin_size) as usize % self.azimuth_bins;
+        let row = (((elevation + 90.0) / self.bin_size) as usize).min(self.elevation_bins - 1);
+        (row, column)
+    }
+
+    pub fn value(&self, row: usize, column: usize) -> f32 {
+        self.values[row * self.azimuth_bins + column]
+    }
+}
+
+/// Steer towards `goal` (Mid-360 frame, metres) through the free direction VFH+ prefers.
+/// Holds when no direction is free or the goal is reached.
+pub fn vfh_avoidance(octree: &Octree, goal: [f32; 3], config: &VfhConfig, state: &mut VfhState) -> MavlinkArgs {
+    let hold = MavlinkArgs::velocity_setpoint(AVOIDANCE_FRAME, 0.0, 0.0, 0.0);
+    let goal_distance = length(goal);
+    if goal_distance < 1e-3 {
+        return hold;
+    }
+    let goal_direction = [goal[0] / goal_distance, goal[1] / goal_distance, goal[2] / goal_distance];
+
+    let histogram = PolarHistogram::from_octree(octree, config);
+    let blocked = binary_histogram(&histogram, config, state);
+    let Some(direction) = select_direction(&histogram, &blocked, goal_direction, config, state) else {
+        state.previous_direction = None;
+        return hold;
+    };
+    state.previous_direction = Some(direction);
+
+    // Slow down where the histogram is denser and when arriving at the goal
+    let (row, column) = histogram.bin_of(direction);
+    let density = (histogram.value(row, column) / config.threshold_high).clamp(0.0, 1.0);
+    let speed = (config.max_speed * (1.0 - density)).min(goal_distance);
+    let (vx, vy, vz) = mid360_to_frd(direction[0] * speed, direction[1] * speed, direction[2] * speed);
+    MavlinkArgs::velocity_setpoint(AVOIDANCE_FRAME, vx, vy, vz)
+}
+
+/// Threshold the histogram with hysteresis against the last update
+fn binary_histogram(histogram: &PolarHistogram, config: &VfhConfig, state: &mut VfhState) -> Vec<bool> {
+    if state.blocked.len() != histogram.values.len() {
+        state.blocked = vec![false; histogram.values.len()];
+    }
+    let blocked: Vec<bool> = histogram
+        .values
+        .iter()
+        .zip(&state.blocked)
+        .map(|(value, was_blocked)| {
+            if *value > config.threshold_high {
+                true
+            } else if *value < config.threshold_low {
+                false
+            } else {
+                *was_blocked
+            }
+        })
+        .collect();
+    state.blocked = blocked.clone();
+    blocked
+}
+
+/// Cheapest free direction with enough free bins around it, `None` if there is none
+fn select_direction(
+    histogram: &PolarHistogram,
+    blocked: &[bool],
+    goal_direction: [f32; 3],
+    config: &VfhConfig,
+    state: &VfhState,
+) -> Option<[f32; 3]> {
+    let margin = (config.valley_width / 2.0 / histogram.bin_size).ceil() as isize;
+    let in_valley = |row: usize, column: usize| {
+        for d_row in -margin..=margin {
+            let r = row as isize + d_row;
+            if r < 0 || r >= histogram.elevation_bins as isize {
+                continue;
+            }
+            for d_column in -margin..=margin {
+                let c = (column as isize + d_column).rem_euclid(histogram.azimuth_bins as isize);
+                if blocked[r as usize * histogram.azimuth_bins + c as usize] {
+                    return false;
+                }
+            }
+        }
+        true
+    };
+
+    // Straight at the goal whenever its bin is in a valley
+    let (goal_row, goal_column) = histogram.bin_of(goal_direction);
+    let (_, goal_elevation) = azimuth_elevation(goal_direction);
+    if goal_elevation.abs() <= config.max_elevation && in_valley(goal_row, goal_column) {
+        return Some(goal_direction);
+    }
+
+    let heading = [1.0, 0.0, 0.0];
+    let mut best: Option<(f32, [f32; 3])> = None;
+    for row in 0..histogram.elevation_bins {
+        let elevation = -90.0 + (row as f32 + 0.5) * histogram.bin_size;
+        if elevation.abs() > config.max_elevation {
+            continue;
+        }
+        for column in 0..histogram.azimuth_bins {
+            if !in_valley(row, column) {
+                continue;
+            }
+            let candidate = histogram.bin_direction(row, column);
+            let cost = config.goal_weight * angle_between(candidate, goal_direction)
+                + config.heading_weight * angle_between(candidate, heading)
+                + config.previous_weight * state.previous_direction.map_or(0.0, |previous| angle_between(candidate, previous));
+            if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                best = Some((cost, candidate));
+            }
+        }
+    }
+    best.map(|(_, direction)| direction)
+}
+
+/// Azimuth and elevation of a direction in degrees, azimuth counter-clockwise from the front
+fn azimuth_elevation(direction: [f32; 3]) -> (f32, f32) {
+    let azimuth = direction[1].atan2(direction[0]).to_degrees();
+    let elevation = direction[2].atan2(direction[0].hypot(direction[1])).to_degrees();
+    (azimuth, elevation)
+}
+
+fn direction_from(azimuth: f32, elevation: f32) -> [f32; 3] {
+    [elevation.cos() * azimuth.cos(), elevation.cos() * azimuth.sin(), elevation.sin()]
+}
+
+fn angle_between(a: [f32; 3], b: [f32; 3]) -> f32 {
+    dot(a, b).clamp(-1.0, 1.0).acos()
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn length(a: [f32; 3]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Octree from Mid-360 points (x forward, y left, z up)
+    fn map(points: &[[f32; 3]]) -> Octree {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        for point in points {
+            octree.insert(*point, 7, 100).unwrap();
+        }
+        octree
+    }
+
+    /// Wall across the x axis at `x`, `half_width` to each side and 2 m high
+    fn wall(x: f32, half_width: f32) -> Octree {
+        let steps = (half_width / 0.05) as i32;
+        let points: Vec<[f32; 3]> = (-steps..=steps)
+            .flat_map(|i| (-20..=20).map(move |j| [x, i as f32 * 0.05, j as f32 * 0.05]))
+            .collect();
+        map(&points)
+    }
+
+    fn azimuth(direction: [f32; 3]) -> f32 {
+        azimuth_elevation(direction).0
+    }
+
+    #[test]
+    fn bins_start_behind_and_below() {
+        let histogram = PolarHistogram::new(5.0);
+        assert_eq!((histogram.azimuth_bins, histogram.elevation_bins), (72, 36));
+        assert_eq!(histogram.bin_of([1.0, 0.0, 0.0]), (18, 36));
+        assert_eq!(histogram.bin_of([-1.0, 0.0, 0.0]), (18, 0));
+        assert_eq!(histogram.bin_of([0.0, 1.0, 0.0]), (18, 54));
+        assert_eq!(histogram.bin_of([0.0, -1.0, 0.0]), (18, 18));
+        assert_eq!(histogram.bin_of([0.0, 0.0, 1.0]).0, 35);
+        assert_eq!(histogram.bin_of([0.0, 0.0, -1.0]).0, 0);
+        for (row, column) in [(18, 36), (3, 70), (30, 5)] {
+            assert_eq!(histogram.bin_of(histogram.bin_direction(row, column)), (row, column));
+        }
+    }
+
+    #[test]
+    fn add_covers_the_enlarged_angle() {
+        let mut histogram = PolarHistogram::new(5.0);
+        histogram.add([1.0, 0.0, 0.0], 10.0, 0.5);
+        // Bin centers within 10° plus half a bin of the nose
+        assert_eq!(histogram.value(18, 36), 0.5);
+        assert_eq!(histogram.value(18, 37), 0.5);
+        assert_eq!(histogram.value(17, 34), 0.5);
+        assert_eq!(histogram.value(18, 39), 0.0);
+        assert_eq!(histogram.value(21, 36), 0.0);
+        assert_eq!(histogram.value(18, 0), 0.0);
+
+        // Without enlargement only the bin around a bin center is hit
+        let mut histogram = PolarHistogram::new(5.0);
+        histogram.add(histogram.bin_direction(18, 36), 0.0, 0.5);
+        assert_eq!(histogram.value(18, 36), 0.5);
+        assert_eq!(histogram.values.iter().filter(|value| **value > 0.0).count(), 1);
+    }
+
+    #[test]
+    fn closer_leaves_cover_more_bins() {
+        let config = VfhConfig::default();
+        let covered = |x: f32| {
+            let histogram = PolarHistogram::from_octree(&map(&[[x, 0.0, 0.0]]), &config);
+            histogram.values.iter().filter(|value| **value > 0.0).count()
+        };
+        assert!(covered(0.8) > covered(2.5));
+        assert_eq!(covered(config.window_radius + 1.0), 0);
+    }
+
+    #[test]
+    fn hysteresis_keeps_bins_between_the_thresholds() {
+        let config = VfhConfig::default();
+        let mut state = VfhState::default();
+        let mut histogram = PolarHistogram::new(config.bin_size);
+        let between = (config.threshold_low + config.threshold_high) / 2.0;
+
+        histogram.values[0] = between;
+        assert!(!binary_histogram(&histogram, &config, &mut state)[0]);
+        histogram.values[0] = config.threshold_high + 0.1;
+        assert!(binary_histogram(&histogram, &config, &mut state)[0]);
+        histogram.values[0] = between;
+        assert!(binary_histogram(&histogram, &config, &mut state)[0]);
+        histogram.values[0] = config.threshold_low - 0.1;
+        assert!(!binary_histogram(&histogram, &config, &mut state)[0]);
+        histogram.values[0] = between;
+        assert!(!binary_histogram(&histogram, &config, &mut state)[0]);
+    }
+
+    #[test]
+    fn open_goal_bin_goes_straight() {
+        let config = VfhConfig::default();
+        let mut state = VfhState::default();
+        let goal = [3.0, 1.0, 0.5];
+        let command = vfh_avoidance(&map(&[]), goal, &config, &mut state);
+        let direction = state.previous_direction.unwrap();
+        let goal_distance = length(goal);
+        for i in 0..3 {
+            assert!((direction[i] - goal[i] / goal_distance).abs() < 1e-6);
+        }
+        // Full speed in FRD
+        let (vx, vy, vz) = mid360_to_frd(direction[0], direction[1], direction[2]);
+        assert!((command.vx - vx * config.max_speed).abs() < 1e-6);
+        assert!((command.vy - vy * config.max_speed).abs() < 1e-6);
+        assert!((command.vz - vz * config.max_speed).abs() < 1e-6);
+        assert_eq!(command.coordinate_frame, AVOIDANCE_FRAME as u8);
+
+        // A wall off to the side leaves the goal bin open
+        vfh_avoidance(&wall(1.5, 1.0), [0.0, 3.0, 0.0], &config, &mut state);
+        assert!((azimuth(state.previous_direction.unwrap()) - 90.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn wall_ahead_picks_a_side_valley() {
+        let config = VfhConfig::default();
+        let mut state = VfhState::default();
+        let octree = wall(1.5, 1.0);
+        let command = vfh_avoidance(&octree, [5.0, 0.0, 0.0], &config, &mut state);
+        let direction = state.previous_direction.unwrap();
+
+        // Past the wall edge, enlarged by the vehicle radius
+        let edge = (1.0f32).atan2(1.5).to_degrees();
+        assert!(azimuth(direction).abs() > edge, "{:?}", direction);
+        assert!(azimuth(direction).abs() < 90.0, "{:?}", direction);
+        let histogram = PolarHistogram::from_octree(&octree, &config);
+        let (row, column) = histogram.bin_of(direction);
+        assert!(!state.blocked[row * histogram.azimuth_bins + column]);
+        assert!(command.vy.abs() > 0.0);
+
+        // The next update sticks to the same side
+        let side = azimuth(direction).signum();
+        vfh_avoidance(&octree, [5.0, 0.0, 0.0], &config, &mut state);
+        assert_eq!(azimuth(state.previous_direction.unwrap()).signum(), side);
+    }
+
+    #[test]
+    fn enclosed_vehicle_holds() {
+        let config = VfhConfig::default();
+        let mut state = VfhState::default();
+        let points: Vec<[f32; 3]> = (0..72)
+            .flat_map(|i| {
+                let azimuth = (i as f32 * 5.0).to_radians();
+                (-12..=12).map(move |j| [0.8 * azimuth.cos(), 0.8 * azimuth.sin(), j as f32 * 0.05])
+            })
+            .collect();
+        let command = vfh_avoidance(&map(&points), [5.0, 0.0, 0.0], &config, &mut state);
+        assert_eq!([command.vx, command.vy, command.vz], [0.0; 3]);
+        assert_eq!(state.previous_direction, None);
+    }
+}
@@ -0,0 +1,24 @@
+#![allow(dead_code)]
+use crate::data_reader::structor::LaserPoint;
+
+/// Read points from a Livox Viewer CSV export.
+///
+/// Only the `X`, `Y`, `Z` and `Reflectivity` columns are used, so both the full Livox Viewer
+/// export and files written by `structor_to_file::octree_to_csv` can be loaded.
+/// Zero points (no return) are dropped, the same as for UDP packets.
+pub fn read_laserpoint_csv(file_name: &str) -> Result<Vec<LaserPoint>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_path(file_name)?;
+
+    let mut points = Vec::new();
+    for record in reader.deserialize() {
+        let point: LaserPoint = record?;
+        if point.x == 0.0 && point.y == 0.0 && point.z == 0.0 {
+            continue;
+        }
+        points.push(point);
+    }
+    Ok(points)
+}
@@ -0,0 +1,642 @@
+#![allow(dead_code)]
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bevy::ecs::system::Resource;
+use bevy::math::Quat;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::calculator::mavlink_args::MavlinkArgs;
+use crate::data_reader::mcap::{self, McapWriter};
+use crate::data_reader::structor::{LaserPoint, Point3};
+use crate::data_reader::udp_reader::ImuData;
+
+pub const TOPIC_POINTS: &str = "/lidar/points";
+pub const TOPIC_IMU: &str = "/imu";
+pub const TOPIC_POSE: &str = "/pose";
+pub const TOPIC_APF_PATH: &str = "/planner/apf_path";
+pub const TOPIC_MAVLINK_ARGS: &str = "/mavlink/set_position_target_local_ned";
+
+/// Everything is logged in the Mid-360 sensor frame
+pub const FRAME_ID: &str = "mid360";
+/// Except vehicle poses, which come from the autopilot's local NED frame
+pub const POSE_FRAME_ID: &str = "local_ned";
+
+const PROFILE: &str = "";
+const MESSAGE_ENCODING: &str = "json";
+const SCHEMA_ENCODING: &str = "jsonschema";
+
+/// x, y, z as float32 followed by a uint8 intensity
+const POINT_STRIDE: u32 = 13;
+/// foxglove.PackedElementField numeric types
+const NUMERIC_TYPE_UINT8: u8 = 1;
+const NUMERIC_TYPE_FLOAT32: u8 = 7;
+
+pub(crate) const TIME_SCHEMA: &str = r#"{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}}"#;
+pub(crate) const VECTOR3_SCHEMA: &str =
+    r#"{"type":"object","properties":{"x":{"type":"number"},"y":{"type":"number"},"z":{"type":"number"}}}"#;
+const QUATERNION_SCHEMA: &str = r#"{"type":"object","properties":{"x":{"type":"number"},"y":{"type":"number"},"z":{"type":"number"},"w":{"type":"number"}}}"#;
+
+/// foxglove.Time
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub sec: u32,
+    pub nsec: u32,
+}
+
+impl Timestamp {
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self {
+            sec: (nanos / 1_000_000_000) as u32,
+            nsec: (nanos % 1_000_000_000) as u32,
+        }
+    }
+}
+
+/// foxglove.Vector3
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Vector3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+/// foxglove.Quaternion
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quaternion {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub w: f64,
+}
+
+/// foxglove.Pose
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Pose {
+    pub position: Vector3,
+    pub orientation: Quaternion,
+}
+
+impl Pose {
+    pub fn new(position: Point3, orientation: Quat) -> Self {
+        Self {
+            position: Vector3 { x: position.x as f64, y: position.y as f64, z: position.z as f64 },
+            orientation: Quaternion {
+                x: orientation.x as f64,
+                y: orientation.y as f64,
+                z: orientation.z as f64,
+                w: orientation.w as f64,
+            },
+        }
+    }
+
+    pub fn position(&self) -> Point3 {
+        Point3::new(self.position.x as f32, self.position.y as f32, self.position.z as f32)
+    }
+
+    pub fn orientation(&self) -> Quat {
+        Quat::from_xyzw(
+            self.orientation.x as f32,
+            self.orientation.y as f32,
+            self.orientation.z as f32,
+            self.orientation.w as f32,
+        )
+    }
+}
+
+/// foxglove.PackedElementField
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackedElementField {
+    pub name: String,
+    pub offset: u32,
+    #[serde(rename = "type")]
+    pub numeric_type: u8,
+}
+
+/// foxglove.PointCloud, `data` is base64 as required by the JSON encoding
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PointCloudMessage {
+    pub timestamp: Timestamp,
+    pub frame_id: String,
+    pub pose: Pose,
+    pub point_stride: u32,
+    pub fields: Vec<PackedElementField>,
+    pub data: String,
+}
+
+impl PointCloudMessage {
+    /// Pack points as float32 x/y/z plus a uint8 intensity in the sensor frame
+    pub fn new(points: &[LaserPoint], time_nanos: u64) -> Self {
+        Self {
+            timestamp: Timestamp::from_nanos(time_nanos),
+            frame_id: FRAME_ID.to_string(),
+            pose: Pose::new(Point3::new(0.0, 0.0, 0.0), Quat::IDENTITY),
+            point_stride: POINT_STRIDE,
+            fields: vec![
+                PackedElementField { name: "x".to_string(), offset: 0, numeric_type: NUMERIC_TYPE_FLOAT32 },
+                PackedElementField { name: "y".to_string(), offset: 4, numeric_type: NUMERIC_TYPE_FLOAT32 },
+                PackedElementField { name: "z".to_string(), offset: 8, numeric_type: NUMERIC_TYPE_FLOAT32 },
+                PackedElementField { name: "intensity".to_string(), offset: 12, numeric_type: NUMERIC_TYPE_UINT8 },
+            ],
+            data: BASE64.encode(pack_points(points)),
+        }
+    }
+}
+
+/// foxglove.PoseInFrame
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PoseInFrameMessage {
+    pub timestamp: Timestamp,
+    pub frame_id: String,
+    pub pose: Pose,
+}
+
+/// foxglove.PosesInFrame
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PosesInFrameMessage {
+    pub timestamp: Timestamp,
+    pub frame_id: String,
+    pub poses: Vec<Pose>,
+}
+
+impl PosesInFrameMessage {
+    pub fn from_path(path: &[Point3], time_nanos: u64) -> Self {
+        Self {
+            timestamp: Timestamp::from_nanos(time_nanos),
+            frame_id: FRAME_ID.to_string(),
+            poses: path.iter().map(|point| Pose::new(*point, Quat::IDENTITY)).collect(),
+        }
+    }
+}
+
+/// Mid-360 IMU sample. Angular velocity in rad/s, acceleration in g like the sensor reports it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImuMessage {
+    pub timestamp: Timestamp,
+    pub frame_id: String,
+    /// Sensor clock from the Livox packet header
+    pub sensor_timestamp: u64,
+    pub angular_velocity: Vector3,
+    pub linear_acceleration: Vector3,
+}
+
+impl ImuMessage {
+    pub fn new(imu: &ImuData, time_nanos: u64) -> Self {
+        Self {
+            timestamp: Timestamp::from_nanos(time_nanos),
+            frame_id: FRAME_ID.to_string(),
+            sensor_timestamp: imu.timestamp,
+            angular_velocity: Vector3 { x: imu.gyro_x as f64, y: imu.gyro_y as f64, z: imu.gyro_z as f64 },
+            linear_acceleration: Vector3 { x: imu.acc_x as f64, y: imu.acc_y as f64, z: imu.acc_z as f64 },
+        }
+    }
+}
+
+/// One decoded message of a flight log
+#[derive(Debug, Clone)]
+pub enum FlightLogEvent {
+    Points(Vec<LaserPoint>),
+    Imu(ImuMessage),
+    Pose(Pose),
+    ApfPath(Vec<Point3>),
+    MavlinkArgs(MavlinkArgs),
+}
+
+#[derive(Debug, Clone)]
+pub struct FlightLogEntry {
+    /// Nanoseconds since the UNIX epoch
+    pub log_time: u64,
+    pub event: FlightLogEvent,
+}
+
+/// Records one MCAP log per flight. Every topic uses JSON messages with JSON schemas,
+/// and the point cloud, pose and path topics use the Foxglove schemas so they render directly.
+#[derive(Resource)]
+pub struct FlightRecorder {
+    writer: McapWriter<BufWriter<File>>,
+    points_channel: u16,
+    imu_channel: u16,
+    pose_channel: u16,
+    path_channel: u16,
+    mavlink_channel: u16,
+}
+
+impl FlightRecorder {
+    pub fn create(file_name: &str) -> std::io::Result<Self> {
+        let mut writer = McapWriter::new(BufWriter::new(File::create(file_name)?), PROFILE)?;
+        let metadata = BTreeMap::new();
+
+        let schema = writer.add_schema("foxglove.PointCloud", SCHEMA_ENCODING, point_cloud_schema().as_bytes())?;
+        let points_channel = writer.add_channel(schema, TOPIC_POINTS, MESSAGE_ENCODING, &metadata)?;
+        let schema = writer.add_schema("WorldWithoutAnime.ImuData", SCHEMA_ENCODING, imu_schema().as_bytes())?;
+        let imu_channel = writer.add_channel(schema, TOPIC_IMU, MESSAGE_ENCODING, &metadata)?;
+        let schema = writer.add_schema("foxglove.PoseInFrame", SCHEMA_ENCODING, pose_in_frame_schema().as_bytes())?;
+        let pose_channel = writer.add_channel(schema, TOPIC_POSE, MESSAGE_ENCODING, &metadata)?;
+        let schema = writer.add_schema("foxglove.PosesInFrame", SCHEMA_ENCODING, poses_in_frame_schema().as_bytes())?;
+        let path_channel = writer.add_channel(schema, TOPIC_APF_PATH, MESSAGE_ENCODING, &metadata)?;
+        let schema = writer.add_schema("WorldWithoutAnime.MavlinkArgs", SCHEMA_ENCODING, mavlink_args_schema().as_bytes())?;
+        let mavlink_channel = writer.add_channel(schema, TOPIC_MAVLINK_ARGS, MESSAGE_ENCODING, &metadata)?;
+
+        Ok(Self {
+            writer,
+            points_channel,
+            imu_channel,
+            pose_channel,
+            path_channel,
+            mavlink_channel,
+        })
+    }
+
+    /// Record one integrated lidar frame as it came from the sensor
+    pub fn record_points(&mut self, points: &[LaserPoint]) -> std::io::Result<()> {
+        let now = now_nanos();
+        self.write_json(self.points_channel, now, &PointCloudMessage::new(points, now))
+    }
+
+    pub fn record_imu(&mut self, imu: &ImuData) -> std::io::Result<()> {
+        let now = now_nanos();
+        self.write_json(self.imu_channel, now, &ImuMessage::new(imu, now))
+    }
+
+    /// Record an estimated vehicle pose in the autopilot's local NED frame
+    pub fn record_pose(&mut self, position: Point3, orientation: Quat) -> std::io::Result<()> {
+        let now = now_nanos();
+        let message = PoseInFrameMessage {
+            timestamp: Timestamp::from_nanos(now),
+            frame_id: POSE_FRAME_ID.to_string(),
+            pose: Pose::new(position, orientation),
+        };
+        self.write_json(self.pose_channel, now, &message)
+    }
+
+    pub fn record_path(&mut self, path: &[Point3]) -> std::io::Result<()> {
+        let now = now_nanos();
+        self.write_json(self.path_channel, now, &PosesInFrameMessage::from_path(path, now))
+    }
+
+    /// Record a SET_POSITION_TARGET_LOCAL_NED command that was sent to the autopilot
+    pub fn record_mavlink_args(&mut self, args: &MavlinkArgs) -> std::io::Result<()> {
+        let now = now_nanos();
+        self.write_json(self.mavlink_channel, now, args)
+    }
+
+    /// Close the log. Dropping the recorder does the same but swallows errors.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.finish()
+    }
+
+    fn write_json<T: Serialize>(&mut self, channel_id: u16, log_time: u64, message: &T) -> std::io::Result<()> {
+        let data = serde_json::to_vec(message).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        self.writer.write_message(channel_id, log_time, log_time, &data)
+    }
+}
+
+/// Read every message this crate records from an MCAP file, sorted by log time.
+/// Topics from other tools are skipped.
+pub fn load_flight_log(file_name: &str) -> std::io::Result<Vec<FlightLogEntry>> {
+    let file = mcap::load_mcap(file_name)?;
+    let mut entries = Vec::with_capacity(file.messages.len());
+
+    for message in &file.messages {
+        let event = match file.topic(message) {
+            Some(TOPIC_POINTS) => {
+                let cloud: PointCloudMessage = parse_json(&message.data)?;
+                FlightLogEvent::Points(unpack_points(&cloud)?)
+            }
+            Some(TOPIC_IMU) => FlightLogEvent::Imu(parse_json(&message.data)?),
+            Some(TOPIC_POSE) => {
+                let pose: PoseInFrameMessage = parse_json(&message.data)?;
+                FlightLogEvent::Pose(pose.pose)
+            }
+            Some(TOPIC_APF_PATH) => {
+                let path: PosesInFrameMessage = parse_json(&message.data)?;
+                FlightLogEvent::ApfPath(path.poses.iter().map(|pose| pose.position()).collect())
+            }
+            Some(TOPIC_MAVLINK_ARGS) => FlightLogEvent::MavlinkArgs(parse_json(&message.data)?),
+            _ => continue,
+        };
+        entries.push(FlightLogEntry { log_time: message.log_time, event });
+    }
+
+    // Stable, so messages logged in the same nanosecond keep their file order
+    entries.sort_by_key(|entry| entry.log_time);
+    Ok(entries)
+}
+
+/// Replays a flight log against the viewer clock
+#[derive(Resource)]
+pub struct FlightPlayback {
+    entries: Vec<FlightLogEntry>,
+    cursor: usize,
+    elapsed: Duration,
+}
+
+impl FlightPlayback {
+    pub fn new(entries: Vec<FlightLogEntry>) -> Self {
+        Self { entries, cursor: 0, elapsed: Duration::ZERO }
+    }
+
+    pub fn load(file_name: &str) -> std::io::Result<Self> {
+        Ok(Self::new(load_flight_log(file_name)?))
+    }
+
+    /// Move the playback clock forward and return the entries that became due, in log order
+    pub fn advance(&mut self, delta: Duration) -> &[FlightLogEntry] {
+        let start = self.cursor;
+        let Some(first) = self.entries.first() else {
+            return &[];
+        };
+        self.elapsed += delta;
+        let until = first.log_time.saturating_add(self.elapsed.as_nanos() as u64);
+        while self.cursor < self.entries.len() && self.entries[self.cursor].log_time <= until {
+            self.cursor += 1;
+        }
+        &self.entries[start..self.cursor]
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.cursor >= self.entries.len()
+    }
+
+    pub fn entries(&self) -> &[FlightLogEntry] {
+        &self.entries
+    }
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn parse_json<T: for<'de> Deserialize<'de>>(data: &[u8]) -> std::io::Result<T> {
+    serde_json::from_slice(data).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn pack_points(points: &[LaserPoint]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(points.len() * POINT_STRIDE as usize);
+    for point in points {
+        // Writing into a Vec cannot fail
+        data.write_f32::<LittleEndian>(point.x).unwrap();
+        data.write_f32::<LittleEndian>(point.y).unwrap();
+        data.write_f32::<LittleEndian>(point.z).unwrap();
+        data.write_u8(point.reflectivity).unwrap();
+    }
+    data
+}
+
+/// Decode a point cloud that uses the layout written by `pack_points`
+fn unpack_points(cloud: &PointCloudMessage) -> std::io::Result<Vec<LaserPoint>> {
+    let data = BASE64
+        .decode(&cloud.data)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Point cloud data is not base64: {}", e)))?;
+    let field = |name: &str, numeric_type: u8| {
+        cloud
+            .fields
+            .iter()
+            .find(|field| field.name == name && field.numeric_type == numeric_type)
+            .map(|field| field.offset as usize)
+    };
+    let (x, y, z) = match (
+        field("x", NUMERIC_TYPE_FLOAT32),
+        field("y", NUMERIC_TYPE_FLOAT32),
+        field("z", NUMERIC_TYPE_FLOAT32),
+    ) {
+        (Some(x), Some(y), Some(z)) => (x, y, z),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Point cloud has no float32 x/y/z fields")),
+    };
+    let intensity = field("intensity", NUMERIC_TYPE_UINT8);
+
+    let stride = cloud.point_stride as usize;
+    if stride < 12 || [x, y, z].iter().any(|offset| offset + 4 > stride) || intensity.is_some_and(|offset| offset >= stride) {
+        return Err(Error::new(ErrorKind::InvalidData, "Point cloud fields do not fit the point stride"));
+    }
+    if data.len() % stride != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Point cloud data is not a whole number of points"));
+    }
+
+    let mut points = Vec::with_capacity(data.len() / stride);
+    for record in data.chunks_exact(stride) {
+        let read = |offset: usize| Cursor::new(&record[offset..offset + 4]).read_f32::<LittleEndian>();
+        let reflectivity = intensity.map_or(0, |offset| record[offset]);
+        points.push(LaserPoint::new(read(x)?, read(y)?, read(z)?, reflectivity));
+    }
+    Ok(points)
+}
+
+fn pose_schema() -> String {
+    format!(
+        r#"{{"type":"object","properties":{{"position":{},"orientation":{}}}}}"#,
+        VECTOR3_SCHEMA, QUATERNION_SCHEMA
+    )
+}
+
+pub(crate) fn point_cloud_schema() -> String {
+    format!(
+        concat!(
+            r#"{{"title":"foxglove.PointCloud","type":"object","properties":{{"#,
+            r#""timestamp":{},"frame_id":{{"type":"string"}},"pose":{},"point_stride":{{"type":"integer"}},"#,
+            r#""fields":{{"type":"array","items":{{"type":"object","properties":{{"name":{{"type":"string"}},"offset":{{"type":"integer"}},"type":{{"type":"integer"}}}}}}}},"#,
+            r#""data":{{"type":"string","contentEncoding":"base64"}}}}}}"#
+        ),
+        TIME_SCHEMA,
+        pose_schema()
+    )
+}
+
+pub(crate) fn pose_in_frame_schema() -> String {
+    format!(
+        r#"{{"title":"foxglove.PoseInFrame","type":"object","properties":{{"timestamp":{},"frame_id":{{"type":"string"}},"pose":{}}}}}"#,
+        TIME_SCHEMA,
+        pose_schema()
+    )
+}
+
+pub(crate) fn poses_in_frame_schema() -> String {
+    format!(
+        r#"{{"title":"foxglove.PosesInFrame","type":"object","properties":{{"timestamp":{},"frame_id":{{"type":"string"}},"poses":{{"type":"array","items":{}}}}}}}"#,
+        TIME_SCHEMA,
+        pose_schema()
+    )
+}
+
+pub(crate) fn imu_schema() -> String {
+    format!(
+        r#"{{"title":"WorldWithoutAnime.ImuData","type":"object","properties":{{"timestamp":{},"frame_id":{{"type":"string"}},"sensor_timestamp":{{"type":"integer"}},"angular_velocity":{},"linear_acceleration":{}}}}}"#,
+        TIME_SCHEMA, VECTOR3_SCHEMA, VECTOR3_SCHEMA
+    )
+}
+
+pub(crate) fn mavlink_args_schema() -> String {
+    let integers = ["time_boot_ms", "target_system", "target_component", "coordinate_frame", "type_mask"];
+    let numbers = ["x", "y", "z", "vx", "vy", "vz", "afx", "afy", "afz", "yaw", "yaw_rate"];
+    let properties: Vec<String> = integers
+        .iter()
+        .map(|name| format!(r#""{}":{{"type":"integer"}}"#, name))
+        .chain(numbers.iter().map(|name| format!(r#""{}":{{"type":"number"}}"#, name)))
+        .collect();
+    format!(
+        r#"{{"title":"WorldWithoutAnime.MavlinkArgs","type":"object","properties":{{{}}}}}"#,
+        properties.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::mavlink_args::MavFrame;
+
+    fn imu() -> ImuData {
+        ImuData {
+            version: 1,
+            length: 48,
+            time_interval: 0,
+            dot_num: 1,
+            udp_cnt: 0,
+            frame_cnt: 0,
+            data_type: 0,
+            time_type: 0,
+            reserved: Vec::new(),
+            crc32: 0,
+            timestamp: 123_456_789,
+            gyro_x: 0.1,
+            gyro_y: -0.2,
+            gyro_z: 0.3,
+            acc_x: 0.0,
+            acc_y: 0.0,
+            acc_z: 1.0,
+        }
+    }
+
+    fn entry(log_time: u64) -> FlightLogEntry {
+        FlightLogEntry { log_time, event: FlightLogEvent::ApfPath(Vec::new()) }
+    }
+
+    fn points(points: &[LaserPoint]) -> Vec<(f32, f32, f32, u8)> {
+        points.iter().map(|point| (point.x, point.y, point.z, point.reflectivity)).collect()
+    }
+
+    fn cloud() -> PointCloudMessage {
+        PointCloudMessage::new(&[LaserPoint::new(1.0, -2.0, 3.5, 7), LaserPoint::new(0.25, 0.0, -1.0, 255)], 0)
+    }
+
+    #[test]
+    fn recorded_log_loads_back() {
+        let path = std::env::temp_dir().join(format!("flight_log_test_{}.mcap", std::process::id()));
+        let file_name = path.to_str().unwrap();
+        let cloud = [LaserPoint::new(1.0, -2.0, 3.5, 7), LaserPoint::new(0.25, 0.0, -1.0, 255)];
+        let apf_path = [Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.5, -0.5)];
+        let args = MavlinkArgs::velocity_setpoint(MavFrame::BodyOffsetNed, 0.5, -0.25, 0.0);
+        let orientation = Quat::from_rotation_z(0.5);
+
+        let mut recorder = FlightRecorder::create(file_name).unwrap();
+        recorder.record_points(&cloud).unwrap();
+        recorder.record_imu(&imu()).unwrap();
+        recorder.record_pose(Point3::new(2.0, 3.0, -1.0), orientation).unwrap();
+        recorder.record_path(&apf_path).unwrap();
+        recorder.record_mavlink_args(&args).unwrap();
+        recorder.finish().unwrap();
+        let entries = load_flight_log(file_name).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries.len(), 5);
+        assert!(entries.windows(2).all(|pair| pair[0].log_time <= pair[1].log_time));
+        match &entries[0].event {
+            FlightLogEvent::Points(loaded) => assert_eq!(points(loaded), points(&cloud)),
+            event => panic!("{:?}", event),
+        }
+        match &entries[1].event {
+            FlightLogEvent::Imu(message) => {
+                assert_eq!(message.sensor_timestamp, 123_456_789);
+                assert_eq!(message.frame_id, FRAME_ID);
+                assert!((message.angular_velocity.y + 0.2).abs() < 1e-6);
+                assert_eq!(message.linear_acceleration.z, 1.0);
+            }
+            event => panic!("{:?}", event),
+        }
+        match &entries[2].event {
+            FlightLogEvent::Pose(pose) => {
+                assert_eq!(pose.position(), Point3::new(2.0, 3.0, -1.0));
+                assert!(pose.orientation().angle_between(orientation) < 1e-5);
+            }
+            event => panic!("{:?}", event),
+        }
+        match &entries[3].event {
+            FlightLogEvent::ApfPath(loaded) => assert_eq!(loaded, &apf_path),
+            event => panic!("{:?}", event),
+        }
+        match &entries[4].event {
+            FlightLogEvent::MavlinkArgs(loaded) => {
+                assert_eq!((loaded.coordinate_frame, loaded.type_mask), (args.coordinate_frame, args.type_mask));
+                assert_eq!((loaded.vx, loaded.vy, loaded.vz), (0.5, -0.25, 0.0));
+            }
+            event => panic!("{:?}", event),
+        }
+    }
+
+    #[test]
+    fn playback_returns_the_entries_that_became_due() {
+        let mut playback = FlightPlayback::new(vec![entry(1_000), entry(1_000), entry(1_400), entry(2_000), entry(5_000)]);
+        let times = |entries: &[FlightLogEntry]| entries.iter().map(|entry| entry.log_time).collect::<Vec<_>>();
+
+        assert_eq!(times(playback.advance(Duration::ZERO)), vec![1_000, 1_000]);
+        assert_eq!(times(playback.advance(Duration::from_nanos(399))), Vec::<u64>::new());
+        assert_eq!(times(playback.advance(Duration::from_nanos(1))), vec![1_400]);
+        assert_eq!(times(playback.advance(Duration::from_nanos(1_000))), vec![2_000]);
+        assert!(!playback.is_finished());
+        assert_eq!(times(playback.advance(Duration::from_secs(1))), vec![5_000]);
+        assert!(playback.is_finished());
+        assert!(playback.advance(Duration::from_secs(1)).is_empty());
+
+        assert!(FlightPlayback::new(Vec::new()).advance(Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn unpack_points_reads_the_packed_layout() {
+        assert_eq!(points(&unpack_points(&cloud()).unwrap()), vec![(1.0, -2.0, 3.5, 7), (0.25, 0.0, -1.0, 255)]);
+
+        // Without intensity, in a wider record
+        let mut wide = cloud();
+        wide.fields.retain(|field| field.name != "intensity");
+        wide.point_stride = 16;
+        wide.data = BASE64.encode([0u8; 32]);
+        assert_eq!(points(&unpack_points(&wide).unwrap()), vec![(0.0, 0.0, 0.0, 0); 2]);
+    }
+
+    #[test]
+    fn unpack_points_rejects_bad_clouds() {
+        let mut not_base64 = cloud();
+        not_base64.data = "not base64!".to_string();
+        assert!(unpack_points(&not_base64).is_err());
+
+        let mut no_z = cloud();
+        no_z.fields.retain(|field| field.name != "z");
+        assert!(unpack_points(&no_z).is_err());
+
+        let mut z_as_uint8 = cloud();
+        z_as_uint8.fields[2].numeric_type = NUMERIC_TYPE_UINT8;
+        assert!(unpack_points(&z_as_uint8).is_err());
+
+        let mut offset_past_stride = cloud();
+        offset_past_stride.fields[1].offset = 10;
+        assert!(unpack_points(&offset_past_stride).is_err());
+
+        let mut intensity_past_stride = cloud();
+        intensity_past_stride.fields[3].offset = 13;
+        assert!(unpack_points(&intensity_past_stride).is_err());
+
+        let mut short_stride = cloud();
+        short_stride.point_stride = 8;
+        assert!(unpack_points(&short_stride).is_err());
+
+        let mut stride_past_data = cloud();
+        stride_past_data.point_stride = 100;
+        assert!(unpack_points(&stride_past_data).is_err());
+
+        let mut truncated = cloud();
+        truncated.data = BASE64.encode(&BASE64.decode(&truncated.data).unwrap()[..20]);
+        assert!(unpack_points(&truncated).is_err());
+    }
+}
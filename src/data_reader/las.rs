@@ -0,0 +1,326 @@
+#![allow(dead_code)]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Error, ErrorKind, Read, Write};
+
+use crate::data_reader::point_cloud_file::initial_capacity;
+use crate::data_reader::structor::LaserPoint;
+
+const LAS_14_HEADER_SIZE: u16 = 375;
+/// Point data record format 6: the base format for LAS 1.4
+const POINT_FORMAT: u8 = 6;
+const POINT_RECORD_LENGTH: u16 = 30;
+/// Coordinates are stored as integers in millimetres, like the Mid-360 itself
+const SCALE: f64 = 0.001;
+/// Global encoding bit that must be set for point formats 6-10: the CRS is given as an OGC WKT VLR
+const GLOBAL_ENCODING_WKT: u16 = 1 << 4;
+const VLR_HEADER_SIZE: u32 = 54;
+/// User id and record id of the OGC coordinate system WKT record
+const WKT_USER_ID: &str = "LASF_Projection";
+const WKT_RECORD_ID: u16 = 2112;
+/// Points are saved in the local map frame, which has no geographic reference
+const LOCAL_FRAME_WKT: &str = "LOCAL_CS[\"WorldWithoutAnime map\",LOCAL_DATUM[\"Map origin\",0],\
+UNIT[\"metre\",1],AXIS[\"X\",OTHER],AXIS[\"Y\",OTHER],AXIS[\"Z\",OTHER]]";
+
+pub fn save_las(points: &[LaserPoint], file_name: &str) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(file_name)?);
+    write_las(points, &mut writer)?;
+    writer.flush()
+}
+
+pub fn load_las(file_name: &str) -> std::io::Result<Vec<LaserPoint>> {
+    read_las(&mut BufReader::new(File::open(file_name)?))
+}
+
+/// Write a LAS 1.4 file with point data record format 6.
+/// Reflectivity is stretched to the full 16-bit intensity range (x257).
+pub fn write_las<W: Write>(points: &[LaserPoint], writer: &mut W) -> std::io::Result<()> {
+    let mut min = [f64::MAX; 3];
+    let mut max = [f64::MIN; 3];
+    for point in points {
+        for (i, value) in [point.x, point.y, point.z].into_iter().enumerate() {
+            min[i] = min[i].min(value as f64);
+            max[i] = max[i].max(value as f64);
+        }
+    }
+    if points.is_empty() {
+        min = [0.0; 3];
+        max = [0.0; 3];
+    }
+    let (year, day_of_year) = creation_date();
+    // The WKT string is stored null-terminated
+    let wkt_length = LOCAL_FRAME_WKT.len() as u16 + 1;
+    let point_offset = LAS_14_HEADER_SIZE as u32 + VLR_HEADER_SIZE + wkt_length as u32;
+
+    writer.write_all(b"LASF")?;
+    writer.write_u16::<LittleEndian>(0)?; // file source id
+    writer.write_u16::<LittleEndian>(GLOBAL_ENCODING_WKT)?;
+    writer.write_all(&[0u8; 16])?; // project GUID
+    writer.write_u8(1)?; // version major
+    writer.write_u8(4)?; // version minor
+    writer.write_all(&fixed_string("OTHER", 32))?; // system identifier
+    writer.write_all(&fixed_string("WorldWithoutAnime", 32))?; // generating software
+    writer.write_u16::<LittleEndian>(day_of_year)?;
+    writer.write_u16::<LittleEndian>(year)?;
+    writer.write_u16::<LittleEndian>(LAS_14_HEADER_SIZE)?;
+    writer.write_u32::<LittleEndian>(point_offset)?;
+    writer.write_u32::<LittleEndian>(1)?; // number of VLRs
+    writer.write_u8(POINT_FORMAT)?;
+    writer.write_u16::<LittleEndian>(POINT_RECORD_LENGTH)?;
+    writer.write_u32::<LittleEndian>(0)?; // legacy point count, must be 0 for formats 6-10
+    for _ in 0..5 {
+        writer.write_u32::<LittleEndian>(0)?; // legacy points by return
+    }
+    for _ in 0..3 {
+        writer.write_f64::<LittleEndian>(SCALE)?;
+    }
+    for _ in 0..3 {
+        writer.write_f64::<LittleEndian>(0.0)?; // offsets
+    }
+    for i in 0..3 {
+        writer.write_f64::<LittleEndian>(max[i])?;
+        writer.write_f64::<LittleEndian>(min[i])?;
+    }
+    writer.write_u64::<LittleEndian>(0)?; // start of waveform data
+    writer.write_u64::<LittleEndian>(0)?; // start of first EVLR
+    writer.write_u32::<LittleEndian>(0)?; // number of EVLRs
+    writer.write_u64::<LittleEndian>(points.len() as u64)?;
+    writer.write_u64::<LittleEndian>(points.len() as u64)?; // every point is a first return
+    for _ in 1..15 {
+        writer.write_u64::<LittleEndian>(0)?;
+    }
+
+    writer.write_u16::<LittleEndian>(0)?; // reserved
+    writer.write_all(&fixed_string(WKT_USER_ID, 16))?;
+    writer.write_u16::<LittleEndian>(WKT_RECORD_ID)?;
+    writer.write_u16::<LittleEndian>(wkt_length)?;
+    writer.write_all(&fixed_string("OGC coordinate system WKT", 32))?;
+    writer.write_all(LOCAL_FRAME_WKT.as_bytes())?;
+    writer.write_u8(0)?;
+
+    for point in points {
+        writer.write_i32::<LittleEndian>((point.x as f64 / SCALE).round() as i32)?;
+        writer.write_i32::<LittleEndian>((point.y as f64 / SCALE).round() as i32)?;
+        writer.write_i32::<LittleEndian>((point.z as f64 / SCALE).round() as i32)?;
+        writer.write_u16::<LittleEndian>(point.reflectivity as u16 * 257)?;
+        writer.write_u8(0x11)?; // return 1 of 1
+        writer.write_u8(0)?; // classification flags, scanner channel, scan direction, edge
+        writer.write_u8(0)?; // classification: never classified
+        writer.write_u8(0)?; // user data
+        writer.write_i16::<LittleEndian>(0)?; // scan angle
+        writer.write_u16::<LittleEndian>(0)?; // point source id
+        writer.write_f64::<LittleEndian>(0.0)?; // GPS time
+    }
+    Ok(())
+}
+
+/// Read an uncompressed LAS 1.0-1.4 file with any point data record format
+pub fn read_las<R: Read>(reader: &mut R) -> std::io::Result<Vec<LaserPoint>> {
+    // The public header block is at most 375 bytes and always starts with the same fields
+    let mut header = vec![0u8; 227];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"LASF" {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a LAS file"));
+    }
+
+    let mut cursor = Cursor::new(&header[..]);
+    cursor.set_position(24);
+    let version_major = cursor.read_u8()?;
+    let version_minor = cursor.read_u8()?;
+    if version_major != 1 || version_minor > 4 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported LAS version {}.{}", version_major, version_minor),
+        ));
+    }
+    cursor.set_position(94);
+    let header_size = cursor.read_u16::<LittleEndian>()? as usize;
+    let point_offset = cursor.read_u32::<LittleEndian>()? as usize;
+    let _vlr_count = cursor.read_u32::<LittleEndian>()?;
+    let point_format = cursor.read_u8()?;
+    let record_length = cursor.read_u16::<LittleEndian>()? as usize;
+    let legacy_count = cursor.read_u32::<LittleEndian>()? as u64;
+    cursor.set_position(131);
+    let mut scale = [0.0f64; 3];
+    for value in scale.iter_mut() {
+        *value = cursor.read_f64::<LittleEndian>()?;
+    }
+    let mut offset = [0.0f64; 3];
+    for value in offset.iter_mut() {
+        *value = cursor.read_f64::<LittleEndian>()?;
+    }
+
+    if point_format & 0x80 != 0 || point_format & 0x40 != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Compressed (LAZ) point data is not supported"));
+    }
+    if record_length < 14 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Point record length {} is too short", record_length)));
+    }
+
+    // Read the rest of the header, the VLRs and any padding up to the point data
+    if header_size < header.len() || point_offset < header_size {
+        return Err(Error::new(ErrorKind::InvalidData, "LAS header is truncated"));
+    }
+    let rest_length = (point_offset - header.len()) as u64;
+    let mut rest = Vec::new();
+    reader.by_ref().take(rest_length).read_to_end(&mut rest)?;
+    if rest.len() as u64 != rest_length {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "LAS file ends before the point data"));
+    }
+
+    let mut point_count = legacy_count;
+    if version_minor >= 4 && header_size >= 255 {
+        // 64-bit point count of LAS 1.4 lives at byte 247
+        let mut cursor = Cursor::new(&rest[247 - header.len()..]);
+        let extended_count = cursor.read_u64::<LittleEndian>()?;
+        if extended_count > 0 {
+            point_count = extended_count;
+        }
+    }
+
+    let mut points = Vec::with_capacity(initial_capacity(point_count));
+    let mut record = vec![0u8; record_length];
+    for _ in 0..point_count {
+        reader.read_exact(&mut record)?;
+        let mut cursor = Cursor::new(&record[..]);
+        let x = cursor.read_i32::<LittleEndian>()? as f64 * scale[0] + offset[0];
+        let y = cursor.read_i32::<LittleEndian>()? as f64 * scale[1] + offset[1];
+        let z = cursor.read_i32::<LittleEndian>()? as f64 * scale[2] + offset[2];
+        let intensity = cursor.read_u16::<LittleEndian>()?;
+        let reflectivity = ((intensity as u32 + 128) / 257).min(255) as u8;
+        points.push(LaserPoint::new(x as f32, y as f32, z as f32, reflectivity));
+    }
+
+    Ok(points)
+}
+
+fn fixed_string(value: &str, length: usize) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.resize(length, 0);
+    bytes
+}
+
+/// (year, day of year) of the current UTC date for the LAS header
+fn creation_date() -> (u16, u16) {
+    let days = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86400) as i64;
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy_march = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy_march + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let is_leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let day = doy_march - (153 * mp + 2) / 5 + 1;
+    let month_starts = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let mut day_of_year = month_starts[(month - 1) as usize] + day;
+    if is_leap && month > 2 {
+        day_of_year += 1;
+    }
+    (year as u16, day_of_year as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Millimetre coordinates survive the LAS integer quantization exactly
+    fn test_points() -> Vec<LaserPoint> {
+        (0..200)
+            .map(|i| {
+                let t = i as f32 * 0.05;
+                let mm = |v: f32| (v * 1000.0).round() / 1000.0;
+                LaserPoint::new(mm(t.sin() * 8.0), mm(t.cos() * 8.0), mm(t - 5.0), (i % 256) as u8)
+            })
+            .collect()
+    }
+
+    fn assert_same_points(a: &[LaserPoint], b: &[LaserPoint]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a.x - b.x).abs() < 1e-5 && (a.y - b.y).abs() < 1e-5 && (a.z - b.z).abs() < 1e-5, "{:?} vs {:?}", a, b);
+            assert_eq!(a.reflectivity, b.reflectivity);
+        }
+    }
+
+    #[test]
+    fn round_trip_in_memory() {
+        let points = test_points();
+        let mut buffer = Vec::new();
+        write_las(&points, &mut buffer).unwrap();
+        assert_eq!(buffer.len(), 375 + 54 + LOCAL_FRAME_WKT.len() + 1 + points.len() * POINT_RECORD_LENGTH as usize);
+
+        let loaded = read_las(&mut buffer.as_slice()).unwrap();
+        assert_same_points(&loaded, &points);
+    }
+
+    #[test]
+    fn round_trip_through_file() {
+        let points = test_points();
+        let path = std::env::temp_dir().join(format!("las_test_{}.las", std::process::id()));
+        let file_name = path.to_str().unwrap();
+
+        save_las(&points, file_name).unwrap();
+        let loaded = load_las(file_name).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_same_points(&loaded, &points);
+    }
+
+    #[test]
+    fn wkt_bit_comes_with_a_wkt_record() {
+        let mut buffer = Vec::new();
+        write_las(&[], &mut buffer).unwrap();
+
+        let mut cursor = Cursor::new(&buffer[..]);
+        cursor.set_position(6);
+        assert_eq!(cursor.read_u16::<LittleEndian>().unwrap() & GLOBAL_ENCODING_WKT, GLOBAL_ENCODING_WKT);
+        cursor.set_position(96);
+        let point_offset = cursor.read_u32::<LittleEndian>().unwrap() as usize;
+        assert_eq!(cursor.read_u32::<LittleEndian>().unwrap(), 1);
+        assert_eq!(point_offset, buffer.len());
+
+        let vlr = &buffer[LAS_14_HEADER_SIZE as usize..];
+        assert_eq!(&vlr[2..17], WKT_USER_ID.as_bytes());
+        let mut cursor = Cursor::new(&vlr[18..22]);
+        assert_eq!(cursor.read_u16::<LittleEndian>().unwrap(), WKT_RECORD_ID);
+        let length = cursor.read_u16::<LittleEndian>().unwrap() as usize;
+        let wkt = &vlr[VLR_HEADER_SIZE as usize..VLR_HEADER_SIZE as usize + length];
+        assert_eq!(wkt, [LOCAL_FRAME_WKT.as_bytes(), &[0]].concat());
+        assert!(read_las(&mut buffer.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_truncated_data() {
+        let mut buffer = Vec::new();
+        write_las(&test_points(), &mut buffer).unwrap();
+
+        let mut bad_magic = buffer.clone();
+        bad_magic[0] = b'X';
+        assert!(read_las(&mut bad_magic.as_slice()).is_err());
+
+        let truncated = &buffer[..buffer.len() - 3];
+        assert!(read_las(&mut &truncated[..]).is_err());
+        let header_only = &buffer[..300];
+        assert!(read_las(&mut &header_only[..]).is_err());
+    }
+
+    #[test]
+    fn huge_header_counts_fail_without_allocating() {
+        let mut buffer = Vec::new();
+        write_las(&test_points()[..1], &mut buffer).unwrap();
+
+        let mut huge_offset = buffer.clone();
+        huge_offset[96..100].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read_las(&mut huge_offset.as_slice()).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        let mut huge_count = buffer.clone();
+        huge_count[247..255].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(read_las(&mut huge_count.as_slice()).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,435 @@
+#![allow(dead_code)]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Error, ErrorKind, Read, Write};
+
+/// Magic bytes at the start and end of every MCAP file (format version 0)
+pub const MCAP_MAGIC: [u8; 8] = [0x89, b'M', b'C', b'A', b'P', b'0', b'\r', b'\n'];
+
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_CHUNK: u8 = 0x06;
+const OP_DATA_END: u8 = 0x0F;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct McapSchema {
+    pub id: u16,
+    pub name: String,
+    pub encoding: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct McapChannel {
+    pub id: u16,
+    pub schema_id: u16,
+    pub topic: String,
+    pub message_encoding: String,
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct McapMessage {
+    pub channel_id: u16,
+    pub sequence: u32,
+    /// Nanoseconds since the UNIX epoch
+    pub log_time: u64,
+    pub publish_time: u64,
+    pub data: Vec<u8>,
+}
+
+/// Streaming writer for unchunked, unindexed MCAP files.
+/// Foxglove and the `mcap` CLI read these directly; `mcap recover` can add an index.
+pub struct McapWriter<W: Write> {
+    writer: W,
+    next_schema_id: u16,
+    next_channel_id: u16,
+    sequences: HashMap<u16, u32>,
+    finished: bool,
+}
+
+impl<W: Write> McapWriter<W> {
+    pub fn new(mut writer: W, profile: &str) -> std::io::Result<Self> {
+        writer.write_all(&MCAP_MAGIC)?;
+        let mut content = Vec::new();
+        put_string(&mut content, profile);
+        put_string(&mut content, "WorldWithoutAnime");
+        write_record(&mut writer, OP_HEADER, &content)?;
+
+        Ok(Self {
+            writer,
+            // Schema id 0 means "no schema", so ids start at 1
+            next_schema_id: 1,
+            next_channel_id: 0,
+            sequences: HashMap::new(),
+            finished: false,
+        })
+    }
+
+    pub fn add_schema(&mut self, name: &str, encoding: &str, data: &[u8]) -> std::io::Result<u16> {
+        let id = self.next_schema_id;
+        self.next_schema_id += 1;
+
+        let mut content = Vec::new();
+        content.write_u16::<LittleEndian>(id)?;
+        put_string(&mut content, name);
+        put_string(&mut content, encoding);
+        content.write_u32::<LittleEndian>(data.len() as u32)?;
+        content.extend_from_slice(data);
+        write_record(&mut self.writer, OP_SCHEMA, &content)?;
+        Ok(id)
+    }
+
+    pub fn add_channel(
+        &mut self,
+        schema_id: u16,
+        topic: &str,
+        message_encoding: &str,
+        metadata: &BTreeMap<String, String>,
+    ) -> std::io::Result<u16> {
+        let id = self.next_channel_id;
+        self.next_channel_id += 1;
+
+        let mut map = Vec::new();
+        for (key, value) in metadata {
+            put_string(&mut map, key);
+            put_string(&mut map, value);
+        }
+        let mut content = Vec::new();
+        content.write_u16::<LittleEndian>(id)?;
+        content.write_u16::<LittleEndian>(schema_id)?;
+        put_string(&mut content, topic);
+        put_string(&mut content, message_encoding);
+        content.write_u32::<LittleEndian>(map.len() as u32)?;
+        content.extend_from_slice(&map);
+        write_record(&mut self.writer, OP_CHANNEL, &content)?;
+        self.sequences.insert(id, 0);
+        Ok(id)
+    }
+
+    pub fn write_message(&mut self, channel_id: u16, log_time: u64, publish_time: u64, data: &[u8]) -> std::io::Result<()> {
+        let sequence = self.sequences.get_mut(&channel_id).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, format!("MCAP channel {} was never added", channel_id))
+        })?;
+        let current = *sequence;
+        *sequence = sequence.wrapping_add(1);
+
+        // Point frames are large, so the payload is written without copying it into a record buffer
+        self.writer.write_u8(OP_MESSAGE)?;
+        self.writer.write_u64::<LittleEndian>((2 + 4 + 8 + 8 + data.len()) as u64)?;
+        self.writer.write_u16::<LittleEndian>(channel_id)?;
+        self.writer.write_u32::<LittleEndian>(current)?;
+        self.writer.write_u64::<LittleEndian>(log_time)?;
+        self.writer.write_u64::<LittleEndian>(publish_time)?;
+        self.writer.write_all(data)
+    }
+
+    /// Write the data end and footer records and the closing magic.
+    /// Called automatically on drop, but errors are only reported when called directly.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        let mut content = Vec::new();
+        content.write_u32::<LittleEndian>(0)?; // data section CRC, 0 = not computed
+        write_record(&mut self.writer, OP_DATA_END, &content)?;
+
+        let mut content = Vec::new();
+        content.write_u64::<LittleEndian>(0)?; // summary start, 0 = no summary section
+        content.write_u64::<LittleEndian>(0)?; // summary offset start
+        content.write_u32::<LittleEndian>(0)?; // summary CRC
+        write_record(&mut self.writer, OP_FOOTER, &content)?;
+
+        self.writer.write_all(&MCAP_MAGIC)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for McapWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Everything in an MCAP file, with messages in file order
+#[derive(Debug, Default)]
+pub struct McapFile {
+    pub profile: String,
+    pub schemas: HashMap<u16, McapSchema>,
+    pub channels: HashMap<u16, McapChannel>,
+    pub messages: Vec<McapMessage>,
+}
+
+impl McapFile {
+    pub fn channel_by_topic(&self, topic: &str) -> Option<&McapChannel> {
+        self.channels.values().find(|channel| channel.topic == topic)
+    }
+
+    pub fn topic(&self, message: &McapMessage) -> Option<&str> {
+        self.channels.get(&message.channel_id).map(|channel| channel.topic.as_str())
+    }
+}
+
+pub fn load_mcap(file_name: &str) -> std::io::Result<McapFile> {
+    read_mcap(&mut BufReader::new(File::open(file_name)?))
+}
+
+/// Read an MCAP file. Uncompressed chunks are supported, compressed ones are rejected.
+/// A file that ends mid-record (e.g. the recorder was killed) yields everything before the cut.
+pub fn read_mcap<R: Read>(reader: &mut R) -> std::io::Result<McapFile> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != MCAP_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not an MCAP file"));
+    }
+
+    let mut file = McapFile::default();
+    loop {
+        let opcode = match reader.read_u8() {
+            Ok(opcode) => opcode,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let content = match read_record_content(reader) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        if opcode == OP_FOOTER {
+            break;
+        }
+        parse_record(opcode, &content, &mut file)?;
+    }
+    Ok(file)
+}
+
+fn read_record_content<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let length = reader.read_u64::<LittleEndian>()?;
+    let mut content = Vec::new();
+    // `take` instead of a pre-sized buffer so a corrupt length cannot allocate gigabytes
+    reader.take(length).read_to_end(&mut content)?;
+    if content.len() as u64 != length {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "MCAP record is truncated"));
+    }
+    Ok(content)
+}
+
+fn parse_record(opcode: u8, content: &[u8], file: &mut McapFile) -> std::io::Result<()> {
+    let mut cursor = Cursor::new(content);
+    match opcode {
+        OP_HEADER => {
+            file.profile = get_string(&mut cursor)?;
+        }
+        OP_SCHEMA => {
+            let id = cursor.read_u16::<LittleEndian>()?;
+            let name = get_string(&mut cursor)?;
+            let encoding = get_string(&mut cursor)?;
+            let data = get_bytes(&mut cursor)?;
+            file.schemas.insert(id, McapSchema { id, name, encoding, data });
+        }
+        OP_CHANNEL => {
+            let id = cursor.read_u16::<LittleEndian>()?;
+            let schema_id = cursor.read_u16::<LittleEndian>()?;
+            let topic = get_string(&mut cursor)?;
+            let message_encoding = get_string(&mut cursor)?;
+            let map = get_bytes(&mut cursor)?;
+            let mut metadata = BTreeMap::new();
+            let mut map_cursor = Cursor::new(&map[..]);
+            while (map_cursor.position() as usize) < map.len() {
+                let key = get_string(&mut map_cursor)?;
+                let value = get_string(&mut map_cursor)?;
+                metadata.insert(key, value);
+            }
+            file.channels.insert(id, McapChannel { id, schema_id, topic, message_encoding, metadata });
+        }
+        OP_MESSAGE => {
+            let channel_id = cursor.read_u16::<LittleEndian>()?;
+            let sequence = cursor.read_u32::<LittleEndian>()?;
+            let log_time = cursor.read_u64::<LittleEndian>()?;
+            let publish_time = cursor.read_u64::<LittleEndian>()?;
+            let data = content[cursor.position() as usize..].to_vec();
+            file.messages.push(McapMessage { channel_id, sequence, log_time, publish_time, data });
+        }
+        OP_CHUNK => {
+            let _message_start_time = cursor.read_u64::<LittleEndian>()?;
+            let _message_end_time = cursor.read_u64::<LittleEndian>()?;
+            let _uncompressed_size = cursor.read_u64::<LittleEndian>()?;
+            let _uncompressed_crc = cursor.read_u32::<LittleEndian>()?;
+            let compression = get_string(&mut cursor)?;
+            if !compression.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Compressed MCAP chunks ({}) are not supported", compression),
+                ));
+            }
+            let records_length = cursor.read_u64::<LittleEndian>()? as usize;
+            let start = cursor.position() as usize;
+            let records = content
+                .get(start..start + records_length)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "MCAP chunk is truncated"))?;
+            let mut chunk = Cursor::new(records);
+            while (chunk.position() as usize) < records.len() {
+                let opcode = chunk.read_u8()?;
+                let content = read_record_content(&mut chunk)?;
+                parse_record(opcode, &content, file)?;
+            }
+        }
+        // Indexes, statistics, attachments and metadata are not needed to replay messages
+        _ => {}
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(writer: &mut W, opcode: u8, content: &[u8]) -> std::io::Result<()> {
+    writer.write_u8(opcode)?;
+    writer.write_u64::<LittleEndian>(content.len() as u64)?;
+    writer.write_all(content)
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn get_bytes(cursor: &mut Cursor<&[u8]>) -> std::io::Result<Vec<u8>> {
+    let length = cursor.read_u32::<LittleEndian>()? as usize;
+    let start = cursor.position() as usize;
+    let bytes = cursor
+        .get_ref()
+        .get(start..start + length)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "MCAP field runs past the end of its record"))?
+        .to_vec();
+    cursor.set_position((start + length) as u64);
+    Ok(bytes)
+}
+
+fn get_string(cursor: &mut Cursor<&[u8]>) -> std::io::Result<String> {
+    String::from_utf8(get_bytes(cursor)?).map_err(|_| Error::new(ErrorKind::InvalidData, "MCAP string is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_file() -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut writer = McapWriter::new(&mut buffer, "test").unwrap();
+        let schema = writer.add_schema("foxglove.PointCloud", "jsonschema", b"{}").unwrap();
+        let metadata = BTreeMap::from([("frame".to_string(), "mid360".to_string())]);
+        let points = writer.add_channel(schema, "/points", "json", &metadata).unwrap();
+        let pose = writer.add_channel(0, "/pose", "json", &BTreeMap::new()).unwrap();
+        writer.write_message(points, 1_000, 1_001, b"first").unwrap();
+        writer.write_message(pose, 2_000, 2_002, b"pose").unwrap();
+        writer.write_message(points, 3_000, 3_003, b"second").unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        buffer
+    }
+
+    #[test]
+    fn round_trip_in_memory() {
+        let buffer = test_file();
+        assert_eq!(buffer[..8], MCAP_MAGIC);
+        assert_eq!(buffer[buffer.len() - 8..], MCAP_MAGIC);
+
+        let file = read_mcap(&mut buffer.as_slice()).unwrap();
+        assert_eq!(file.profile, "test");
+        assert_eq!(
+            file.schemas[&1],
+            McapSchema { id: 1, name: "foxglove.PointCloud".to_string(), encoding: "jsonschema".to_string(), data: b"{}".to_vec() }
+        );
+
+        let points = file.channel_by_topic("/points").unwrap();
+        assert_eq!((points.id, points.schema_id, points.message_encoding.as_str()), (0, 1, "json"));
+        assert_eq!(points.metadata.get("frame").map(String::as_str), Some("mid360"));
+        let pose = file.channel_by_topic("/pose").unwrap();
+        assert_eq!((pose.id, pose.schema_id), (1, 0));
+        assert!(pose.metadata.is_empty());
+
+        let messages: Vec<(&str, u32, u64, u64, &[u8])> = file
+            .messages
+            .iter()
+            .map(|m| (file.topic(m).unwrap(), m.sequence, m.log_time, m.publish_time, m.data.as_slice()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                ("/points", 0, 1_000, 1_001, &b"first"[..]),
+                ("/pose", 0, 2_000, 2_002, &b"pose"[..]),
+                ("/points", 1, 3_000, 3_003, &b"second"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip_through_file() {
+        let path = std::env::temp_dir().join(format!("mcap_test_{}.mcap", std::process::id()));
+        std::fs::write(&path, test_file()).unwrap();
+        let file = load_mcap(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.messages.len(), 3);
+    }
+
+    #[test]
+    fn truncated_file_keeps_the_messages_before_the_cut() {
+        let buffer = test_file();
+        let cut = buffer.windows(6).position(|w| w == b"second").unwrap();
+        let file = read_mcap(&mut &buffer[..cut]).unwrap();
+        assert_eq!(file.messages.len(), 2);
+        assert_eq!(file.messages[1].data, b"pose");
+
+        let mut bad_magic = buffer.clone();
+        bad_magic[1] = b'X';
+        assert!(read_mcap(&mut bad_magic.as_slice()).is_err());
+    }
+
+    #[test]
+    fn reads_uncompressed_chunks_and_rejects_compressed_ones() {
+        let mut records = Vec::new();
+        let mut channel = Vec::new();
+        channel.write_u16::<LittleEndian>(3).unwrap();
+        channel.write_u16::<LittleEndian>(0).unwrap();
+        put_string(&mut channel, "/chunked");
+        put_string(&mut channel, "raw");
+        channel.write_u32::<LittleEndian>(0).unwrap();
+        write_record(&mut records, OP_CHANNEL, &channel).unwrap();
+        let mut message = Vec::new();
+        message.write_u16::<LittleEndian>(3).unwrap();
+        message.write_u32::<LittleEndian>(7).unwrap();
+        message.write_u64::<LittleEndian>(42).unwrap();
+        message.write_u64::<LittleEndian>(43).unwrap();
+        message.extend_from_slice(b"data");
+        write_record(&mut records, OP_MESSAGE, &message).unwrap();
+
+        let chunk_file = |compression: &str| {
+            let mut chunk = Vec::new();
+            chunk.write_u64::<LittleEndian>(42).unwrap();
+            chunk.write_u64::<LittleEndian>(42).unwrap();
+            chunk.write_u64::<LittleEndian>(records.len() as u64).unwrap();
+            chunk.write_u32::<LittleEndian>(0).unwrap();
+            put_string(&mut chunk, compression);
+            chunk.write_u64::<LittleEndian>(records.len() as u64).unwrap();
+            chunk.extend_from_slice(&records);
+            let mut buffer = MCAP_MAGIC.to_vec();
+            write_record(&mut buffer, OP_CHUNK, &chunk).unwrap();
+            buffer
+        };
+
+        let file = read_mcap(&mut chunk_file("").as_slice()).unwrap();
+        assert_eq!(file.channels[&3].topic, "/chunked");
+        assert_eq!(file.messages, vec![McapMessage { channel_id: 3, sequence: 7, log_time: 42, publish_time: 43, data: b"data".to_vec() }]);
+        assert!(read_mcap(&mut chunk_file("zstd").as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_messages_on_unknown_channels() {
+        let mut writer = McapWriter::new(Vec::new(), "test").unwrap();
+        assert!(writer.write_message(5, 0, 0, b"").is_err());
+    }
+}
@@ -2,4 +2,11 @@ pub mod udp_reader;
 pub mod structor;
 pub mod structor_to_file;
 pub mod io;
-pub mod sensor_detect;
\ No newline at end of file
+pub mod sensor_detect;
+pub mod point_cloud_file;
+pub mod pcd;
+pub mod ply;
+pub mod las;
+pub mod csv_reader;
+pub mod mcap;
+pub mod flight_log;
@@ -0,0 +1,289 @@
+#![allow(dead_code)]
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+
+use crate::data_reader::point_cloud_file::{initial_capacity, intensity_to_reflectivity, is_intensity_field, ScalarType};
+use crate::data_reader::structor::LaserPoint;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcdDataFormat {
+    Ascii,
+    Binary,
+}
+
+pub fn save_pcd(points: &[LaserPoint], file_name: &str, format: PcdDataFormat) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(file_name)?);
+    write_pcd(points, &mut writer, format)?;
+    writer.flush()
+}
+
+pub fn load_pcd(file_name: &str) -> std::io::Result<Vec<LaserPoint>> {
+    read_pcd(&mut BufReader::new(File::open(file_name)?))
+}
+
+/// Write a PCD v0.7 cloud with fields x y z intensity (all float32)
+pub fn write_pcd<W: Write>(points: &[LaserPoint], writer: &mut W, format: PcdDataFormat) -> std::io::Result<()> {
+    writeln!(writer, "# .PCD v0.7 - Point Cloud Data file format")?;
+    writeln!(writer, "VERSION 0.7")?;
+    writeln!(writer, "FIELDS x y z intensity")?;
+    writeln!(writer, "SIZE 4 4 4 4")?;
+    writeln!(writer, "TYPE F F F F")?;
+    writeln!(writer, "COUNT 1 1 1 1")?;
+    writeln!(writer, "WIDTH {}", points.len())?;
+    writeln!(writer, "HEIGHT 1")?;
+    writeln!(writer, "VIEWPOINT 0 0 0 1 0 0 0")?;
+    writeln!(writer, "POINTS {}", points.len())?;
+
+    match format {
+        PcdDataFormat::Ascii => {
+            writeln!(writer, "DATA ascii")?;
+            for point in points {
+                writeln!(writer, "{} {} {} {}", point.x, point.y, point.z, point.reflectivity)?;
+            }
+        }
+        PcdDataFormat::Binary => {
+            writeln!(writer, "DATA binary")?;
+            for point in points {
+                writer.write_f32::<LittleEndian>(point.x)?;
+                writer.write_f32::<LittleEndian>(point.y)?;
+                writer.write_f32::<LittleEndian>(point.z)?;
+                writer.write_f32::<LittleEndian>(point.reflectivity as f32)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Values of one field in one point; PCL descriptors use a few hundred at most
+const MAX_FIELD_COUNT: usize = 1 << 16;
+
+struct PcdField {
+    name: String,
+    scalar: ScalarType,
+    count: usize,
+}
+
+/// Read an ascii or binary PCD cloud. Any field layout is accepted as long as it has
+/// x, y and z; an intensity/reflectivity field is used when present.
+pub fn read_pcd<R: BufRead>(reader: &mut R) -> std::io::Result<Vec<LaserPoint>> {
+    let mut names: Vec<String> = Vec::new();
+    let mut sizes: Vec<usize> = Vec::new();
+    let mut types: Vec<char> = Vec::new();
+    let mut counts: Vec<usize> = Vec::new();
+    let mut point_count: Option<usize> = None;
+    let mut width_height = (0usize, 1usize);
+    let data_format;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "PCD header has no DATA line"));
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let mut tokens = trimmed.split_whitespace();
+        let key = tokens.next().unwrap_or("").to_ascii_uppercase();
+        let values: Vec<&str> = tokens.collect();
+        match key.as_str() {
+            "FIELDS" => names = values.iter().map(|v| v.to_string()).collect(),
+            "SIZE" => sizes = parse_list(&values)?,
+            "TYPE" => types = values.iter().map(|v| v.chars().next().unwrap_or('?')).collect(),
+            "COUNT" => counts = parse_list(&values)?,
+            "WIDTH" => width_height.0 = parse_list(&values)?.first().copied().unwrap_or(0),
+            "HEIGHT" => width_height.1 = parse_list(&values)?.first().copied().unwrap_or(1),
+            "POINTS" => point_count = parse_list(&values)?.first().copied(),
+            "DATA" => {
+                data_format = match values.first().map(|v| v.to_ascii_lowercase()) {
+                    Some(ref v) if v == "ascii" => PcdDataFormat::Ascii,
+                    Some(ref v) if v == "binary" => PcdDataFormat::Binary,
+                    other => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Unsupported PCD DATA type {:?}", other),
+                        ))
+                    }
+                };
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    if counts.is_empty() {
+        counts = vec![1; names.len()];
+    }
+    if sizes.len() != names.len() || types.len() != names.len() || counts.len() != names.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "PCD FIELDS, SIZE, TYPE and COUNT do not match"));
+    }
+    if let Some(count) = counts.iter().find(|count| **count > MAX_FIELD_COUNT) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported PCD COUNT {}", count)));
+    }
+
+    let mut fields = Vec::with_capacity(names.len());
+    for i in 0..names.len() {
+        let scalar = match (types[i], sizes[i]) {
+            ('I', 1) => ScalarType::I8,
+            ('I', 2) => ScalarType::I16,
+            ('I', 4) => ScalarType::I32,
+            ('U', 1) => ScalarType::U8,
+            ('U', 2) => ScalarType::U16,
+            ('U', 4) => ScalarType::U32,
+            ('F', 4) => ScalarType::F32,
+            ('F', 8) => ScalarType::F64,
+            (t, s) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported PCD field type {}{} for {}", t, s, names[i]),
+                ))
+            }
+        };
+        fields.push(PcdField { name: names[i].clone(), scalar, count: counts[i] });
+    }
+
+    let find = |wanted: &dyn Fn(&str) -> bool| {
+        let mut column = 0;
+        for field in &fields {
+            if wanted(&field.name) {
+                return Some(column);
+            }
+            column += field.count;
+        }
+        None
+    };
+    let (x, y, z) = match (find(&|n| n == "x"), find(&|n| n == "y"), find(&|n| n == "z")) {
+        (Some(x), Some(y), Some(z)) => (x, y, z),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "PCD cloud has no x/y/z fields")),
+    };
+    let intensity = find(&is_intensity_field);
+
+    let point_count = point_count.unwrap_or(width_height.0.saturating_mul(width_height.1));
+    let column_count: usize = fields.iter().map(|f| f.count).sum();
+    let mut points = Vec::with_capacity(initial_capacity(point_count as u64));
+    let mut values = vec![0.0f64; column_count];
+
+    for _ in 0..point_count {
+        match data_format {
+            PcdDataFormat::Ascii => {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "PCD ascii data ended early"));
+                }
+                let mut tokens = line.split_whitespace();
+                for value in values.iter_mut() {
+                    *value = tokens
+                        .next()
+                        .and_then(|t| t.parse::<f64>().ok())
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed PCD ascii row"))?;
+                }
+            }
+            PcdDataFormat::Binary => {
+                let mut column = 0;
+                for field in &fields {
+                    for _ in 0..field.count {
+                        values[column] = field.scalar.read_le(reader)?;
+                        column += 1;
+                    }
+                }
+            }
+        }
+
+        let (px, py, pz) = (values[x] as f32, values[y] as f32, values[z] as f32);
+        // PCL marks invalid points of organized clouds with NaN
+        if !px.is_finite() || !py.is_finite() || !pz.is_finite() {
+            continue;
+        }
+        let reflectivity = intensity.map_or(0, |column| intensity_to_reflectivity(values[column]));
+        points.push(LaserPoint::new(px, py, pz, reflectivity));
+    }
+
+    Ok(points)
+}
+
+fn parse_list(values: &[&str]) -> std::io::Result<Vec<usize>> {
+    values
+        .iter()
+        .map(|v| v.parse::<usize>().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid PCD number {}", v))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_points() -> Vec<LaserPoint> {
+        (0..200)
+            .map(|i| {
+                let t = i as f32 * 0.05;
+                LaserPoint::new(t.sin() * 8.0, t.cos() * 8.0, t - 5.0, (i % 256) as u8)
+            })
+            .collect()
+    }
+
+    fn assert_same_points(a: &[LaserPoint], b: &[LaserPoint]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert_eq!((a.x, a.y, a.z, a.reflectivity), (b.x, b.y, b.z, b.reflectivity));
+        }
+    }
+
+    #[test]
+    fn round_trip_in_memory() {
+        let points = test_points();
+        for format in [PcdDataFormat::Ascii, PcdDataFormat::Binary] {
+            let mut buffer = Vec::new();
+            write_pcd(&points, &mut buffer, format).unwrap();
+            let loaded = read_pcd(&mut buffer.as_slice()).unwrap();
+            assert_same_points(&loaded, &points);
+        }
+    }
+
+    #[test]
+    fn round_trip_through_file() {
+        let points = test_points();
+        let path = std::env::temp_dir().join(format!("pcd_test_{}.pcd", std::process::id()));
+        let file_name = path.to_str().unwrap();
+
+        save_pcd(&points, file_name, PcdDataFormat::Binary).unwrap();
+        let loaded = load_pcd(file_name).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_same_points(&loaded, &points);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        for format in [PcdDataFormat::Ascii, PcdDataFormat::Binary] {
+            let mut buffer = Vec::new();
+            write_pcd(&test_points(), &mut buffer, format).unwrap();
+            let truncated = &buffer[..buffer.len() - 20];
+            assert!(read_pcd(&mut &truncated[..]).is_err());
+        }
+
+        let header_only = b"FIELDS x y z\nSIZE 4 4 4\nTYPE F F F\nPOINTS 1\n";
+        assert!(read_pcd(&mut &header_only[..]).is_err());
+    }
+
+    #[test]
+    fn huge_header_counts_fail_without_allocating() {
+        let mut buffer = b"FIELDS x y z\nSIZE 4 4 4\nTYPE F F F\nPOINTS 4000000000\nDATA binary\n".to_vec();
+        buffer.extend_from_slice(&[0u8; 12]);
+        let error = read_pcd(&mut buffer.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+        let huge_count = b"FIELDS x y z\nSIZE 4 4 4\nTYPE F F F\nCOUNT 1 1 4000000000\nPOINTS 1\nDATA binary\n";
+        assert_eq!(read_pcd(&mut &huge_count[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn skips_nan_points_and_reads_other_layouts() {
+        let data = b"FIELDS rgb x y z intensity\nSIZE 4 8 8 8 2\nTYPE U F F F U\nWIDTH 2\nHEIGHT 1\nDATA ascii\n\
+0 1.5 -2 3 70\n0 nan nan nan 0\n";
+        let points = read_pcd(&mut &data[..]).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!((points[0].x, points[0].y, points[0].z, points[0].reflectivity), (1.5, -2.0, 3.0, 70));
+    }
+}
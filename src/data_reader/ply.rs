@@ -0,0 +1,247 @@
+#![allow(dead_code)]
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+
+use crate::data_reader::point_cloud_file::{initial_capacity, intensity_to_reflectivity, is_intensity_field, ScalarType};
+use crate::data_reader::structor::LaserPoint;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+pub fn save_ply(points: &[LaserPoint], file_name: &str, format: PlyFormat) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(file_name)?);
+    write_ply(points, &mut writer, format)?;
+    writer.flush()
+}
+
+pub fn load_ply(file_name: &str) -> std::io::Result<Vec<LaserPoint>> {
+    read_ply(&mut BufReader::new(File::open(file_name)?))
+}
+
+/// Write a PLY vertex cloud with float x/y/z and a uchar intensity property
+pub fn write_ply<W: Write>(points: &[LaserPoint], writer: &mut W, format: PlyFormat) -> std::io::Result<()> {
+    let format_name = match format {
+        PlyFormat::Ascii => "ascii",
+        PlyFormat::BinaryLittleEndian => "binary_little_endian",
+        PlyFormat::BinaryBigEndian => "binary_big_endian",
+    };
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format {} 1.0", format_name)?;
+    writeln!(writer, "comment generated by WorldWithoutAnime")?;
+    writeln!(writer, "element vertex {}", points.len())?;
+    writeln!(writer, "property float x")?;
+    writeln!(writer, "property float y")?;
+    writeln!(writer, "property float z")?;
+    writeln!(writer, "property uchar intensity")?;
+    writeln!(writer, "end_header")?;
+
+    for point in points {
+        match format {
+            PlyFormat::Ascii => {
+                writeln!(writer, "{} {} {} {}", point.x, point.y, point.z, point.reflectivity)?;
+            }
+            PlyFormat::BinaryLittleEndian => {
+                writer.write_f32::<LittleEndian>(point.x)?;
+                writer.write_f32::<LittleEndian>(point.y)?;
+                writer.write_f32::<LittleEndian>(point.z)?;
+                writer.write_u8(point.reflectivity)?;
+            }
+            PlyFormat::BinaryBigEndian => {
+                writer.write_f32::<BigEndian>(point.x)?;
+                writer.write_f32::<BigEndian>(point.y)?;
+                writer.write_f32::<BigEndian>(point.z)?;
+                writer.write_u8(point.reflectivity)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_scalar_type(name: &str) -> Option<ScalarType> {
+    match name {
+        "char" | "int8" => Some(ScalarType::I8),
+        "uchar" | "uint8" => Some(ScalarType::U8),
+        "short" | "int16" => Some(ScalarType::I16),
+        "ushort" | "uint16" => Some(ScalarType::U16),
+        "int" | "int32" => Some(ScalarType::I32),
+        "uint" | "uint32" => Some(ScalarType::U32),
+        "float" | "float32" => Some(ScalarType::F32),
+        "double" | "float64" => Some(ScalarType::F64),
+        _ => None,
+    }
+}
+
+/// Read the vertex element of a PLY file. The vertex element must come first and
+/// hold scalar properties only, which is what point cloud tools write.
+pub fn read_ply<R: BufRead>(reader: &mut R) -> std::io::Result<Vec<LaserPoint>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim() != "ply" {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a PLY file"));
+    }
+
+    let mut format = None;
+    let mut vertex_count = None;
+    let mut in_vertex = false;
+    let mut seen_vertex = false;
+    let mut properties: Vec<(String, ScalarType)> = Vec::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "PLY header has no end_header"));
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["end_header"] => break,
+            ["format", name, _version] => {
+                format = match *name {
+                    "ascii" => Some(PlyFormat::Ascii),
+                    "binary_little_endian" => Some(PlyFormat::BinaryLittleEndian),
+                    "binary_big_endian" => Some(PlyFormat::BinaryBigEndian),
+                    _ => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown PLY format {}", name))),
+                };
+            }
+            ["element", name, count] => {
+                in_vertex = *name == "vertex";
+                if in_vertex {
+                    if seen_vertex || vertex_count.is_some() {
+                        return Err(Error::new(ErrorKind::InvalidData, "Duplicate PLY vertex element"));
+                    }
+                    seen_vertex = true;
+                    vertex_count = count.parse::<usize>().ok();
+                } else if !seen_vertex {
+                    return Err(Error::new(ErrorKind::InvalidData, "PLY vertex element must come first"));
+                }
+            }
+            ["property", "list", ..] if in_vertex => {
+                return Err(Error::new(ErrorKind::InvalidData, "List properties on PLY vertices are not supported"));
+            }
+            ["property", type_name, name] if in_vertex => {
+                let scalar = parse_scalar_type(type_name).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("Unknown PLY property type {}", type_name))
+                })?;
+                properties.push((name.to_string(), scalar));
+            }
+            _ => {}
+        }
+    }
+
+    let format = format.ok_or_else(|| Error::new(ErrorKind::InvalidData, "PLY header has no format line"))?;
+    let vertex_count = vertex_count.ok_or_else(|| Error::new(ErrorKind::InvalidData, "PLY file has no vertex element"))?;
+    let column = |wanted: &dyn Fn(&str) -> bool| properties.iter().position(|(name, _)| wanted(name));
+    let (x, y, z) = match (column(&|n| n == "x"), column(&|n| n == "y"), column(&|n| n == "z")) {
+        (Some(x), Some(y), Some(z)) => (x, y, z),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "PLY vertices have no x/y/z properties")),
+    };
+    let intensity = column(&is_intensity_field);
+
+    let mut points = Vec::with_capacity(initial_capacity(vertex_count as u64));
+    let mut values = vec![0.0f64; properties.len()];
+    for _ in 0..vertex_count {
+        match format {
+            PlyFormat::Ascii => {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "PLY ascii data ended early"));
+                }
+                let mut tokens = line.split_whitespace();
+                for value in values.iter_mut() {
+                    *value = tokens
+                        .next()
+                        .and_then(|t| t.parse::<f64>().ok())
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed PLY ascii row"))?;
+                }
+            }
+            PlyFormat::BinaryLittleEndian => {
+                for (value, (_, scalar)) in values.iter_mut().zip(&properties) {
+                    *value = scalar.read_le(reader)?;
+                }
+            }
+            PlyFormat::BinaryBigEndian => {
+                for (value, (_, scalar)) in values.iter_mut().zip(&properties) {
+                    *value = scalar.read_be(reader)?;
+                }
+            }
+        }
+        let reflectivity = intensity.map_or(0, |column| intensity_to_reflectivity(values[column]));
+        points.push(LaserPoint::new(values[x] as f32, values[y] as f32, values[z] as f32, reflectivity));
+    }
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [PlyFormat; 3] = [PlyFormat::Ascii, PlyFormat::BinaryLittleEndian, PlyFormat::BinaryBigEndian];
+
+    fn test_points() -> Vec<LaserPoint> {
+        (0..200)
+            .map(|i| {
+                let t = i as f32 * 0.05;
+                LaserPoint::new(t.sin() * 8.0, t.cos() * 8.0, t - 5.0, (i % 256) as u8)
+            })
+            .collect()
+    }
+
+    fn assert_same_points(a: &[LaserPoint], b: &[LaserPoint]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert_eq!((a.x, a.y, a.z, a.reflectivity), (b.x, b.y, b.z, b.reflectivity));
+        }
+    }
+
+    #[test]
+    fn round_trip_in_memory() {
+        let points = test_points();
+        for format in FORMATS {
+            let mut buffer = Vec::new();
+            write_ply(&points, &mut buffer, format).unwrap();
+            let loaded = read_ply(&mut buffer.as_slice()).unwrap();
+            assert_same_points(&loaded, &points);
+        }
+    }
+
+    #[test]
+    fn round_trip_through_file() {
+        let points = test_points();
+        let path = std::env::temp_dir().join(format!("ply_test_{}.ply", std::process::id()));
+        let file_name = path.to_str().unwrap();
+
+        save_ply(&points, file_name, PlyFormat::BinaryBigEndian).unwrap();
+        let loaded = load_ply(file_name).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_same_points(&loaded, &points);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        for format in FORMATS {
+            let mut buffer = Vec::new();
+            write_ply(&test_points(), &mut buffer, format).unwrap();
+            let truncated = &buffer[..buffer.len() - 20];
+            assert!(read_ply(&mut &truncated[..]).is_err());
+        }
+
+        let no_end_header = b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\n";
+        assert!(read_ply(&mut &no_end_header[..]).is_err());
+    }
+
+    #[test]
+    fn huge_vertex_count_fails_without_allocating() {
+        let mut buffer = b"ply\nformat binary_little_endian 1.0\nelement vertex 4000000000000\n\
+property float x\nproperty float y\nproperty float z\nend_header\n"
+            .to_vec();
+        buffer.extend_from_slice(&[0u8; 12]);
+        let error = read_ply(&mut buffer.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,114 @@
+#![allow(dead_code)]
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+
+use crate::data_reader::structor::LaserPoint;
+use crate::data_reader::{las, pcd, ply};
+
+/// Numeric type of a PCD/PLY field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl ScalarType {
+    pub fn size(&self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::F64 => 8,
+        }
+    }
+
+    pub fn read_le<R: Read>(&self, reader: &mut R) -> std::io::Result<f64> {
+        Ok(match self {
+            ScalarType::I8 => reader.read_i8()? as f64,
+            ScalarType::U8 => reader.read_u8()? as f64,
+            ScalarType::I16 => reader.read_i16::<LittleEndian>()? as f64,
+            ScalarType::U16 => reader.read_u16::<LittleEndian>()? as f64,
+            ScalarType::I32 => reader.read_i32::<LittleEndian>()? as f64,
+            ScalarType::U32 => reader.read_u32::<LittleEndian>()? as f64,
+            ScalarType::F32 => reader.read_f32::<LittleEndian>()? as f64,
+            ScalarType::F64 => reader.read_f64::<LittleEndian>()?,
+        })
+    }
+
+    pub fn read_be<R: Read>(&self, reader: &mut R) -> std::io::Result<f64> {
+        Ok(match self {
+            ScalarType::I8 => reader.read_i8()? as f64,
+            ScalarType::U8 => reader.read_u8()? as f64,
+            ScalarType::I16 => reader.read_i16::<BigEndian>()? as f64,
+            ScalarType::U16 => reader.read_u16::<BigEndian>()? as f64,
+            ScalarType::I32 => reader.read_i32::<BigEndian>()? as f64,
+            ScalarType::U32 => reader.read_u32::<BigEndian>()? as f64,
+            ScalarType::F32 => reader.read_f32::<BigEndian>()? as f64,
+            ScalarType::F64 => reader.read_f64::<BigEndian>()?,
+        })
+    }
+}
+
+/// Field names other tools use for what we call reflectivity
+pub fn is_intensity_field(name: &str) -> bool {
+    matches!(
+        name.to_ascii_lowercase().as_str(),
+        "intensity" | "reflectivity" | "scalar_intensity" | "i"
+    )
+}
+
+/// Clamp an intensity read from a file into the 0-255 reflectivity range
+pub fn intensity_to_reflectivity(value: f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+/// Points reserved up front for a count read from a file header. A corrupt header can claim
+/// any count, so larger clouds grow as their points are actually read.
+const MAX_PREALLOCATED_POINTS: u64 = 1 << 20;
+
+pub fn initial_capacity(point_count: u64) -> usize {
+    point_count.min(MAX_PREALLOCATED_POINTS) as usize
+}
+
+/// Flatten the per-depth leaf centers from `Octree::octree_to_map` into one point list
+pub fn octree_map_to_points(map: &HashMap<u32, Vec<LaserPoint>>) -> Vec<LaserPoint> {
+    let mut depths: Vec<&u32> = map.keys().collect();
+    depths.sort();
+    depths.into_iter().flat_map(|depth| map[depth].iter().cloned()).collect()
+}
+
+/// Save points in the format given by the file extension (.pcd, .ply or .las)
+pub fn save_point_cloud(points: &[LaserPoint], file_name: &str) -> std::io::Result<()> {
+    match extension(file_name).as_str() {
+        "pcd" => pcd::save_pcd(points, file_name, pcd::PcdDataFormat::Binary),
+        "ply" => ply::save_ply(points, file_name, ply::PlyFormat::BinaryLittleEndian),
+        "las" => las::save_las(points, file_name),
+        other => Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported point cloud extension \"{}\"", other))),
+    }
+}
+
+/// Load points from a .pcd, .ply or .las file, chosen by the file extension
+pub fn load_point_cloud(file_name: &str) -> std::io::Result<Vec<LaserPoint>> {
+    match extension(file_name).as_str() {
+        "pcd" => pcd::load_pcd(file_name),
+        "ply" => ply::load_ply(file_name),
+        "las" => las::load_las(file_name),
+        other => Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported point cloud extension \"{}\"", other))),
+    }
+}
+
+fn extension(file_name: &str) -> String {
+    Path::new(file_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase()
+}
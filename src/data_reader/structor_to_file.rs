@@ -3,12 +3,29 @@ use crate::octree::octree;
 use crate::calculator::mavlink_args::MavlinkArgs;
 use std::io::Write;
 
-// TODO: implement octree_to_csv
-pub fn octree_to_csv(_octree_input: octree::Octree, file_name: &str) {
-    let mut file = std::fs::File::create(file_name)
-        .unwrap_or_else(|_| panic!("Failed to create {}", file_name));
+/// Write every occupied leaf as one row: center, averaged reflectivity and depth.
+/// The column names match `LaserPoint`, so the file can be loaded back with `csv_reader`.
+pub fn octree_to_csv(octree_input: &octree::Octree, file_name: &str) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_path(file_name)?;
+
+    writer.write_record(["X", "Y", "Z", "Reflectivity", "Depth"])?;
 
-    writeln!(file, "x,y,z,reflectivity").expect("Failed to write to file");
+    let leaves = octree_input.octree_to_map();
+    let mut depths: Vec<&u32> = leaves.keys().collect();
+    depths.sort();
+    for depth in depths {
+        for point in &leaves[depth] {
+            writer.write_record([
+                point.x.to_string(),
+                point.y.to_string(),
+                point.z.to_string(),
+                point.reflectivity.to_string(),
+                depth.to_string(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
 }
 
 #[allow(non_snake_case)]
@@ -32,4 +49,47 @@ pub fn MavlinkArgs_to_file(mavlink_args: &MavlinkArgs, file_name: &str) {
     writeln!(file, "afz: {}", mavlink_args.afz).expect("Failed to write to file");
     writeln!(file, "yaw: {}", mavlink_args.yaw).expect("Failed to write to file");
     writeln!(file, "yaw_rate: {}", mavlink_args.yaw_rate).expect("Failed to write to file");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_reader::csv_reader;
+    use crate::data_reader::structor::LaserPoint;
+
+    #[test]
+    fn octree_csv_loads_back_as_leaf_centers() {
+        let mut octree = octree::Octree::new([[-10.0; 3], [10.0; 3]]);
+        let points: Vec<LaserPoint> = (0..300)
+            .map(|i| {
+                let t = i as f32 * 0.03;
+                LaserPoint::new(t.sin() * 8.0, t.cos() * 8.0, t - 4.0, (i % 256) as u8)
+            })
+            .collect();
+        octree.insert_batch(&points, 5).unwrap();
+
+        let path = std::env::temp_dir().join(format!("octree_csv_test_{}.csv", std::process::id()));
+        let file_name = path.to_str().unwrap();
+        octree_to_csv(&octree, file_name).unwrap();
+        let loaded = csv_reader::read_laserpoint_csv(file_name).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut expected: Vec<(f32, f32, f32, u8)> = octree
+            .octree_to_map()
+            .values()
+            .flatten()
+            .map(|p| (p.x, p.y, p.z, p.reflectivity))
+            .collect();
+        let mut loaded: Vec<(f32, f32, f32, u8)> = loaded.iter().map(|p| (p.x, p.y, p.z, p.reflectivity)).collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        loaded.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(loaded, expected);
+    }
+
+    #[test]
+    fn octree_csv_reports_io_errors() {
+        let octree = octree::Octree::new([[-1.0; 3], [1.0; 3]]);
+        let file_name = std::env::temp_dir().join("no_such_directory").join("map.csv");
+        assert!(octree_to_csv(&octree, file_name.to_str().unwrap()).is_err());
+    }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 mod data_reader;
 mod octree;
 mod calculator;
-mod visualization;
\ No newline at end of file
+mod visualization;
+mod mavlink;
\ No newline at end of file
@@ -2,13 +2,113 @@ mod octree;
 mod data_reader;
 mod visualization;
 mod calculator;
+mod mavlink;
+
+use visualization::rendering_components_octree::{PointSource, RunOptions};
 
 fn main() {
-    if !data_reader::sensor_detect::is_imu_sensor_online() || !data_reader::sensor_detect::is_lidar_online() {
+    // `--csv <file>` replays a Livox Viewer CSV export instead of the live sensor,
+    // `--playback <file>` replays an MCAP flight log and `--record <file>` writes one.
+    // `--foxglove <address>` serves Foxglove clients, `--headless` runs without a window
+    // and serves Foxglove on the default address unless one is given.
+    // `--mavlink <udp:host:port|udpin:addr:port|serial:device:baud>` streams avoidance setpoints
+    // to the autopilot at `--setpoint-rate <hz>` as `--system-id <id>`.
+    // `--avoidance <repulsion|vfh>` picks the local avoidance, `--planner <apf|astar|theta|rrt>` the path planner.
+    // `--goals <file>` flies the waypoints in the file, one `<mid360|ned> x y z [radius]` per line.
+    // `--benchmark <map[,map...]>` runs every planner on saved octree maps, prints the results and exits.
+    // `--octree-benchmark <cloud[,cloud...]>` times building and querying .pcd/.ply/.las clouds with every
+    // octree backend, or only the one given by `--octree-backend <pointer|linear>`, and exits.
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|index| args.get(index + 1))
+            .cloned()
+    };
+    if let Some(maps) = arg_value("--benchmark") {
+        let maps: Vec<_> = maps
+            .split(',')
+            .map(|file_name| {
+                calculator::planner_benchmark::BenchmarkMap::load(file_name)
+                    .unwrap_or_else(|e| panic!("Failed to load map {}: {}", file_name, e))
+            })
+            .collect();
+        let report = calculator::planner_benchmark::run_benchmark(
+            &maps,
+            &calculator::planner::GlobalPlanner::ALL,
+            &calculator::planner_benchmark::BenchmarkConfig::default(),
+        );
+        print!("{}", report);
+        return;
+    }
+    if let Some(clouds) = arg_value("--octree-benchmark") {
+        let clouds: Vec<_> = clouds
+            .split(',')
+            .map(|file_name| {
+                octree::octree_benchmark::BenchmarkCloud::load(file_name)
+                    .unwrap_or_else(|e| panic!("Failed to load point cloud {}: {}", file_name, e))
+            })
+            .collect();
+        let backends = match arg_value("--octree-backend") {
+            Some(backend) => vec![octree::octree_backend::OctreeBackendKind::parse(&backend).unwrap_or_else(|e| panic!("{}", e))],
+            None => octree::octree_backend::OctreeBackendKind::ALL.to_vec(),
+        };
+        let report = octree::octree_benchmark::run_octree_benchmark(
+            &clouds,
+            &backends,
+            &octree::octree_benchmark::OctreeBenchmarkConfig::default(),
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        print!("{}", report);
+        return;
+    }
+    let source = match (arg_value("--csv"), arg_value("--playback")) {
+        (_, Some(file_name)) => PointSource::Playback(file_name),
+        (Some(file_name), None) => PointSource::Csv(file_name),
+        (None, None) => PointSource::Sensor,
+    };
+    let headless = args.iter().any(|arg| arg == "--headless");
+    let foxglove_address = arg_value("--foxglove")
+        .or_else(|| headless.then(|| visualization::foxglove_server::DEFAULT_FOXGLOVE_ADDRESS.to_string()));
+    let mavlink_endpoint = arg_value("--mavlink").map(|endpoint| {
+        mavlink::link::MavlinkEndpoint::parse(&endpoint).unwrap_or_else(|e| panic!("{}", e))
+    });
+    let mut mavlink_config = mavlink::link::MavlinkLinkConfig::default();
+    if let Some(rate) = arg_value("--setpoint-rate").and_then(|rate| rate.parse().ok()) {
+        mavlink_config.setpoint_rate_hz = rate;
+    }
+    if let Some(system_id) = arg_value("--system-id").and_then(|id| id.parse().ok()) {
+        mavlink_config.system_id = system_id;
+    }
+    let avoidance_method = arg_value("--avoidance")
+        .map(|method| calculator::crash_detector::AvoidanceMethod::parse(&method).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or_default();
+    let planner = arg_value("--planner")
+        .map(|planner| calculator::planner::GlobalPlanner::parse(&planner).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or_default();
+    let goals = arg_value("--goals")
+        .map(|file_name| calculator::goal::load_goals(&file_name).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or_default();
+    let options = RunOptions {
+        source,
+        record_file: arg_value("--record"),
+        foxglove_address,
+        headless,
+        mavlink_endpoint,
+        mavlink_config,
+        avoidance_method,
+        planner,
+        goals,
+    };
+
+    if options.source == PointSource::Sensor && (!data_reader::sensor_detect::is_imu_sensor_online() || !data_reader::sensor_detect::is_lidar_online()) {
         panic!("Sensors are not online");
     }    
 
-    visualization::rendering_components_octree::run_bevy();   
+    if let Err(e) = visualization::rendering_components_octree::run_bevy(options) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
 
 #[allow(dead_code)]
@@ -25,7 +125,20 @@ fn async_main() {
         
     });
     // 在主线程运行 Bevy
-    visualization::rendering_components_octree::run_bevy();
+    let result = visualization::rendering_components_octree::run_bevy(RunOptions {
+        source: PointSource::Sensor,
+        record_file: None,
+        foxglove_address: None,
+        headless: false,
+        mavlink_endpoint: None,
+        mavlink_config: mavlink::link::MavlinkLinkConfig::default(),
+        avoidance_method: calculator::crash_detector::AvoidanceMethod::default(),
+        planner: calculator::planner::GlobalPlanner::default(),
+        goals: Vec::new(),
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+    }
 
     // 保持 Tokio 运行时存活（注意：Bevy 可能会无限阻塞，此代码可能无法到达）
     // 通常 Bevy 会接管主线程，Tokio 任务在后台运行
@@ -0,0 +1,154 @@
+#![allow(dead_code)]
+use std::fmt;
+
+/// Start byte of a MAVLink v2 frame
+pub const MAVLINK_STX_V2: u8 = 0xFD;
+/// STX, length, incompat flags, compat flags, sequence, system, component, 3-byte message id
+pub const HEADER_LEN: usize = 10;
+pub const CHECKSUM_LEN: usize = 2;
+pub const SIGNATURE_LEN: usize = 13;
+/// Incompatibility flag for signed frames
+pub const INCOMPAT_FLAG_SIGNED: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MavlinkError {
+    /// The checksum did not match, usually line noise or a wrong CRC_EXTRA
+    BadChecksum { message_id: u32 },
+    /// No CRC_EXTRA is known for this id, so the frame cannot be checked
+    UnknownMessage(u32),
+    /// Frames with unknown incompatibility flags must be dropped
+    UnsupportedFlags(u8),
+    /// A payload did not decode into the expected message
+    InvalidPayload { message_id: u32 },
+}
+
+impl fmt::Display for MavlinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MavlinkError::BadChecksum { message_id } => write!(f, "bad checksum on message {}", message_id),
+            MavlinkError::UnknownMessage(id) => write!(f, "unknown message id {}", id),
+            MavlinkError::UnsupportedFlags(flags) => write!(f, "unsupported incompat flags {:#04x}", flags),
+            MavlinkError::InvalidPayload { message_id } => write!(f, "invalid payload for message {}", message_id),
+        }
+    }
+}
+
+/// One MAVLink v2 frame with its payload still serialized
+#[derive(Debug, Clone, PartialEq)]
+pub struct MavlinkFrame {
+    pub sequence: u8,
+    pub system_id: u8,
+    pub component_id: u8,
+    pub message_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl MavlinkFrame {
+    /// Serialize with trailing zero bytes of the payload removed, as MAVLink v2 requires
+    pub fn encode(&self, crc_extra: u8) -> Vec<u8> {
+        let mut payload_len = self.payload.len();
+        while payload_len > 1 && self.payload[payload_len - 1] == 0 {
+            payload_len -= 1;
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload_len + CHECKSUM_LEN);
+        bytes.push(MAVLINK_STX_V2);
+        bytes.push(payload_len as u8);
+        bytes.push(0); // incompat flags
+        bytes.push(0); // compat flags
+        bytes.push(self.sequence);
+        bytes.push(self.system_id);
+        bytes.push(self.component_id);
+        bytes.extend_from_slice(&self.message_id.to_le_bytes()[..3]);
+        bytes.extend_from_slice(&self.payload[..payload_len]);
+
+        let mut crc = crc_calculate(&bytes[1..]);
+        crc = crc_accumulate(crc_extra, crc);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// X.25 / MCRF4XX checksum step used by MAVLink
+pub fn crc_accumulate(byte: u8, crc: u16) -> u16 {
+    let mut tmp = byte ^ (crc & 0xFF) as u8;
+    tmp ^= tmp << 4;
+    let tmp = tmp as u16;
+    (crc >> 8) ^ (tmp << 8) ^ (tmp << 3) ^ (tmp >> 4)
+}
+
+pub fn crc_calculate(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| crc_accumulate(*byte, crc))
+}
+
+/// Splits a byte stream (serial or a sequence of UDP datagrams) into checked frames.
+/// MAVLink v1 frames and garbage between frames are skipped.
+#[derive(Debug, Default)]
+pub struct FrameParser {
+    buffer: Vec<u8>,
+}
+
+impl FrameParser {
+    pub fn new() -> Self {
+        Self { buffer: Vec::new() }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Next complete frame, `None` when more bytes are needed.
+    /// `crc_extra` returns the CRC_EXTRA of a message id, see `messages::crc_extra`.
+    pub fn next_frame(&mut self, crc_extra: impl Fn(u32) -> Option<u8>) -> Option<Result<MavlinkFrame, MavlinkError>> {
+        let start = self.buffer.iter().position(|byte| *byte == MAVLINK_STX_V2);
+        match start {
+            Some(start) => {
+                self.buffer.drain(..start);
+            }
+            None => {
+                self.buffer.clear();
+                return None;
+            }
+        }
+        if self.buffer.len() < HEADER_LEN {
+            return None;
+        }
+
+        let payload_len = self.buffer[1] as usize;
+        let incompat_flags = self.buffer[2];
+        let signature_len = if incompat_flags & INCOMPAT_FLAG_SIGNED != 0 { SIGNATURE_LEN } else { 0 };
+        let frame_len = HEADER_LEN + payload_len + CHECKSUM_LEN + signature_len;
+        if self.buffer.len() < frame_len {
+            return None;
+        }
+
+        let message_id = u32::from_le_bytes([self.buffer[7], self.buffer[8], self.buffer[9], 0]);
+        let checked_len = HEADER_LEN + payload_len;
+        let received_crc = u16::from_le_bytes([self.buffer[checked_len], self.buffer[checked_len + 1]]);
+
+        if incompat_flags & !INCOMPAT_FLAG_SIGNED != 0 {
+            self.buffer.drain(..frame_len);
+            return Some(Err(MavlinkError::UnsupportedFlags(incompat_flags)));
+        }
+        let Some(extra) = crc_extra(message_id) else {
+            self.buffer.drain(..frame_len);
+            return Some(Err(MavlinkError::UnknownMessage(message_id)));
+        };
+        let crc = crc_accumulate(extra, crc_calculate(&self.buffer[1..checked_len]));
+        if crc != received_crc {
+            // The start byte may have been noise, resync from the next byte
+            self.buffer.drain(..1);
+            return Some(Err(MavlinkError::BadChecksum { message_id }));
+        }
+
+        let frame = MavlinkFrame {
+            sequence: self.buffer[4],
+            system_id: self.buffer[5],
+            component_id: self.buffer[6],
+            message_id,
+            payload: self.buffer[HEADER_LEN..checked_len].to_vec(),
+        };
+        self.buffer.drain(..frame_len);
+        Some(Ok(frame))
+    }
+}
@@ -0,0 +1,496 @@
+#![allow(dead_code)]
+use bevy::ecs::event::{Event, EventWriter};
+use bevy::ecs::system::{Res, Resource};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::process::Command;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::calculator::mavlink_args::MavlinkArgs;
+use crate::mavlink::frame::{FrameParser, MavlinkFrame};
+use crate::mavlink::messages::{self, Heartbeat, MavlinkMessage, MAV_COMP_ID_ONBOARD_COMPUTER};
+
+/// How often the link thread polls for incoming bytes and checks what is due
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Received frames nobody collected are dropped beyond this, oldest first
+const MAX_QUEUED_FRAMES: usize = 1024;
+
+/// Byte transport under a MAVLink link
+pub trait MavlinkTransport: Send {
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()>;
+    /// Append whatever bytes have arrived without blocking
+    fn receive(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    remote: Option<SocketAddr>,
+    /// In listen mode replies go to whoever sent the last datagram
+    follow_sender: bool,
+}
+
+impl UdpTransport {
+    /// Send to a fixed address, e.g. PX4 SITL on 127.0.0.1:14540 or a mavlink-router endpoint
+    pub fn connect(remote: &str) -> std::io::Result<Self> {
+        let remote = remote
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Cannot resolve {}", remote)))?;
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, remote: Some(remote), follow_sender: false })
+    }
+
+    /// Listen on a local address and answer the last sender, like `udpin:` in MAVProxy.
+    /// Nothing is sent until the first datagram arrives.
+    pub fn listen(local: &str) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, remote: None, follow_sender: true })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl MavlinkTransport for UdpTransport {
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        match self.remote {
+            Some(remote) => self.socket.send_to(bytes, remote).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn receive(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+        let mut datagram = [0u8; 65536];
+        loop {
+            match self.socket.recv_from(&mut datagram) {
+                Ok((size, sender)) => {
+                    buffer.extend_from_slice(&datagram[..size]);
+                    if self.follow_sender {
+                        self.remote = Some(sender);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                // ICMP port unreachable from an earlier send shows up here on some systems
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+pub struct SerialTransport {
+    port: File,
+    received: Receiver<Vec<u8>>,
+}
+
+impl SerialTransport {
+    /// Open a serial device such as /dev/ttyTHS1. The line is put into raw mode at
+    /// `baud_rate` with `stty`, so this only works on Linux.
+    pub fn open(device: &str, baud_rate: u32) -> std::io::Result<Self> {
+        let status = Command::new("stty")
+            .args(["-F", device, &baud_rate.to_string(), "raw", "-echo"])
+            .status()?;
+        if !status.success() {
+            return Err(Error::other(format!("stty could not configure {}", device)));
+        }
+        let port = OpenOptions::new().read(true).write(true).open(device)?;
+
+        // Reads on a tty block, so they get their own thread
+        let mut reader = port.try_clone()?;
+        let (sender, received) = channel();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(size) => {
+                        if sender.send(buffer[..size].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        eprintln!("Serial read failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { port, received })
+    }
+}
+
+impl MavlinkTransport for SerialTransport {
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.port.write_all(bytes)
+    }
+
+    fn receive(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+        while let Ok(bytes) = self.received.try_recv() {
+            buffer.extend_from_slice(&bytes);
+        }
+        Ok(())
+    }
+}
+
+/// Where the autopilot is, parsed from `udp:<host:port>`, `udpin:<addr:port>` or `serial:<device>:<baud>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum MavlinkEndpoint {
+    Udp(String),
+    UdpIn(String),
+    Serial { device: String, baud_rate: u32 },
+}
+
+impl MavlinkEndpoint {
+    pub fn parse(endpoint: &str) -> Result<Self, String> {
+        let (kind, rest) = endpoint
+            .split_once(':')
+            .ok_or_else(|| format!("MAVLink endpoint \"{}\" has no type prefix", endpoint))?;
+        match kind {
+            "udp" => Ok(MavlinkEndpoint::Udp(rest.to_string())),
+            "udpin" => Ok(MavlinkEndpoint::UdpIn(rest.to_string())),
+            "serial" => {
+                let (device, baud_rate) = rest
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("Serial endpoint \"{}\" needs a baud rate", endpoint))?;
+                let baud_rate = baud_rate
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid baud rate \"{}\"", baud_rate))?;
+                Ok(MavlinkEndpoint::Serial { device: device.to_string(), baud_rate })
+            }
+            other => Err(format!("Unknown MAVLink endpoint type \"{}\"", other)),
+        }
+    }
+
+    pub fn open(&self) -> std::io::Result<Box<dyn MavlinkTransport>> {
+        Ok(match self {
+            MavlinkEndpoint::Udp(remote) => Box::new(UdpTransport::connect(remote)?),
+            MavlinkEndpoint::UdpIn(local) => Box::new(UdpTransport::listen(local)?),
+            MavlinkEndpoint::Serial { device, baud_rate } => Box::new(SerialTransport::open(device, *baud_rate)?),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MavlinkLinkConfig {
+    /// Our own ids. A companion computer shares the system id of its autopilot.
+    pub system_id: u8,
+    pub component_id: u8,
+    /// PX4 leaves offboard mode when setpoints arrive slower than 2 Hz
+    pub setpoint_rate_hz: f32,
+    pub heartbeat_rate_hz: f32,
+}
+
+impl Default for MavlinkLinkConfig {
+    fn default() -> Self {
+        Self {
+            system_id: 1,
+            component_id: MAV_COMP_ID_ONBOARD_COMPUTER,
+            setpoint_rate_hz: 20.0,
+            heartbeat_rate_hz: 1.0,
+        }
+    }
+}
+
+struct LinkWriter {
+    transport: Box<dyn MavlinkTransport>,
+    sequence: u8,
+    system_id: u8,
+    component_id: u8,
+}
+
+impl LinkWriter {
+    fn send<M: MavlinkMessage>(&mut self, message: &M) -> std::io::Result<()> {
+        let frame = MavlinkFrame {
+            sequence: self.sequence,
+            system_id: self.system_id,
+            component_id: self.component_id,
+            message_id: M::ID,
+            payload: message.serialize(),
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.transport.send(&frame.encode(M::CRC_EXTRA))
+    }
+}
+
+#[derive(Default)]
+struct LinkState {
+    setpoint: Option<MavlinkArgs>,
+    sent_setpoints: Vec<MavlinkArgs>,
+    /// Frames with the time they were read from the transport
+    received: Vec<(Instant, MavlinkFrame)>,
+    stopped: bool,
+}
+
+/// A MAVLink v2 link to the autopilot. A background thread sends our HEARTBEAT and streams
+/// the current setpoint at the configured rates, independent of the app frame rate.
+#[derive(Resource)]
+pub struct MavlinkLink {
+    writer: Arc<Mutex<LinkWriter>>,
+    state: Arc<Mutex<LinkState>>,
+    config: MavlinkLinkConfig,
+    boot: Instant,
+}
+
+impl MavlinkLink {
+    pub fn start(transport: Box<dyn MavlinkTransport>, config: MavlinkLinkConfig) -> Self {
+        let writer = Arc::new(Mutex::new(LinkWriter {
+            transport,
+            sequence: 0,
+            system_id: config.system_id,
+            component_id: config.component_id,
+        }));
+        let state = Arc::new(Mutex::new(LinkState::default()));
+        let boot = Instant::now();
+
+        let thread_writer = writer.clone();
+        let thread_state = state.clone();
+        thread::spawn(move || link_loop(thread_writer, thread_state, config, boot));
+
+        Self { writer, state, config, boot }
+    }
+
+    pub fn config(&self) -> MavlinkLinkConfig {
+        self.config
+    }
+
+    /// Milliseconds since the link started, used as `time_boot_ms` of our messages
+    pub fn time_boot_ms(&self) -> u32 {
+        self.boot.elapsed().as_millis() as u32
+    }
+
+    /// Setpoint to stream from now on, `None` stops streaming. An invalid setpoint also
+    /// stops streaming, so the autopilot falls back to its own failsafe instead of
+    /// following the last valid command.
+    pub fn set_setpoint(&self, setpoint: Option<MavlinkArgs>) -> Result<(), String> {
+        let validated = setpoint.map(|args| args.validate()).transpose();
+        self.state.lock().unwrap().setpoint = if validated.is_ok() { setpoint } else { None };
+        validated.map(|_| ())
+    }
+
+    /// Send one message right away, outside the setpoint stream
+    pub fn send<M: MavlinkMessage>(&self, message: &M) -> std::io::Result<()> {
+        self.writer.lock().unwrap().send(message)
+    }
+
+    /// Setpoints sent since the last call, with the `time_boot_ms` they went out with
+    pub fn take_sent_setpoints(&self) -> Vec<MavlinkArgs> {
+        std::mem::take(&mut self.state.lock().unwrap().sent_setpoints)
+    }
+
+    /// Frames received since the last call that passed the checksum
+    pub fn take_received(&self) -> Vec<MavlinkFrame> {
+        self.take_received_with_time().into_iter().map(|(_, frame)| frame).collect()
+    }
+
+    /// Like `take_received`, with the time each frame was read from the transport
+    pub fn take_received_with_time(&self) -> Vec<(Instant, MavlinkFrame)> {
+        std::mem::take(&mut self.state.lock().unwrap().received)
+    }
+}
+
+/// A frame the link received, as an event so every system that reads MAVLink sees all of them
+#[derive(Event, Debug, Clone)]
+pub struct MavlinkReceived {
+    pub received: Instant,
+    pub frame: MavlinkFrame,
+}
+
+/// Drain the frames the link received into `MavlinkReceived` events
+pub fn mavlink_receive_system(link: Option<Res<MavlinkLink>>, mut events: EventWriter<MavlinkReceived>) {
+    let Some(link) = link else {
+        return;
+    };
+    events.send_batch(
+        link.take_received_with_time()
+            .into_iter()
+            .map(|(received, frame)| MavlinkReceived { received, frame }),
+    );
+}
+
+impl Drop for MavlinkLink {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().stopped = true;
+    }
+}
+
+fn link_loop(writer: Arc<Mutex<LinkWriter>>, state: Arc<Mutex<LinkState>>, config: MavlinkLinkConfig, boot: Instant) {
+    let period = |rate_hz: f32| Duration::from_secs_f32(1.0 / rate_hz.max(0.1));
+    let setpoint_period = period(config.setpoint_rate_hz);
+    let heartbeat_period = period(config.heartbeat_rate_hz);
+    let mut last_setpoint: Option<Instant> = None;
+    let mut last_heartbeat: Option<Instant> = None;
+    let mut parser = FrameParser::new();
+    let mut bytes = Vec::new();
+
+    loop {
+        let setpoint = {
+            let state = state.lock().unwrap();
+            if state.stopped {
+                return;
+            }
+            state.setpoint
+        };
+
+        bytes.clear();
+        let mut writer = writer.lock().unwrap();
+        if let Err(e) = writer.transport.receive(&mut bytes) {
+            eprintln!("MAVLink receive failed: {}", e);
+        }
+
+        let now = Instant::now();
+        let due = |last: Option<Instant>, period: Duration| last.is_none_or(|last| now.duration_since(last) >= period);
+        if due(last_heartbeat, heartbeat_period) {
+            last_heartbeat = Some(now);
+            if let Err(e) = writer.send(&Heartbeat::onboard_controller()) {
+                eprintln!("MAVLink heartbeat failed: {}", e);
+            }
+        }
+        if let Some(mut setpoint) = setpoint {
+            if due(last_setpoint, setpoint_period) {
+                last_setpoint = Some(now);
+                setpoint.time_boot_ms = now.duration_since(boot).as_millis() as u32;
+                // Locked across the send, so a setpoint that arrived is already in `sent_setpoints`
+                let mut recorded = state.lock().unwrap();
+                match writer.send(&setpoint) {
+                    Ok(()) => recorded.sent_setpoints.push(setpoint),
+                    Err(e) => eprintln!("MAVLink setpoint failed: {}", e),
+                }
+            }
+        } else {
+            last_setpoint = None;
+        }
+        drop(writer);
+
+        parser.push(&bytes);
+        let mut state = state.lock().unwrap();
+        while let Some(result) = parser.next_frame(messages::crc_extra) {
+            // Unknown messages and line noise are expected on a shared link
+            if let Ok(frame) = result {
+                state.received.push((now, frame));
+            }
+        }
+        let overflow = state.received.len().saturating_sub(MAX_QUEUED_FRAMES);
+        state.received.drain(..overflow);
+        drop(state);
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::mavlink_args::MavFrame;
+    use crate::mavlink::frame::MavlinkError;
+    use crate::mavlink::mock_autopilot::MockAutopilot;
+
+    fn setpoint(vx: f32) -> MavlinkArgs {
+        MavlinkArgs::velocity_setpoint(MavFrame::LocalNed, vx, 0.5, -0.25)
+    }
+
+    #[test]
+    fn frame_round_trip_truncates_trailing_zeros() {
+        let heartbeat = Heartbeat { custom_mode: 0, mav_type: 2, autopilot: 12, base_mode: 0, system_status: 0, mavlink_version: 0 };
+        let frame = MavlinkFrame {
+            sequence: 7,
+            system_id: 1,
+            component_id: 1,
+            message_id: Heartbeat::ID,
+            payload: heartbeat.serialize(),
+        };
+        let bytes = frame.encode(Heartbeat::CRC_EXTRA);
+        // Payload ends after the autopilot byte
+        assert_eq!(bytes[1], 6);
+
+        let mut parser = FrameParser::new();
+        parser.push(&[0x00, 0x13]);
+        parser.push(&bytes);
+        let decoded = parser.next_frame(messages::crc_extra).unwrap().unwrap();
+        assert_eq!(decoded.sequence, 7);
+        assert_eq!(Heartbeat::deserialize(&decoded.payload).unwrap(), heartbeat);
+        assert!(parser.next_frame(messages::crc_extra).is_none());
+    }
+
+    #[test]
+    fn corrupted_frame_fails_checksum() {
+        let frame = MavlinkFrame {
+            sequence: 0,
+            system_id: 1,
+            component_id: 191,
+            message_id: MavlinkArgs::ID,
+            payload: setpoint(1.0).serialize(),
+        };
+        let mut bytes = frame.encode(MavlinkArgs::CRC_EXTRA);
+        assert_eq!(setpoint(1.0).serialize().len(), MavlinkArgs::LEN);
+        bytes[20] ^= 0x40;
+
+        let mut parser = FrameParser::new();
+        parser.push(&bytes);
+        assert_eq!(
+            parser.next_frame(messages::crc_extra),
+            Some(Err(MavlinkError::BadChecksum { message_id: MavlinkArgs::ID }))
+        );
+    }
+
+    #[test]
+    fn link_streams_heartbeat_and_setpoints_to_autopilot() {
+        let mut autopilot = MockAutopilot::bind("127.0.0.1:0").unwrap();
+        let transport = UdpTransport::connect(&autopilot.local_addr().to_string()).unwrap();
+        let config = MavlinkLinkConfig { setpoint_rate_hz: 50.0, ..MavlinkLinkConfig::default() };
+        let link = MavlinkLink::start(Box::new(transport), config);
+        link.set_setpoint(Some(setpoint(1.5))).unwrap();
+
+        assert!(autopilot.wait_for(|autopilot| autopilot.messages::<MavlinkArgs>().len() >= 5, Duration::from_secs(2)));
+        link.set_setpoint(None).unwrap();
+
+        let heartbeats = autopilot.messages::<Heartbeat>();
+        assert!(!heartbeats.is_empty());
+        assert_eq!(heartbeats[0].mav_type, messages::MAV_TYPE_ONBOARD_CONTROLLER);
+
+        let setpoints = autopilot.messages::<MavlinkArgs>();
+        assert!(setpoints.iter().all(|args| args.vx == 1.5 && args.vz == -0.25 && args.type_mask == setpoint(1.5).type_mask));
+        assert!(setpoints.windows(2).all(|pair| pair[1].time_boot_ms >= pair[0].time_boot_ms));
+
+        // One sequence counter across all messages from this component
+        let frames = autopilot.frames();
+        assert!(frames.iter().all(|frame| frame.system_id == 1 && frame.component_id == MAV_COMP_ID_ONBOARD_COMPUTER));
+        for pair in frames.windows(2) {
+            assert_eq!(pair[1].sequence, pair[0].sequence.wrapping_add(1));
+        }
+        assert!(link.take_sent_setpoints().len() >= setpoints.len());
+    }
+
+    #[test]
+    fn listening_link_answers_the_autopilot() {
+        let transport = UdpTransport::listen("127.0.0.1:0").unwrap();
+        let address = transport.local_addr().unwrap();
+        let link = MavlinkLink::start(Box::new(transport), MavlinkLinkConfig::default());
+
+        let mut autopilot = MockAutopilot::bind("127.0.0.1:0").unwrap();
+        autopilot.connect(address);
+        let heartbeat = Heartbeat { custom_mode: 6 << 16, mav_type: 2, autopilot: 12, base_mode: 129, system_status: 4, mavlink_version: 3 };
+        autopilot.send(&heartbeat).unwrap();
+
+        let start = Instant::now();
+        let mut received = Vec::new();
+        while received.is_empty() && start.elapsed() < Duration::from_secs(2) {
+            received = link.take_received();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].system_id, 1);
+        assert_eq!(Heartbeat::deserialize(&received[0].payload).unwrap(), heartbeat);
+
+        // The link only learns the autopilot address from that first datagram
+        assert!(autopilot.wait_for(|autopilot| !autopilot.messages::<Heartbeat>().is_empty(), Duration::from_secs(3)));
+    }
+}
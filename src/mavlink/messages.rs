@@ -0,0 +1,768 @@
+#![allow(dead_code)]
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{Cursor, Read};
+
+use crate::calculator::mavlink_args::{MavFrame, MavlinkArgs};
+use crate::mavlink::frame::MavlinkError;
+
+/// MAV_TYPE_ONBOARD_CONTROLLER
+pub const MAV_TYPE_ONBOARD_CONTROLLER: u8 = 18;
+/// MAV_AUTOPILOT_INVALID, used by components that are not flight controllers
+pub const MAV_AUTOPILOT_INVALID: u8 = 8;
+/// MAV_STATE_ACTIVE
+pub const MAV_STATE_ACTIVE: u8 = 4;
+/// MAV_COMP_ID_ONBOARD_COMPUTER
+pub const MAV_COMP_ID_ONBOARD_COMPUTER: u8 = 191;
+/// MAV_COMP_ID_AUTOPILOT1
+pub const MAV_COMP_ID_AUTOPILOT1: u8 = 1;
+pub const MAVLINK_VERSION: u8 = 3;
+/// MAV_AUTOPILOT_ARDUPILOTMEGA
+pub const MAV_AUTOPILOT_ARDUPILOTMEGA: u8 = 3;
+/// MAV_AUTOPILOT_PX4
+pub const MAV_AUTOPILOT_PX4: u8 = 12;
+/// MAV_MODE_FLAG_CUSTOM_MODE_ENABLED
+pub const MAV_MODE_FLAG_CUSTOM_MODE_ENABLED: u8 = 1;
+/// MAV_MODE_FLAG_SAFETY_ARMED
+pub const MAV_MODE_FLAG_SAFETY_ARMED: u8 = 128;
+/// MAV_TYPE values that ArduPilot runs as Plane or Rover rather than Copter
+pub const MAV_TYPE_FIXED_WING: u8 = 1;
+pub const MAV_TYPE_GROUND_ROVER: u8 = 10;
+pub const MAV_TYPE_SURFACE_BOAT: u8 = 11;
+/// MAV_FRAME_LOCAL_NED
+pub const MAV_FRAME_LOCAL_NED: u8 = MavFrame::LocalNed as u8;
+/// MAV_FRAME_BODY_FRD
+pub const MAV_FRAME_BODY_FRD: u8 = MavFrame::BodyFrd as u8;
+/// MAV_DISTANCE_SENSOR_LASER
+pub const MAV_DISTANCE_SENSOR_LASER: u8 = 0;
+/// MAV_CMD_NAV_WAYPOINT
+pub const MAV_CMD_NAV_WAYPOINT: u16 = 16;
+/// MAV_MISSION_TYPE_MISSION
+pub const MAV_MISSION_TYPE_MISSION: u8 = 0;
+/// MAV_MISSION_RESULT values
+pub const MAV_MISSION_ACCEPTED: u8 = 0;
+pub const MAV_MISSION_ERROR: u8 = 1;
+pub const MAV_MISSION_UNSUPPORTED_FRAME: u8 = 2;
+pub const MAV_MISSION_UNSUPPORTED: u8 = 3;
+pub const MAV_MISSION_INVALID_SEQUENCE: u8 = 13;
+
+/// A MAVLink message with a fixed wire layout. `serialize` writes the full payload in
+/// wire order (fields sorted by size), `deserialize` accepts payloads with trailing zeros cut off.
+pub trait MavlinkMessage: Sized {
+    const ID: u32;
+    const CRC_EXTRA: u8;
+    const LEN: usize;
+
+    fn serialize(&self) -> Vec<u8>;
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError>;
+}
+
+/// CRC_EXTRA of every message this crate can check
+pub fn crc_extra(message_id: u32) -> Option<u8> {
+    match message_id {
+        Heartbeat::ID => Some(Heartbeat::CRC_EXTRA),
+        MavlinkArgs::ID => Some(MavlinkArgs::CRC_EXTRA),
+        Attitude::ID => Some(Attitude::CRC_EXTRA),
+        LocalPositionNed::ID => Some(LocalPositionNed::CRC_EXTRA),
+        Odometry::ID => Some(Odometry::CRC_EXTRA),
+        ExtendedSysState::ID => Some(ExtendedSysState::CRC_EXTRA),
+        ObstacleDistance::ID => Some(ObstacleDistance::CRC_EXTRA),
+        MissionCount::ID => Some(MissionCount::CRC_EXTRA),
+        MissionClearAll::ID => Some(MissionClearAll::CRC_EXTRA),
+        MissionAck::ID => Some(MissionAck::CRC_EXTRA),
+        MissionRequestInt::ID => Some(MissionRequestInt::CRC_EXTRA),
+        MissionItemInt::ID => Some(MissionItemInt::CRC_EXTRA),
+        _ => None,
+    }
+}
+
+/// Restore the zeros MAVLink v2 truncated from the end of a payload. Longer payloads
+/// carry extension fields we do not know and are accepted as they are.
+fn full_payload<M: MavlinkMessage>(payload: &[u8]) -> Result<Vec<u8>, MavlinkError> {
+    if payload.is_empty() {
+        return Err(MavlinkError::InvalidPayload { message_id: M::ID });
+    }
+    let mut bytes = payload.to_vec();
+    if bytes.len() < M::LEN {
+        bytes.resize(M::LEN, 0);
+    }
+    Ok(bytes)
+}
+
+/// HEARTBEAT (#0)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    pub custom_mode: u32,
+    pub mav_type: u8,
+    pub autopilot: u8,
+    pub base_mode: u8,
+    pub system_status: u8,
+    pub mavlink_version: u8,
+}
+
+impl Heartbeat {
+    /// Heartbeat of this companion computer
+    pub fn onboard_controller() -> Self {
+        Self {
+            custom_mode: 0,
+            mav_type: MAV_TYPE_ONBOARD_CONTROLLER,
+            autopilot: MAV_AUTOPILOT_INVALID,
+            base_mode: 0,
+            system_status: MAV_STATE_ACTIVE,
+            mavlink_version: MAVLINK_VERSION,
+        }
+    }
+}
+
+impl MavlinkMessage for Heartbeat {
+    const ID: u32 = 0;
+    const CRC_EXTRA: u8 = 50;
+    const LEN: usize = 9;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.custom_mode.to_le_bytes());
+        payload.push(self.mav_type);
+        payload.push(self.autopilot);
+        payload.push(self.base_mode);
+        payload.push(self.system_status);
+        payload.push(self.mavlink_version);
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        let mut cursor = Cursor::new(&payload[..]);
+        let invalid = |_| MavlinkError::InvalidPayload { message_id: Self::ID };
+        Ok(Self {
+            custom_mode: cursor.read_u32::<LittleEndian>().map_err(invalid)?,
+            mav_type: cursor.read_u8().map_err(invalid)?,
+            autopilot: cursor.read_u8().map_err(invalid)?,
+            base_mode: cursor.read_u8().map_err(invalid)?,
+            system_status: cursor.read_u8().map_err(invalid)?,
+            mavlink_version: cursor.read_u8().map_err(invalid)?,
+        })
+    }
+}
+
+/// SET_POSITION_TARGET_LOCAL_NED (#84), whose fields `MavlinkArgs` mirrors
+impl MavlinkMessage for MavlinkArgs {
+    const ID: u32 = 84;
+    const CRC_EXTRA: u8 = 143;
+    const LEN: usize = 53;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.time_boot_ms.to_le_bytes());
+        for value in [
+            self.x, self.y, self.z, self.vx, self.vy, self.vz, self.afx, self.afy, self.afz, self.yaw, self.yaw_rate,
+        ] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload.extend_from_slice(&self.type_mask.to_le_bytes());
+        payload.push(self.target_system);
+        payload.push(self.target_component);
+        payload.push(self.coordinate_frame);
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        let mut cursor = Cursor::new(&payload[..]);
+        let invalid = |_| MavlinkError::InvalidPayload { message_id: Self::ID };
+        let time_boot_ms = cursor.read_u32::<LittleEndian>().map_err(invalid)?;
+        let mut values = [0.0f32; 11];
+        for value in values.iter_mut() {
+            *value = cursor.read_f32::<LittleEndian>().map_err(invalid)?;
+        }
+        let type_mask = cursor.read_u16::<LittleEndian>().map_err(invalid)?;
+        let target_system = cursor.read_u8().map_err(invalid)?;
+        let target_component = cursor.read_u8().map_err(invalid)?;
+        let coordinate_frame = cursor.read_u8().map_err(invalid)?;
+        Ok(MavlinkArgs::new(
+            time_boot_ms,
+            target_system,
+            target_component,
+            coordinate_frame,
+            type_mask,
+            values[0],
+            values[1],
+            values[2],
+            values[3],
+            values[4],
+            values[5],
+            values[6],
+            values[7],
+            values[8],
+            values[9],
+            values[10],
+        ))
+    }
+}
+
+/// ATTITUDE (#30), angles in rad and rates in rad/s, FRD body relative to NED
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Attitude {
+    pub time_boot_ms: u32,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub rollspeed: f32,
+    pub pitchspeed: f32,
+    pub yawspeed: f32,
+}
+
+impl MavlinkMessage for Attitude {
+    const ID: u32 = 30;
+    const CRC_EXTRA: u8 = 39;
+    const LEN: usize = 28;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.time_boot_ms.to_le_bytes());
+        for value in [self.roll, self.pitch, self.yaw, self.rollspeed, self.pitchspeed, self.yawspeed] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        let mut cursor = Cursor::new(&payload[..]);
+        let invalid = |_| MavlinkError::InvalidPayload { message_id: Self::ID };
+        let time_boot_ms = cursor.read_u32::<LittleEndian>().map_err(invalid)?;
+        let mut values = [0.0f32; 6];
+        cursor.read_f32_into::<LittleEndian>(&mut values).map_err(invalid)?;
+        Ok(Self {
+            time_boot_ms,
+            roll: values[0],
+            pitch: values[1],
+            yaw: values[2],
+            rollspeed: values[3],
+            pitchspeed: values[4],
+            yawspeed: values[5],
+        })
+    }
+}
+
+/// LOCAL_POSITION_NED (#32), metres and m/s in the autopilot's local NED frame
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LocalPositionNed {
+    pub time_boot_ms: u32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub vx: f32,
+    pub vy: f32,
+    pub vz: f32,
+}
+
+impl MavlinkMessage for LocalPositionNed {
+    const ID: u32 = 32;
+    const CRC_EXTRA: u8 = 185;
+    const LEN: usize = 28;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.time_boot_ms.to_le_bytes());
+        for value in [self.x, self.y, self.z, self.vx, self.vy, self.vz] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        let mut cursor = Cursor::new(&payload[..]);
+        let invalid = |_| MavlinkError::InvalidPayload { message_id: Self::ID };
+        let time_boot_ms = cursor.read_u32::<LittleEndian>().map_err(invalid)?;
+        let mut values = [0.0f32; 6];
+        cursor.read_f32_into::<LittleEndian>(&mut values).map_err(invalid)?;
+        Ok(Self {
+            time_boot_ms,
+            x: values[0],
+            y: values[1],
+            z: values[2],
+            vx: values[3],
+            vy: values[4],
+            vz: values[5],
+        })
+    }
+}
+
+/// ODOMETRY (#331). `q` is w, x, y, z. Velocities are given in `child_frame_id`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Odometry {
+    pub time_usec: u64,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub q: [f32; 4],
+    pub vx: f32,
+    pub vy: f32,
+    pub vz: f32,
+    pub rollspeed: f32,
+    pub pitchspeed: f32,
+    pub yawspeed: f32,
+    pub pose_covariance: [f32; 21],
+    pub velocity_covariance: [f32; 21],
+    pub frame_id: u8,
+    pub child_frame_id: u8,
+    // Extensions
+    pub reset_counter: u8,
+    pub estimator_type: u8,
+    pub quality: i8,
+}
+
+impl MavlinkMessage for Odometry {
+    const ID: u32 = 331;
+    const CRC_EXTRA: u8 = 91;
+    /// 230 bytes of base fields plus 3 bytes of extensions
+    const LEN: usize = 233;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.time_usec.to_le_bytes());
+        for value in [self.x, self.y, self.z] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in self.q {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [self.vx, self.vy, self.vz, self.rollspeed, self.pitchspeed, self.yawspeed] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in self.pose_covariance.iter().chain(self.velocity_covariance.iter()) {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload.push(self.frame_id);
+        payload.push(self.child_frame_id);
+        payload.push(self.reset_counter);
+        payload.push(self.estimator_type);
+        payload.push(self.quality as u8);
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        let mut cursor = Cursor::new(&payload[..]);
+        let invalid = |_| MavlinkError::InvalidPayload { message_id: Self::ID };
+        let time_usec = cursor.read_u64::<LittleEndian>().map_err(invalid)?;
+        let mut values = [0.0f32; 13];
+        cursor.read_f32_into::<LittleEndian>(&mut values).map_err(invalid)?;
+        let mut pose_covariance = [0.0f32; 21];
+        cursor.read_f32_into::<LittleEndian>(&mut pose_covariance).map_err(invalid)?;
+        let mut velocity_covariance = [0.0f32; 21];
+        cursor.read_f32_into::<LittleEndian>(&mut velocity_covariance).map_err(invalid)?;
+        let mut tail = [0u8; 5];
+        cursor.read_exact(&mut tail).map_err(invalid)?;
+        Ok(Self {
+            time_usec,
+            x: values[0],
+            y: values[1],
+            z: values[2],
+            q: [values[3], values[4], values[5], values[6]],
+            vx: values[7],
+            vy: values[8],
+            vz: values[9],
+            rollspeed: values[10],
+            pitchspeed: values[11],
+            yawspeed: values[12],
+            pose_covariance,
+            velocity_covariance,
+            frame_id: tail[0],
+            child_frame_id: tail[1],
+            reset_counter: tail[2],
+            estimator_type: tail[3],
+            quality: tail[4] as i8,
+        })
+    }
+}
+
+/// MAV_LANDED_STATE
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LandedState {
+    #[default]
+    Undefined,
+    OnGround,
+    InAir,
+    Takeoff,
+    Landing,
+}
+
+impl LandedState {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => LandedState::OnGround,
+            2 => LandedState::InAir,
+            3 => LandedState::Takeoff,
+            4 => LandedState::Landing,
+            _ => LandedState::Undefined,
+        }
+    }
+}
+
+/// EXTENDED_SYS_STATE (#245)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ExtendedSysState {
+    pub vtol_state: u8,
+    pub landed_state: u8,
+}
+
+impl MavlinkMessage for ExtendedSysState {
+    const ID: u32 = 245;
+    const CRC_EXTRA: u8 = 130;
+    const LEN: usize = 2;
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.vtol_state, self.landed_state]
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        Ok(Self { vtol_state: payload[0], landed_state: payload[1] })
+    }
+}
+
+/// OBSTACLE_DISTANCE (#330). `distances` in cm, `distances[i]` lies at
+/// `angle_offset + i * increment_f` degrees, clockwise from the front of `frame`.
+/// `u16::MAX` marks a sector as unknown, `max_distance + 1` as free.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObstacleDistance {
+    pub time_usec: u64,
+    pub distances: [u16; 72],
+    pub min_distance: u16,
+    pub max_distance: u16,
+    pub sensor_type: u8,
+    pub increment: u8,
+    // Extensions
+    pub increment_f: f32,
+    pub angle_offset: f32,
+    pub frame: u8,
+}
+
+impl MavlinkMessage for ObstacleDistance {
+    const ID: u32 = 330;
+    const CRC_EXTRA: u8 = 23;
+    /// 158 bytes of base fields plus 9 bytes of extensions
+    const LEN: usize = 167;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.time_usec.to_le_bytes());
+        for distance in self.distances {
+            payload.extend_from_slice(&distance.to_le_bytes());
+        }
+        payload.extend_from_slice(&self.min_distance.to_le_bytes());
+        payload.extend_from_slice(&self.max_distance.to_le_bytes());
+        payload.push(self.sensor_type);
+        payload.push(self.increment);
+        payload.extend_from_slice(&self.increment_f.to_le_bytes());
+        payload.extend_from_slice(&self.angle_offset.to_le_bytes());
+        payload.push(self.frame);
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        let mut cursor = Cursor::new(&payload[..]);
+        let invalid = |_| MavlinkError::InvalidPayload { message_id: Self::ID };
+        let time_usec = cursor.read_u64::<LittleEndian>().map_err(invalid)?;
+        let mut distances = [0u16; 72];
+        cursor.read_u16_into::<LittleEndian>(&mut distances).map_err(invalid)?;
+        Ok(Self {
+            time_usec,
+            distances,
+            min_distance: cursor.read_u16::<LittleEndian>().map_err(invalid)?,
+            max_distance: cursor.read_u16::<LittleEndian>().map_err(invalid)?,
+            sensor_type: cursor.read_u8().map_err(invalid)?,
+            increment: cursor.read_u8().map_err(invalid)?,
+            increment_f: cursor.read_f32::<LittleEndian>().map_err(invalid)?,
+            angle_offset: cursor.read_f32::<LittleEndian>().map_err(invalid)?,
+            frame: cursor.read_u8().map_err(invalid)?,
+        })
+    }
+}
+
+/// MISSION_COUNT (#44), starts a mission upload of `count` items
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MissionCount {
+    pub count: u16,
+    pub target_system: u8,
+    pub target_component: u8,
+    // Extensions
+    pub mission_type: u8,
+}
+
+impl MavlinkMessage for MissionCount {
+    const ID: u32 = 44;
+    const CRC_EXTRA: u8 = 221;
+    const LEN: usize = 5;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.count.to_le_bytes());
+        payload.extend([self.target_system, self.target_component, self.mission_type]);
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        Ok(Self {
+            count: u16::from_le_bytes([payload[0], payload[1]]),
+            target_system: payload[2],
+            target_component: payload[3],
+            mission_type: payload[4],
+        })
+    }
+}
+
+/// MISSION_CLEAR_ALL (#45)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MissionClearAll {
+    pub target_system: u8,
+    pub target_component: u8,
+    // Extensions
+    pub mission_type: u8,
+}
+
+impl MavlinkMessage for MissionClearAll {
+    const ID: u32 = 45;
+    const CRC_EXTRA: u8 = 232;
+    const LEN: usize = 3;
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.target_system, self.target_component, self.mission_type]
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        Ok(Self { target_system: payload[0], target_component: payload[1], mission_type: payload[2] })
+    }
+}
+
+/// MISSION_ACK (#47), `mission_result` is a MAV_MISSION_RESULT
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MissionAck {
+    pub target_system: u8,
+    pub target_component: u8,
+    pub mission_result: u8,
+    // Extensions
+    pub mission_type: u8,
+}
+
+impl MavlinkMessage for MissionAck {
+    const ID: u32 = 47;
+    const CRC_EXTRA: u8 = 153;
+    const LEN: usize = 4;
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.target_system, self.target_component, self.mission_result, self.mission_type]
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        Ok(Self {
+            target_system: payload[0],
+            target_component: payload[1],
+            mission_result: payload[2],
+            mission_type: payload[3],
+        })
+    }
+}
+
+/// MISSION_REQUEST_INT (#51), asks the sender of a mission for item `seq`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MissionRequestInt {
+    pub seq: u16,
+    pub target_system: u8,
+    pub target_component: u8,
+    // Extensions
+    pub mission_type: u8,
+}
+
+impl MavlinkMessage for MissionRequestInt {
+    const ID: u32 = 51;
+    const CRC_EXTRA: u8 = 196;
+    const LEN: usize = 5;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        payload.extend_from_slice(&self.seq.to_le_bytes());
+        payload.extend([self.target_system, self.target_component, self.mission_type]);
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        Ok(Self {
+            seq: u16::from_le_bytes([payload[0], payload[1]]),
+            target_system: payload[2],
+            target_component: payload[3],
+            mission_type: payload[4],
+        })
+    }
+}
+
+/// MISSION_ITEM_INT (#73). In local frames `x` and `y` are metres * 1e4 and `z` is metres.
+/// `current` 2 asks to fly to the item right away instead of storing it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MissionItemInt {
+    pub param1: f32,
+    pub param2: f32,
+    pub param3: f32,
+    pub param4: f32,
+    pub x: i32,
+    pub y: i32,
+    pub z: f32,
+    pub seq: u16,
+    pub command: u16,
+    pub target_system: u8,
+    pub target_component: u8,
+    pub frame: u8,
+    pub current: u8,
+    pub autocontinue: u8,
+    // Extensions
+    pub mission_type: u8,
+}
+
+impl MavlinkMessage for MissionItemInt {
+    const ID: u32 = 73;
+    const CRC_EXTRA: u8 = 38;
+    const LEN: usize = 38;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+        for value in [self.param1, self.param2, self.param3, self.param4] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload.extend_from_slice(&self.x.to_le_bytes());
+        payload.extend_from_slice(&self.y.to_le_bytes());
+        payload.extend_from_slice(&self.z.to_le_bytes());
+        payload.extend_from_slice(&self.seq.to_le_bytes());
+        payload.extend_from_slice(&self.command.to_le_bytes());
+        payload.extend([
+            self.target_system,
+            self.target_component,
+            self.frame,
+            self.current,
+            self.autocontinue,
+            self.mission_type,
+        ]);
+        payload
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, MavlinkError> {
+        let payload = full_payload::<Self>(payload)?;
+        let mut cursor = Cursor::new(&payload[..]);
+        let invalid = |_| MavlinkError::InvalidPayload { message_id: Self::ID };
+        let mut params = [0.0f32; 4];
+        cursor.read_f32_into::<LittleEndian>(&mut params).map_err(invalid)?;
+        Ok(Self {
+            param1: params[0],
+            param2: params[1],
+            param3: params[2],
+            param4: params[3],
+            x: cursor.read_i32::<LittleEndian>().map_err(invalid)?,
+            y: cursor.read_i32::<LittleEndian>().map_err(invalid)?,
+            z: cursor.read_f32::<LittleEndian>().map_err(invalid)?,
+            seq: cursor.read_u16::<LittleEndian>().map_err(invalid)?,
+            command: cursor.read_u16::<LittleEndian>().map_err(invalid)?,
+            target_system: cursor.read_u8().map_err(invalid)?,
+            target_component: cursor.read_u8().map_err(invalid)?,
+            frame: cursor.read_u8().map_err(invalid)?,
+            current: cursor.read_u8().map_err(invalid)?,
+            autocontinue: cursor.read_u8().map_err(invalid)?,
+            mission_type: cursor.read_u8().map_err(invalid)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serialize to the full wire length, then read back both the full payload and the
+    /// payload with its trailing zeros cut off, as MAVLink v2 sends it
+    fn assert_round_trip<M: MavlinkMessage + PartialEq + std::fmt::Debug>(message: M) {
+        let payload = message.serialize();
+        assert_eq!(payload.len(), M::LEN, "message {}", M::ID);
+        assert_eq!(M::deserialize(&payload).unwrap(), message);
+
+        let end = payload.iter().rposition(|byte| *byte != 0).map_or(1, |last| last + 1);
+        assert_eq!(M::deserialize(&payload[..end]).unwrap(), message);
+        assert!(M::deserialize(&[]).is_err());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        assert_round_trip(Heartbeat { custom_mode: 0x0604_0000, base_mode: 0x81, ..Heartbeat::onboard_controller() });
+        assert_round_trip(Attitude {
+            time_boot_ms: 1234,
+            roll: 0.1,
+            pitch: -0.2,
+            yaw: 3.0,
+            rollspeed: 0.01,
+            pitchspeed: 0.02,
+            yawspeed: 0.0,
+        });
+        assert_round_trip(LocalPositionNed { time_boot_ms: 99, x: 1.0, y: -2.0, z: -3.5, vx: 0.5, vy: 0.0, vz: 0.0 });
+        assert_round_trip(Odometry {
+            time_usec: 1_000_000,
+            x: 1.0,
+            y: 2.0,
+            z: -3.0,
+            q: [1.0, 0.0, 0.0, 0.0],
+            vx: 0.1,
+            vy: 0.2,
+            vz: 0.3,
+            rollspeed: 0.0,
+            pitchspeed: 0.0,
+            yawspeed: 0.5,
+            pose_covariance: [0.25; 21],
+            velocity_covariance: [0.5; 21],
+            frame_id: 1,
+            child_frame_id: 12,
+            reset_counter: 2,
+            estimator_type: 0,
+            quality: -1,
+        });
+        assert_round_trip(ExtendedSysState { vtol_state: 0, landed_state: 2 });
+        assert_round_trip(MissionCount { count: 300, target_system: 1, target_component: 191, mission_type: 0 });
+        assert_round_trip(MissionClearAll { target_system: 1, target_component: 1, mission_type: 0 });
+        assert_round_trip(MissionAck { target_system: 1, target_component: 1, mission_result: MAV_MISSION_INVALID_SEQUENCE, mission_type: 0 });
+        assert_round_trip(MissionRequestInt { seq: 513, target_system: 1, target_component: 1, mission_type: 0 });
+        assert_round_trip(MissionItemInt {
+            param1: 1.0,
+            x: 12_345,
+            y: -67_890,
+            z: -10.0,
+            seq: 7,
+            command: MAV_CMD_NAV_WAYPOINT,
+            target_system: 191,
+            target_component: 1,
+            frame: MAV_FRAME_LOCAL_NED,
+            autocontinue: 1,
+            ..MissionItemInt::default()
+        });
+    }
+
+    #[test]
+    fn set_position_target_round_trips() {
+        let args = MavlinkArgs::velocity_setpoint(MavFrame::BodyOffsetNed, 1.0, -0.5, 0.25).with_yaw_rate(0.3);
+        let payload = args.serialize();
+        assert_eq!(payload.len(), MavlinkArgs::LEN);
+        let read = MavlinkArgs::deserialize(&payload).unwrap();
+        assert_eq!(read.serialize(), payload);
+        assert_eq!((read.type_mask, read.coordinate_frame), (args.type_mask, args.coordinate_frame));
+        assert!(MavlinkArgs::deserialize(&[]).is_err());
+    }
+
+    #[test]
+    fn every_message_has_a_crc_extra() {
+        for id in [Heartbeat::ID, MavlinkArgs::ID, Attitude::ID, Odometry::ID, ObstacleDistance::ID, MissionItemInt::ID] {
+            assert!(crc_extra(id).is_some(), "message {}", id);
+        }
+        assert_eq!(crc_extra(MavlinkArgs::ID), Some(143));
+        assert_eq!(crc_extra(u32::MAX), None);
+    }
+}
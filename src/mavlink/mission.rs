@@ -0,0 +1,428 @@
+#![allow(dead_code)]
+use bevy::ecs::event::EventReader;
+use bevy::ecs::system::{Local, Res, ResMut};
+use std::time::{Duration, Instant};
+
+use crate::calculator::coordinate_switch::mid360_to_frd;
+use crate::calculator::goal::{GoalQueue, NavGoal};
+use crate::calculator::mavlink_args::MavFrame;
+use crate::data_reader::structor::Point3;
+use crate::mavlink::frame::MavlinkFrame;
+use crate::mavlink::link::{MavlinkLink, MavlinkReceived};
+use crate::mavlink::messages::{
+    MavlinkMessage, MissionAck, MissionClearAll, MissionCount, MissionItemInt, MissionRequestInt,
+    MAV_CMD_NAV_WAYPOINT, MAV_MISSION_ACCEPTED, MAV_MISSION_ERROR, MAV_MISSION_INVALID_SEQUENCE,
+    MAV_MISSION_TYPE_MISSION, MAV_MISSION_UNSUPPORTED, MAV_MISSION_UNSUPPORTED_FRAME,
+};
+
+/// An item that did not arrive within this time is requested again
+pub const ITEM_TIMEOUT: Duration = Duration::from_millis(1500);
+/// Requests for one item before the upload is given up
+pub const MAX_ITEM_REQUESTS: u32 = 5;
+/// `current` of a MISSION_ITEM_INT that asks to fly there right away
+const CURRENT_GUIDED: u8 = 2;
+
+/// A message for the ground station that sent the mission
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissionReply {
+    Request(MissionRequestInt),
+    Ack(MissionAck),
+}
+
+impl MissionReply {
+    pub fn send(&self, link: &MavlinkLink) -> std::io::Result<()> {
+        match self {
+            MissionReply::Request(message) => link.send(message),
+            MissionReply::Ack(message) => link.send(message),
+        }
+    }
+}
+
+/// What a ground station did to the goals
+#[derive(Debug, Clone, PartialEq)]
+pub enum MissionUpdate {
+    /// A complete mission that replaces the queue, flown from item `start` on
+    Uploaded { goals: Vec<NavGoal>, start: usize },
+    Cleared,
+    /// A single waypoint to fly to right away
+    GoTo(NavGoal),
+}
+
+#[derive(Debug, Clone)]
+struct Upload {
+    partner: (u8, u8),
+    count: u16,
+    goals: Vec<NavGoal>,
+    start: usize,
+    requested: Instant,
+    requests: u32,
+}
+
+/// Receiving side of the MAVLink mission upload protocol. Missions addressed to our
+/// system and component become waypoints, only MAV_CMD_NAV_WAYPOINT in MAV_FRAME_LOCAL_NED
+/// or MAV_FRAME_BODY_FRD is accepted.
+#[derive(Debug, Clone)]
+pub struct MissionReceiver {
+    system_id: u8,
+    component_id: u8,
+    upload: Option<Upload>,
+}
+
+impl MissionReceiver {
+    pub fn new(system_id: u8, component_id: u8) -> Self {
+        Self { system_id, component_id, upload: None }
+    }
+
+    pub fn is_uploading(&self) -> bool {
+        self.upload.is_some()
+    }
+
+    /// Handle one received frame, returns what to send back and what changed
+    pub fn handle(&mut self, frame: &MavlinkFrame, now: Instant) -> (Vec<MissionReply>, Option<MissionUpdate>) {
+        let partner = (frame.system_id, frame.component_id);
+        match frame.message_id {
+            MissionCount::ID => {
+                let Ok(message) = MissionCount::deserialize(&frame.payload) else {
+                    return (Vec::new(), None);
+                };
+                if !self.is_for_us(message.target_system, message.target_component) {
+                    return (Vec::new(), None);
+                }
+                if message.mission_type != MAV_MISSION_TYPE_MISSION {
+                    return (vec![self.ack(partner, MAV_MISSION_UNSUPPORTED, message.mission_type)], None);
+                }
+                if message.count == 0 {
+                    self.upload = None;
+                    return (vec![self.ack(partner, MAV_MISSION_ACCEPTED, message.mission_type)], Some(MissionUpdate::Cleared));
+                }
+                self.upload = Some(Upload {
+                    partner,
+                    count: message.count,
+                    goals: Vec::with_capacity(message.count as usize),
+                    start: 0,
+                    requested: now,
+                    requests: 1,
+                });
+                (vec![self.request(partner, 0)], None)
+            }
+            MissionItemInt::ID => {
+                let Ok(item) = MissionItemInt::deserialize(&frame.payload) else {
+                    return (Vec::new(), None);
+                };
+                if !self.is_for_us(item.target_system, item.target_component) || item.mission_type != MAV_MISSION_TYPE_MISSION {
+                    return (Vec::new(), None);
+                }
+                if item.current == CURRENT_GUIDED {
+                    return match mission_item_goal(&item) {
+                        Ok(goal) => (vec![self.ack(partner, MAV_MISSION_ACCEPTED, item.mission_type)], Some(MissionUpdate::GoTo(goal))),
+                        Err(result) => (vec![self.ack(partner, result, item.mission_type)], None),
+                    };
+                }
+                self.handle_item(partner, &item, now)
+            }
+            MissionClearAll::ID => {
+                let Ok(message) = MissionClearAll::deserialize(&frame.payload) else {
+                    return (Vec::new(), None);
+                };
+                if !self.is_for_us(message.target_system, message.target_component) {
+                    return (Vec::new(), None);
+                }
+                if message.mission_type != MAV_MISSION_TYPE_MISSION {
+                    return (vec![self.ack(partner, MAV_MISSION_UNSUPPORTED, message.mission_type)], None);
+                }
+                self.upload = None;
+                (vec![self.ack(partner, MAV_MISSION_ACCEPTED, message.mission_type)], Some(MissionUpdate::Cleared))
+            }
+            _ => (Vec::new(), None),
+        }
+    }
+
+    /// Ask again for an item that is overdue. The upload fails after `MAX_ITEM_REQUESTS`.
+    pub fn poll(&mut self, now: Instant) -> Vec<MissionReply> {
+        let Some(upload) = self.upload.as_mut() else {
+            return Vec::new();
+        };
+        if now.saturating_duration_since(upload.requested) < ITEM_TIMEOUT {
+            return Vec::new();
+        }
+        let partner = upload.partner;
+        if upload.requests >= MAX_ITEM_REQUESTS {
+            self.upload = None;
+            return vec![self.ack(partner, MAV_MISSION_ERROR, MAV_MISSION_TYPE_MISSION)];
+        }
+        upload.requested = now;
+        upload.requests += 1;
+        let seq = upload.goals.len() as u16;
+        vec![self.request(partner, seq)]
+    }
+
+    fn handle_item(&mut self, partner: (u8, u8), item: &MissionItemInt, now: Instant) -> (Vec<MissionReply>, Option<MissionUpdate>) {
+        let Some(upload) = self.upload.as_mut().filter(|upload| upload.partner == partner) else {
+            return (Vec::new(), None);
+        };
+        if item.seq >= upload.count {
+            self.upload = None;
+            return (vec![self.ack(partner, MAV_MISSION_INVALID_SEQUENCE, item.mission_type)], None);
+        }
+        // A repeated item, our request for the next one got lost
+        let expected = upload.goals.len() as u16;
+        if item.seq != expected {
+            upload.requested = now;
+            return (vec![self.request(partner, expected)], None);
+        }
+        let goal = match mission_item_goal(item) {
+            Ok(goal) => goal,
+            Err(result) => {
+                self.upload = None;
+                return (vec![self.ack(partner, result, item.mission_type)], None);
+            }
+        };
+        if item.current == 1 {
+            upload.start = upload.goals.len();
+        }
+        upload.goals.push(goal);
+        if upload.goals.len() < upload.count as usize {
+            upload.requested = now;
+            upload.requests = 1;
+            let seq = upload.goals.len() as u16;
+            return (vec![self.request(partner, seq)], None);
+        }
+        let upload = self.upload.take().unwrap();
+        (
+            vec![self.ack(partner, MAV_MISSION_ACCEPTED, MAV_MISSION_TYPE_MISSION)],
+            Some(MissionUpdate::Uploaded { goals: upload.goals, start: upload.start }),
+        )
+    }
+
+    fn is_for_us(&self, target_system: u8, target_component: u8) -> bool {
+        target_system == self.system_id && target_component == self.component_id
+    }
+
+    fn request(&self, partner: (u8, u8), seq: u16) -> MissionReply {
+        MissionReply::Request(MissionRequestInt {
+            seq,
+            target_system: partner.0,
+            target_component: partner.1,
+            mission_type: MAV_MISSION_TYPE_MISSION,
+        })
+    }
+
+    fn ack(&self, partner: (u8, u8), mission_result: u8, mission_type: u8) -> MissionReply {
+        MissionReply::Ack(MissionAck {
+            target_system: partner.0,
+            target_component: partner.1,
+            mission_result,
+            mission_type,
+        })
+    }
+}
+
+/// The waypoint of a mission item, or the MAV_MISSION_RESULT to reject it with.
+/// `param2` is the acceptance radius, 0 keeps the default.
+pub fn mission_item_goal(item: &MissionItemInt) -> Result<NavGoal, u8> {
+    if item.command != MAV_CMD_NAV_WAYPOINT {
+        return Err(MAV_MISSION_UNSUPPORTED);
+    }
+    let (x, y, z) = (item.x as f32 / 1e4, item.y as f32 / 1e4, item.z);
+    let goal = match MavFrame::from_u8(item.frame) {
+        Some(MavFrame::LocalNed) => NavGoal::local_ned(Point3::new(x, y, z)),
+        Some(MavFrame::BodyFrd) => {
+            let (x, y, z) = mid360_to_frd(x, y, z);
+            NavGoal::mid360(Point3::new(x, y, z))
+        }
+        _ => return Err(MAV_MISSION_UNSUPPORTED_FRAME),
+    };
+    if !(x.is_finite() && y.is_finite() && z.is_finite()) {
+        return Err(MAV_MISSION_ERROR);
+    }
+    Ok(if item.param2 > 0.0 { goal.with_acceptance_radius(item.param2) } else { goal })
+}
+
+/// Take missions from ground stations into the goal queue
+pub fn mission_system(
+    link: Option<Res<MavlinkLink>>,
+    mut received: EventReader<MavlinkReceived>,
+    mut receiver: Local<Option<MissionReceiver>>,
+    mut goals: ResMut<GoalQueue>,
+) {
+    let Some(link) = link else {
+        return;
+    };
+    let config = link.config();
+    let receiver = receiver.get_or_insert_with(|| MissionReceiver::new(config.system_id, config.component_id));
+    let now = Instant::now();
+    let mut replies = receiver.poll(now);
+    for event in received.read() {
+        let (frame_replies, update) = receiver.handle(&event.frame, event.received);
+        replies.extend(frame_replies);
+        match update {
+            Some(MissionUpdate::Uploaded { goals: mission, start }) => {
+                println!("Mission: {} waypoints, starting at {}", mission.len(), start);
+                goals.clear();
+                goals.extend(mission.into_iter().skip(start));
+            }
+            Some(MissionUpdate::Cleared) => {
+                println!("Mission: cleared");
+                goals.clear();
+            }
+            Some(MissionUpdate::GoTo(goal)) => goals.set(goal),
+            None => {}
+        }
+    }
+    for reply in replies {
+        if let Err(e) = reply.send(&link) {
+            println!("Error: failed to answer mission upload: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mavlink::messages::{MAV_FRAME_BODY_FRD, MAV_FRAME_LOCAL_NED};
+
+    const US: (u8, u8) = (1, 191);
+    const GCS: (u8, u8) = (255, 190);
+
+    fn frame<M: MavlinkMessage>(from: (u8, u8), message: &M) -> MavlinkFrame {
+        MavlinkFrame { sequence: 0, system_id: from.0, component_id: from.1, message_id: M::ID, payload: message.serialize() }
+    }
+
+    fn count(count: u16) -> MavlinkFrame {
+        frame(GCS, &MissionCount { count, target_system: US.0, target_component: US.1, mission_type: MAV_MISSION_TYPE_MISSION })
+    }
+
+    /// Waypoint `seq` at (seq, 2 seq, -1) m in local NED
+    fn item(seq: u16) -> MissionItemInt {
+        MissionItemInt {
+            x: seq as i32 * 10_000,
+            y: seq as i32 * 20_000,
+            z: -1.0,
+            seq,
+            command: MAV_CMD_NAV_WAYPOINT,
+            target_system: US.0,
+            target_component: US.1,
+            frame: MAV_FRAME_LOCAL_NED,
+            autocontinue: 1,
+            mission_type: MAV_MISSION_TYPE_MISSION,
+            ..MissionItemInt::default()
+        }
+    }
+
+    fn request(seq: u16) -> MissionReply {
+        MissionReply::Request(MissionRequestInt { seq, target_system: GCS.0, target_component: GCS.1, mission_type: MAV_MISSION_TYPE_MISSION })
+    }
+
+    fn ack(mission_result: u8) -> MissionReply {
+        MissionReply::Ack(MissionAck { target_system: GCS.0, target_component: GCS.1, mission_result, mission_type: MAV_MISSION_TYPE_MISSION })
+    }
+
+    fn waypoint(seq: u16) -> NavGoal {
+        NavGoal::local_ned(Point3::new(seq as f32, 2.0 * seq as f32, -1.0))
+    }
+
+    #[test]
+    fn upload_requests_every_item_and_acks() {
+        let mut receiver = MissionReceiver::new(US.0, US.1);
+        let now = Instant::now();
+        assert_eq!(receiver.handle(&count(3), now), (vec![request(0)], None));
+        assert!(receiver.is_uploading());
+        assert_eq!(receiver.handle(&frame(GCS, &item(0)), now), (vec![request(1)], None));
+        let second = MissionItemInt { current: 1, param2: 0.5, ..item(1) };
+        assert_eq!(receiver.handle(&frame(GCS, &second), now), (vec![request(2)], None));
+        let (replies, update) = receiver.handle(&frame(GCS, &item(2)), now);
+        assert_eq!(replies, vec![ack(MAV_MISSION_ACCEPTED)]);
+        let goals = vec![waypoint(0), waypoint(1).with_acceptance_radius(0.5), waypoint(2)];
+        assert_eq!(update, Some(MissionUpdate::Uploaded { goals, start: 1 }));
+        assert!(!receiver.is_uploading());
+    }
+
+    #[test]
+    fn repeated_item_requests_the_next_one_again() {
+        let mut receiver = MissionReceiver::new(US.0, US.1);
+        let now = Instant::now();
+        receiver.handle(&count(2), now);
+        receiver.handle(&frame(GCS, &item(0)), now);
+        // Our request for item 1 was lost, the ground station sends item 0 again
+        assert_eq!(receiver.handle(&frame(GCS, &item(0)), now), (vec![request(1)], None));
+        let (replies, update) = receiver.handle(&frame(GCS, &item(1)), now);
+        assert_eq!(replies, vec![ack(MAV_MISSION_ACCEPTED)]);
+        assert_eq!(update, Some(MissionUpdate::Uploaded { goals: vec![waypoint(0), waypoint(1)], start: 0 }));
+    }
+
+    #[test]
+    fn item_past_the_count_is_an_invalid_sequence() {
+        let mut receiver = MissionReceiver::new(US.0, US.1);
+        let now = Instant::now();
+        receiver.handle(&count(2), now);
+        assert_eq!(receiver.handle(&frame(GCS, &item(2)), now), (vec![ack(MAV_MISSION_INVALID_SEQUENCE)], None));
+        assert!(!receiver.is_uploading());
+        // Items without an upload are ignored
+        assert_eq!(receiver.handle(&frame(GCS, &item(0)), now), (Vec::new(), None));
+    }
+
+    #[test]
+    fn overdue_items_are_requested_until_the_upload_fails() {
+        let mut receiver = MissionReceiver::new(US.0, US.1);
+        let mut now = Instant::now();
+        receiver.handle(&count(2), now);
+        receiver.handle(&frame(GCS, &item(0)), now);
+        assert!(receiver.poll(now + ITEM_TIMEOUT / 2).is_empty());
+        for _ in 1..MAX_ITEM_REQUESTS {
+            now += ITEM_TIMEOUT;
+            assert_eq!(receiver.poll(now), vec![request(1)]);
+        }
+        now += ITEM_TIMEOUT;
+        assert_eq!(receiver.poll(now), vec![ack(MAV_MISSION_ERROR)]);
+        assert!(!receiver.is_uploading());
+        assert!(receiver.poll(now + ITEM_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn unsupported_items_reject_the_upload() {
+        let mut receiver = MissionReceiver::new(US.0, US.1);
+        let now = Instant::now();
+        receiver.handle(&count(2), now);
+        let global = MissionItemInt { frame: MavFrame::Global as u8, ..item(0) };
+        assert_eq!(receiver.handle(&frame(GCS, &global), now), (vec![ack(MAV_MISSION_UNSUPPORTED_FRAME)], None));
+        assert!(!receiver.is_uploading());
+
+        receiver.handle(&count(2), now);
+        let takeoff = MissionItemInt { command: 22, ..item(0) };
+        assert_eq!(receiver.handle(&frame(GCS, &takeoff), now), (vec![ack(MAV_MISSION_UNSUPPORTED)], None));
+    }
+
+    #[test]
+    fn body_frd_items_become_mid360_goals() {
+        let body = MissionItemInt { frame: MAV_FRAME_BODY_FRD, x: 20_000, y: 10_000, z: 0.5, ..item(0) };
+        assert_eq!(mission_item_goal(&body), Ok(NavGoal::mid360(Point3::new(2.0, -1.0, -0.5))));
+    }
+
+    #[test]
+    fn messages_for_others_are_ignored() {
+        let mut receiver = MissionReceiver::new(US.0, US.1);
+        let now = Instant::now();
+        let other = frame(GCS, &MissionCount { count: 2, target_system: US.0, target_component: 1, mission_type: MAV_MISSION_TYPE_MISSION });
+        assert_eq!(receiver.handle(&other, now), (Vec::new(), None));
+        assert!(!receiver.is_uploading());
+
+        // Items from another ground station do not interfere with an upload
+        receiver.handle(&count(1), now);
+        assert_eq!(receiver.handle(&frame((254, 190), &item(0)), now), (Vec::new(), None));
+        assert!(receiver.is_uploading());
+    }
+
+    #[test]
+    fn guided_item_and_clear_all() {
+        let mut receiver = MissionReceiver::new(US.0, US.1);
+        let now = Instant::now();
+        let guided = MissionItemInt { current: CURRENT_GUIDED, ..item(3) };
+        assert_eq!(receiver.handle(&frame(GCS, &guided), now), (vec![ack(MAV_MISSION_ACCEPTED)], Some(MissionUpdate::GoTo(waypoint(3)))));
+
+        receiver.handle(&count(2), now);
+        let clear = frame(GCS, &MissionClearAll { target_system: US.0, target_component: US.1, mission_type: MAV_MISSION_TYPE_MISSION });
+        assert_eq!(receiver.handle(&clear, now), (vec![ack(MAV_MISSION_ACCEPTED)], Some(MissionUpdate::Cleared)));
+        assert!(!receiver.is_uploading());
+        assert_eq!(receiver.handle(&count(0), now), (vec![ack(MAV_MISSION_ACCEPTED)], Some(MissionUpdate::Cleared)));
+    }
+}
@@ -0,0 +1,109 @@
+#![allow(dead_code)]
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::mavlink::frame::{FrameParser, MavlinkFrame};
+use crate::mavlink::messages::{self, MavlinkMessage, MAV_COMP_ID_AUTOPILOT1};
+
+/// A UDP endpoint that stands in for PX4/ArduPilot in tests. It records every frame that
+/// passes the checksum and can answer with its own messages as system 1, component 1.
+pub struct MockAutopilot {
+    socket: UdpSocket,
+    parser: FrameParser,
+    frames: Vec<MavlinkFrame>,
+    /// Where the link last sent from, replies go there
+    link_address: Option<SocketAddr>,
+    sequence: u8,
+}
+
+impl MockAutopilot {
+    pub fn bind(address: &str) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+        Ok(Self {
+            socket,
+            parser: FrameParser::new(),
+            frames: Vec::new(),
+            link_address: None,
+            sequence: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().expect("Mock autopilot socket has no address")
+    }
+
+    /// Send to a link in listen mode before it has heard from us
+    pub fn connect(&mut self, link_address: SocketAddr) {
+        self.link_address = Some(link_address);
+    }
+
+    /// Record everything that arrives within `duration`
+    pub fn poll(&mut self, duration: Duration) -> std::io::Result<()> {
+        let start = Instant::now();
+        let mut datagram = [0u8; 65536];
+        while start.elapsed() < duration {
+            match self.socket.recv_from(&mut datagram) {
+                Ok((size, sender)) => {
+                    self.link_address = Some(sender);
+                    self.parser.push(&datagram[..size]);
+                    while let Some(result) = self.parser.next_frame(messages::crc_extra) {
+                        if let Ok(frame) = result {
+                            self.frames.push(frame);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll until `condition` holds or `timeout` passes, returns whether it held
+    pub fn wait_for(&mut self, condition: impl Fn(&Self) -> bool, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if condition(self) {
+                return true;
+            }
+            if self.poll(Duration::from_millis(20)).is_err() {
+                return false;
+            }
+        }
+        condition(self)
+    }
+
+    pub fn frames(&self) -> &[MavlinkFrame] {
+        &self.frames
+    }
+
+    /// Every received message of one type, in arrival order
+    pub fn messages<M: MavlinkMessage>(&self) -> Vec<M> {
+        self.frames
+            .iter()
+            .filter(|frame| frame.message_id == M::ID)
+            .filter_map(|frame| M::deserialize(&frame.payload).ok())
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn send<M: MavlinkMessage>(&mut self, message: &M) -> std::io::Result<()> {
+        let address = self
+            .link_address
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotConnected, "Mock autopilot has not heard from a link yet"))?;
+        let frame = MavlinkFrame {
+            sequence: self.sequence,
+            system_id: 1,
+            component_id: MAV_COMP_ID_AUTOPILOT1,
+            message_id: M::ID,
+            payload: message.serialize(),
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.socket.send_to(&frame.encode(M::CRC_EXTRA), address).map(|_| ())
+    }
+}
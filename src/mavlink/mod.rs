@@ -0,0 +1,7 @@
+pub mod frame;
+pub mod messages;
+pub mod link;
+pub mod mock_autopilot;
+pub mod telemetry;
+pub mod obstacle_distance;
+pub mod mission;
\ No newline at end of file
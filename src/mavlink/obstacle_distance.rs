@@ -0,0 +1,234 @@
+#![allow(dead_code)]
+use bevy::ecs::system::Resource;
+use bevy::math::{EulerRot, Quat, Vec3};
+
+use crate::calculator::coordinate_switch::mid360_to_frd;
+use crate::mavlink::messages::{ObstacleDistance, MAV_DISTANCE_SENSOR_LASER, MAV_FRAME_BODY_FRD};
+use crate::octree::octree::Octree;
+
+/// Number of sectors in one OBSTACLE_DISTANCE message
+pub const OBSTACLE_DISTANCE_SECTORS: usize = 72;
+/// Width of one sector in degrees
+pub const SECTOR_WIDTH: f32 = 360.0 / OBSTACLE_DISTANCE_SECTORS as f32;
+
+/// Which occupied leaves count as obstacles around the vehicle. Lengths in metres,
+/// heights relative to the sensor with up positive.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct ObstacleDistanceConfig {
+    /// Closer obstacles are reported at this distance, the Mid-360 sees nothing nearer
+    pub min_distance: f32,
+    /// Sectors without obstacles up to here are reported free
+    pub max_distance: f32,
+    /// Leaves below this height, e.g. the ground, are ignored
+    pub min_height: f32,
+    /// Leaves above this height, e.g. a ceiling, are ignored
+    pub max_height: f32,
+    /// Subtracted from every distance since leaves are only known by their center,
+    /// half a voxel is a good value
+    pub clearance: f32,
+}
+
+impl Default for ObstacleDistanceConfig {
+    fn default() -> Self {
+        Self {
+            min_distance: 0.1,
+            max_distance: 10.0,
+            min_height: -0.3,
+            max_height: 0.5,
+            clearance: 0.04,
+        }
+    }
+}
+
+/// Roll and pitch part of a vehicle orientation, used to keep the height band level
+/// while the vehicle tilts. Yaw is dropped because sectors stay relative to the nose.
+pub fn level_rotation(orientation: Quat) -> Quat {
+    let (_, pitch, roll) = orientation.to_euler(EulerRot::ZYX);
+    Quat::from_euler(EulerRot::ZYX, 0.0, pitch, roll)
+}
+
+/// Sector of a horizontal FRD direction, sector 0 faces forward and indices go clockwise
+pub fn sector_index(x: f32, y: f32) -> usize {
+    let angle = y.atan2(x).to_degrees().rem_euclid(360.0);
+    (angle / SECTOR_WIDTH).round() as usize % OBSTACLE_DISTANCE_SECTORS
+}
+
+/// Closest horizontal obstacle distance per sector in metres, `None` where nothing is
+/// within `max_distance`. `level` rotates FRD body vectors into the level frame,
+/// `Quat::IDENTITY` without attitude.
+pub fn sector_distances(
+    octree: &Octree,
+    config: &ObstacleDistanceConfig,
+    level: Quat,
+) -> [Option<f32>; OBSTACLE_DISTANCE_SECTORS] {
+    let mut sectors = [None; OBSTACLE_DISTANCE_SECTORS];
+    let search_radius = config.max_distance + config.min_height.abs().max(config.max_height.abs()) + config.clearance;
+    for (_, center) in octree.radius_search([0.0, 0.0, 0.0], search_radius) {
+        let (x, y, z) = mid360_to_frd(center[0], center[1], center[2]);
+        let point = level * Vec3::new(x, y, z);
+        let height = -point.z;
+        if height < config.min_height || height > config.max_height {
+            continue;
+        }
+        let distance = (point.x.hypot(point.y) - config.clearance).max(0.0);
+        if distance > config.max_distance {
+            continue;
+        }
+        let sector = &mut sectors[sector_index(point.x, point.y)];
+        if sector.is_none_or(|closest| distance < closest) {
+            *sector = Some(distance);
+        }
+    }
+    sectors
+}
+
+/// OBSTACLE_DISTANCE message for the current map, in MAV_FRAME_BODY_FRD
+pub fn obstacle_distance_from_octree(
+    octree: &Octree,
+    config: &ObstacleDistanceConfig,
+    level: Quat,
+    time_usec: u64,
+) -> ObstacleDistance {
+    let min_distance = (config.min_distance * 100.0).round() as u16;
+    let max_distance = (config.max_distance * 100.0).round().min((u16::MAX - 1) as f32) as u16;
+    let mut distances = [max_distance + 1; OBSTACLE_DISTANCE_SECTORS];
+    for (cm, distance) in distances.iter_mut().zip(sector_distances(octree, config, level)) {
+        if let Some(distance) = distance {
+            *cm = ((distance * 100.0).round() as u16).clamp(min_distance, max_distance);
+        }
+    }
+    ObstacleDistance {
+        time_usec,
+        distances,
+        min_distance,
+        max_distance,
+        sensor_type: MAV_DISTANCE_SENSOR_LASER,
+        increment: SECTOR_WIDTH as u8,
+        increment_f: SECTOR_WIDTH,
+        angle_offset: 0.0,
+        frame: MAV_FRAME_BODY_FRD,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mavlink::messages::MavlinkMessage;
+
+    const MAX_DEPTH: u32 = 7;
+    /// Leaf size of the test octree, distances are only known to the leaf center
+    const VOXEL: f32 = 20.0 / (1 << MAX_DEPTH) as f32;
+
+    /// Octree from Mid-360 points (x forward, y left, z up)
+    fn map(points: &[[f32; 3]]) -> Octree {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        for point in points {
+            octree.insert(*point, MAX_DEPTH, 100).unwrap();
+        }
+        octree
+    }
+
+    fn config() -> ObstacleDistanceConfig {
+        ObstacleDistanceConfig { clearance: 0.0, ..ObstacleDistanceConfig::default() }
+    }
+
+    fn assert_near(distance: Option<f32>, expected: f32) {
+        let distance = distance.unwrap_or_else(|| panic!("expected an obstacle at {}", expected));
+        assert!((distance - expected).abs() < VOXEL, "{} != {}", distance, expected);
+    }
+
+    #[test]
+    fn sectors_start_forward_and_go_clockwise() {
+        assert_eq!(sector_index(1.0, 0.0), 0);
+        assert_eq!(sector_index(0.0, 1.0), 18); // right
+        assert_eq!(sector_index(-1.0, 0.0), 36);
+        assert_eq!(sector_index(0.0, -1.0), 54); // left
+        // Sectors are centered on their angle
+        let direction = |degrees: f32| (degrees.to_radians().cos(), degrees.to_radians().sin());
+        for (degrees, sector) in [(2.4, 0), (2.6, 1), (357.6, 0), (357.4, 71), (-10.0, 70)] {
+            let (x, y) = direction(degrees);
+            assert_eq!(sector_index(x, y), sector, "{} degrees", degrees);
+        }
+    }
+
+    #[test]
+    fn octree_obstacles_land_in_their_sectors() {
+        // Mid-360 y is left, so a negative y is on the right of the vehicle
+        let octree = map(&[[2.0, 0.0, 0.0], [0.0, -3.0, 0.0], [-4.0, 0.0, 0.0], [0.0, 5.0, 0.0], [3.0, -3.0, 0.1]]);
+        let sectors = sector_distances(&octree, &config(), Quat::IDENTITY);
+        assert_near(sectors[0], 2.0);
+        assert_near(sectors[18], 3.0);
+        assert_near(sectors[36], 4.0);
+        assert_near(sectors[54], 5.0);
+        assert_near(sectors[9], 18.0f32.sqrt());
+        assert_eq!(sectors.iter().filter(|sector| sector.is_some()).count(), 5);
+    }
+
+    #[test]
+    fn only_the_height_band_counts() {
+        let octree = map(&[[2.0, 0.0, 0.8], [0.0, -3.0, -0.6], [-4.0, 0.0, 0.3], [0.0, 5.0, -0.2]]);
+        let sectors = sector_distances(&octree, &config(), Quat::IDENTITY);
+        assert_eq!(sectors[0], None, "above max_height");
+        assert_eq!(sectors[18], None, "below min_height");
+        assert_near(sectors[36], 4.0);
+        assert_near(sectors[54], 5.0);
+
+        // Pitched 30 degrees nose down, ground 1.5 m below the vehicle shows up level with
+        // the sensor in the body frame and has to be levelled out of the band
+        let pitch_down = Quat::from_euler(EulerRot::ZYX, 0.0, -30f32.to_radians(), 0.0);
+        let body = pitch_down.inverse() * Vec3::new(3.0, 0.0, 1.5);
+        let ground = map(&[[body.x, -body.y, -body.z]]);
+        assert!(sector_distances(&ground, &config(), Quat::IDENTITY)[0].is_some());
+        assert!(sector_distances(&ground, &config(), level_rotation(pitch_down))[0].is_none());
+    }
+
+    #[test]
+    fn level_rotation_drops_yaw() {
+        let orientation = Quat::from_euler(EulerRot::ZYX, 1.0, 0.2, -0.1);
+        let expected = Quat::from_euler(EulerRot::ZYX, 0.0, 0.2, -0.1);
+        assert!(level_rotation(orientation).angle_between(expected) < 1e-5);
+    }
+
+    #[test]
+    fn free_sectors_are_max_distance_plus_one() {
+        let octree = map(&[[2.0, 0.0, 0.0], [0.1, -0.1, 0.0], [-12.0, 0.0, 0.0]]);
+        let config = ObstacleDistanceConfig { min_distance: 0.3, max_distance: 8.0, ..config() };
+        let message = obstacle_distance_from_octree(&octree, &config, Quat::IDENTITY, 42);
+
+        assert_eq!((message.min_distance, message.max_distance), (30, 800));
+        assert!((message.distances[0] as f32 - 200.0).abs() < VOXEL * 100.0);
+        // Closer than the sensor can see is clamped to min_distance
+        assert_eq!(message.distances[9], 30);
+        // Out of bounds and beyond max_distance both read as free
+        assert_eq!(message.distances[36], 801);
+        assert_eq!(message.distances.iter().filter(|d| **d == 801).count(), OBSTACLE_DISTANCE_SECTORS - 2);
+        assert_eq!((message.increment, message.increment_f, message.angle_offset), (5, 5.0, 0.0));
+        assert_eq!((message.frame, message.sensor_type, message.time_usec), (MAV_FRAME_BODY_FRD, MAV_DISTANCE_SENSOR_LASER, 42));
+    }
+
+    #[test]
+    fn message_is_167_bytes_on_the_wire() {
+        let octree = map(&[[2.0, 0.0, 0.0], [0.0, -3.0, 0.0]]);
+        let message = obstacle_distance_from_octree(&octree, &config(), Quat::IDENTITY, 123_456_789);
+        let payload = message.serialize();
+        assert_eq!(payload.len(), 167);
+        assert_eq!(ObstacleDistance::LEN, 167);
+
+        assert_eq!(u64::from_le_bytes(payload[0..8].try_into().unwrap()), 123_456_789);
+        let sector = |i: usize| u16::from_le_bytes([payload[8 + 2 * i], payload[9 + 2 * i]]);
+        assert_eq!(sector(18), message.distances[18]);
+        assert_eq!(sector(1), 1001);
+        assert_eq!(u16::from_le_bytes([payload[152], payload[153]]), 10); // min_distance
+        assert_eq!(u16::from_le_bytes([payload[154], payload[155]]), 1000); // max_distance
+        assert_eq!((payload[156], payload[157]), (MAV_DISTANCE_SENSOR_LASER, 5));
+        assert_eq!(f32::from_le_bytes(payload[158..162].try_into().unwrap()), 5.0);
+        assert_eq!(f32::from_le_bytes(payload[162..166].try_into().unwrap()), 0.0);
+        assert_eq!(payload[166], MAV_FRAME_BODY_FRD);
+
+        assert_eq!(ObstacleDistance::deserialize(&payload).unwrap(), message);
+        // Receivers without the extensions see only the 158 base bytes
+        let base = ObstacleDistance::deserialize(&payload[..158]).unwrap();
+        assert_eq!(base.distances, message.distances);
+        assert_eq!((base.increment_f, base.frame), (0.0, 0));
+    }
+}
@@ -0,0 +1,565 @@
+#![allow(dead_code)]
+use bevy::ecs::event::EventReader;
+use bevy::ecs::system::{Res, ResMut, Resource};
+use bevy::math::{EulerRot, Quat, Vec3};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::mavlink::frame::MavlinkFrame;
+use crate::mavlink::link::{MavlinkLink, MavlinkReceived};
+use crate::mavlink::messages::{
+    Attitude, ExtendedSysState, Heartbeat, LandedState, LocalPositionNed, MavlinkMessage, Odometry,
+    MAV_AUTOPILOT_ARDUPILOTMEGA, MAV_AUTOPILOT_INVALID, MAV_AUTOPILOT_PX4, MAV_FRAME_BODY_FRD,
+    MAV_MODE_FLAG_CUSTOM_MODE_ENABLED, MAV_MODE_FLAG_SAFETY_ARMED, MAV_TYPE_FIXED_WING, MAV_TYPE_GROUND_ROVER,
+    MAV_TYPE_SURFACE_BOAT,
+};
+
+/// Without a heartbeat for this long the autopilot is treated as gone
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How much pose history is kept for aligning lidar frames
+pub const POSE_HISTORY_LENGTH: Duration = Duration::from_secs(2);
+/// A pose is extrapolated with its velocity at most this far past the newest sample
+pub const MAX_POSE_EXTRAPOLATION: Duration = Duration::from_millis(200);
+/// LOCAL_POSITION_NED is ignored while ODOMETRY arrived within this time
+const ODOMETRY_PREFERENCE: Duration = Duration::from_secs(1);
+/// A timestamp that goes back further than this means the autopilot rebooted
+const REBOOT_JUMP_US: u64 = 1_000_000;
+/// Upper bound on how fast the two clocks can drift apart, in parts per million
+const CLOCK_DRIFT_PPM: i64 = 100;
+
+/// PX4 custom main mode OFFBOARD, stored in bits 16..24 of `custom_mode`
+const PX4_MAIN_MODE_OFFBOARD: u32 = 6;
+/// ArduCopter GUIDED
+const ARDUCOPTER_MODE_GUIDED: u32 = 4;
+/// ArduPlane and ArduRover GUIDED
+const ARDUPLANE_MODE_GUIDED: u32 = 15;
+
+/// Maps between autopilot boot timestamps and the local monotonic clock.
+///
+/// Every sample arrives some transport delay after it was taken, so `local - remote` is
+/// an upper bound of the clock offset and the smallest value seen is the best estimate.
+/// The estimate may rise by `CLOCK_DRIFT_PPM` to follow a slower autopilot clock.
+#[derive(Resource, Debug, Clone)]
+pub struct TelemetryClock {
+    epoch: Instant,
+    /// local_us - remote_us
+    offset_us: Option<i64>,
+    last_remote_us: u64,
+    last_local_us: i64,
+}
+
+impl TelemetryClock {
+    pub fn new() -> Self {
+        Self { epoch: Instant::now(), offset_us: None, last_remote_us: 0, last_local_us: 0 }
+    }
+
+    /// Local time at which a sample stamped `remote_us` after autopilot boot was taken,
+    /// given that it was received at `received`
+    pub fn align(&mut self, remote_us: u64, received: Instant) -> Instant {
+        let local_us = received.saturating_duration_since(self.epoch).as_micros() as i64;
+        if remote_us + REBOOT_JUMP_US < self.last_remote_us {
+            self.offset_us = None;
+        }
+        let candidate = local_us - remote_us as i64;
+        let offset = match self.offset_us {
+            Some(offset) => {
+                let drift = (local_us - self.last_local_us).max(0) * CLOCK_DRIFT_PPM / 1_000_000;
+                (offset + drift).min(candidate)
+            }
+            None => candidate,
+        };
+        self.offset_us = Some(offset);
+        self.last_remote_us = remote_us;
+        self.last_local_us = local_us;
+        self.epoch + Duration::from_micros((offset + remote_us as i64).max(0) as u64)
+    }
+
+    /// Autopilot boot time in microseconds at local time `time`, `None` before the first sample
+    pub fn to_remote(&self, time: Instant) -> Option<u64> {
+        let local_us = time.saturating_duration_since(self.epoch).as_micros() as i64;
+        self.offset_us.map(|offset| (local_us - offset).max(0) as u64)
+    }
+
+    /// Current offset estimate in microseconds, `None` before the first sample
+    pub fn offset_us(&self) -> Option<i64> {
+        self.offset_us
+    }
+}
+
+impl Default for TelemetryClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A telemetry message together with the local time it was taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stamped<M> {
+    pub message: M,
+    pub time: Instant,
+}
+
+/// Flight mode as far as the avoidance cares about it
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FlightMode {
+    #[default]
+    Unknown,
+    /// PX4 OFFBOARD
+    Offboard,
+    /// ArduPilot GUIDED
+    Guided,
+    /// Any other mode, with the raw `custom_mode`
+    Other(u32),
+}
+
+impl FlightMode {
+    pub fn from_heartbeat(heartbeat: &Heartbeat) -> Self {
+        if heartbeat.base_mode & MAV_MODE_FLAG_CUSTOM_MODE_ENABLED == 0 {
+            return FlightMode::Unknown;
+        }
+        let custom_mode = heartbeat.custom_mode;
+        match heartbeat.autopilot {
+            MAV_AUTOPILOT_PX4 if (custom_mode >> 16) & 0xFF == PX4_MAIN_MODE_OFFBOARD => FlightMode::Offboard,
+            MAV_AUTOPILOT_ARDUPILOTMEGA => {
+                let guided = match heartbeat.mav_type {
+                    MAV_TYPE_FIXED_WING | MAV_TYPE_GROUND_ROVER | MAV_TYPE_SURFACE_BOAT => ARDUPLANE_MODE_GUIDED,
+                    _ => ARDUCOPTER_MODE_GUIDED,
+                };
+                if custom_mode == guided {
+                    FlightMode::Guided
+                } else {
+                    FlightMode::Other(custom_mode)
+                }
+            }
+            _ => FlightMode::Other(custom_mode),
+        }
+    }
+}
+
+/// HEARTBEAT and EXTENDED_SYS_STATE of the autopilot
+#[derive(Resource, Debug, Clone, Default)]
+pub struct AutopilotStatus {
+    pub heartbeat: Option<Stamped<Heartbeat>>,
+    pub mode: FlightMode,
+    pub landed_state: LandedState,
+    pub vtol_state: u8,
+}
+
+impl AutopilotStatus {
+    /// Whether a heartbeat arrived within `HEARTBEAT_TIMEOUT` of `now`
+    pub fn is_alive(&self, now: Instant) -> bool {
+        self.heartbeat
+            .is_some_and(|heartbeat| now.saturating_duration_since(heartbeat.time) < HEARTBEAT_TIMEOUT)
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.heartbeat
+            .is_some_and(|heartbeat| heartbeat.message.base_mode & MAV_MODE_FLAG_SAFETY_ARMED != 0)
+    }
+
+    pub fn is_px4(&self) -> bool {
+        self.heartbeat.is_some_and(|heartbeat| heartbeat.message.autopilot == MAV_AUTOPILOT_PX4)
+    }
+
+    /// Avoidance commands are only allowed to fly the vehicle in OFFBOARD or GUIDED
+    pub fn avoidance_in_control(&self, now: Instant) -> bool {
+        self.is_alive(now) && matches!(self.mode, FlightMode::Offboard | FlightMode::Guided)
+    }
+
+    /// Whether setpoints should be streamed at all. PX4 refuses to switch into OFFBOARD
+    /// unless setpoints already arrive, and ignores them in every other mode.
+    pub fn should_stream_setpoints(&self, now: Instant) -> bool {
+        self.avoidance_in_control(now) || (self.is_alive(now) && self.is_px4())
+    }
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub struct VehicleAttitude(pub Option<Stamped<Attitude>>);
+
+#[derive(Resource, Debug, Clone, Default)]
+pub struct VehicleLocalPosition(pub Option<Stamped<LocalPositionNed>>);
+
+#[derive(Resource, Debug, Clone, Default)]
+pub struct VehicleOdometry(pub Option<Stamped<Odometry>>);
+
+/// Vehicle state in the autopilot's local NED frame.
+/// `orientation` rotates FRD body vectors into NED.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VehiclePose {
+    /// Autopilot boot time of the estimate in microseconds
+    pub time_boot_us: u64,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub orientation: Quat,
+}
+
+impl VehiclePose {
+    pub fn from_odometry(odometry: &Odometry) -> Self {
+        let [w, x, y, z] = odometry.q;
+        let orientation = Quat::from_xyzw(x, y, z, w).normalize();
+        let velocity = Vec3::new(odometry.vx, odometry.vy, odometry.vz);
+        let velocity = if odometry.child_frame_id == MAV_FRAME_BODY_FRD {
+            orientation * velocity
+        } else {
+            velocity
+        };
+        Self {
+            time_boot_us: odometry.time_usec,
+            position: Vec3::new(odometry.x, odometry.y, odometry.z),
+            velocity,
+            orientation,
+        }
+    }
+
+    pub fn from_local_position(position: &LocalPositionNed, attitude: Option<&Attitude>) -> Self {
+        let orientation = attitude.map_or(Quat::IDENTITY, |attitude| {
+            Quat::from_euler(EulerRot::ZYX, attitude.yaw, attitude.pitch, attitude.roll)
+        });
+        Self {
+            time_boot_us: position.time_boot_ms as u64 * 1000,
+            position: Vec3::new(position.x, position.y, position.z),
+            velocity: Vec3::new(position.vx, position.vy, position.vz),
+            orientation,
+        }
+    }
+
+    /// Roll, pitch and yaw in rad
+    pub fn euler(&self) -> (f32, f32, f32) {
+        let (yaw, pitch, roll) = self.orientation.to_euler(EulerRot::ZYX);
+        (roll, pitch, yaw)
+    }
+}
+
+/// Recent vehicle poses in autopilot time, used to look up where the vehicle was when
+/// a lidar frame was taken. Map local times with `TelemetryClock::to_remote` first.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct PoseHistory {
+    poses: VecDeque<VehiclePose>,
+}
+
+impl PoseHistory {
+    pub fn new() -> Self {
+        Self { poses: VecDeque::new() }
+    }
+
+    /// Add a pose. Out of order poses are dropped, older ones than `POSE_HISTORY_LENGTH` expire.
+    /// A timestamp far in the past means the autopilot rebooted and starts a new history.
+    pub fn push(&mut self, pose: VehiclePose) {
+        if let Some(last) = self.poses.back() {
+            if pose.time_boot_us + REBOOT_JUMP_US < last.time_boot_us {
+                self.poses.clear();
+            } else if pose.time_boot_us < last.time_boot_us {
+                return;
+            }
+        }
+        self.poses.push_back(pose);
+        let history_us = POSE_HISTORY_LENGTH.as_micros() as u64;
+        while self.poses.front().is_some_and(|first| pose.time_boot_us - first.time_boot_us > history_us) {
+            self.poses.pop_front();
+        }
+    }
+
+    pub fn latest(&self) -> Option<&VehiclePose> {
+        self.poses.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.poses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.poses.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.poses.clear();
+    }
+
+    /// Pose at autopilot time `time_boot_us`, interpolated between the two samples around it
+    /// or extrapolated from the newest one by at most `MAX_POSE_EXTRAPOLATION`
+    pub fn pose_at(&self, time_boot_us: u64) -> Option<VehiclePose> {
+        let last = self.poses.back()?;
+        if time_boot_us >= last.time_boot_us {
+            let dt_us = time_boot_us - last.time_boot_us;
+            if dt_us > MAX_POSE_EXTRAPOLATION.as_micros() as u64 {
+                return None;
+            }
+            let position = last.position + last.velocity * (dt_us as f32 * 1e-6);
+            return Some(VehiclePose { time_boot_us, position, ..*last });
+        }
+        let after = self.poses.iter().position(|pose| pose.time_boot_us > time_boot_us)?;
+        if after == 0 {
+            return None;
+        }
+        let a = &self.poses[after - 1];
+        let b = &self.poses[after];
+        let t = (time_boot_us - a.time_boot_us) as f32 / (b.time_boot_us - a.time_boot_us) as f32;
+        Some(VehiclePose {
+            time_boot_us,
+            position: a.position.lerp(b.position, t),
+            velocity: a.velocity.lerp(b.velocity, t),
+            orientation: a.orientation.slerp(b.orientation, t),
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn ingest_frame(
+    frame: &MavlinkFrame,
+    received: Instant,
+    clock: &mut TelemetryClock,
+    status: &mut AutopilotStatus,
+    attitude: &mut VehicleAttitude,
+    local_position: &mut VehicleLocalPosition,
+    odometry: &mut VehicleOdometry,
+    poses: &mut PoseHistory,
+) {
+    match frame.message_id {
+        Heartbeat::ID => {
+            let Ok(heartbeat) = Heartbeat::deserialize(&frame.payload) else {
+                return;
+            };
+            // Ground stations and companion computers send heartbeats too
+            if heartbeat.autopilot == MAV_AUTOPILOT_INVALID {
+                return;
+            }
+            status.mode = FlightMode::from_heartbeat(&heartbeat);
+            status.heartbeat = Some(Stamped { message: heartbeat, time: received });
+        }
+        ExtendedSysState::ID => {
+            let Ok(state) = ExtendedSysState::deserialize(&frame.payload) else {
+                return;
+            };
+            status.landed_state = LandedState::from_u8(state.landed_state);
+            status.vtol_state = state.vtol_state;
+        }
+        Attitude::ID => {
+            let Ok(message) = Attitude::deserialize(&frame.payload) else {
+                return;
+            };
+            let time = clock.align(message.time_boot_ms as u64 * 1000, received);
+            attitude.0 = Some(Stamped { message, time });
+        }
+        LocalPositionNed::ID => {
+            let Ok(message) = LocalPositionNed::deserialize(&frame.payload) else {
+                return;
+            };
+            let time = clock.align(message.time_boot_ms as u64 * 1000, received);
+            local_position.0 = Some(Stamped { message, time });
+            let odometry_recent = odometry.0.is_some_and(|odometry| {
+                time.saturating_duration_since(odometry.time) < ODOMETRY_PREFERENCE
+            });
+            if !odometry_recent {
+                let attitude = attitude.0.as_ref().map(|attitude| &attitude.message);
+                poses.push(VehiclePose::from_local_position(&message, attitude));
+            }
+        }
+        Odometry::ID => {
+            let Ok(message) = Odometry::deserialize(&frame.payload) else {
+                return;
+            };
+            let time = clock.align(message.time_usec, received);
+            odometry.0 = Some(Stamped { message, time });
+            poses.push(VehiclePose::from_odometry(&message));
+        }
+        _ => {}
+    }
+}
+
+/// Decode the frames the link received into the telemetry resources
+#[allow(clippy::too_many_arguments)]
+pub fn telemetry_system(
+    link: Option<Res<MavlinkLink>>,
+    mut received: EventReader<MavlinkReceived>,
+    mut clock: ResMut<TelemetryClock>,
+    mut status: ResMut<AutopilotStatus>,
+    mut attitude: ResMut<VehicleAttitude>,
+    mut local_position: ResMut<VehicleLocalPosition>,
+    mut odometry: ResMut<VehicleOdometry>,
+    mut poses: ResMut<PoseHistory>,
+) {
+    let Some(link) = link else {
+        return;
+    };
+    // Only our own vehicle, a companion computer shares the system id of its autopilot
+    let system_id = link.config().system_id;
+    for event in received.read() {
+        if event.frame.system_id != system_id {
+            continue;
+        }
+        ingest_frame(
+            &event.frame,
+            event.received,
+            &mut clock,
+            &mut status,
+            &mut attitude,
+            &mut local_position,
+            &mut odometry,
+            &mut poses,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn first_sample_aligns_to_its_arrival() {
+        let mut clock = TelemetryClock::new();
+        assert_eq!(clock.to_remote(clock.epoch), None);
+        let received = clock.epoch + Duration::from_millis(10_000);
+        assert_eq!(clock.align(2_000_000, received), received);
+        assert_eq!(clock.offset_us(), Some(8_000_000));
+        assert_eq!(clock.to_remote(received), Some(2_000_000));
+    }
+
+    #[test]
+    fn align_keeps_the_smallest_transport_delay() {
+        let mut clock = TelemetryClock::new();
+        let epoch = clock.epoch;
+        clock.align(2_000_000, epoch + Duration::from_millis(10_050));
+        // Arrives faster than the first sample, so the offset estimate drops
+        assert_eq!(clock.align(2_500_000, epoch + Duration::from_millis(10_520)), epoch + Duration::from_millis(10_520));
+        assert_eq!(clock.offset_us(), Some(8_020_000));
+
+        // A late sample is placed at when it was taken, not when it arrived.
+        // The offset may only rise by the drift bound: 0.7 s * 100 ppm = 70 us.
+        let aligned = clock.align(3_000_000, epoch + Duration::from_millis(11_220));
+        assert_eq!(clock.offset_us(), Some(8_020_070));
+        assert_eq!(aligned, epoch + Duration::from_micros(11_020_070));
+    }
+
+    #[test]
+    fn align_follows_a_slower_clock_within_the_drift_bound() {
+        let mut clock = TelemetryClock::new();
+        let epoch = clock.epoch;
+        // The autopilot clock loses 1 ms every second, ten times the allowed drift
+        for i in 0..=10u64 {
+            clock.align(i * 999_000, epoch + Duration::from_secs(1 + i));
+        }
+        assert_eq!(clock.offset_us(), Some(1_000_000 + 10 * 100));
+    }
+
+    #[test]
+    fn autopilot_reboot_resets_the_offset() {
+        let mut clock = TelemetryClock::new();
+        let epoch = clock.epoch;
+        clock.align(60_000_000, epoch + Duration::from_millis(61_000));
+        let received = epoch + Duration::from_millis(70_000);
+        assert_eq!(clock.align(500_000, received), received);
+        assert_eq!(clock.offset_us(), Some(69_500_000));
+
+        // Jitter smaller than the reboot jump keeps the estimate
+        clock.align(300_000, epoch + Duration::from_millis(70_100));
+        assert_eq!(clock.offset_us(), Some(69_500_010));
+    }
+
+    fn pose(time_boot_us: u64, x: f32, vx: f32, yaw: f32) -> VehiclePose {
+        VehiclePose {
+            time_boot_us,
+            position: Vec3::new(x, 0.0, -1.0),
+            velocity: Vec3::new(vx, 0.0, 0.0),
+            orientation: Quat::from_rotation_z(yaw),
+        }
+    }
+
+    #[test]
+    fn pose_at_interpolates_between_samples() {
+        let mut history = PoseHistory::new();
+        history.push(pose(1_000_000, 0.0, 1.0, 0.0));
+        history.push(pose(1_100_000, 0.2, 3.0, 0.4));
+
+        let middle = history.pose_at(1_025_000).unwrap();
+        assert_eq!(middle.time_boot_us, 1_025_000);
+        assert!((middle.position.x - 0.05).abs() < 1e-6);
+        assert!((middle.velocity.x - 1.5).abs() < 1e-6);
+        assert!((middle.orientation.angle_between(Quat::from_rotation_z(0.1))).abs() < 1e-5);
+
+        let first = history.pose_at(1_000_000).unwrap();
+        assert_eq!(first.position, Vec3::new(0.0, 0.0, -1.0));
+        assert!(history.pose_at(999_999).is_none());
+    }
+
+    #[test]
+    fn pose_at_extrapolates_a_short_way_only() {
+        let mut history = PoseHistory::new();
+        history.push(pose(1_000_000, 1.0, 2.0, 0.3));
+
+        let ahead = history.pose_at(1_150_000).unwrap();
+        assert!((ahead.position.x - 1.3).abs() < 1e-6);
+        assert_eq!(ahead.orientation, Quat::from_rotation_z(0.3));
+        let limit = 1_000_000 + MAX_POSE_EXTRAPOLATION.as_micros() as u64;
+        assert!(history.pose_at(limit).is_some());
+        assert!(history.pose_at(limit + 1).is_none());
+        assert!(PoseHistory::new().pose_at(0).is_none());
+    }
+
+    #[test]
+    fn push_drops_stale_poses_and_restarts_after_a_reboot() {
+        let mut history = PoseHistory::new();
+        for i in 0..30u64 {
+            history.push(pose(10_000_000 + i * 100_000, i as f32, 0.0, 0.0));
+        }
+        // 2 s of history at 10 Hz
+        assert_eq!(history.len(), 21);
+        history.push(pose(12_000_000, -1.0, 0.0, 0.0));
+        assert_eq!(history.latest().unwrap().time_boot_us, 12_900_000);
+
+        history.push(pose(100_000, 0.0, 0.0, 0.0));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.latest().unwrap().time_boot_us, 100_000);
+    }
+
+    fn heartbeat(autopilot: u8, mav_type: u8, base_mode: u8, custom_mode: u32) -> Heartbeat {
+        Heartbeat { custom_mode, mav_type, autopilot, base_mode, system_status: 4, mavlink_version: 3 }
+    }
+
+    #[test]
+    fn px4_offboard_is_read_from_the_main_mode() {
+        let custom = MAV_MODE_FLAG_CUSTOM_MODE_ENABLED | MAV_MODE_FLAG_SAFETY_ARMED;
+        let offboard = heartbeat(MAV_AUTOPILOT_PX4, 2, custom, PX4_MAIN_MODE_OFFBOARD << 16);
+        assert_eq!(FlightMode::from_heartbeat(&offboard), FlightMode::Offboard);
+        let position = heartbeat(MAV_AUTOPILOT_PX4, 2, custom, 3 << 16);
+        assert_eq!(FlightMode::from_heartbeat(&position), FlightMode::Other(3 << 16));
+        let no_custom_mode = heartbeat(MAV_AUTOPILOT_PX4, 2, MAV_MODE_FLAG_SAFETY_ARMED, PX4_MAIN_MODE_OFFBOARD << 16);
+        assert_eq!(FlightMode::from_heartbeat(&no_custom_mode), FlightMode::Unknown);
+    }
+
+    #[test]
+    fn ardupilot_guided_depends_on_the_vehicle_type() {
+        let custom = MAV_MODE_FLAG_CUSTOM_MODE_ENABLED;
+        let quadrotor = 2;
+        let cases = [
+            (quadrotor, ARDUCOPTER_MODE_GUIDED, FlightMode::Guided),
+            (quadrotor, ARDUPLANE_MODE_GUIDED, FlightMode::Other(ARDUPLANE_MODE_GUIDED)),
+            (MAV_TYPE_FIXED_WING, ARDUPLANE_MODE_GUIDED, FlightMode::Guided),
+            (MAV_TYPE_FIXED_WING, ARDUCOPTER_MODE_GUIDED, FlightMode::Other(ARDUCOPTER_MODE_GUIDED)),
+            (MAV_TYPE_GROUND_ROVER, ARDUPLANE_MODE_GUIDED, FlightMode::Guided),
+            (MAV_TYPE_SURFACE_BOAT, ARDUPLANE_MODE_GUIDED, FlightMode::Guided),
+        ];
+        for (mav_type, custom_mode, expected) in cases {
+            let message = heartbeat(MAV_AUTOPILOT_ARDUPILOTMEGA, mav_type, custom, custom_mode);
+            assert_eq!(FlightMode::from_heartbeat(&message), expected, "type {} mode {}", mav_type, custom_mode);
+        }
+    }
+
+    #[test]
+    fn avoidance_only_controls_a_live_vehicle_in_offboard_or_guided() {
+        let now = Instant::now();
+        let message = heartbeat(MAV_AUTOPILOT_PX4, 2, MAV_MODE_FLAG_CUSTOM_MODE_ENABLED, 3 << 16);
+        let mut status = AutopilotStatus {
+            heartbeat: Some(Stamped { message, time: now }),
+            mode: FlightMode::from_heartbeat(&message),
+            ..AutopilotStatus::default()
+        };
+        assert!(!status.avoidance_in_control(now));
+        assert!(status.should_stream_setpoints(now));
+
+        status.mode = FlightMode::Offboard;
+        assert!(status.avoidance_in_control(now));
+        assert!(!status.avoidance_in_control(now + HEARTBEAT_TIMEOUT));
+        assert!(!status.should_stream_setpoints(now + HEARTBEAT_TIMEOUT));
+    }
+}
\ No newline at end of file
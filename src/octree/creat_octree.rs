@@ -1,15 +1,31 @@
+#![allow(dead_code)]
 use crate::data_reader;
+use crate::data_reader::structor::LaserPoint;
 use crate::octree::octree::*;
 use crate::calculator::voxel_grid;
 use std::net::UdpSocket;
 
 pub fn creat_octree_from_udp(boundary: f32, max_depth: u32, voxel_size: f32, frame_integration_time: u32) -> Octree {
-    let mut max_depth = max_depth;
     let socket_laserpoint = UdpSocket::bind("0.0.0.0:56301").expect("Port bind failed");
-    let mut points = data_reader::udp_reader::read_laserpoint(
+    let points = data_reader::udp_reader::read_laserpoint(
         &socket_laserpoint,
         frame_integration_time
     ).unwrap();
+
+    creat_octree_from_points(points, boundary, max_depth, voxel_size)
+}
+
+/// Build an octree from a Livox Viewer CSV export instead of the live sensor
+pub fn creat_octree_from_csv(file_name: &str, boundary: f32, max_depth: u32, voxel_size: f32) -> Result<Octree, String> {
+    let points = data_reader::csv_reader::read_laserpoint_csv(file_name)
+        .map_err(|e| format!("Failed to read {}: {}", file_name, e))?;
+
+    Ok(creat_octree_from_points(points, boundary, max_depth, voxel_size))
+}
+
+pub fn creat_octree_from_points(points: Vec<LaserPoint>, boundary: f32, max_depth: u32, voxel_size: f32) -> Octree {
+    let mut max_depth = max_depth;
+    let mut points = points;
     if voxel_size >= 0.05 {
         points = voxel_grid::voxel_grid_filter(&points, voxel_size);
     }
@@ -19,11 +35,7 @@ pub fn creat_octree_from_udp(boundary: f32, max_depth: u32, voxel_size: f32, fra
         max_depth = 6;
     }
 
-    for point in points {
-        let point_coordinate = [point.x, point.y, point.z];
-        let point_reflectivity = point.reflectivity;
-        octree.insert(point_coordinate, max_depth, point_reflectivity).unwrap();
-    }
+    octree.insert_batch(&points, max_depth).unwrap();
 
     octree
-}
\ No newline at end of file
+}
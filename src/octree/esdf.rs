@@ -0,0 +1,449 @@
+#![allow(dead_code)]
+use std::collections::{HashMap, HashSet, VecDeque};
+use bevy::ecs::system::Resource;
+
+use crate::octree::octree::{Octree, OctreeNode, Occupancy};
+
+const NO_OBSTACLE: u32 = u32::MAX;
+
+/// Euclidean signed distance field sampled on a regular grid.
+///
+/// Obstacle cells are taken from the occupied leaves of an `Octree`. Free cells store the
+/// distance to the closest obstacle cell center, obstacle cells the negated distance to the
+/// closest free cell center, both capped at `max_distance`. The zero crossing lies on the
+/// faces between free and obstacle cells, and the gradient points out of obstacles everywhere.
+/// Calling `update_from_octree` again only re-propagates the free cells affected by the
+/// obstacles that appeared or disappeared since the last update.
+#[derive(Debug, Resource)]
+pub struct Esdf {
+    origin: [f32; 3],
+    resolution: f32,
+    dims: [usize; 3],
+    max_distance: f32,
+    distance: Vec<f32>,
+    nearest: Vec<u32>,
+    obstacles: HashSet<u32>,
+}
+
+impl Esdf {
+    /// Create an empty field covering `bounds` with cubic cells of size `resolution`
+    pub fn new(bounds: [[f32; 3]; 2], resolution: f32, max_distance: f32) -> Self {
+        let mut dims = [0; 3];
+        for i in 0..3 {
+            dims[i] = ((bounds[1][i] - bounds[0][i]) / resolution).ceil().max(1.0) as usize;
+        }
+        let cell_count = dims[0] * dims[1] * dims[2];
+        Esdf {
+            origin: bounds[0],
+            resolution,
+            dims,
+            max_distance,
+            distance: vec![max_distance; cell_count],
+            nearest: vec![NO_OBSTACLE; cell_count],
+            obstacles: HashSet::new(),
+        }
+    }
+
+    pub fn resolution(&self) -> f32 {
+        self.resolution
+    }
+
+    pub fn max_distance(&self) -> f32 {
+        self.max_distance
+    }
+
+    /// Synchronise the obstacle cells with the occupied leaves of `octree`
+    /// and incrementally repair the distances around the cells that changed
+    pub fn update_from_octree(&mut self, octree: &Octree) {
+        let mut current = HashSet::new();
+        self.collect_obstacles(octree.get_root(), &mut current);
+
+        let removed: Vec<u32> = self.obstacles.difference(&current).copied().collect();
+        let inserted: Vec<u32> = current.difference(&self.obstacles).copied().collect();
+        self.obstacles = current;
+
+        let mut lower = VecDeque::new();
+        self.raise(&removed, &mut lower);
+        for index in inserted {
+            self.distance[index as usize] = 0.0;
+            self.nearest[index as usize] = index;
+            lower.push_back(index);
+        }
+        self.lower(lower);
+        self.fill_inside();
+    }
+
+    /// Rasterize the occupied leaves into grid cells whose centers fall inside the leaf
+    fn collect_obstacles(&self, node: &OctreeNode, cells: &mut HashSet<u32>) {
+        match node {
+            OctreeNode::Internal { children, .. } => {
+                for child in children.iter() {
+                    self.collect_obstacles(child, cells);
+                }
+            }
+            OctreeNode::Leaf { bounds, center, occupancy, .. } => {
+                if *occupancy != Occupancy::Occupied {
+                    return;
+                }
+                let mut min = [0usize; 3];
+                let mut max = [0usize; 3];
+                for i in 0..3 {
+                    let lo = ((bounds[0][i] - self.origin[i]) / self.resolution - 0.5).ceil();
+                    let hi = ((bounds[1][i] - self.origin[i]) / self.resolution - 0.5).floor();
+                    min[i] = lo.max(0.0) as usize;
+                    max[i] = hi.min(self.dims[i] as f32 - 1.0).max(0.0) as usize;
+                    if hi < lo || hi < 0.0 {
+                        // Leaf smaller than a cell: fall back to the cell holding its center
+                        if let Some(index) = self.cell_of(*center) {
+                            cells.insert(index);
+                        }
+                        return;
+                    }
+                }
+                for x in min[0]..=max[0] {
+                    for y in min[1]..=max[1] {
+                        for z in min[2]..=max[2] {
+                            cells.insert(self.index([x, y, z]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Invalidate every cell whose nearest obstacle was removed and queue the
+    /// still-valid cells on the border of the invalidated region for re-propagation
+    fn raise(&mut self, removed: &[u32], lower: &mut VecDeque<u32>) {
+        let mut queue: VecDeque<u32> = VecDeque::new();
+        for &index in removed {
+            self.distance[index as usize] = self.max_distance;
+            self.nearest[index as usize] = NO_OBSTACLE;
+            queue.push_back(index);
+        }
+
+        while let Some(index) = queue.pop_front() {
+            let coord = self.coord(index);
+            for neighbour in self.neighbours(coord) {
+                let nearest = self.nearest[neighbour as usize];
+                if nearest == NO_OBSTACLE {
+                    continue;
+                }
+                if !self.obstacles.contains(&nearest) {
+                    self.distance[neighbour as usize] = self.max_distance;
+                    self.nearest[neighbour as usize] = NO_OBSTACLE;
+                    queue.push_back(neighbour);
+                } else {
+                    lower.push_back(neighbour);
+                }
+            }
+        }
+    }
+
+    /// Breadth-first propagation of nearest-obstacle indices, stopping at `max_distance`
+    fn lower(&mut self, mut queue: VecDeque<u32>) {
+        while let Some(index) = queue.pop_front() {
+            let nearest = self.nearest[index as usize];
+            if nearest == NO_OBSTACLE {
+                continue;
+            }
+            let obstacle = self.cell_center(self.coord(nearest));
+            for neighbour in self.neighbours(self.coord(index)) {
+                let candidate = Self::distance_between(self.cell_center(self.coord(neighbour)), obstacle);
+                if candidate >= self.max_distance {
+                    continue;
+                }
+                if candidate < self.distance[neighbour as usize] {
+                    self.distance[neighbour as usize] = candidate;
+                    self.nearest[neighbour as usize] = nearest;
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+
+    /// Negative distance from every obstacle cell to the closest free cell center.
+    /// Recomputed over all obstacle cells, which costs about as much as collecting them.
+    fn fill_inside(&mut self) {
+        let mut queue = VecDeque::new();
+        let mut nearest_free: HashMap<u32, u32> = HashMap::new();
+        for &index in &self.obstacles {
+            self.distance[index as usize] = -self.max_distance;
+        }
+        // Free cells touching an obstacle seed the propagation inwards
+        for &index in &self.obstacles {
+            for neighbour in self.neighbours(self.coord(index)) {
+                if !self.obstacles.contains(&neighbour) && !nearest_free.contains_key(&neighbour) {
+                    nearest_free.insert(neighbour, neighbour);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+
+        while let Some(index) = queue.pop_front() {
+            let free = nearest_free[&index];
+            let free_center = self.cell_center(self.coord(free));
+            for neighbour in self.neighbours(self.coord(index)) {
+                if !self.obstacles.contains(&neighbour) {
+                    continue;
+                }
+                let candidate = Self::distance_between(self.cell_center(self.coord(neighbour)), free_center);
+                if candidate < self.max_distance && -candidate > self.distance[neighbour as usize] {
+                    self.distance[neighbour as usize] = -candidate;
+                    nearest_free.insert(neighbour, free);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+
+    /// Signed distance to the nearest obstacle at the center of the cell holding `point`
+    pub fn cell_distance(&self, point: [f32; 3]) -> f32 {
+        match self.cell_of(point) {
+            Some(index) => self.distance[index as usize],
+            None => self.max_distance,
+        }
+    }
+
+    /// Trilinearly interpolated signed distance to the nearest obstacle, negative inside
+    pub fn distance(&self, point: [f32; 3]) -> f32 {
+        self.distance_and_gradient(point).0
+    }
+
+    /// Trilinearly interpolated distance gradient, pointing away from obstacles
+    pub fn gradient(&self, point: [f32; 3]) -> [f32; 3] {
+        self.distance_and_gradient(point).1
+    }
+
+    /// Interpolate distance and its gradient from the 8 cell centers surrounding `point`.
+    /// Points outside the grid report `max_distance` and a zero gradient.
+    pub fn distance_and_gradient(&self, point: [f32; 3]) -> (f32, [f32; 3]) {
+        let mut base = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+        for i in 0..3 {
+            let g = (point[i] - self.origin[i]) / self.resolution - 0.5;
+            if g < -0.5 || g > self.dims[i] as f32 - 0.5 {
+                return (self.max_distance, [0.0; 3]);
+            }
+            // Clamp so both interpolation corners stay inside the grid
+            let g = g.clamp(0.0, (self.dims[i] as f32 - 1.0).max(0.0));
+            let b = (g.floor() as usize).min(self.dims[i].saturating_sub(2));
+            base[i] = b;
+            frac[i] = if self.dims[i] > 1 { g - b as f32 } else { 0.0 };
+        }
+
+        let mut c = [[[0.0f32; 2]; 2]; 2];
+        for dx in 0..2 {
+            for dy in 0..2 {
+                for dz in 0..2 {
+                    let coord = [
+                        (base[0] + dx).min(self.dims[0] - 1),
+                        (base[1] + dy).min(self.dims[1] - 1),
+                        (base[2] + dz).min(self.dims[2] - 1),
+                    ];
+                    c[dx][dy][dz] = self.distance[self.index(coord) as usize];
+                }
+            }
+        }
+
+        let [fx, fy, fz] = frac;
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        let c00 = lerp(c[0][0][0], c[1][0][0], fx);
+        let c10 = lerp(c[0][1][0], c[1][1][0], fx);
+        let c01 = lerp(c[0][0][1], c[1][0][1], fx);
+        let c11 = lerp(c[0][1][1], c[1][1][1], fx);
+        let c0 = lerp(c00, c10, fy);
+        let c1 = lerp(c01, c11, fy);
+        let distance = lerp(c0, c1, fz);
+
+        // Partial derivatives of the trilinear interpolant
+        let dx = lerp(
+            lerp(c[1][0][0] - c[0][0][0], c[1][1][0] - c[0][1][0], fy),
+            lerp(c[1][0][1] - c[0][0][1], c[1][1][1] - c[0][1][1], fy),
+            fz,
+        );
+        let dy = lerp(c10 - c00, c11 - c01, fz);
+        let dz = c1 - c0;
+
+        let gradient = [
+            dx / self.resolution,
+            dy / self.resolution,
+            dz / self.resolution,
+        ];
+        (distance, gradient)
+    }
+
+    fn cell_of(&self, point: [f32; 3]) -> Option<u32> {
+        let mut coord = [0usize; 3];
+        for i in 0..3 {
+            let g = ((point[i] - self.origin[i]) / self.resolution).floor();
+            if g < 0.0 || g >= self.dims[i] as f32 {
+                return None;
+            }
+            coord[i] = g as usize;
+        }
+        Some(self.index(coord))
+    }
+
+    fn index(&self, coord: [usize; 3]) -> u32 {
+        (coord[0] + self.dims[0] * (coord[1] + self.dims[1] * coord[2])) as u32
+    }
+
+    fn coord(&self, index: u32) -> [usize; 3] {
+        let index = index as usize;
+        [
+            index % self.dims[0],
+            (index / self.dims[0]) % self.dims[1],
+            index / (self.dims[0] * self.dims[1]),
+        ]
+    }
+
+    fn cell_center(&self, coord: [usize; 3]) -> [f32; 3] {
+        [
+            self.origin[0] + (coord[0] as f32 + 0.5) * self.resolution,
+            self.origin[1] + (coord[1] as f32 + 0.5) * self.resolution,
+            self.origin[2] + (coord[2] as f32 + 0.5) * self.resolution,
+        ]
+    }
+
+    /// Indices of the 26-connected neighbours of a cell
+    fn neighbours(&self, coord: [usize; 3]) -> Vec<u32> {
+        let mut result = Vec::with_capacity(26);
+        for dx in -1i64..=1 {
+            for dy in -1i64..=1 {
+                for dz in -1i64..=1 {
+                    if dx == 0 && dy == 0 && dz == 0 {
+                        continue;
+                    }
+                    let x = coord[0] as i64 + dx;
+                    let y = coord[1] as i64 + dy;
+                    let z = coord[2] as i64 + dz;
+                    if x < 0 || y < 0 || z < 0
+                        || x >= self.dims[0] as i64
+                        || y >= self.dims[1] as i64
+                        || z >= self.dims[2] as i64
+                    {
+                        continue;
+                    }
+                    result.push(self.index([x as usize, y as usize, z as usize]));
+                }
+            }
+        }
+        result
+    }
+
+    fn distance_between(a: [f32; 3], b: [f32; 3]) -> f32 {
+        let dx = a[0] - b[0];
+        let dy = a[1] - b[1];
+        let dz = a[2] - b[2];
+        (dx * dx + dy * dy + dz * dz).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: [[f32; 3]; 2] = [[0.0; 3], [4.0; 3]];
+    const RESOLUTION: f32 = 0.25;
+
+    /// An octree whose depth 4 leaves match the ESDF cells one to one
+    fn octree_with(cells: &[[usize; 3]]) -> Octree {
+        let mut octree = Octree::new(BOUNDS);
+        for cell in cells {
+            let point = cell.map(|c| (c as f32 + 0.5) * RESOLUTION);
+            octree.insert(point, 4, 100).unwrap();
+        }
+        octree
+    }
+
+    fn block(min: [usize; 3], max: [usize; 3]) -> Vec<[usize; 3]> {
+        let mut cells = Vec::new();
+        for x in min[0]..=max[0] {
+            for y in min[1]..=max[1] {
+                for z in min[2]..=max[2] {
+                    cells.push([x, y, z]);
+                }
+            }
+        }
+        cells
+    }
+
+    fn built(cells: &[[usize; 3]]) -> Esdf {
+        let mut esdf = Esdf::new(BOUNDS, RESOLUTION, 1.0);
+        esdf.update_from_octree(&octree_with(cells));
+        esdf
+    }
+
+    fn center(cell: [usize; 3]) -> [f32; 3] {
+        cell.map(|c| (c as f32 + 0.5) * RESOLUTION)
+    }
+
+    #[test]
+    fn free_cells_hold_the_distance_to_the_closest_obstacle() {
+        let esdf = built(&[[8, 8, 8]]);
+        assert_eq!(esdf.cell_distance(center([8, 8, 8])), -RESOLUTION);
+        assert_eq!(esdf.cell_distance(center([9, 8, 8])), RESOLUTION);
+        assert!((esdf.cell_distance(center([10, 9, 8])) - RESOLUTION * 5f32.sqrt()).abs() < 1e-5);
+        // Capped at max_distance
+        assert_eq!(esdf.cell_distance(center([0, 0, 0])), 1.0);
+    }
+
+    #[test]
+    fn distance_is_negative_deeper_inside_obstacles() {
+        let esdf = built(&block([4, 4, 4], [12, 12, 12]));
+        let surface = esdf.cell_distance(center([4, 8, 8]));
+        let inside = esdf.cell_distance(center([6, 8, 8]));
+        let core = esdf.cell_distance(center([8, 8, 8]));
+        assert_eq!(surface, -RESOLUTION);
+        assert!((inside - -3.0 * RESOLUTION).abs() < 1e-5);
+        assert!(core < inside);
+
+        // The gradient still points out of the obstacle deep inside it
+        let gradient = esdf.gradient([1.6, 2.05, 2.05]);
+        assert!(gradient[0] < 0.0, "{:?}", gradient);
+    }
+
+    #[test]
+    fn interpolates_between_cell_centers() {
+        let esdf = built(&[[8, 8, 8]]);
+        let a = center([9, 8, 8]);
+        let b = center([10, 8, 8]);
+        let halfway = [(a[0] + b[0]) / 2.0, a[1], a[2]];
+        let expected = (esdf.cell_distance(a) + esdf.cell_distance(b)) / 2.0;
+        assert!((esdf.distance(halfway) - expected).abs() < 1e-5);
+        assert!((esdf.distance(a) - esdf.cell_distance(a)).abs() < 1e-5);
+
+        // Along x the field grows by one resolution per cell, so the slope is 1
+        let (_, gradient) = esdf.distance_and_gradient(halfway);
+        assert!((gradient[0] - 1.0).abs() < 1e-4, "{:?}", gradient);
+        // The zero crossing lies on the face of the obstacle cell
+        assert!(esdf.distance([2.25, a[1], a[2]]).abs() < 1e-5);
+        // Outside the grid
+        assert_eq!(esdf.distance_and_gradient([-1.0, 2.0, 2.0]), (1.0, [0.0; 3]));
+    }
+
+    #[test]
+    fn incremental_lowering_matches_a_fresh_build() {
+        let first = block([4, 4, 4], [5, 10, 10]);
+        let mut second = first.clone();
+        second.extend(block([11, 2, 2], [11, 6, 6]));
+
+        let mut esdf = built(&first);
+        esdf.update_from_octree(&octree_with(&second));
+        assert_eq!(esdf.distance, built(&second).distance);
+    }
+
+    #[test]
+    fn incremental_raising_matches_a_fresh_build() {
+        let first = [block([4, 4, 4], [5, 10, 10]), block([11, 2, 2], [11, 6, 6])].concat();
+        let second = block([4, 4, 4], [5, 10, 10]);
+
+        let mut esdf = built(&first);
+        esdf.update_from_octree(&octree_with(&second));
+        assert_eq!(esdf.distance, built(&second).distance);
+
+        esdf.update_from_octree(&octree_with(&[]));
+        assert!(esdf.distance.iter().all(|distance| *distance == 1.0));
+    }
+}
@@ -0,0 +1,336 @@
+#![allow(dead_code)]
+use std::collections::HashMap;
+use bevy::ecs::system::Resource;
+
+use crate::data_reader::structor::LaserPoint;
+use crate::octree::octree::{Octree, OctreeNode};
+
+/// Deepest level a locational code fits in a `u64` (1 marker bit + 3 bits per level)
+pub const LINEAR_OCTREE_MAX_DEPTH: u32 = 21;
+
+const ROOT_KEY: u64 = 1;
+
+/// Hashed linear octree keyed by Morton locational codes.
+///
+/// A key is a leading marker bit followed by one 3-bit child index per level, so
+/// `key >> 3` is the parent and `(key << 3) | i` is child `i`. The child index uses the same
+/// x = 1, y = 2, z = 4 bit order as `Octree`. Only occupied leaves are stored; free space is
+/// implicit. `branches` counts the occupied leaves below every inner key so that queries can
+/// skip empty subtrees without walking them.
+///
+/// All points of one octree are expected to be inserted with the same `max_depth`.
+#[derive(Debug, Resource)]
+pub struct LinearOctree {
+    bounds: [[f32; 3]; 2],
+    leaves: HashMap<u64, [u32; 2]>,
+    branches: HashMap<u64, u32>,
+}
+
+impl LinearOctree {
+    pub fn new(bounds: [[f32; 3]; 2]) -> Self {
+        LinearOctree {
+            bounds,
+            leaves: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    /// Number of occupied leaves
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn insert(
+        &mut self,
+        point: [f32; 3],
+        max_depth: u32,
+        point_reflectivity: u8
+    ) -> Result<(), String> {
+        if max_depth > LINEAR_OCTREE_MAX_DEPTH {
+            return Err(format!(
+                "max_depth {} exceeds linear octree limit {}",
+                max_depth, LINEAR_OCTREE_MAX_DEPTH
+            ));
+        }
+        let key = match self.key_at(point, max_depth) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        // An occupied ancestor (left behind by optimize) absorbs the point
+        let mut ancestor = key;
+        loop {
+            if let Some(reflectivity) = self.leaves.get_mut(&ancestor) {
+                reflectivity[0] += point_reflectivity as u32;
+                reflectivity[1] += 1;
+                return Ok(());
+            }
+            if ancestor == ROOT_KEY {
+                break;
+            }
+            ancestor >>= 3;
+        }
+
+        self.add_leaf(key, [point_reflectivity as u32, 1]);
+        Ok(())
+    }
+
+    /// Locational code of the cell holding `point` at `depth`, or `None` if out of bounds.
+    /// Descends with the same center arithmetic as `Octree`, so every point lands in the same
+    /// cell as it would there, including points exactly on a center, which go to the lower child.
+    fn key_at(&self, point: [f32; 3], depth: u32) -> Option<u64> {
+        let epsilon = 0.00001;
+        for i in 0..3 {
+            if point[i] < self.bounds[0][i] - epsilon || point[i] > self.bounds[1][i] + epsilon {
+                return None;
+            }
+        }
+
+        let mut bounds = self.bounds;
+        let mut key = ROOT_KEY;
+        for _ in 0..depth {
+            let center = [0, 1, 2].map(|i| (bounds[0][i] + bounds[1][i]) / 2.0);
+            let child = Octree::get_index(&center, point);
+            bounds = Octree::calculate_child_bounds(&bounds, &center, child);
+            key = (key << 3) | child as u64;
+        }
+        Some(key)
+    }
+
+    fn depth_of(key: u64) -> u32 {
+        (63 - key.leading_zeros()) / 3
+    }
+
+    /// AABB of the cell addressed by `key`
+    fn key_bounds(&self, key: u64) -> [[f32; 3]; 2] {
+        let depth = Self::depth_of(key);
+        let mut index = [0u64; 3];
+        for level in 0..depth {
+            let child = (key >> (3 * level)) & 7;
+            index[0] |= (child & 1) << level;
+            index[1] |= ((child >> 1) & 1) << level;
+            index[2] |= ((child >> 2) & 1) << level;
+        }
+
+        let cells = (1u64 << depth) as f32;
+        let mut bounds = [[0.0; 3]; 2];
+        for i in 0..3 {
+            let size = (self.bounds[1][i] - self.bounds[0][i]) / cells;
+            bounds[0][i] = self.bounds[0][i] + index[i] as f32 * size;
+            bounds[1][i] = bounds[0][i] + size;
+        }
+        bounds
+    }
+
+    fn key_center(&self, key: u64) -> [f32; 3] {
+        let bounds = self.key_bounds(key);
+        [
+            (bounds[0][0] + bounds[1][0]) / 2.0,
+            (bounds[0][1] + bounds[1][1]) / 2.0,
+            (bounds[0][2] + bounds[1][2]) / 2.0,
+        ]
+    }
+
+    fn add_leaf(&mut self, key: u64, reflectivity: [u32; 2]) {
+        self.leaves.insert(key, reflectivity);
+        let mut ancestor = key;
+        while ancestor != ROOT_KEY {
+            ancestor >>= 3;
+            *self.branches.entry(ancestor).or_insert(0) += 1;
+        }
+    }
+
+    fn remove_leaf(&mut self, key: u64) -> Option<[u32; 2]> {
+        let reflectivity = self.leaves.remove(&key)?;
+        let mut ancestor = key;
+        while ancestor != ROOT_KEY {
+            ancestor >>= 3;
+            if let Some(count) = self.branches.get_mut(&ancestor) {
+                *count -= 1;
+                if *count == 0 {
+                    self.branches.remove(&ancestor);
+                }
+            }
+        }
+        Some(reflectivity)
+    }
+
+    pub fn octree_to_map(&self) -> HashMap<u32, Vec<LaserPoint>> {
+        let mut meshes = HashMap::new();
+        for (key, reflectivity) in &self.leaves {
+            let center = self.key_center(*key);
+            let reflectivity = (reflectivity[0] as f32 / reflectivity[1] as f32).round() as u8;
+            let data = LaserPoint::new(center[0], center[1], center[2], reflectivity);
+            meshes.entry(Self::depth_of(*key)).or_insert_with(Vec::new).push(data);
+        }
+        meshes
+    }
+
+    pub fn refresh(&mut self) {
+        self.leaves.clear();
+        self.branches.clear();
+    }
+
+    pub fn cast_ray(&self, origin: [f32; 3], direction: [f32; 3], max_distance: f32) -> Option<f32> {
+        let mut closest = None;
+        if !Octree::is_finite_point(origin) || !Octree::is_finite_point(direction) {
+            return closest;
+        }
+        self.cast_ray_internal(ROOT_KEY, origin, direction, max_distance, &mut closest);
+        closest
+    }
+
+    fn cast_ray_internal(
+        &self,
+        key: u64,
+        origin: [f32; 3],
+        direction: [f32; 3],
+        max_distance: f32,
+        closest: &mut Option<f32>,
+    ) {
+        let bounds = self.key_bounds(key);
+        let (t_enter, t_exit) = match OctreeNode::aabb_ray_intersection(&bounds, origin, direction) {
+            Some((enter, exit)) => (enter.max(0.0), exit),
+            None => return,
+        };
+        let current_max = closest.map_or(max_distance, |t| t.min(max_distance));
+        if t_enter > current_max || t_exit < 0.0 {
+            return;
+        }
+
+        if self.leaves.contains_key(&key) {
+            *closest = Some(t_enter);
+            return;
+        }
+        if !self.branches.contains_key(&key) {
+            return;
+        }
+
+        let mut candidates: Vec<(f32, u64)> = (0..8)
+            .map(|i| (key << 3) | i)
+            .filter(|child| self.leaves.contains_key(child) || self.branches.contains_key(child))
+            .filter_map(|child| {
+                OctreeNode::aabb_ray_intersection(&self.key_bounds(child), origin, direction)
+                    .map(|(enter, _)| (enter.max(0.0), child))
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        for (_, child) in candidates {
+            self.cast_ray_internal(child, origin, direction, max_distance, closest);
+        }
+    }
+
+    /// Find the `k` occupied leaves closest to `point`, sorted by ascending distance.
+    /// Empty for a NaN or infinite `point`.
+    pub fn k_nearest(&self, point: [f32; 3], k: usize) -> Vec<(f32, [f32; 3])> {
+        let mut result = Vec::with_capacity(k);
+        if k > 0 && Octree::is_finite_point(point) {
+            self.k_nearest_internal(ROOT_KEY, point, k, &mut result);
+        }
+        result
+    }
+
+    fn k_nearest_internal(&self, key: u64, point: [f32; 3], k: usize, result: &mut Vec<(f32, [f32; 3])>) {
+        if self.leaves.contains_key(&key) {
+            let center = self.key_center(key);
+            let distance = Self::point_distance(point, center);
+            if result.len() == k && distance >= result[k - 1].0 {
+                return;
+            }
+            let index = result.partition_point(|(d, _)| *d <= distance);
+            result.insert(index, (distance, center));
+            result.truncate(k);
+            return;
+        }
+        if !self.branches.contains_key(&key) {
+            return;
+        }
+
+        let mut candidates: Vec<(f32, u64)> = (0..8)
+            .map(|i| (key << 3) | i)
+            .filter(|child| self.leaves.contains_key(child) || self.branches.contains_key(child))
+            .map(|child| (OctreeNode::aabb_point_distance(&self.key_bounds(child), point), child))
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        for (box_distance, child) in candidates {
+            if result.len() == k && box_distance > result[k - 1].0 {
+                break;
+            }
+            self.k_nearest_internal(child, point, k, result);
+        }
+    }
+
+    /// Find all occupied leaves whose center lies within `radius` of `point`, none for a NaN
+    /// or infinite `point`
+    pub fn radius_search(&self, point: [f32; 3], radius: f32) -> Vec<(f32, [f32; 3])> {
+        let mut result = Vec::new();
+        if !Octree::is_finite_point(point) {
+            return result;
+        }
+        self.radius_search_internal(ROOT_KEY, point, radius, &mut result);
+        result
+    }
+
+    fn radius_search_internal(&self, key: u64, point: [f32; 3], radius: f32, result: &mut Vec<(f32, [f32; 3])>) {
+        if OctreeNode::aabb_point_distance(&self.key_bounds(key), point) > radius {
+            return;
+        }
+        if self.leaves.contains_key(&key) {
+            let center = self.key_center(key);
+            let distance = Self::point_distance(point, center);
+            if distance <= radius {
+                result.push((distance, center));
+            }
+            return;
+        }
+        if !self.branches.contains_key(&key) {
+            return;
+        }
+        for i in 0..8 {
+            self.radius_search_internal((key << 3) | i, point, radius, result);
+        }
+    }
+
+    pub fn nearest_obstacle_distance(&self, point: [f32; 3]) -> Option<f32> {
+        self.k_nearest(point, 1).first().map(|(distance, _)| *distance)
+    }
+
+    /// Merge every group of 8 occupied siblings into their parent, deepest level first
+    pub fn optimize(&mut self) {
+        let deepest = self.leaves.keys().map(|key| Self::depth_of(*key)).max().unwrap_or(0);
+        for depth in (1..=deepest).rev() {
+            let parents: Vec<u64> = self.leaves
+                .keys()
+                .filter(|key| Self::depth_of(**key) == depth)
+                .map(|key| key >> 3)
+                .collect();
+            for parent in parents {
+                let all_occupied = (0..8).all(|i| self.leaves.contains_key(&((parent << 3) | i)));
+                if !all_occupied {
+                    continue;
+                }
+                let mut reflectivity = [0, 0];
+                for i in 0..8 {
+                    if let Some(child) = self.remove_leaf((parent << 3) | i) {
+                        reflectivity[0] += child[0];
+                        reflectivity[1] += child[1];
+                    }
+                }
+                self.add_leaf(parent, reflectivity);
+            }
+        }
+    }
+
+    fn point_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+        let dx = a[0] - b[0];
+        let dy = a[1] - b[1];
+        let dz = a[2] - b[2];
+        (dx * dx + dy * dy + dz * dz).sqrt()
+    }
+}
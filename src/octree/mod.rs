@@ -1,2 +1,8 @@
 pub mod octree;
-pub mod creat_octree;
\ No newline at end of file
+pub mod creat_octree;
+pub mod esdf;
+pub mod linear_octree;
+pub mod octree_backend;
+pub mod octree_benchmark;
+pub mod octree_file;
+pub mod octomap;
\ No newline at end of file
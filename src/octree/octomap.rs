@@ -0,0 +1,585 @@
+#![allow(dead_code)]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+
+use crate::octree::octree::{Occupancy, Octree, OctreeNode};
+
+// OctoMap addresses cells with 16-bit keys per axis; key 32768 is the cell whose
+// minimum corner sits at the origin, and child indices use the same x = 1, y = 2, z = 4 order.
+const TREE_DEPTH: u32 = 16;
+const KEY_OFFSET: i64 = 32768;
+
+// Default OcTree sensor model, in log-odds
+const LOG_ODDS_HIT: f32 = 0.847298; // p = 0.7
+const CLAMPING_MAX: f32 = 3.476099; // p = 0.97
+const CLAMPING_MIN: f32 = -1.996998; // p = 0.12
+
+const BT_HEADER: &str = "# Octomap OcTree binary file";
+const OT_HEADER: &str = "# Octomap OcTree file";
+
+/// In-memory OctoMap tree used as the intermediate form for import and export
+#[derive(Debug)]
+struct OcTreeNode {
+    log_odds: f32,
+    children: Option<Box<[Option<OcTreeNode>; 8]>>,
+}
+
+impl OcTreeNode {
+    fn new(log_odds: f32) -> Self {
+        OcTreeNode { log_odds, children: None }
+    }
+
+    fn is_occupied(&self) -> bool {
+        self.log_odds > 0.0
+    }
+
+    fn child_mut(&mut self, index: usize) -> &mut OcTreeNode {
+        let children = self.children.get_or_insert_with(|| Box::new(Default::default()));
+        children[index].get_or_insert_with(|| OcTreeNode::new(0.0))
+    }
+
+    fn size(&self) -> u64 {
+        1 + self.children.as_ref().map_or(0, |children| {
+            children.iter().flatten().map(|child| child.size()).sum()
+        })
+    }
+
+    /// Inner nodes carry the maximum log-odds of their children, as in OctoMap
+    fn update_inner_occupancy(&mut self) {
+        if let Some(children) = self.children.as_mut() {
+            let mut max = f32::NEG_INFINITY;
+            for child in children.iter_mut().flatten() {
+                child.update_inner_occupancy();
+                max = max.max(child.log_odds);
+            }
+            self.log_odds = max;
+        }
+    }
+}
+
+/// Resolution (leaf size) of an octree whose finest leaves sit at `max_depth`
+pub fn octree_resolution(octree: &Octree, max_depth: u32) -> f32 {
+    let bounds = octree.get_root().bounds();
+    (bounds[1][0] - bounds[0][0]) / (1u32 << max_depth) as f32
+}
+
+pub fn save_bt(octree: &Octree, max_depth: u32, file_name: &str) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(file_name)?);
+    write_bt(octree, max_depth, &mut writer)?;
+    writer.flush()
+}
+
+pub fn save_ot(octree: &Octree, max_depth: u32, file_name: &str) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(file_name)?);
+    write_ot(octree, max_depth, &mut writer)?;
+    writer.flush()
+}
+
+pub fn load_bt(file_name: &str, max_depth: u32) -> std::io::Result<Octree> {
+    read_bt(&mut BufReader::new(File::open(file_name)?), max_depth)
+}
+
+pub fn load_ot(file_name: &str, max_depth: u32) -> std::io::Result<Octree> {
+    read_ot(&mut BufReader::new(File::open(file_name)?), max_depth)
+}
+
+/// Write a maximum-likelihood binary OctoMap (`.bt`) of the occupied leaves
+pub fn write_bt<W: Write>(octree: &Octree, max_depth: u32, writer: &mut W) -> std::io::Result<()> {
+    let (root, resolution) = build_octomap(octree, max_depth)?;
+    write_header(writer, BT_HEADER, tree_size(&root), resolution)?;
+    if tree_size(&root) == 0 {
+        return Ok(());
+    }
+    write_binary_node(&root, writer)
+}
+
+/// Write a full OctoMap (`.ot`) carrying the occupancy log-odds of every node
+pub fn write_ot<W: Write>(octree: &Octree, max_depth: u32, writer: &mut W) -> std::io::Result<()> {
+    let (root, resolution) = build_octomap(octree, max_depth)?;
+    write_header(writer, OT_HEADER, tree_size(&root), resolution)?;
+    if tree_size(&root) == 0 {
+        return Ok(());
+    }
+    write_full_node(&root, writer)
+}
+
+/// Read a binary OctoMap (`.bt`) into an `Octree` whose leaves at `max_depth` match its resolution
+pub fn read_bt<R: BufRead>(reader: &mut R, max_depth: u32) -> std::io::Result<Octree> {
+    let (size, resolution) = read_header(reader, BT_HEADER)?;
+    let mut root = OcTreeNode::new(0.0);
+    if size > 0 {
+        read_binary_node(reader, &mut root, 0)?;
+        root.update_inner_occupancy();
+    }
+    octomap_to_octree(&root, size, resolution, max_depth)
+}
+
+/// Read a full OctoMap (`.ot`) into an `Octree` whose leaves at `max_depth` match its resolution
+pub fn read_ot<R: BufRead>(reader: &mut R, max_depth: u32) -> std::io::Result<Octree> {
+    let (size, resolution) = read_header(reader, OT_HEADER)?;
+    let root = if size > 0 { read_full_node(reader, 0)? } else { OcTreeNode::new(0.0) };
+    octomap_to_octree(&root, size, resolution, max_depth)
+}
+
+/// Node count as OctoMap sees it, a tree without leaves has no root either
+fn tree_size(root: &OcTreeNode) -> u64 {
+    if root.children.is_none() {
+        0
+    } else {
+        root.size()
+    }
+}
+
+fn check_max_depth(max_depth: u32) -> std::io::Result<()> {
+    if max_depth == 0 || max_depth > TREE_DEPTH {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("max_depth must be within 1..={} to map onto OctoMap keys", TREE_DEPTH),
+        ));
+    }
+    Ok(())
+}
+
+/// Convert the leaves of `octree` into an OctoMap tree.
+///
+/// The octree must be a cube centered on the origin so that its leaves line up with
+/// OctoMap's key grid; a leaf at depth `d` lands at OctoMap depth `16 - max_depth + d`.
+fn build_octomap(octree: &Octree, max_depth: u32) -> std::io::Result<(OcTreeNode, f32)> {
+    check_max_depth(max_depth)?;
+    let bounds = octree.get_root().bounds();
+    let size = bounds[1][0] - bounds[0][0];
+    let tolerance = size * 1e-4;
+    for i in 0..3 {
+        if (bounds[1][i] - bounds[0][i] - size).abs() > tolerance
+            || (bounds[0][i] + bounds[1][i]).abs() > tolerance
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "OctoMap export needs cubic octree bounds centered on the origin",
+            ));
+        }
+    }
+
+    let resolution = octree_resolution(octree, max_depth);
+    let mut root = OcTreeNode::new(0.0);
+    add_leaves(&mut root, octree.get_root(), resolution, max_depth);
+    root.update_inner_occupancy();
+    Ok((root, resolution))
+}
+
+fn add_leaves(root: &mut OcTreeNode, node: &OctreeNode, resolution: f32, max_depth: u32) {
+    match node {
+        OctreeNode::Internal { children, .. } => {
+            for child in children.iter() {
+                add_leaves(root, child, resolution, max_depth);
+            }
+        }
+        OctreeNode::Leaf { bounds, depth, occupancy, reflectivity, .. } => {
+            // A free leaf only means no point hit it, not that it was seen to be empty,
+            // so it stays unknown space in OctoMap
+            if *occupancy == Occupancy::Free {
+                return;
+            }
+            let log_odds = (reflectivity[1].max(1) as f32 * LOG_ODDS_HIT).min(CLAMPING_MAX);
+            let mut key = [0i64; 3];
+            for i in 0..3 {
+                key[i] = (bounds[0][i] / resolution).round() as i64 + KEY_OFFSET;
+            }
+            // Leaves finer than max_depth are folded into their ancestor cell
+            insert_leaf(root, key, (*depth).min(max_depth), max_depth, log_odds);
+        }
+    }
+}
+
+/// Insert a leaf whose minimum corner has `key` at octree depth `depth`
+fn insert_leaf(root: &mut OcTreeNode, key: [i64; 3], depth: u32, max_depth: u32, log_odds: f32) {
+    if depth == 0 {
+        // The root is centered on the origin and therefore spans 8 OctoMap nodes
+        let half = 1i64 << (max_depth - 1);
+        for index in 0..8 {
+            let mut child_key = key;
+            for (axis, k) in child_key.iter_mut().enumerate() {
+                if index & (1 << axis) != 0 {
+                    *k += half;
+                }
+            }
+            insert_leaf(root, child_key, 1, max_depth, log_odds);
+        }
+        return;
+    }
+
+    let target_depth = TREE_DEPTH - max_depth + depth;
+    let mut node = root;
+    for level in 0..target_depth {
+        let bit = TREE_DEPTH - 1 - level;
+        let mut index = 0;
+        for (axis, k) in key.iter().enumerate() {
+            if (k >> bit) & 1 != 0 {
+                index |= 1 << axis;
+            }
+        }
+        node = node.child_mut(index);
+    }
+    node.log_odds = log_odds;
+}
+
+fn octomap_to_octree(root: &OcTreeNode, size: u64, resolution: f32, max_depth: u32) -> std::io::Result<Octree> {
+    check_max_depth(max_depth)?;
+    if tree_size(root) != size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Tree size mismatch: header says {}, read {}", size, tree_size(root)),
+        ));
+    }
+
+    let boundary = resolution * (1u32 << (max_depth - 1)) as f32;
+    let mut octree = Octree::new([[-boundary; 3], [boundary; 3]]);
+    let half = 1i64 << (max_depth - 1);
+    let key_range = [KEY_OFFSET - half, KEY_OFFSET + half];
+    add_octomap_leaves(&mut octree, root, [0; 3], 0, resolution, max_depth, key_range);
+    Ok(octree)
+}
+
+fn add_octomap_leaves(
+    octree: &mut Octree,
+    node: &OcTreeNode,
+    key: [i64; 3],
+    om_depth: u32,
+    resolution: f32,
+    max_depth: u32,
+    key_range: [i64; 2],
+) {
+    let span = 1i64 << (TREE_DEPTH - om_depth);
+    if (0..3).any(|i| key[i] + span <= key_range[0] || key[i] >= key_range[1]) {
+        return;
+    }
+
+    let children = match node.children.as_ref() {
+        Some(children) => children,
+        None => {
+            if !node.is_occupied() {
+                return;
+            }
+            let depth = om_depth as i64 - (TREE_DEPTH - max_depth) as i64;
+            if depth < 1 {
+                // Leaf larger than the octree children: split it virtually until it fits
+                let half = span / 2;
+                for index in 0..8 {
+                    let mut child_key = key;
+                    for (axis, k) in child_key.iter_mut().enumerate() {
+                        if index & (1 << axis) != 0 {
+                            *k += half;
+                        }
+                    }
+                    add_octomap_leaves(octree, node, child_key, om_depth + 1, resolution, max_depth, key_range);
+                }
+                return;
+            }
+
+            let center = [
+                ((key[0] - KEY_OFFSET) as f32 + span as f32 / 2.0) * resolution,
+                ((key[1] - KEY_OFFSET) as f32 + span as f32 / 2.0) * resolution,
+                ((key[2] - KEY_OFFSET) as f32 + span as f32 / 2.0) * resolution,
+            ];
+            // OctoMap carries no reflectivity; keep the hit count implied by the log-odds
+            let hits = (node.log_odds / LOG_ODDS_HIT).ceil().max(1.0) as u32;
+            for _ in 0..hits {
+                let _ = octree.insert(center, depth as u32, 0);
+            }
+            return;
+        }
+    };
+
+    let half = span / 2;
+    for (index, child) in children.iter().enumerate() {
+        if let Some(child) = child {
+            let mut child_key = key;
+            for (axis, k) in child_key.iter_mut().enumerate() {
+                if index & (1 << axis) != 0 {
+                    *k += half;
+                }
+            }
+            add_octomap_leaves(octree, child, child_key, om_depth + 1, resolution, max_depth, key_range);
+        }
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, first_line: &str, size: u64, resolution: f32) -> std::io::Result<()> {
+    writeln!(writer, "{}", first_line)?;
+    writeln!(writer, "# (feel free to add / change comments, but leave the first line as it is!)")?;
+    writeln!(writer, "#")?;
+    writeln!(writer, "id OcTree")?;
+    writeln!(writer, "size {}", size)?;
+    writeln!(writer, "res {}", resolution as f64)?;
+    writeln!(writer, "data")
+}
+
+/// Parse the text header up to the `data` line and return (size, resolution)
+fn read_header<R: BufRead>(reader: &mut R, first_line: &str) -> std::io::Result<(u64, f32)> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.trim_end().starts_with(first_line) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Expected header \"{}\"", first_line)));
+    }
+
+    let mut id = None;
+    let mut size = None;
+    let mut resolution = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "OctoMap header has no data line"));
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let mut fields = trimmed.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("data"), _) => break,
+            (Some("id"), Some(value)) => id = Some(value.to_string()),
+            (Some("size"), Some(value)) => size = value.parse::<u64>().ok(),
+            (Some("res"), Some(value)) => resolution = value.parse::<f64>().ok(),
+            _ => {}
+        }
+    }
+
+    if id.as_deref() != Some("OcTree") {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported OctoMap tree type {:?}, only OcTree is supported", id),
+        ));
+    }
+    match (size, resolution) {
+        (Some(size), Some(resolution)) if resolution > 0.0 => Ok((size, resolution as f32)),
+        _ => Err(Error::new(ErrorKind::InvalidData, "OctoMap header is missing size or res")),
+    }
+}
+
+fn write_binary_node<W: Write>(node: &OcTreeNode, writer: &mut W) -> std::io::Result<()> {
+    let children = match node.children.as_ref() {
+        Some(children) => children,
+        None => return Ok(()),
+    };
+
+    // Two bits per child, child 0 in the low bits: 10 occupied leaf, 01 free leaf, 11 inner node
+    let mut bits: u16 = 0;
+    for (index, child) in children.iter().enumerate() {
+        if let Some(child) = child {
+            let code = if child.children.is_some() {
+                0b11
+            } else if child.is_occupied() {
+                0b10
+            } else {
+                0b01
+            };
+            bits |= code << (index * 2);
+        }
+    }
+    writer.write_u8((bits & 0xFF) as u8)?;
+    writer.write_u8((bits >> 8) as u8)?;
+
+    for child in children.iter().flatten() {
+        if child.children.is_some() {
+            write_binary_node(child, writer)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_binary_node<R: Read>(reader: &mut R, node: &mut OcTreeNode, depth: u32) -> std::io::Result<()> {
+    if depth >= TREE_DEPTH {
+        return Err(Error::new(ErrorKind::InvalidData, "OctoMap tree deeper than 16 levels"));
+    }
+    let low = reader.read_u8()? as u16;
+    let high = reader.read_u8()? as u16;
+    let bits = low | (high << 8);
+
+    let mut inner = Vec::new();
+    for index in 0..8 {
+        match (bits >> (index * 2)) & 0b11 {
+            0b10 => node.child_mut(index).log_odds = CLAMPING_MAX,
+            0b01 => node.child_mut(index).log_odds = CLAMPING_MIN,
+            0b11 => {
+                node.child_mut(index);
+                inner.push(index);
+            }
+            _ => {}
+        }
+    }
+
+    for index in inner {
+        read_binary_node(reader, node.child_mut(index), depth + 1)?;
+    }
+    Ok(())
+}
+
+fn write_full_node<W: Write>(node: &OcTreeNode, writer: &mut W) -> std::io::Result<()> {
+    writer.write_f32::<LittleEndian>(node.log_odds)?;
+    let mut bits: u8 = 0;
+    if let Some(children) = node.children.as_ref() {
+        for (index, child) in children.iter().enumerate() {
+            if child.is_some() {
+                bits |= 1 << index;
+            }
+        }
+    }
+    writer.write_u8(bits)?;
+
+    if let Some(children) = node.children.as_ref() {
+        for child in children.iter().flatten() {
+            write_full_node(child, writer)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_full_node<R: Read>(reader: &mut R, depth: u32) -> std::io::Result<OcTreeNode> {
+    if depth > TREE_DEPTH {
+        return Err(Error::new(ErrorKind::InvalidData, "OctoMap tree deeper than 16 levels"));
+    }
+    let mut node = OcTreeNode::new(reader.read_f32::<LittleEndian>()?);
+    let bits = reader.read_u8()?;
+    for index in 0..8 {
+        if bits & (1 << index) != 0 {
+            let child = read_full_node(reader, depth + 1)?;
+            *node.child_mut(index) = child;
+        }
+    }
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_reader::structor::LaserPoint;
+
+    const MAX_DEPTH: u32 = 5;
+
+    fn test_octree() -> Octree {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        let mut points = Vec::new();
+        for i in 0..500 {
+            let t = i as f32 * 0.05;
+            points.push(LaserPoint::new(t.sin() * 7.0, t.cos() * 7.0, t * 0.7 - 9.0, 100));
+        }
+        // A solid block that optimize() merges into one coarser occupied leaf
+        for x in 0..2 {
+            for y in 0..2 {
+                for z in 0..2 {
+                    points.push(LaserPoint::new(1.5 + x as f32 * 0.625, 1.5 + y as f32 * 0.625, 1.5 + z as f32 * 0.625, 100));
+                }
+            }
+        }
+        octree.insert_batch(&points, MAX_DEPTH).unwrap();
+        octree.optimize();
+        octree
+    }
+
+    /// Occupied leaves as (depth, center) rounded to a tenth of a millimetre, sorted
+    fn occupied_leaves(octree: &Octree) -> Vec<(u32, [i64; 3])> {
+        let mut leaves: Vec<(u32, [i64; 3])> = octree
+            .octree_to_map()
+            .into_iter()
+            .flat_map(|(depth, points)| {
+                points.into_iter().map(move |point| (depth, [point.x, point.y, point.z].map(|c| (c * 1e4).round() as i64)))
+            })
+            .collect();
+        leaves.sort();
+        leaves
+    }
+
+    /// Every leaf of a tree read back from OctoMap data
+    fn octomap_leaves(node: &OcTreeNode, leaves: &mut Vec<f32>) {
+        match node.children.as_ref() {
+            Some(children) => children.iter().flatten().for_each(|child| octomap_leaves(child, leaves)),
+            None => leaves.push(node.log_odds),
+        }
+    }
+
+    #[test]
+    fn bt_round_trip_keeps_occupied_leaves() {
+        let octree = test_octree();
+        let mut buffer = Vec::new();
+        write_bt(&octree, MAX_DEPTH, &mut buffer).unwrap();
+        let loaded = read_bt(&mut buffer.as_slice(), MAX_DEPTH).unwrap();
+        assert_eq!(loaded.get_root().bounds(), octree.get_root().bounds());
+        assert_eq!(occupied_leaves(&loaded), occupied_leaves(&octree));
+        assert!(occupied_leaves(&octree).iter().any(|(depth, _)| *depth < MAX_DEPTH));
+    }
+
+    #[test]
+    fn ot_round_trip_keeps_occupied_leaves() {
+        let octree = test_octree();
+        let mut buffer = Vec::new();
+        write_ot(&octree, MAX_DEPTH, &mut buffer).unwrap();
+        let loaded = read_ot(&mut buffer.as_slice(), MAX_DEPTH).unwrap();
+        assert_eq!(occupied_leaves(&loaded), occupied_leaves(&octree));
+    }
+
+    #[test]
+    fn free_leaves_are_exported_as_unknown() {
+        let octree = test_octree();
+
+        let mut buffer = Vec::new();
+        write_bt(&octree, MAX_DEPTH, &mut buffer).unwrap();
+        let mut reader = buffer.as_slice();
+        read_header(&mut reader, BT_HEADER).unwrap();
+        let mut root = OcTreeNode::new(0.0);
+        read_binary_node(&mut reader, &mut root, 0).unwrap();
+        let mut leaves = Vec::new();
+        octomap_leaves(&root, &mut leaves);
+        assert!(!leaves.is_empty());
+        assert!(leaves.iter().all(|log_odds| *log_odds > 0.0));
+
+        let mut buffer = Vec::new();
+        write_ot(&octree, MAX_DEPTH, &mut buffer).unwrap();
+        let mut reader = buffer.as_slice();
+        read_header(&mut reader, OT_HEADER).unwrap();
+        let mut leaves = Vec::new();
+        octomap_leaves(&read_full_node(&mut reader, 0).unwrap(), &mut leaves);
+        assert!(leaves.iter().all(|log_odds| *log_odds > 0.0));
+    }
+
+    #[test]
+    fn empty_octree_is_an_empty_octomap() {
+        let octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        for write in [write_bt::<Vec<u8>>, write_ot::<Vec<u8>>] {
+            let mut buffer = Vec::new();
+            write(&octree, MAX_DEPTH, &mut buffer).unwrap();
+            assert!(String::from_utf8(buffer.clone()).unwrap().contains("size 0\n"));
+        }
+        let mut buffer = Vec::new();
+        write_bt(&octree, MAX_DEPTH, &mut buffer).unwrap();
+        assert!(occupied_leaves(&read_bt(&mut buffer.as_slice(), MAX_DEPTH).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn parses_header_fields_and_comments() {
+        let header = "# Octomap OcTree binary file\n# comment\n\nid OcTree\nsize 42\nres 0.05\ndata\nrest";
+        let mut reader = header.as_bytes();
+        let (size, resolution) = read_header(&mut reader, BT_HEADER).unwrap();
+        assert_eq!((size, resolution), (42, 0.05));
+        assert_eq!(reader, b"rest");
+
+        let parse = |text: &str| read_header(&mut text.as_bytes(), BT_HEADER).map_err(|e| e.kind());
+        assert_eq!(parse("# Octomap OcTree file\nid OcTree\nsize 1\nres 0.1\ndata\n"), Err(ErrorKind::InvalidData));
+        assert_eq!(parse("# Octomap OcTree binary file\nid ColorOcTree\nsize 1\nres 0.1\ndata\n"), Err(ErrorKind::InvalidData));
+        assert_eq!(parse("# Octomap OcTree binary file\nid OcTree\nsize 1\ndata\n"), Err(ErrorKind::InvalidData));
+        assert_eq!(parse("# Octomap OcTree binary file\nid OcTree\nsize 1\nres 0.1\n"), Err(ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn rejects_truncated_data_and_size_mismatch() {
+        let mut buffer = Vec::new();
+        write_bt(&test_octree(), MAX_DEPTH, &mut buffer).unwrap();
+        let truncated = &buffer[..buffer.len() - 3];
+        assert!(read_bt(&mut &truncated[..], MAX_DEPTH).is_err());
+
+        let text = String::from_utf8_lossy(&buffer).into_owned();
+        let size_line = text.lines().find(|line| line.starts_with("size ")).unwrap().to_string();
+        let wrong = text.replacen(&size_line, "size 3", 1);
+        assert!(read_bt(&mut wrong.as_bytes(), MAX_DEPTH).is_err());
+    }
+}
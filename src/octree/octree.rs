@@ -12,7 +12,7 @@ pub enum Occupancy {
     Occupied,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum OctreeNode {
     Internal {
         bounds: [[f32; 3]; 2],
@@ -71,7 +71,7 @@ impl OctreeNode {
                 }
 
                 // Sort children by their enter time to process closer ones first
-                candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
 
                 let mut closest = None;
                 for (child_enter, child) in candidates {
@@ -121,8 +121,23 @@ impl OctreeNode {
         }
     }
 
+    /// Shortest distance from a point to the AABB of the node (0.0 if inside)
+    pub fn distance_to_point(&self, point: [f32; 3]) -> f32 {
+        Self::aabb_point_distance(self.bounds(), point)
+    }
+
+    /// Shortest distance from a point to an AABB (0.0 if inside)
+    pub(crate) fn aabb_point_distance(bbox: &[[f32; 3]; 2], point: [f32; 3]) -> f32 {
+        let mut sum = 0.0;
+        for i in 0..3 {
+            let d = (bbox[0][i] - point[i]).max(0.0).max(point[i] - bbox[1][i]);
+            sum += d * d;
+        }
+        sum.sqrt()
+    }
+
     /// Determine if a ray intersects with an AABB and compute the intersection parameters
-    fn aabb_ray_intersection(bbox: &[[f32; 3]; 2], origin: [f32; 3], direction: [f32; 3]) -> Option<(f32, f32)> {
+    pub(crate) fn aabb_ray_intersection(bbox: &[[f32; 3]; 2], origin: [f32; 3], direction: [f32; 3]) -> Option<(f32, f32)> {
         let mut t_enter = -std::f32::INFINITY;
         let mut t_leave = std::f32::INFINITY;
 
@@ -183,6 +198,15 @@ impl Octree {
         &mut self.root
     }
 
+    pub fn get_root(&self) -> &OctreeNode {
+        &self.root
+    }
+
+    /// Wrap an already built node tree, e.g. one loaded from disk
+    pub(crate) fn from_root(root: OctreeNode) -> Self {
+        Octree { root }
+    }
+
     pub fn insert(
         &mut self,
         point: [f32; 3],
@@ -246,7 +270,94 @@ impl Octree {
         }
     }
 
-    fn get_index(
+    /// Insert a whole frame of points at once.
+    ///
+    /// Points are bucketed by octant and the top levels of the tree are filled by one
+    /// thread per subtree. The resulting tree is identical to inserting the points one by one
+    /// with `insert`.
+    pub fn insert_batch(
+        &mut self,
+        points: &[LaserPoint],
+        max_depth: u32,
+    ) -> Result<(), String> {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let parallel_levels = if cores > 8 { 2 } else if cores > 1 { 1 } else { 0 };
+        let points: Vec<&LaserPoint> = points.iter().collect();
+        Self::insert_batch_internal(&mut self.root, points, 0, max_depth, parallel_levels)
+    }
+
+    fn insert_batch_internal(
+        node: &mut OctreeNode,
+        points: Vec<&LaserPoint>,
+        current_depth: u32,
+        max_depth: u32,
+        parallel_levels: u32,
+    ) -> Result<(), String> {
+        // Subtrees smaller than this are not worth a thread
+        const MIN_POINTS_PER_THREAD: usize = 1024;
+
+        // point out-of-bounds check, same as insert_internal
+        let bounds = *node.bounds();
+        let epsilon = 0.00001;
+        let points: Vec<&LaserPoint> = points
+            .into_iter()
+            .filter(|point| {
+                let point = [point.x, point.y, point.z];
+                (0..3).all(|i| point[i] >= bounds[0][i] - epsilon && point[i] <= bounds[1][i] + epsilon)
+            })
+            .collect();
+        if points.is_empty() {
+            return Ok(());
+        }
+
+        if let OctreeNode::Leaf { occupancy, reflectivity, .. } = node {
+            if *occupancy == Occupancy::Occupied || current_depth >= max_depth {
+                *occupancy = Occupancy::Occupied;
+                for point in points {
+                    reflectivity[0] += point.reflectivity as u32;
+                    reflectivity[1] += 1;
+                }
+                return Ok(());
+            }
+            Self::split(node, current_depth)?;
+        }
+
+        let (center, children) = match node {
+            OctreeNode::Internal { center, children, .. } => (*center, children),
+            OctreeNode::Leaf { .. } => return Err("Leaf was not split".to_string()),
+        };
+
+        let mut buckets: [Vec<&LaserPoint>; 8] = Default::default();
+        for point in points {
+            buckets[Self::get_index(&center, [point.x, point.y, point.z])].push(point);
+        }
+
+        if parallel_levels == 0 {
+            for (child, bucket) in children.iter_mut().zip(buckets) {
+                Self::insert_batch_internal(child, bucket, current_depth + 1, max_depth, 0)?;
+            }
+            return Ok(());
+        }
+
+        std::thread::scope(|scope| {
+            let mut handles = Vec::new();
+            for (child, bucket) in children.iter_mut().zip(buckets) {
+                if bucket.len() < MIN_POINTS_PER_THREAD {
+                    Self::insert_batch_internal(child, bucket, current_depth + 1, max_depth, 0)?;
+                } else {
+                    handles.push(scope.spawn(move || {
+                        Self::insert_batch_internal(child, bucket, current_depth + 1, max_depth, parallel_levels - 1)
+                    }));
+                }
+            }
+            for handle in handles {
+                handle.join().map_err(|_| "Octree insertion thread panicked".to_string())??;
+            }
+            Ok(())
+        })
+    }
+
+    pub(crate) fn get_index(
         center: &[f32; 3],
         point: [f32; 3]
     ) -> usize {
@@ -286,7 +397,7 @@ impl Octree {
         Ok(())
     }
 
-    fn create_children(
+    pub(crate) fn create_children(
         parent_bounds: &[[f32; 3]; 2],
         parent_center: &[f32; 3],
         parent_depth: u32
@@ -320,7 +431,7 @@ impl Octree {
         children
     }
     
-    fn calculate_child_bounds(
+    pub(crate) fn calculate_child_bounds(
         parent_bounds: &[[f32; 3]; 2],
         parent_center: &[f32; 3],
         index: usize,
@@ -399,9 +510,156 @@ impl Octree {
 
     //TODO: Implement ray casting
     pub fn cast_ray(&self, origin: [f32; 3], direction: [f32; 3], max_distance: f32) -> Option<f32> {
+        if !Self::is_finite_point(origin) || !Self::is_finite_point(direction) {
+            return None;
+        }
         self.root.cast_ray(origin, direction, max_distance, &mut None)
     }
 
+    /// Find the `k` occupied leaves closest to `point`
+    ///
+    /// # Returns
+    /// A list of (distance, leaf center) sorted by ascending distance, empty for a NaN or
+    /// infinite `point`
+    pub fn k_nearest(&self, point: [f32; 3], k: usize) -> Vec<(f32, [f32; 3])> {
+        let mut result = Vec::with_capacity(k);
+        if k > 0 && Self::is_finite_point(point) {
+            Self::k_nearest_internal(&self.root, point, k, &mut result);
+        }
+        result
+    }
+
+    fn k_nearest_internal(
+        node: &OctreeNode,
+        point: [f32; 3],
+        k: usize,
+        result: &mut Vec<(f32, [f32; 3])>,
+    ) {
+        match node {
+            OctreeNode::Internal { children, .. } => {
+                // Visit closer children first so the k-th distance shrinks quickly
+                let mut candidates: Vec<(f32, &OctreeNode)> = children
+                    .iter()
+                    .map(|child| (child.distance_to_point(point), child.as_ref()))
+                    .collect();
+                candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                for (box_distance, child) in candidates {
+                    if result.len() == k && box_distance > result[k - 1].0 {
+                        break;
+                    }
+                    Self::k_nearest_internal(child, point, k, result);
+                }
+            }
+            OctreeNode::Leaf { center, occupancy, .. } => {
+                if *occupancy != Occupancy::Occupied {
+                    return;
+                }
+                let distance = Self::point_distance(point, *center);
+                if result.len() == k && distance >= result[k - 1].0 {
+                    return;
+                }
+                let index = result.partition_point(|(d, _)| *d <= distance);
+                result.insert(index, (distance, *center));
+                result.truncate(k);
+            }
+        }
+    }
+
+    /// Find all occupied leaves whose center lies within `radius` of `point`
+    ///
+    /// # Returns
+    /// A list of (distance, leaf center), in no particular order, empty for a NaN or infinite `point`
+    pub fn radius_search(&self, point: [f32; 3], radius: f32) -> Vec<(f32, [f32; 3])> {
+        let mut result = Vec::new();
+        if !Self::is_finite_point(point) {
+            return result;
+        }
+        Self::radius_search_internal(&self.root, point, radius, &mut result);
+        result
+    }
+
+    fn radius_search_internal(
+        node: &OctreeNode,
+        point: [f32; 3],
+        radius: f32,
+        result: &mut Vec<(f32, [f32; 3])>,
+    ) {
+        if node.distance_to_point(point) > radius {
+            return;
+        }
+        match node {
+            OctreeNode::Internal { children, .. } => {
+                for child in children.iter() {
+                    Self::radius_search_internal(child, point, radius, result);
+                }
+            }
+            OctreeNode::Leaf { center, occupancy, .. } => {
+                if *occupancy == Occupancy::Occupied {
+                    let distance = Self::point_distance(point, *center);
+                    if distance <= radius {
+                        result.push((distance, *center));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Distance from `point` to the center of the closest occupied leaf,
+    /// or `None` if the octree holds no obstacles or `point` is not finite
+    pub fn nearest_obstacle_distance(&self, point: [f32; 3]) -> Option<f32> {
+        self.k_nearest(point, 1).first().map(|(distance, _)| *distance)
+    }
+
+    /// Whether a body of radius `clearance` can move along the segment from `from` to `to`
+    /// without touching an occupied leaf. Casts the center line plus two rings of four rays
+    /// around it, at half and at the full clearance, so leaves between the rays are not missed.
+    pub fn segment_clear(&self, from: [f32; 3], to: [f32; 3], clearance: f32) -> bool {
+        let offset = [to[0] - from[0], to[1] - from[1], to[2] - from[2]];
+        let length = Self::point_distance(from, to);
+        if length <= f32::EPSILON {
+            return true;
+        }
+        let direction = offset.map(|d| d / length);
+        // Two unit vectors perpendicular to the segment
+        let helper = if direction[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+        let side = Self::normalized(Self::cross(direction, helper));
+        let up = Self::cross(direction, side);
+
+        let mut origins = vec![from];
+        for (ring, radius) in [(0, clearance / 2.0), (1, clearance)] {
+            for quarter in 0..4 {
+                let angle = (quarter as f32 + ring as f32 * 0.5) * std::f32::consts::FRAC_PI_2;
+                let (sin, cos) = angle.sin_cos();
+                origins.push([0, 1, 2].map(|i| from[i] + (side[i] * cos + up[i] * sin) * radius));
+            }
+        }
+        origins
+            .into_iter()
+            .all(|origin| self.cast_ray(origin, direction, length).is_none())
+    }
+
+    fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+        [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+    }
+
+    fn normalized(a: [f32; 3]) -> [f32; 3] {
+        let length = Self::point_distance(a, [0.0; 3]);
+        a.map(|x| x / length)
+    }
+
+    /// Whether every coordinate is neither NaN nor infinite, queries from other points find nothing
+    pub(crate) fn is_finite_point(point: [f32; 3]) -> bool {
+        point.iter().all(|c| c.is_finite())
+    }
+
+    fn point_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+        let dx = a[0] - b[0];
+        let dy = a[1] - b[1];
+        let dz = a[2] - b[2];
+        (dx * dx + dy * dy + dz * dz).sqrt()
+    }
+
     pub fn optimize(&mut self) {
         Self::optimize_recursive_internal(&mut self.root);
     }
@@ -467,4 +725,169 @@ impl Octree {
             OctreeNode::Internal { children, .. } => children.iter().all(|c| Self::is_fully_free(c)),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random frame, including points outside the bounds
+    /// and points lying exactly on octant boundaries
+    fn test_frame(count: usize, seed: u32) -> Vec<LaserPoint> {
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 8) as f32 / (1u32 << 24) as f32
+        };
+        let mut points = Vec::with_capacity(count);
+        for i in 0..count {
+            let x = next() * 24.0 - 12.0;
+            let y = next() * 24.0 - 12.0;
+            let z = if i % 10 == 0 { 0.0 } else { next() * 24.0 - 12.0 };
+            let reflectivity = (next() * 255.0) as u8;
+            points.push(LaserPoint::new(x, y, z, reflectivity));
+        }
+        points
+    }
+
+    fn sequential(points: &[LaserPoint], max_depth: u32) -> Octree {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        for point in points {
+            octree.insert([point.x, point.y, point.z], max_depth, point.reflectivity).unwrap();
+        }
+        octree
+    }
+
+    #[test]
+    fn batch_insert_matches_sequential_insert() {
+        let points = test_frame(20000, 7);
+        for max_depth in [1, 4, 7] {
+            let expected = sequential(&points, max_depth);
+            let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+            octree.insert_batch(&points, max_depth).unwrap();
+            assert_eq!(octree.root, expected.root, "max_depth {}", max_depth);
+        }
+    }
+
+    #[test]
+    fn threaded_batch_insert_matches_sequential_insert() {
+        // Force two threaded levels regardless of the number of cores on the test machine
+        let points = test_frame(40000, 3);
+        let expected = sequential(&points, 6);
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        Octree::insert_batch_internal(&mut octree.root, points.iter().collect(), 0, 6, 2).unwrap();
+        assert_eq!(octree.root, expected.root);
+    }
+
+    #[test]
+    fn batch_insert_into_optimized_tree_matches_sequential_insert() {
+        let first = test_frame(5000, 11);
+        let second = test_frame(5000, 13);
+
+        let mut expected = sequential(&first, 5);
+        expected.optimize();
+        for point in &second {
+            expected.insert([point.x, point.y, point.z], 5, point.reflectivity).unwrap();
+        }
+
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        octree.insert_batch(&first, 5).unwrap();
+        octree.optimize();
+        octree.insert_batch(&second, 5).unwrap();
+
+        assert_eq!(octree.root, expected.root);
+    }
+
+    #[test]
+    fn batch_insert_of_empty_frame_keeps_root_leaf() {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        octree.insert_batch(&[], 6).unwrap();
+        assert_eq!(octree.root, Octree::new([[-10.0; 3], [10.0; 3]]).root);
+    }
+
+    /// Centers of all occupied leaves, the reference for the spatial queries
+    fn occupied_centers(octree: &Octree) -> Vec<[f32; 3]> {
+        octree.octree_to_map().values().flatten().map(|point| [point.x, point.y, point.z]).collect()
+    }
+
+    fn brute_force_distances(centers: &[[f32; 3]], point: [f32; 3]) -> Vec<f32> {
+        let mut distances: Vec<f32> = centers.iter().map(|center| Octree::point_distance(point, *center)).collect();
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        distances
+    }
+
+    fn query_points() -> Vec<[f32; 3]> {
+        test_frame(50, 17).iter().map(|point| [point.x, point.y, point.z]).collect()
+    }
+
+    #[test]
+    fn k_nearest_matches_brute_force() {
+        let mut octree = sequential(&test_frame(300, 5), 5);
+        for optimized in [false, true] {
+            if optimized {
+                octree.optimize();
+            }
+            let centers = occupied_centers(&octree);
+            for point in query_points() {
+                let expected = brute_force_distances(&centers, point);
+                for k in [1, 5, 40] {
+                    let found = octree.k_nearest(point, k);
+                    let distances: Vec<f32> = found.iter().map(|(distance, _)| *distance).collect();
+                    assert_eq!(distances, expected[..k], "point {:?}, k {}", point, k);
+                    for (distance, center) in found {
+                        assert_eq!(distance, Octree::point_distance(point, center));
+                        assert!(centers.contains(&center));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn k_nearest_returns_at_most_the_occupied_leaves() {
+        let octree = sequential(&test_frame(10, 23), 4);
+        let count = occupied_centers(&octree).len();
+        assert_eq!(octree.k_nearest([0.0; 3], count + 10).len(), count);
+        assert!(octree.k_nearest([0.0; 3], 0).is_empty());
+    }
+
+    #[test]
+    fn radius_search_matches_brute_force() {
+        let octree = sequential(&test_frame(300, 5), 5);
+        let centers = occupied_centers(&octree);
+        for point in query_points() {
+            for radius in [0.5, 2.0, 6.0] {
+                let expected: Vec<f32> = brute_force_distances(&centers, point)
+                    .into_iter()
+                    .filter(|distance| *distance <= radius)
+                    .collect();
+                let mut distances: Vec<f32> = octree.radius_search(point, radius).iter().map(|(distance, _)| *distance).collect();
+                distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                assert_eq!(distances, expected, "point {:?}, radius {}", point, radius);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_obstacle_distance_matches_brute_force() {
+        let octree = sequential(&test_frame(300, 29), 5);
+        let centers = occupied_centers(&octree);
+        for point in query_points() {
+            let expected = brute_force_distances(&centers, point)[0];
+            assert_eq!(octree.nearest_obstacle_distance(point), Some(expected));
+        }
+        assert_eq!(Octree::new([[-10.0; 3], [10.0; 3]]).nearest_obstacle_distance([0.0; 3]), None);
+    }
+
+    #[test]
+    fn non_finite_queries_find_nothing() {
+        let octree = sequential(&test_frame(300, 29), 5);
+        for point in [[f32::NAN, 0.0, 0.0], [0.0, f32::NAN, 1.0], [f32::INFINITY, 0.0, 0.0], [0.0, 0.0, f32::NEG_INFINITY]] {
+            assert!(octree.k_nearest(point, 3).is_empty(), "{:?}", point);
+            assert!(octree.radius_search(point, 5.0).is_empty(), "{:?}", point);
+            assert_eq!(octree.nearest_obstacle_distance(point), None, "{:?}", point);
+            assert_eq!(octree.cast_ray(point, [1.0, 0.0, 0.0], 20.0), None, "{:?}", point);
+            assert_eq!(octree.cast_ray([0.0; 3], point, 20.0), None, "{:?}", point);
+        }
+    }
+}
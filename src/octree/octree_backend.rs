@@ -0,0 +1,263 @@
+#![allow(dead_code)]
+use std::collections::HashMap;
+
+use crate::data_reader::structor::LaserPoint;
+use crate::octree::octree::Octree;
+use crate::octree::linear_octree::LinearOctree;
+
+/// Storage-independent octree API, so the pointer tree and the Morton-keyed
+/// linear tree can be swapped and benchmarked against each other
+pub trait OctreeBackend: Send + Sync {
+    fn insert(&mut self, point: [f32; 3], max_depth: u32, point_reflectivity: u8) -> Result<(), String>;
+
+    fn octree_to_map(&self) -> HashMap<u32, Vec<LaserPoint>>;
+
+    fn cast_ray(&self, origin: [f32; 3], direction: [f32; 3], max_distance: f32) -> Option<f32>;
+
+    fn k_nearest(&self, point: [f32; 3], k: usize) -> Vec<(f32, [f32; 3])>;
+
+    fn radius_search(&self, point: [f32; 3], radius: f32) -> Vec<(f32, [f32; 3])>;
+
+    fn nearest_obstacle_distance(&self, point: [f32; 3]) -> Option<f32> {
+        self.k_nearest(point, 1).first().map(|(distance, _)| *distance)
+    }
+
+    fn optimize(&mut self);
+
+    fn refresh(&mut self);
+}
+
+impl OctreeBackend for Octree {
+    fn insert(&mut self, point: [f32; 3], max_depth: u32, point_reflectivity: u8) -> Result<(), String> {
+        Octree::insert(self, point, max_depth, point_reflectivity)
+    }
+
+    fn octree_to_map(&self) -> HashMap<u32, Vec<LaserPoint>> {
+        Octree::octree_to_map(self)
+    }
+
+    fn cast_ray(&self, origin: [f32; 3], direction: [f32; 3], max_distance: f32) -> Option<f32> {
+        Octree::cast_ray(self, origin, direction, max_distance)
+    }
+
+    fn k_nearest(&self, point: [f32; 3], k: usize) -> Vec<(f32, [f32; 3])> {
+        Octree::k_nearest(self, point, k)
+    }
+
+    fn radius_search(&self, point: [f32; 3], radius: f32) -> Vec<(f32, [f32; 3])> {
+        Octree::radius_search(self, point, radius)
+    }
+
+    fn optimize(&mut self) {
+        Octree::optimize(self)
+    }
+
+    fn refresh(&mut self) {
+        Octree::refresh(self)
+    }
+}
+
+impl OctreeBackend for LinearOctree {
+    fn insert(&mut self, point: [f32; 3], max_depth: u32, point_reflectivity: u8) -> Result<(), String> {
+        LinearOctree::insert(self, point, max_depth, point_reflectivity)
+    }
+
+    fn octree_to_map(&self) -> HashMap<u32, Vec<LaserPoint>> {
+        LinearOctree::octree_to_map(self)
+    }
+
+    fn cast_ray(&self, origin: [f32; 3], direction: [f32; 3], max_distance: f32) -> Option<f32> {
+        LinearOctree::cast_ray(self, origin, direction, max_distance)
+    }
+
+    fn k_nearest(&self, point: [f32; 3], k: usize) -> Vec<(f32, [f32; 3])> {
+        LinearOctree::k_nearest(self, point, k)
+    }
+
+    fn radius_search(&self, point: [f32; 3], radius: f32) -> Vec<(f32, [f32; 3])> {
+        LinearOctree::radius_search(self, point, radius)
+    }
+
+    fn optimize(&mut self) {
+        LinearOctree::optimize(self)
+    }
+
+    fn refresh(&mut self) {
+        LinearOctree::refresh(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OctreeBackendKind {
+    /// `Octree`: boxed pointer tree
+    Pointer,
+    /// `LinearOctree`: hashed Morton-code leaves
+    Linear,
+}
+
+impl OctreeBackendKind {
+    pub const ALL: [OctreeBackendKind; 2] = [OctreeBackendKind::Pointer, OctreeBackendKind::Linear];
+
+    pub fn parse(backend: &str) -> Result<Self, String> {
+        match backend {
+            "pointer" => Ok(OctreeBackendKind::Pointer),
+            "linear" => Ok(OctreeBackendKind::Linear),
+            _ => Err(format!("Unknown octree backend {}, expected pointer or linear", backend)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OctreeBackendKind::Pointer => "pointer",
+            OctreeBackendKind::Linear => "linear",
+        }
+    }
+}
+
+pub fn new_backend(kind: OctreeBackendKind, bounds: [[f32; 3]; 2]) -> Box<dyn OctreeBackend> {
+    match kind {
+        OctreeBackendKind::Pointer => Box::new(Octree::new(bounds)),
+        OctreeBackendKind::Linear => Box::new(LinearOctree::new(bounds)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: [[f32; 3]; 2] = [[-10.0; 3], [10.0; 3]];
+
+    /// Deterministic pseudo-random points, every fourth one snapped to cell boundaries
+    /// of some depth so that ties between children are covered
+    fn test_points(count: usize, seed: u32) -> Vec<LaserPoint> {
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 8) as f32 / (1u32 << 24) as f32
+        };
+        let mut points = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut point = [next() * 20.0 - 10.0, next() * 20.0 - 10.0, next() * 20.0 - 10.0];
+            if i % 4 == 0 {
+                let cells = (1 << (i / 4 % 6)) as f32;
+                point = point.map(|c| ((c + 10.0) / 20.0 * cells).round() / cells * 20.0 - 10.0);
+            }
+            points.push(LaserPoint::new(point[0], point[1], point[2], (next() * 255.0) as u8));
+        }
+        points
+    }
+
+    fn build(kind: OctreeBackendKind, points: &[LaserPoint], optimize: bool) -> Box<dyn OctreeBackend> {
+        let mut backend = new_backend(kind, BOUNDS);
+        for point in points {
+            backend.insert([point.x, point.y, point.z], 5, point.reflectivity).unwrap();
+        }
+        if optimize {
+            backend.optimize();
+        }
+        backend
+    }
+
+    fn sorted_leaves(backend: &dyn OctreeBackend) -> Vec<(u32, [f32; 3], u8)> {
+        let mut leaves: Vec<(u32, [f32; 3], u8)> = backend
+            .octree_to_map()
+            .into_iter()
+            .flat_map(|(depth, points)| points.into_iter().map(move |point| (depth, [point.x, point.y, point.z], point.reflectivity)))
+            .collect();
+        leaves.sort_by(|a, b| (a.0, a.1[0], a.1[1], a.1[2]).partial_cmp(&(b.0, b.1[0], b.1[1], b.1[2])).unwrap());
+        leaves
+    }
+
+    /// `Octree` pads every child by a small epsilon, so its boxes are slightly larger
+    fn assert_close(a: f32, b: f32, what: &str) {
+        assert!((a - b).abs() < 1e-3, "{}: {} != {}", what, a, b);
+    }
+
+    #[test]
+    fn backends_store_the_same_leaves() {
+        let points = test_points(5000, 3);
+        for optimize in [false, true] {
+            let pointer = sorted_leaves(build(OctreeBackendKind::Pointer, &points, optimize).as_ref());
+            let linear = sorted_leaves(build(OctreeBackendKind::Linear, &points, optimize).as_ref());
+            assert_eq!(pointer.len(), linear.len(), "optimize {}", optimize);
+            for (a, b) in pointer.iter().zip(&linear) {
+                assert_eq!((a.0, a.2), (b.0, b.2), "leaf {:?} vs {:?}", a, b);
+                for i in 0..3 {
+                    assert_close(a.1[i], b.1[i], "leaf center");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn points_on_centers_go_to_the_lower_child() {
+        for kind in OctreeBackendKind::ALL {
+            let backend = build(kind, &[LaserPoint::new(0.0, 5.0, -5.0, 100)], false);
+            let leaves = sorted_leaves(backend.as_ref());
+            assert_eq!(leaves.len(), 1);
+            let center = leaves[0].1;
+            assert!(center[0] < 0.0 && center[1] < 5.0 && center[2] < -5.0, "{}: {:?}", kind.name(), center);
+        }
+    }
+
+    #[test]
+    fn backends_answer_queries_alike() {
+        let points = test_points(3000, 11);
+        let pointer = build(OctreeBackendKind::Pointer, &points, true);
+        let linear = build(OctreeBackendKind::Linear, &points, true);
+        for query in test_points(100, 5) {
+            let point = [query.x, query.y, query.z];
+
+            let a = pointer.k_nearest(point, 6);
+            let b = linear.k_nearest(point, 6);
+            assert_eq!(a.len(), b.len());
+            for ((da, _), (db, _)) in a.iter().zip(&b) {
+                assert_close(*da, *db, "k_nearest");
+            }
+
+            let mut a: Vec<f32> = pointer.radius_search(point, 2.0).iter().map(|(d, _)| *d).collect();
+            let mut b: Vec<f32> = linear.radius_search(point, 2.0).iter().map(|(d, _)| *d).collect();
+            a.sort_by(|x, y| x.partial_cmp(y).unwrap());
+            b.sort_by(|x, y| x.partial_cmp(y).unwrap());
+            assert_eq!(a.len(), b.len(), "radius_search around {:?}", point);
+            for (da, db) in a.iter().zip(&b) {
+                assert_close(*da, *db, "radius_search");
+            }
+
+            // Aimed off the grid, rays through cell corners can graze a leaf in one backend only
+            let offset = [1.37 - point[0], -2.71 - point[1], 0.53 - point[2]];
+            let norm = offset.iter().map(|c| c * c).sum::<f32>().sqrt().max(1e-3);
+            let direction = offset.map(|c| c / norm);
+            match (pointer.cast_ray(point, direction, 20.0), linear.cast_ray(point, direction, 20.0)) {
+                (Some(a), Some(b)) => assert_close(a, b, "cast_ray"),
+                (a, b) => assert_eq!(a, b, "cast_ray from {:?}", point),
+            }
+        }
+    }
+
+    #[test]
+    fn backends_find_nothing_from_non_finite_points() {
+        let points = test_points(1000, 13);
+        for kind in OctreeBackendKind::ALL {
+            let backend = build(kind, &points, true);
+            for point in [[f32::NAN, 1.0, 2.0], [0.0, f32::INFINITY, 0.0]] {
+                assert!(backend.k_nearest(point, 4).is_empty(), "{}: {:?}", kind.name(), point);
+                assert!(backend.radius_search(point, 3.0).is_empty(), "{}: {:?}", kind.name(), point);
+                assert_eq!(backend.nearest_obstacle_distance(point), None, "{}: {:?}", kind.name(), point);
+                assert_eq!(backend.cast_ray(point, [0.0, 0.0, 1.0], 20.0), None, "{}: {:?}", kind.name(), point);
+                assert_eq!(backend.cast_ray([0.5; 3], point, 20.0), None, "{}: {:?}", kind.name(), point);
+            }
+        }
+    }
+
+    #[test]
+    fn benchmark_times_every_backend() {
+        use crate::octree::octree_benchmark::{run_octree_benchmark, BenchmarkCloud, OctreeBenchmarkConfig};
+        let clouds = [BenchmarkCloud { name: "test".to_string(), points: test_points(500, 7) }];
+        let config = OctreeBenchmarkConfig { queries: 20, ..OctreeBenchmarkConfig::default() };
+        let report = run_octree_benchmark(&clouds, &OctreeBackendKind::ALL, &config).unwrap();
+        assert_eq!(report.rows.len(), 2);
+        assert_eq!(report.rows[0].leaves, report.rows[1].leaves);
+        assert!(report.to_string().contains("linear"));
+    }
+}
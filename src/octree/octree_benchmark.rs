@@ -0,0 +1,168 @@
+#![allow(dead_code)]
+use std::fmt;
+use std::path::Path;
+use std::time::Instant;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::data_reader::point_cloud_file;
+use crate::data_reader::structor::LaserPoint;
+use crate::octree::octree_backend::{new_backend, OctreeBackendKind};
+
+#[derive(Debug, Clone)]
+pub struct OctreeBenchmarkConfig {
+    pub boundary: f32,   // Every backend covers [-boundary, boundary] on each axis
+    pub max_depth: u32,
+    pub queries: usize,  // Queries of each kind, the same for every backend
+    pub k: usize,        // Neighbours of one k-nearest query
+    pub radius: f32,     // Radius of one radius search
+    pub ray_length: f32, // Maximum distance of one ray cast
+    pub seed: u64,       // Same seed and clouds give the same queries
+}
+
+impl Default for OctreeBenchmarkConfig {
+    fn default() -> Self {
+        Self {
+            boundary: 10.0,
+            max_depth: 7,
+            queries: 1000,
+            k: 8,
+            radius: 1.0,
+            ray_length: 10.0,
+            seed: 0,
+        }
+    }
+}
+
+/// One point cloud of the suite
+pub struct BenchmarkCloud {
+    pub name: String,
+    pub points: Vec<LaserPoint>,
+}
+
+impl BenchmarkCloud {
+    /// Load a .pcd, .ply or .las file, see `point_cloud_file::load_point_cloud`
+    pub fn load(file_name: &str) -> std::io::Result<Self> {
+        let name = Path::new(file_name)
+            .file_name()
+            .map_or_else(|| file_name.to_string(), |name| name.to_string_lossy().into_owned());
+        Ok(Self { name, points: point_cloud_file::load_point_cloud(file_name)? })
+    }
+}
+
+/// Timings of one backend on one cloud, queries in microseconds per query
+#[derive(Debug, Clone, PartialEq)]
+pub struct OctreeBenchmarkRow {
+    pub backend: &'static str,
+    pub cloud: String,
+    pub points: usize,
+    pub leaves: usize, // Occupied leaves after `optimize`
+    pub insert_ms: f32,
+    pub optimize_ms: f32,
+    pub k_nearest_us: f32,
+    pub radius_search_us: f32,
+    pub cast_ray_us: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OctreeBenchmarkReport {
+    pub rows: Vec<OctreeBenchmarkRow>,
+}
+
+impl fmt::Display for OctreeBenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<8} {:<24} {:>8} {:>8} {:>10} {:>11} {:>9} {:>9} {:>9}",
+            "backend", "cloud", "points", "leaves", "insert ms", "optimize ms", "knn us", "radius us", "ray us"
+        )?;
+        for row in &self.rows {
+            writeln!(
+                f,
+                "{:<8} {:<24} {:>8} {:>8} {:>10.1} {:>11.1} {:>9.1} {:>9.1} {:>9.1}",
+                row.backend,
+                row.cloud,
+                row.points,
+                row.leaves,
+                row.insert_ms,
+                row.optimize_ms,
+                row.k_nearest_us,
+                row.radius_search_us,
+                row.cast_ray_us,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Build every cloud with every backend and time the same queries on each
+pub fn run_octree_benchmark(
+    clouds: &[BenchmarkCloud],
+    backends: &[OctreeBackendKind],
+    config: &OctreeBenchmarkConfig,
+) -> Result<OctreeBenchmarkReport, String> {
+    let bounds = [[-config.boundary; 3], [config.boundary; 3]];
+    let queries = sample_queries(config);
+    let mut report = OctreeBenchmarkReport::default();
+    for cloud in clouds {
+        for kind in backends {
+            let mut backend = new_backend(*kind, bounds);
+
+            let started = Instant::now();
+            for point in &cloud.points {
+                backend.insert([point.x, point.y, point.z], config.max_depth, point.reflectivity)?;
+            }
+            let insert_ms = started.elapsed().as_secs_f32() * 1000.0;
+            let started = Instant::now();
+            backend.optimize();
+            let optimize_ms = started.elapsed().as_secs_f32() * 1000.0;
+
+            let per_query_us = |started: Instant| started.elapsed().as_secs_f32() * 1e6 / queries.len().max(1) as f32;
+            let started = Instant::now();
+            for (point, _) in &queries {
+                backend.k_nearest(*point, config.k);
+            }
+            let k_nearest_us = per_query_us(started);
+            let started = Instant::now();
+            for (point, _) in &queries {
+                backend.radius_search(*point, config.radius);
+            }
+            let radius_search_us = per_query_us(started);
+            let started = Instant::now();
+            for (point, direction) in &queries {
+                backend.cast_ray(*point, *direction, config.ray_length);
+            }
+            let cast_ray_us = per_query_us(started);
+
+            report.rows.push(OctreeBenchmarkRow {
+                backend: kind.name(),
+                cloud: cloud.name.clone(),
+                points: cloud.points.len(),
+                leaves: backend.octree_to_map().values().map(Vec::len).sum(),
+                insert_ms,
+                optimize_ms,
+                k_nearest_us,
+                radius_search_us,
+                cast_ray_us,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Query points inside the bounds, each with a unit ray direction
+fn sample_queries(config: &OctreeBenchmarkConfig) -> Vec<([f32; 3], [f32; 3])> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    (0..config.queries)
+        .map(|_| {
+            let point = [0; 3].map(|_| rng.gen_range(-config.boundary..config.boundary));
+            let direction = loop {
+                let candidate = [0; 3].map(|_| rng.gen_range(-1.0f32..1.0));
+                let norm = (candidate[0].powi(2) + candidate[1].powi(2) + candidate[2].powi(2)).sqrt();
+                if norm > 1e-3 && norm <= 1.0 {
+                    break candidate.map(|c| c / norm);
+                }
+            };
+            (point, direction)
+        })
+        .collect()
+}
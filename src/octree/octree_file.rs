@@ -0,0 +1,251 @@
+#![allow(dead_code)]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+
+use crate::octree::octree::{Occupancy, Octree, OctreeNode};
+
+/// Binary octree map format
+///
+/// ```text
+/// magic       4 bytes  "WWAO"
+/// version     u16
+/// bounds      6 x f32  root min xyz, max xyz
+/// depth       u32      root depth
+/// node_count  u64
+/// nodes       pre-order, one tag byte each:
+///             0 = internal, followed by its 8 children
+///             1 = free leaf, 2 = occupied leaf, followed by reflectivity sum and point count (2 x u32)
+/// ```
+///
+/// Child bounds, centers and depths are not stored; they are rebuilt with the same
+/// split rule used during insertion, so a loaded tree is identical to the saved one.
+/// All numbers are little endian.
+pub const OCTREE_FILE_MAGIC: [u8; 4] = *b"WWAO";
+pub const OCTREE_FILE_VERSION: u16 = 1;
+
+const TAG_INTERNAL: u8 = 0;
+const TAG_FREE_LEAF: u8 = 1;
+const TAG_OCCUPIED_LEAF: u8 = 2;
+
+/// Refuse to rebuild trees deeper than this from a (possibly corrupt) file
+const MAX_FILE_DEPTH: u32 = 32;
+
+pub fn save_octree(octree: &Octree, file_name: &str) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(file_name)?);
+    write_octree(octree, &mut writer)?;
+    writer.flush()
+}
+
+pub fn load_octree(file_name: &str) -> std::io::Result<Octree> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    read_octree(&mut reader)
+}
+
+pub fn write_octree<W: Write>(octree: &Octree, writer: &mut W) -> std::io::Result<()> {
+    let root = octree.get_root();
+    let (bounds, depth) = match root {
+        OctreeNode::Internal { bounds, depth, .. } => (bounds, depth),
+        OctreeNode::Leaf { bounds, depth, .. } => (bounds, depth),
+    };
+
+    writer.write_all(&OCTREE_FILE_MAGIC)?;
+    writer.write_u16::<LittleEndian>(OCTREE_FILE_VERSION)?;
+    for corner in bounds {
+        for value in corner {
+            writer.write_f32::<LittleEndian>(*value)?;
+        }
+    }
+    writer.write_u32::<LittleEndian>(*depth)?;
+    writer.write_u64::<LittleEndian>(count_nodes(root))?;
+    write_node(root, writer)
+}
+
+pub fn read_octree<R: Read>(reader: &mut R) -> std::io::Result<Octree> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != OCTREE_FILE_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not an octree map file"));
+    }
+    let version = reader.read_u16::<LittleEndian>()?;
+    if version != OCTREE_FILE_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported octree file version {} (expected {})", version, OCTREE_FILE_VERSION),
+        ));
+    }
+
+    let mut bounds = [[0.0f32; 3]; 2];
+    for corner in bounds.iter_mut() {
+        for value in corner.iter_mut() {
+            *value = reader.read_f32::<LittleEndian>()?;
+        }
+    }
+    let depth = reader.read_u32::<LittleEndian>()?;
+    let node_count = reader.read_u64::<LittleEndian>()?;
+    let center = [
+        (bounds[0][0] + bounds[1][0]) / 2.0,
+        (bounds[0][1] + bounds[1][1]) / 2.0,
+        (bounds[0][2] + bounds[1][2]) / 2.0,
+    ];
+
+    let mut nodes_read = 0;
+    let root = read_node(reader, bounds, center, depth, &mut nodes_read)?;
+    if nodes_read != node_count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Node count mismatch: header says {}, read {}", node_count, nodes_read),
+        ));
+    }
+
+    Ok(Octree::from_root(root))
+}
+
+fn count_nodes(node: &OctreeNode) -> u64 {
+    match node {
+        OctreeNode::Internal { children, .. } => 1 + children.iter().map(|c| count_nodes(c)).sum::<u64>(),
+        OctreeNode::Leaf { .. } => 1,
+    }
+}
+
+fn write_node<W: Write>(node: &OctreeNode, writer: &mut W) -> std::io::Result<()> {
+    match node {
+        OctreeNode::Internal { children, .. } => {
+            writer.write_u8(TAG_INTERNAL)?;
+            for child in children.iter() {
+                write_node(child, writer)?;
+            }
+        }
+        OctreeNode::Leaf { occupancy, reflectivity, .. } => {
+            let tag = match occupancy {
+                Occupancy::Free => TAG_FREE_LEAF,
+                Occupancy::Occupied => TAG_OCCUPIED_LEAF,
+            };
+            writer.write_u8(tag)?;
+            writer.write_u32::<LittleEndian>(reflectivity[0])?;
+            writer.write_u32::<LittleEndian>(reflectivity[1])?;
+        }
+    }
+    Ok(())
+}
+
+fn read_node<R: Read>(
+    reader: &mut R,
+    bounds: [[f32; 3]; 2],
+    center: [f32; 3],
+    depth: u32,
+    nodes_read: &mut u64,
+) -> std::io::Result<OctreeNode> {
+    let tag = reader.read_u8()?;
+    *nodes_read += 1;
+
+    match tag {
+        TAG_INTERNAL => {
+            if depth >= MAX_FILE_DEPTH {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Octree deeper than {} levels", MAX_FILE_DEPTH),
+                ));
+            }
+            let mut children = Octree::create_children(&bounds, &center, depth);
+            for child in children.iter_mut() {
+                let (child_bounds, child_center) = match child.as_ref() {
+                    OctreeNode::Leaf { bounds, center, .. } => (*bounds, *center),
+                    OctreeNode::Internal { bounds, center, .. } => (*bounds, *center),
+                };
+                **child = read_node(reader, child_bounds, child_center, depth + 1, nodes_read)?;
+            }
+            Ok(OctreeNode::Internal { bounds, center, depth, children })
+        }
+        TAG_FREE_LEAF | TAG_OCCUPIED_LEAF => {
+            let reflectivity = [
+                reader.read_u32::<LittleEndian>()?,
+                reader.read_u32::<LittleEndian>()?,
+            ];
+            let occupancy = if tag == TAG_OCCUPIED_LEAF { Occupancy::Occupied } else { Occupancy::Free };
+            Ok(OctreeNode::Leaf { bounds, center, depth, occupancy, reflectivity })
+        }
+        _ => Err(Error::new(ErrorKind::InvalidData, format!("Unknown node tag {}", tag))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_reader::structor::LaserPoint;
+
+    fn test_octree() -> Octree {
+        let mut octree = Octree::new([[-10.0; 3], [10.0; 3]]);
+        let mut points = Vec::new();
+        for i in 0..2000 {
+            let t = i as f32 * 0.01;
+            points.push(LaserPoint::new(t.sin() * 8.0, t.cos() * 8.0, t - 10.0, (i % 256) as u8));
+        }
+        // A solid block that optimize() merges into coarser occupied leaves
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    points.push(LaserPoint::new(
+                        2.0 + x as f32 * 0.3125,
+                        2.0 + y as f32 * 0.3125,
+                        2.0 + z as f32 * 0.3125,
+                        100,
+                    ));
+                }
+            }
+        }
+        octree.insert_batch(&points, 6).unwrap();
+        octree.optimize();
+        octree
+    }
+
+    #[test]
+    fn round_trip_in_memory() {
+        let octree = test_octree();
+        let mut buffer = Vec::new();
+        write_octree(&octree, &mut buffer).unwrap();
+
+        let loaded = read_octree(&mut buffer.as_slice()).unwrap();
+        assert_eq!(loaded.get_root(), octree.get_root());
+        assert_eq!(loaded.octree_to_map().len(), octree.octree_to_map().len());
+    }
+
+    #[test]
+    fn round_trip_through_file() {
+        let octree = test_octree();
+        let path = std::env::temp_dir().join(format!("octree_file_test_{}.wwao", std::process::id()));
+        let file_name = path.to_str().unwrap();
+
+        save_octree(&octree, file_name).unwrap();
+        let loaded = load_octree(file_name).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.get_root(), octree.get_root());
+    }
+
+    #[test]
+    fn round_trip_empty_octree() {
+        let octree = Octree::new([[-5.0; 3], [5.0; 3]]);
+        let mut buffer = Vec::new();
+        write_octree(&octree, &mut buffer).unwrap();
+        let loaded = read_octree(&mut buffer.as_slice()).unwrap();
+        assert_eq!(loaded.get_root(), octree.get_root());
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_version() {
+        let mut buffer = Vec::new();
+        write_octree(&test_octree(), &mut buffer).unwrap();
+
+        let mut bad_magic = buffer.clone();
+        bad_magic[0] = b'X';
+        assert!(read_octree(&mut bad_magic.as_slice()).is_err());
+
+        let mut bad_version = buffer.clone();
+        bad_version[4] = 0xFF;
+        assert!(read_octree(&mut bad_version.as_slice()).is_err());
+
+        let truncated = &buffer[..buffer.len() - 3];
+        assert!(read_octree(&mut &truncated[..]).is_err());
+    }
+}
@@ -0,0 +1,429 @@
+#![allow(dead_code)]
+use bevy::ecs::system::Resource;
+use bevy::math::Vec3;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::calculator::coordinate_switch::bevy_to_mid360;
+use crate::calculator::mavlink_args::MavlinkArgs;
+use crate::data_reader::flight_log::{
+    self, ImuMessage, PointCloudMessage, PosesInFrameMessage, Timestamp, Vector3, FRAME_ID,
+    TOPIC_APF_PATH, TOPIC_IMU, TOPIC_MAVLINK_ARGS,
+};
+use crate::data_reader::structor::{LaserPoint, Point3};
+use crate::data_reader::udp_reader::ImuData;
+use crate::visualization::websocket::{self, Message};
+
+pub const FOXGLOVE_SUBPROTOCOL: &str = "foxglove.websocket.v1";
+pub const DEFAULT_FOXGLOVE_ADDRESS: &str = "0.0.0.0:8765";
+
+pub const TOPIC_MAP_POINTS: &str = "/octree/points";
+pub const TOPIC_VELOCITY: &str = "/planner/velocity";
+
+/// Messages waiting for one client. When a slow client falls this far behind, new
+/// messages for it are dropped instead of stalling the app.
+const CLIENT_QUEUE_LENGTH: usize = 16;
+/// Server-to-client binary opcode for message data
+const OP_MESSAGE_DATA: u8 = 0x01;
+
+/// Velocity command in the sensor frame
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VelocityMessage {
+    pub timestamp: Timestamp,
+    pub frame_id: String,
+    pub linear: Vector3,
+}
+
+#[derive(Debug, Clone)]
+struct FoxgloveChannel {
+    id: u32,
+    topic: String,
+    schema_name: String,
+    schema: String,
+}
+
+enum Outgoing {
+    Text(String),
+    MessageData { subscription_id: u32, timestamp: u64, payload: Arc<Vec<u8>> },
+    Pong(Vec<u8>),
+}
+
+struct Client {
+    /// Subscription id chosen by the client -> channel id
+    subscriptions: HashMap<u32, u32>,
+    sender: SyncSender<Outgoing>,
+}
+
+struct ServerState {
+    channels: Vec<FoxgloveChannel>,
+    clients: HashMap<u64, Client>,
+    next_client_id: u64,
+}
+
+/// Foxglove WebSocket protocol server. Every channel is JSON encoded with the same
+/// schemas as the MCAP flight log, so one Foxglove layout works for live and recorded data.
+#[derive(Resource, Clone)]
+pub struct FoxgloveServer {
+    state: Arc<Mutex<ServerState>>,
+    points_channel: u32,
+    imu_channel: u32,
+    path_channel: u32,
+    velocity_channel: u32,
+    mavlink_channel: u32,
+}
+
+impl FoxgloveServer {
+    /// Bind to `address` (e.g. "0.0.0.0:8765") and accept clients on a background thread
+    pub fn start(address: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+
+        let channels = vec![
+            channel(0, TOPIC_MAP_POINTS, "foxglove.PointCloud", flight_log::point_cloud_schema()),
+            channel(1, TOPIC_IMU, "WorldWithoutAnime.ImuData", flight_log::imu_schema()),
+            channel(2, TOPIC_APF_PATH, "foxglove.PosesInFrame", flight_log::poses_in_frame_schema()),
+            channel(3, TOPIC_VELOCITY, "WorldWithoutAnime.Velocity", velocity_schema()),
+            channel(4, TOPIC_MAVLINK_ARGS, "WorldWithoutAnime.MavlinkArgs", flight_log::mavlink_args_schema()),
+        ];
+        let state = Arc::new(Mutex::new(ServerState {
+            channels,
+            clients: HashMap::new(),
+            next_client_id: 0,
+        }));
+
+        let accept_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let state = accept_state.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_client(stream, state) {
+                                eprintln!("Foxglove client error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("Foxglove accept failed: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            state,
+            points_channel: 0,
+            imu_channel: 1,
+            path_channel: 2,
+            velocity_channel: 3,
+            mavlink_channel: 4,
+        })
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    pub fn has_subscribers(&self, channel_id: u32) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .clients
+            .values()
+            .any(|client| client.subscriptions.values().any(|id| *id == channel_id))
+    }
+
+    /// Send a payload to every subscription of the channel
+    pub fn publish(&self, channel_id: u32, timestamp: u64, payload: Vec<u8>) {
+        let payload = Arc::new(payload);
+        let mut state = self.state.lock().unwrap();
+        let mut disconnected = Vec::new();
+        for (client_id, client) in &state.clients {
+            for (subscription_id, _) in client.subscriptions.iter().filter(|(_, id)| **id == channel_id) {
+                let message = Outgoing::MessageData {
+                    subscription_id: *subscription_id,
+                    timestamp,
+                    payload: payload.clone(),
+                };
+                match client.sender.try_send(message) {
+                    Ok(()) | Err(TrySendError::Full(_)) => {}
+                    Err(TrySendError::Disconnected(_)) => disconnected.push(*client_id),
+                }
+            }
+        }
+        for client_id in disconnected {
+            state.clients.remove(&client_id);
+        }
+    }
+
+    /// Leaf centers of the map, e.g. from `octree_map_to_points(&octree.octree_to_map())`
+    pub fn publish_points(&self, points: &[LaserPoint]) {
+        self.publish_json(self.points_channel, |now| PointCloudMessage::new(points, now));
+    }
+
+    pub fn publish_imu(&self, imu: &ImuData) {
+        self.publish_json(self.imu_channel, |now| ImuMessage::new(imu, now));
+    }
+
+    pub fn publish_path(&self, path: &[Point3]) {
+        self.publish_json(self.path_channel, |now| PosesInFrameMessage::from_path(path, now));
+    }
+
+    /// `velocity` is the Bevy-frame `VelocityVector`, it is published in the sensor frame
+    pub fn publish_velocity(&self, velocity: Vec3) {
+        let (x, y, z) = bevy_to_mid360(velocity.x, velocity.y, velocity.z);
+        self.publish_json(self.velocity_channel, |now| VelocityMessage {
+            timestamp: Timestamp::from_nanos(now),
+            frame_id: FRAME_ID.to_string(),
+            linear: Vector3 { x: x as f64, y: y as f64, z: z as f64 },
+        });
+    }
+
+    pub fn publish_mavlink_args(&self, args: &MavlinkArgs) {
+        self.publish_json(self.mavlink_channel, |_| *args);
+    }
+
+    pub fn points_channel(&self) -> u32 {
+        self.points_channel
+    }
+
+    /// Serialize only when someone listens, point clouds are expensive to encode
+    fn publish_json<T: Serialize>(&self, channel_id: u32, message: impl FnOnce(u64) -> T) {
+        if !self.has_subscribers(channel_id) {
+            return;
+        }
+        let now = flight_log::now_nanos();
+        match serde_json::to_vec(&message(now)) {
+            Ok(payload) => self.publish(channel_id, now, payload),
+            Err(e) => eprintln!("Failed to encode Foxglove message: {}", e),
+        }
+    }
+}
+
+fn channel(id: u32, topic: &str, schema_name: &str, schema: String) -> FoxgloveChannel {
+    FoxgloveChannel {
+        id,
+        topic: topic.to_string(),
+        schema_name: schema_name.to_string(),
+        schema,
+    }
+}
+
+fn velocity_schema() -> String {
+    format!(
+        r#"{{"title":"WorldWithoutAnime.Velocity","type":"object","properties":{{"timestamp":{},"frame_id":{{"type":"string"}},"linear":{}}}}}"#,
+        flight_log::TIME_SCHEMA,
+        flight_log::VECTOR3_SCHEMA
+    )
+}
+
+fn handle_client(stream: TcpStream, state: Arc<Mutex<ServerState>>) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = websocket::read_handshake(&mut reader)?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let protocol = request
+        .protocols
+        .iter()
+        .find(|protocol| *protocol == FOXGLOVE_SUBPROTOCOL)
+        .map(|protocol| protocol.as_str());
+    websocket::write_handshake_response(&mut writer, &request.key, protocol)?;
+
+    // The app thread only queues messages, this thread does the blocking writes
+    let (sender, receiver) = sync_channel(CLIENT_QUEUE_LENGTH);
+    let write_stream = stream.try_clone()?;
+    thread::spawn(move || {
+        write_loop(&mut writer, receiver);
+        let _ = write_stream.shutdown(Shutdown::Both);
+    });
+
+    let client_id = {
+        let mut state = state.lock().unwrap();
+        let client_id = state.next_client_id;
+        state.next_client_id += 1;
+        // The queue is still empty, so these cannot be dropped
+        let _ = sender.try_send(Outgoing::Text(server_info().to_string()));
+        let _ = sender.try_send(Outgoing::Text(advertise(&state.channels).to_string()));
+        state.clients.insert(client_id, Client { subscriptions: HashMap::new(), sender: sender.clone() });
+        client_id
+    };
+
+    let result = read_loop(&mut reader, &state, client_id, &sender);
+    state.lock().unwrap().clients.remove(&client_id);
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+fn write_loop<W: Write>(writer: &mut W, receiver: Receiver<Outgoing>) {
+    for outgoing in receiver {
+        let result = match outgoing {
+            Outgoing::Text(text) => websocket::write_frame(writer, websocket::OPCODE_TEXT, text.as_bytes()),
+            Outgoing::Pong(payload) => websocket::write_frame(writer, websocket::OPCODE_PONG, &payload),
+            Outgoing::MessageData { subscription_id, timestamp, payload } => {
+                let mut header = Vec::with_capacity(13);
+                header.push(OP_MESSAGE_DATA);
+                header.extend_from_slice(&subscription_id.to_le_bytes());
+                header.extend_from_slice(&timestamp.to_le_bytes());
+                websocket::write_frame_header(writer, websocket::OPCODE_BINARY, (header.len() + payload.len()) as u64)
+                    .and_then(|_| writer.write_all(&header))
+                    .and_then(|_| writer.write_all(&payload))
+                    .and_then(|_| writer.flush())
+            }
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+}
+
+fn read_loop<R: std::io::Read>(
+    reader: &mut R,
+    state: &Arc<Mutex<ServerState>>,
+    client_id: u64,
+    sender: &SyncSender<Outgoing>,
+) -> std::io::Result<()> {
+    let mut messages = websocket::MessageReader::new(reader);
+    loop {
+        match messages.read_message()? {
+            Message::Text(text) => {
+                let Ok(request) = serde_json::from_str::<Value>(&text) else {
+                    let _ = sender.try_send(Outgoing::Text(status(2, "Invalid JSON request").to_string()));
+                    continue;
+                };
+                handle_request(&request, state, client_id, sender);
+            }
+            Message::Ping(payload) => {
+                let _ = sender.try_send(Outgoing::Pong(payload));
+            }
+            Message::Close => return Ok(()),
+            Message::Binary(_) | Message::Pong(_) => {}
+        }
+    }
+}
+
+/// Handle subscribe/unsubscribe. Other client operations need capabilities we do not advertise.
+fn handle_request(request: &Value, state: &Arc<Mutex<ServerState>>, client_id: u64, sender: &SyncSender<Outgoing>) {
+    let mut state = state.lock().unwrap();
+    let channel_count = state.channels.len() as u64;
+    let Some(client) = state.clients.get_mut(&client_id) else {
+        return;
+    };
+
+    match request["op"].as_str() {
+        Some("subscribe") => {
+            for subscription in request["subscriptions"].as_array().into_iter().flatten() {
+                let (Some(id), Some(channel_id)) = (subscription["id"].as_u64(), subscription["channelId"].as_u64()) else {
+                    continue;
+                };
+                let Ok(id) = u32::try_from(id) else {
+                    let message = format!("Invalid subscription id {}", id);
+                    let _ = sender.try_send(Outgoing::Text(status(1, &message).to_string()));
+                    continue;
+                };
+                if channel_id >= channel_count {
+                    let message = format!("Unknown channel {}", channel_id);
+                    let _ = sender.try_send(Outgoing::Text(status(1, &message).to_string()));
+                    continue;
+                }
+                client.subscriptions.insert(id, channel_id as u32);
+            }
+        }
+        Some("unsubscribe") => {
+            for id in request["subscriptionIds"].as_array().into_iter().flatten() {
+                if let Some(id) = id.as_u64().and_then(|id| u32::try_from(id).ok()) {
+                    client.subscriptions.remove(&id);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn server_info() -> Value {
+    json!({
+        "op": "serverInfo",
+        "name": "WorldWithoutAnime",
+        "capabilities": [],
+        "supportedEncodings": [],
+        "metadata": {},
+        "sessionId": flight_log::now_nanos().to_string(),
+    })
+}
+
+fn advertise(channels: &[FoxgloveChannel]) -> Value {
+    let channels: Vec<Value> = channels
+        .iter()
+        .map(|channel| {
+            json!({
+                "id": channel.id,
+                "topic": channel.topic,
+                "encoding": "json",
+                "schemaName": channel.schema_name,
+                "schema": channel.schema,
+                "schemaEncoding": "jsonschema",
+            })
+        })
+        .collect();
+    json!({ "op": "advertise", "channels": channels })
+}
+
+/// level: 0 info, 1 warning, 2 error
+fn status(level: u8, message: &str) -> Value {
+    json!({ "op": "status", "level": level, "message": message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> (Arc<Mutex<ServerState>>, Receiver<Outgoing>) {
+        let channels = vec![
+            channel(0, TOPIC_MAP_POINTS, "foxglove.PointCloud", flight_log::point_cloud_schema()),
+            channel(1, TOPIC_VELOCITY, "WorldWithoutAnime.Velocity", velocity_schema()),
+        ];
+        let (sender, receiver) = sync_channel(CLIENT_QUEUE_LENGTH);
+        let mut clients = HashMap::new();
+        clients.insert(0, Client { subscriptions: HashMap::new(), sender });
+        (Arc::new(Mutex::new(ServerState { channels, clients, next_client_id: 1 })), receiver)
+    }
+
+    fn subscriptions(state: &Arc<Mutex<ServerState>>) -> HashMap<u32, u32> {
+        state.lock().unwrap().clients[&0].subscriptions.clone()
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let (state, receiver) = test_state();
+        let sender = state.lock().unwrap().clients[&0].sender.clone();
+        let subscribe = json!({ "op": "subscribe", "subscriptions": [{ "id": 7, "channelId": 1 }] });
+        handle_request(&subscribe, &state, 0, &sender);
+        assert_eq!(subscriptions(&state), HashMap::from([(7, 1)]));
+        assert!(receiver.try_recv().is_err());
+
+        let unsubscribe = json!({ "op": "unsubscribe", "subscriptionIds": [7] });
+        handle_request(&unsubscribe, &state, 0, &sender);
+        assert!(subscriptions(&state).is_empty());
+    }
+
+    #[test]
+    fn out_of_range_ids_are_not_truncated() {
+        let (state, receiver) = test_state();
+        let sender = state.lock().unwrap().clients[&0].sender.clone();
+        // 2^32 would become channel 0 and subscription 0 if cast to u32
+        let subscribe = json!({
+            "op": "subscribe",
+            "subscriptions": [{ "id": 1, "channelId": 4294967296u64 }, { "id": 4294967296u64, "channelId": 0 }],
+        });
+        handle_request(&subscribe, &state, 0, &sender);
+        assert!(subscriptions(&state).is_empty());
+        for _ in 0..2 {
+            assert!(matches!(receiver.try_recv(), Ok(Outgoing::Text(text)) if text.contains("\"level\":1")));
+        }
+
+        handle_request(&json!({ "op": "subscribe", "subscriptions": [{ "id": 0, "channelId": 0 }] }), &state, 0, &sender);
+        handle_request(&json!({ "op": "unsubscribe", "subscriptionIds": [4294967296u64] }), &state, 0, &sender);
+        assert_eq!(subscriptions(&state), HashMap::from([(0, 0)]));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod color_calculator;
 pub mod rendering_components_octree;
+pub mod websocket;
+pub mod foxglove_server;
\ No newline at end of file
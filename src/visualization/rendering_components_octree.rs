@@ -3,16 +3,41 @@ use bevy::prelude::*;
 use bevy_flycam::prelude::*;
 use bevy::color::palettes::css::GOLD;
 use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, DiagnosticsStore};
+use bevy::window::PrimaryWindow;
 use crate::calculator::point_divider;
-use crate::data_reader::structor::Point3;
+use crate::data_reader::csv_reader;
+use crate::data_reader::structor_to_file;
+use crate::data_reader::point_cloud_file::octree_map_to_points;
+use crate::data_reader::flight_log::{FlightLogEvent, FlightPlayback, FlightRecorder};
+use crate::data_reader::structor::{LaserPoint, Point3};
 use crate::data_reader::udp_reader::{self, ImuData};
 use crate::visualization::color_calculator;
 use crate::octree::creat_octree;
-use crate::calculator::coordinate_switch::mid360_to_bevy;
-use crate::calculator::apf;
-use crate::calculator::apf::ApfConfig;
+use crate::octree::esdf::Esdf;
+use crate::octree::octree::Octree;
+use crate::calculator::coordinate_switch::{bevy_to_mid360, frd_to_bevy, mid360_to_bevy, mid360_to_frd};
+use crate::calculator::crash_detector::{
+    self, AvoidanceConfig, AvoidanceMethod, AvoidanceState, CollisionConfig, CollisionPrediction, CollisionSeverity,
+};
+use crate::calculator::mavlink_args::MavlinkArgs;
+use crate::calculator::vfh::{self, VfhConfig, VfhState};
+use crate::calculator::planner::{ActivePlanner, GlobalPlanner, PlanError, PlannerConfigs, PlanningMap};
+use crate::calculator::trajectory::{self, Trajectory, TrajectoryConfig};
+use crate::calculator::goal::{self, GoalQueue, NavGoal, ReplanConfig};
+use crate::calculator::path_validation::{self, BlockedSegment};
+use crate::calculator::emergency::{Behaviour, BehaviourConfig, BehaviourInputs, BehaviourState, BreadcrumbTrail};
 use crate::data_reader::io;
+use crate::visualization::foxglove_server::FoxgloveServer;
+use crate::mavlink::link::{self, MavlinkEndpoint, MavlinkLink, MavlinkLinkConfig, MavlinkReceived};
+use crate::mavlink::mission;
+use crate::mavlink::obstacle_distance::{self, ObstacleDistanceConfig};
+use crate::mavlink::telemetry::{
+    self, AutopilotStatus, PoseHistory, TelemetryClock, VehicleAttitude, VehicleLocalPosition, VehicleOdometry,
+    VehiclePose,
+};
+use bevy::app::ScheduleRunnerPlugin;
 use std::net::UdpSocket;
+use std::time::{Duration, Instant};
 
 #[derive(Component)]
 struct Ground;
@@ -38,48 +63,194 @@ pub struct VelocityVector(pub Vec3);
 #[derive(Resource)]
 pub struct Path(pub Vec<Point3>);
 
+/// First segment of `Path` the current map blocks
+#[derive(Resource, Default)]
+pub struct InvalidPathSegment(pub Option<BlockedSegment>);
+
+/// Sent when a new map blocks the path that was being flown, just before it is replanned
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PathInvalidated {
+    pub segment: BlockedSegment,
+}
+
+/// Local avoidance command, a hold while nothing is inside the warning distance
+#[derive(Resource)]
+pub struct AvoidanceCommand(pub Option<MavlinkArgs>);
+
+/// Command for the autopilot chosen by the behaviour state machine. It is only streamed
+/// while the autopilot lets the avoidance fly, see `AutopilotStatus`.
+#[derive(Resource)]
+pub struct MavlinkSetpoint(pub Option<MavlinkArgs>);
+
+/// When the last lidar frame with points arrived, `None` before the first one
+#[derive(Resource, Default)]
+pub struct LidarHealth(pub Option<Instant>);
+
+/// Outcome of the recent plans to the active goal
+#[derive(Resource, Default)]
+pub struct PlannerStatus {
+    pub consecutive_failures: u32,
+    pub last_error: Option<PlanError>,
+}
+
+/// Vehicle pose at the time the current lidar frame was taken, `None` without telemetry
+#[derive(Resource)]
+pub struct FramePose(pub Option<VehiclePose>);
+
+/// `Path` smoothed and timed, with the pose and time it starts from
+#[derive(Resource, Default)]
+pub struct PlannedTrajectory {
+    pub trajectory: Option<Trajectory>,
+    pub origin: Option<VehiclePose>,
+    pub start: Option<Instant>,
+}
+
+impl PlannedTrajectory {
+    /// Position, velocity and acceleration setpoint for `now`. Local NED when the pose at
+    /// planning time is known, relative to the vehicle otherwise.
+    pub fn setpoint(&self, now: Instant) -> Option<MavlinkArgs> {
+        let (trajectory, start) = (self.trajectory.as_ref()?, self.start?);
+        let sample = trajectory.sample(now.saturating_duration_since(start).as_secs_f32());
+        Some(match &self.origin {
+            Some(origin) => sample.to_local_ned(origin.position, origin.orientation),
+            None => sample.to_body_offset(),
+        })
+    }
+}
+
+/// APF path read back from a flight log, drawn next to the replanned one
+#[derive(Resource)]
+pub struct RecordedPath(pub Vec<Point3>);
+
+/// Latest point frame handed from the flight log, or the CSV map, to the octree update
+#[derive(Resource)]
+struct PlaybackFrame(Option<Vec<LaserPoint>>);
+
+/// Where the viewer gets its point frames from
+#[derive(Debug, Clone, PartialEq)]
+pub enum PointSource {
+    Sensor,
+    /// A Livox Viewer CSV export, loaded once
+    Csv(String),
+    /// An MCAP flight log written by `FlightRecorder`
+    Playback(String),
+}
+
 #[derive(Resource)]
 pub struct OctreeConfig {
     boundary: f32,
     max_depth: u32,
     voxel_size: f32,
     frame_integration_time: u32,
+    source: PointSource,
+}
+
+impl OctreeConfig {
+    fn build_octree(&self, points: Vec<LaserPoint>) -> Octree {
+        creat_octree::creat_octree_from_points(points, self.boundary, self.max_depth, self.voxel_size)
+    }
+
+    /// Read one frame from the sensor. CSV and playback frames come from `PlaybackFrame`.
+    fn read_points(&self) -> Vec<LaserPoint> {
+        match &self.source {
+            PointSource::Sensor => {
+                let socket_laserpoint = UdpSocket::bind("0.0.0.0:56301").expect("Port bind failed");
+                udp_reader::read_laserpoint(&socket_laserpoint, self.frame_integration_time).unwrap()
+            }
+            PointSource::Csv(_) | PointSource::Playback(_) => Vec::new(),
+        }
+    }
+}
+
+/// How the app is started, filled in from the command line
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub source: PointSource,
+    /// Write an MCAP flight log of everything the app reads and plans
+    pub record_file: Option<String>,
+    /// Serve the Foxglove WebSocket protocol on this address
+    pub foxglove_address: Option<String>,
+    /// Run without a window, e.g. onboard the drone
+    pub headless: bool,
+    /// Send avoidance setpoints to the autopilot over this link
+    pub mavlink_endpoint: Option<MavlinkEndpoint>,
+    pub mavlink_config: MavlinkLinkConfig,
+    pub avoidance_method: AvoidanceMethod,
+    pub planner: GlobalPlanner,
+    /// Waypoints to fly in order, straight ahead when empty
+    pub goals: Vec<NavGoal>,
 }
 
-pub fn run_bevy() {
+/// Build and run the app. Fails before anything starts when a file given on the command
+/// line cannot be read or a server or link cannot be opened.
+pub fn run_bevy(options: RunOptions) -> Result<(), String> {
+    // The CSV map is only read once, so it is handed to the octree update like a replayed frame
+    let csv_points = match &options.source {
+        PointSource::Csv(file_name) => Some(
+            csv_reader::read_laserpoint_csv(file_name).map_err(|e| format!("Failed to read {}: {}", file_name, e))?,
+        ),
+        _ => None,
+    };
+    let playback = match &options.source {
+        PointSource::Playback(file_name) => {
+            Some(FlightPlayback::load(file_name).map_err(|e| format!("Failed to read {}: {}", file_name, e))?)
+        }
+        _ => None,
+    };
     //let boundary: f32 = io::read_with_default("boundary:", 10.0, None);
     let boundary: f32 = 10.0;
     let max_depth: u32 = io::read_with_default("max_depth:", 7, None);
     let voxel_size: f32 = io::read_with_default("voxel_size:", 0.08, None);
     let frame_integration_time: u32 = io::read_with_default("frame_integration_time:", 100, None);
-    App::new()
-        .add_plugins(DefaultPlugins.set(WindowPlugin {
-            primary_window: Some(Window {
-                title: "WorldWithoutAnime".into(),
+    let planner_configs = PlannerConfigs::new(voxel_size);
+    let clearance = planner_configs.clearance();
+    let mut app = App::new();
+    if options.headless {
+        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0))));
+    } else {
+        app
+            .add_plugins(DefaultPlugins.set(WindowPlugin {
+                primary_window: Some(Window {
+                    title: "WorldWithoutAnime".into(),
+                    ..default()
+                }),
                 ..default()
-            }),
-            ..default()
-        }))
-        .add_plugins( FrameTimeDiagnosticsPlugin)
-        .add_plugins(NoCameraPlayerPlugin)
-        .insert_resource(MovementSettings {
-            sensitivity: 0.00009,
-            speed: 3.0,
-        })
+            }))
+            .add_plugins( FrameTimeDiagnosticsPlugin)
+            .add_plugins(NoCameraPlayerPlugin)
+            .insert_resource(MovementSettings {
+                sensitivity: 0.00009,
+                speed: 3.0,
+            })
+            .add_systems(Startup,
+                |commands: Commands,
+                meshes: ResMut<Assets<Mesh>>,
+                materials: ResMut<Assets<StandardMaterial>>
+                | {
+                setup_bevy(
+                    commands,
+                    meshes,
+                    materials,
+                );
+            })
+            .add_systems(Update, text_update_system)
+            .add_systems(Update, imu_text_system)
+            .add_systems(Update, octree_render_system.after(octree_update_system))
+            .add_systems(Update, draw_gizmos)
+            .add_systems(Update, planner_key_system.before(planner_selection_system))
+            .add_systems(Update, map_export_key_system)
+            .add_systems(Update, goal_click_system.before(goal_system));
+    }
+    app
         .insert_resource(OctreeConfig {
             boundary,
             max_depth,
             voxel_size,
             frame_integration_time,
+            source: options.source.clone(),
         })
-        .insert_resource(ApfConfig {
-            k_att: 2.5,
-            k_rep: 2.5,
-            d0: 0.7,
-            epsilon: 0.1,
-            max_steps: 500,
-            step_size: 0.1,
-        })
+        .insert_resource(planner_configs.clone())
+        .insert_resource(ActivePlanner(options.planner.build(&planner_configs)))
         .insert_resource(ImuData {
             version: 0,
             length: 0,
@@ -99,89 +270,116 @@ pub fn run_bevy() {
             acc_y: 0.0,
             acc_z: 0.0,
         })
+        .insert_resource(Octree::new([[-boundary; 3], [boundary; 3]]))
+        .insert_resource(Esdf::new(
+            [[-boundary; 3], [boundary; 3]],
+            get_size(boundary, max_depth),
+            planner_configs.apf.esdf_max_distance(get_size(boundary, max_depth)),
+        ))
+        .insert_resource(AvoidanceConfig {
+            method: options.avoidance_method,
+            stop_distance: clearance,
+            ..default()
+        })
+        .insert_resource(AvoidanceState::default())
+        .insert_resource(VfhConfig {
+            vehicle_radius: clearance,
+            ..default()
+        })
+        .insert_resource(VfhState::default())
+        .insert_resource(goal_queue(&options.goals, planner_configs.apf.epsilon))
+        .insert_resource(ReplanConfig::default())
+        .insert_resource(InvalidPathSegment::default())
+        .add_event::<PathInvalidated>()
+        .insert_resource(options.planner)
+        .insert_resource(TrajectoryConfig {
+            clearance,
+            ..default()
+        })
+        .insert_resource(PlannedTrajectory::default())
+        .insert_resource(CollisionConfig {
+            inflation: voxel_size / 2.0,
+            ..default()
+        })
+        .insert_resource(CollisionPrediction::default())
         .insert_resource(VelocityVector(Vec3::ZERO))
         .insert_resource(Path(Vec::new()))
-        .add_systems(Startup,
-            |commands: Commands,
-            meshes: ResMut<Assets<Mesh>>,
-            materials: ResMut<Assets<StandardMaterial>>,
-            octree_config: Res<OctreeConfig>
-            | {
-            setup_bevy(
-                commands,
-                meshes,
-                materials,
-                octree_config,
-            );
+        .insert_resource(RecordedPath(Vec::new()))
+        .insert_resource(PlaybackFrame(csv_points))
+        .insert_resource(AvoidanceCommand(None))
+        .insert_resource(MavlinkSetpoint(None))
+        .insert_resource(LidarHealth::default())
+        .insert_resource(PlannerStatus::default())
+        .insert_resource(BehaviourConfig::default())
+        .insert_resource(Behaviour::default())
+        .insert_resource(BreadcrumbTrail::default())
+        .insert_resource(FramePose(None))
+        .insert_resource(TelemetryClock::new())
+        .insert_resource(AutopilotStatus::default())
+        .insert_resource(VehicleAttitude::default())
+        .insert_resource(VehicleLocalPosition::default())
+        .insert_resource(VehicleOdometry::default())
+        .insert_resource(PoseHistory::new())
+        .insert_resource(ObstacleDistanceConfig {
+            max_distance: boundary,
+            clearance: voxel_size / 2.0,
+            ..default()
         })
-        .add_systems(Update, text_update_system)
-        .add_systems(Update, update_imu)
-        .add_systems(Update,
-            |commands: Commands,
-            meshes: ResMut<Assets<Mesh>>,
-            materials: ResMut<Assets<StandardMaterial>>,
-            velocity: ResMut<VelocityVector>,
-            path: ResMut<Path>,
-            octree_config: Res<OctreeConfig>,
-            apf_config: Res<ApfConfig>,
-            query: Query<'_, '_, Entity, With<OctreeEntity>>|
-            octree_update_system(
-                commands,
-                meshes,
-                materials,
-                velocity,
-                path,
-                octree_config,
-                apf_config,
-                query
-            ))
-        .add_systems(Update, draw_gizmos)
-        .run();
+        .add_systems(Update, playback_system.before(octree_update_system))
+        .add_event::<MavlinkReceived>()
+        .add_systems(Update, link::mavlink_receive_system.before(telemetry::telemetry_system))
+        .add_systems(Update, telemetry::telemetry_system.before(octree_update_system))
+        .add_systems(Update, mission::mission_system.after(link::mavlink_receive_system).before(goal_system))
+        .add_systems(Update, planner_selection_system.before(path_planning_system))
+        .add_systems(Update, octree_update_system)
+        .add_systems(Update, goal_system.after(octree_update_system))
+        .add_systems(Update, path_planning_system.after(goal_system))
+        .add_systems(Update, foxglove_publish_system.after(avoidance_system))
+        .add_systems(Update, avoidance_system.after(octree_update_system))
+        .add_systems(Update, collision_prediction_system.after(octree_update_system))
+        .add_systems(Update, trajectory_system.after(path_planning_system))
+        .add_systems(
+            Update,
+            behaviour_system
+                .after(avoidance_system)
+                .after(collision_prediction_system)
+                .after(trajectory_system),
+        )
+        .add_systems(Update, mavlink_link_system.after(behaviour_system))
+        .add_systems(Update, obstacle_distance_system.after(octree_update_system));
+    // The IMU reader blocks until a packet arrives, so it only runs with a live sensor
+    if options.source == PointSource::Sensor {
+        app.add_systems(Update, update_imu);
+    }
+    if let Some(playback) = playback {
+        app.insert_resource(playback);
+    }
+    if let Some(file_name) = &options.record_file {
+        let recorder = FlightRecorder::create(file_name)
+            .map_err(|e| format!("Failed to create {}: {}", file_name, e))?;
+        app.insert_resource(recorder);
+    }
+    if let Some(address) = &options.foxglove_address {
+        let server = FoxgloveServer::start(address)
+            .map_err(|e| format!("Failed to start Foxglove server on {}: {}", address, e))?;
+        println!("Foxglove server listening on ws://{}", address);
+        app.insert_resource(server);
+    }
+    if let Some(endpoint) = &options.mavlink_endpoint {
+        let transport = endpoint
+            .open()
+            .map_err(|e| format!("Failed to open MAVLink endpoint {:?}: {}", endpoint, e))?;
+        app.insert_resource(MavlinkLink::start(transport, options.mavlink_config));
+    }
+    app.run();
+    Ok(())
 }
 
 fn setup_bevy(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    octree_config: Res<OctreeConfig>,
 ) {
-    let boundary = octree_config.boundary;
-    let max_depth = octree_config.max_depth;
-    let voxel_size = octree_config.voxel_size;
-    let frame_integration_time = octree_config.frame_integration_time;
-    let mut octree = creat_octree::creat_octree_from_udp(boundary, max_depth, voxel_size, frame_integration_time);
-    octree.optimize();
-    let leaves = octree.octree_to_map();
-
-    for (depth, group) in leaves {
-        let grouped_pixel_points = point_divider::divide_points(group);
-        let cube_mesh = meshes.add(Mesh::from(
-            Cuboid::new(
-                get_size(boundary, depth),
-                get_size(boundary, depth),
-                get_size(boundary, depth)
-            )
-        ));
-
-        for (reflectivity, group) in &grouped_pixel_points {
-            let material = materials.add(StandardMaterial {
-                emissive: color_calculator::reflectivity_to_color(*reflectivity).into(),
-                ..default()
-            });
-
-            for point in group {
-                let (x, y, z) = mid360_to_bevy(point.x, point.y, point.z);
-                commands.spawn((
-                    //LaserPoint,
-                    Mesh3d(cube_mesh.clone()), // Reuse the same mesh
-                    MeshMaterial3d(material.clone()), // Reuse the same material
-                    Transform::from_translation(Vec3::new(x, y, z)),
-                    OctreeEntity,
-                ));
-            }
-        }
-    };
-
     // Add a camera at [0, 0, 2] and look at front
     commands.spawn((
         Camera3d::default(),
@@ -286,34 +484,500 @@ fn text_update_system(
 }
 
 fn octree_update_system(
+    mut octree: ResMut<Octree>,
+    mut esdf: ResMut<Esdf>,
+    octree_config: Res<OctreeConfig>,
+    mut playback_frame: ResMut<PlaybackFrame>,
+    mut recorder: Option<ResMut<FlightRecorder>>,
+    clock: Res<TelemetryClock>,
+    poses: Res<PoseHistory>,
+    mut frame_pose: ResMut<FramePose>,
+    mut lidar_health: ResMut<LidarHealth>,
+) {
+    let points = match &octree_config.source {
+        PointSource::Sensor => octree_config.read_points(),
+        // Only rebuild when the flight log has a new frame. The CSV map is a single frame,
+        // so it is built once.
+        PointSource::Csv(_) | PointSource::Playback(_) => match playback_frame.0.take() {
+            Some(points) => points,
+            None => return,
+        },
+    };
+    // A sensor frame is integrated up to now, its points were taken around the middle of that window
+    let frame_time = match &octree_config.source {
+        PointSource::Sensor => Instant::now() - Duration::from_millis(octree_config.frame_integration_time as u64 / 2),
+        _ => Instant::now(),
+    };
+    if !points.is_empty() {
+        lidar_health.0 = Some(Instant::now());
+    }
+    frame_pose.0 = clock.to_remote(frame_time).and_then(|time_boot_us| poses.pose_at(time_boot_us));
+    if let Some(recorder) = recorder.as_mut() {
+        if let Err(e) = recorder.record_points(&points) {
+            println!("Error: failed to record point frame: {}", e);
+        }
+        if let Some(pose) = &frame_pose.0 {
+            let position = Point3::new(pose.position.x, pose.position.y, pose.position.z);
+            if let Err(e) = recorder.record_pose(position, pose.orientation) {
+                println!("Error: failed to record vehicle pose: {}", e);
+            }
+        }
+    }
+
+    let mut new_octree = octree_config.build_octree(points);
+    new_octree.optimize();
+    esdf.update_from_octree(&new_octree);
+    *octree = new_octree;
+}
+
+/// Pin new goals to the vehicle pose and move on to the next waypoint once the vehicle,
+/// at the origin of every map, is within the acceptance radius of the active one
+fn goal_system(octree: Res<Octree>, frame_pose: Res<FramePose>, mut goals: ResMut<GoalQueue>) {
+    if let Some(pose) = &frame_pose.0 {
+        goals.anchor(pose.position, pose.orientation);
+    }
+    if !octree.is_changed() {
+        return;
+    }
+    let pose = frame_pose.0.map(|pose| (pose.position, pose.orientation));
+    let Some(distance) = goals.active().and_then(|goal| goal.to_mid360(pose)).map(|goal| goal.norm()) else {
+        return;
+    };
+    if goals.update(distance).is_some() {
+        println!("Goal reached, {} left", goals.len());
+    }
+}
+
+/// Plan to the active goal when it changed, and otherwise only when the new map makes
+/// the last path unusable. A kept path is moved into the frame of the new map and checked
+/// against it, a blocked one is replanned from where the vehicle is now.
+#[allow(clippy::too_many_arguments)]
+fn path_planning_system(
+    octree: Res<Octree>,
+    esdf: Res<Esdf>,
+    goals: Res<GoalQueue>,
+    frame_pose: Res<FramePose>,
+    config: Res<ReplanConfig>,
+    mut planner: ResMut<ActivePlanner>,
+    mut path: ResMut<Path>,
+    mut invalid_segment: ResMut<InvalidPathSegment>,
+    mut invalidated: EventWriter<PathInvalidated>,
+    mut status: ResMut<PlannerStatus>,
+    mut recorder: Option<ResMut<FlightRecorder>>,
+    mut planned_version: Local<Option<u64>>,
+    mut planned_position: Local<Option<Vec3>>,
+    mut path_pose: Local<Option<VehiclePose>>,
+) {
+    // A new planner plans from scratch as well
+    let goal_changed = *planned_version != Some(goals.version()) || planner.is_changed();
+    if !octree.is_changed() && !goal_changed {
+        return;
+    }
+    let pose = frame_pose.0.map(|pose| (pose.position, pose.orientation));
+    let Some(goal) = goals.active().and_then(|goal| goal.to_mid360(pose)) else {
+        // Nothing to fly to, or a local NED goal before the first pose
+        if goal_changed || !path.0.is_empty() {
+            planner.0.cancel();
+            path.0 = Vec::new();
+            invalid_segment.0 = None;
+            *status = PlannerStatus::default();
+        }
+        *planned_version = Some(goals.version());
+        return;
+    };
+
+    let moved = if !octree.is_changed() {
+        Some(0.0)
+    } else {
+        match (*path_pose, frame_pose.0) {
+            (Some(from), Some(to)) => {
+                // Not a new path, the trajectory keeps its own origin
+                path.bypass_change_detection().0 =
+                    goal::reframe_path(&path.0, (from.position, from.orientation), (to.position, to.orientation));
+                *path_pose = Some(to);
+                planned_position.map(|position| position.distance(to.position))
+            }
+            _ => None,
+        }
+    };
+    let blocked = path_validation::first_blocked_segment(&path.0, &octree, planner.0.free_radius());
+    if let (Some(segment), false) = (blocked, goal_changed) {
+        println!("Warning: path blocked at segment {}, replanning", segment.index);
+        invalidated.send(PathInvalidated { segment });
+    }
+    if goal::replan_reason(goal_changed, &path.0, moved, blocked.is_some(), &config).is_none() {
+        invalid_segment.0 = blocked;
+        return;
+    }
+
+    let start = Point3::new(0.0, 0.0, 0.0);
+    let map = PlanningMap { octree: &octree, esdf: &esdf };
+    let vec = match planner.0.plan(start, goal, &map) {
+        Ok(path) => {
+            *status = PlannerStatus::default();
+            path
+        }
+        Err(e) => {
+            // Fly the partial path if there is one, APF's still leads away from the obstacles
+            println!("Error: {} {}", planner.0.name(), e);
+            let partial = e.partial_path().to_vec();
+            status.consecutive_failures += 1;
+            status.last_error = Some(e);
+            partial
+        }
+    };
+    if let Some(recorder) = recorder.as_mut() {
+        if let Err(e) = recorder.record_path(&vec) {
+            println!("Error: failed to record path: {}", e);
+        }
+    }
+    // Partial paths and paths around a moving obstacle may still be blocked
+    invalid_segment.0 = path_validation::first_blocked_segment(&vec, &octree, planner.0.free_radius());
+    path.0 = vec;
+    *planned_version = Some(goals.version());
+    *planned_position = frame_pose.0.map(|pose| pose.position);
+    *path_pose = frame_pose.0;
+}
+
+/// Swap the active planner when the selection or the planner settings change
+fn planner_selection_system(
+    selection: Res<GlobalPlanner>,
+    configs: Res<PlannerConfigs>,
+    mut planner: ResMut<ActivePlanner>,
+) {
+    if !selection.is_changed() && !configs.is_changed() {
+        return;
+    }
+    planner.0 = selection.build(&configs);
+    println!("Planner: {}", planner.0.name());
+}
+
+/// `P` cycles through the planners
+fn planner_key_system(keys: Res<ButtonInput<KeyCode>>, mut selection: ResMut<GlobalPlanner>) {
+    if keys.just_pressed(KeyCode::KeyP) {
+        *selection = selection.next();
+    }
+}
+
+/// `C` writes the current map to `octree_<unix time>.csv` in the working directory
+fn map_export_key_system(keys: Res<ButtonInput<KeyCode>>, octree: Res<Octree>) {
+    if !keys.just_pressed(KeyCode::KeyC) {
+        return;
+    }
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let file_name = format!("octree_{}.csv", seconds);
+    match structor_to_file::octree_to_csv(&octree, &file_name) {
+        Ok(()) => println!("Map written to {}", file_name),
+        Err(e) => println!("Error: failed to write {}: {}", file_name, e),
+    }
+}
+
+/// Right click on the ground grid sets the goal there, at the height of the sensor.
+/// With shift held it is queued after the other waypoints instead.
+fn goal_click_system(
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    mut goals: ResMut<GoalQueue>,
+) {
+    if !buttons.just_pressed(MouseButton::Right) {
+        return;
+    }
+    let (Ok(window), Ok((camera, camera_transform))) = (windows.get_single(), cameras.get_single()) else {
+        return;
+    };
+    let Some(ray) = window
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor).ok())
+    else {
+        return;
+    };
+    let Some(distance) = ray.intersect_plane(Vec3::ZERO, InfinitePlane3d::new(Vec3::Y)) else {
+        return;
+    };
+    let point = ray.get_point(distance);
+    let (x, y, z) = bevy_to_mid360(point.x, point.y, point.z);
+    let goal = NavGoal::mid360(Point3::new(x, y, z));
+    if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        goals.push(goal);
+    } else {
+        goals.set(goal);
+    }
+}
+
+/// Queue of the waypoints given on the command line, or one goal 5 m ahead of the sensor
+fn goal_queue(waypoints: &[NavGoal], default_acceptance_radius: f32) -> GoalQueue {
+    let mut goals = GoalQueue::new(default_acceptance_radius);
+    if waypoints.is_empty() {
+        goals.push(NavGoal::mid360(Point3::new(5.0, 0.0, 0.0)));
+    } else {
+        goals.extend(waypoints.iter().copied());
+    }
+    goals
+}
+
+/// Compute the avoidance command for every new map. With nothing inside the warning
+/// distance the command is a hold in place.
+fn avoidance_system(
+    octree: Res<Octree>,
+    config: Res<AvoidanceConfig>,
+    mut state: ResMut<AvoidanceState>,
+    vfh_config: Res<VfhConfig>,
+    mut vfh_state: ResMut<VfhState>,
+    goals: Res<GoalQueue>,
+    frame_pose: Res<FramePose>,
+    mut last_update: Local<Option<Instant>>,
+    mut velocity: ResMut<VelocityVector>,
+    mut avoidance: ResMut<AvoidanceCommand>,
+) {
+    if !octree.is_changed() {
+        return;
+    }
+    let now = Instant::now();
+    let dt = last_update.map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+    *last_update = Some(now);
+
+    // Telemetry velocity is NED, avoidance works in FRD along the heading, see `AVOIDANCE_FRAME`
+    let current_velocity = frame_pose.0.map(|pose| {
+        let (_, _, yaw) = pose.euler();
+        (Quat::from_rotation_z(-yaw) * pose.velocity).to_array()
+    });
+    // Without a goal VFH holds
+    let goal = goals
+        .active()
+        .and_then(|goal| goal.to_mid360(frame_pose.0.map(|pose| (pose.position, pose.orientation))))
+        .map_or([0.0; 3], |goal| [goal.x, goal.y, goal.z]);
+    let command = match config.method {
+        AvoidanceMethod::Repulsion => {
+            let (_, obstacle_list) = crash_detector::crash_warn_for_octree(&octree, config.warn_distance);
+            crash_detector::obstacle_avoidance(&obstacle_list, &config, &mut state, current_velocity, dt)
+        }
+        AvoidanceMethod::Vfh => vfh::vfh_avoidance(&octree, goal, &vfh_config, &mut vfh_state),
+    };
+    let (x, y, z) = frd_to_bevy(command.vx, command.vy, command.vz);
+    velocity.0 = Vec3::new(x, y, z);
+    avoidance.0 = Some(command);
+}
+
+/// Run the behaviour state machine and pick the setpoint of its state. Nominal flies the
+/// planned trajectory, or the avoidance command while there is none.
+#[allow(clippy::too_many_arguments)]
+fn behaviour_system(
+    config: Res<BehaviourConfig>,
+    mut behaviour: ResMut<Behaviour>,
+    mut trail: ResMut<BreadcrumbTrail>,
+    prediction: Res<CollisionPrediction>,
+    lidar_health: Res<LidarHealth>,
+    planner_status: Res<PlannerStatus>,
+    avoidance_state: Res<AvoidanceState>,
+    avoidance: Res<AvoidanceCommand>,
+    planned: Res<PlannedTrajectory>,
+    poses: Res<PoseHistory>,
+    status: Res<AutopilotStatus>,
+    octree_config: Res<OctreeConfig>,
+    mut path: ResMut<Path>,
+    mut last_update: Local<Option<Instant>>,
+    mut setpoint: ResMut<MavlinkSetpoint>,
+) {
+    let now = Instant::now();
+    let dt = last_update.map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+    *last_update = Some(now);
+
+    // Landed and disarmed, ready to fly again
+    if behaviour.state() != BehaviourState::Nominal && status.is_alive(now) && !status.is_armed() {
+        behaviour.reset();
+    }
+    let sensor_healthy = match (&octree_config.source, lidar_health.0) {
+        // A replayed CSV map is read once and never goes stale
+        (PointSource::Csv(_), _) => true,
+        // Until the first frame there is no map to lose
+        (_, None) => true,
+        (_, Some(last)) => now.duration_since(last).as_secs_f32() < config.sensor_timeout,
+    };
+    let position = poses.latest().map(|pose| pose.position);
+    let inputs = BehaviourInputs {
+        severity: prediction.severity,
+        sensor_healthy,
+        planner_failures: planner_status.consecutive_failures,
+        avoidance_stuck: avoidance_state.yaw_search_time.is_some(),
+        position,
+    };
+    let previous = behaviour.state();
+    if let Some(state) = behaviour.update(&inputs, &mut trail, &config, dt) {
+        println!("Behaviour: {:?} -> {:?}", previous, state);
+        // The trajectory kept running meanwhile, plan again from where the vehicle is now
+        if state == BehaviourState::Nominal {
+            path.0 = Vec::new();
+        }
+    }
+    if let (Some(position), BehaviourState::Nominal | BehaviourState::Caution) = (position, behaviour.state()) {
+        trail.record(position);
+    }
+    setpoint.0 = Some(behaviour.command(planned.setpoint(now), avoidance.0, &config));
+}
+
+/// Turn every new path into a trajectory the vehicle can fly
+fn trajectory_system(
+    octree: Res<Octree>,
+    path: Res<Path>,
+    config: Res<TrajectoryConfig>,
+    frame_pose: Res<FramePose>,
+    mut planned: ResMut<PlannedTrajectory>,
+) {
+    if !path.is_changed() {
+        return;
+    }
+    planned.trajectory = match trajectory::generate_trajectory(&path.0, &octree, &config) {
+        Ok(trajectory) => Some(trajectory),
+        Err(trajectory::TrajectoryError::EmptyPath) => None,
+        Err(e) => {
+            println!("Error: {}", e);
+            None
+        }
+    };
+    planned.origin = frame_pose.0;
+    planned.start = Some(Instant::now());
+}
+
+/// Predict where the vehicle hits something if it keeps its current velocity
+fn collision_prediction_system(
+    octree: Res<Octree>,
+    config: Res<CollisionConfig>,
+    frame_pose: Res<FramePose>,
+    mut prediction: ResMut<CollisionPrediction>,
+) {
+    if !octree.is_changed() {
+        return;
+    }
+    // Telemetry velocity is NED, FRD and the Mid-360 frame only differ in the sign of y and z
+    let velocity = frame_pose.0.map_or([0.0; 3], |pose| {
+        let body = pose.orientation.inverse() * pose.velocity;
+        let (x, y, z) = mid360_to_frd(body.x, body.y, body.z);
+        [x, y, z]
+    });
+    let new_prediction = crash_detector::predict_collision(&octree, velocity, &config);
+    if new_prediction.severity >= CollisionSeverity::Warning && new_prediction.severity > prediction.severity {
+        println!(
+            "Warning: {:?} collision in {:.2} s at {:?}",
+            new_prediction.severity,
+            new_prediction.time_to_collision.unwrap_or(0.0),
+            new_prediction.hit_voxel.unwrap_or_default(),
+        );
+    }
+    *prediction = new_prediction;
+}
+
+/// Hand the current setpoint to the MAVLink link while the autopilot accepts it,
+/// and log the commands it sent
+fn mavlink_link_system(
+    link: Option<Res<MavlinkLink>>,
+    setpoint: Res<MavlinkSetpoint>,
+    status: Res<AutopilotStatus>,
+    mut streaming: Local<bool>,
+    mut recorder: Option<ResMut<FlightRecorder>>,
+    server: Option<Res<FoxgloveServer>>,
+) {
+    let Some(link) = link else {
+        return;
+    };
+    let should_stream = status.should_stream_setpoints(Instant::now());
+    if setpoint.is_changed() || should_stream != *streaming {
+        *streaming = should_stream;
+        if let Err(e) = link.set_setpoint(if should_stream { setpoint.0 } else { None }) {
+            println!("Error: rejected MAVLink setpoint: {}", e);
+        }
+    }
+    for args in link.take_sent_setpoints() {
+        if let Some(recorder) = recorder.as_mut() {
+            if let Err(e) = recorder.record_mavlink_args(&args) {
+                println!("Error: failed to record MAVLink setpoint: {}", e);
+            }
+        }
+        if let Some(server) = &server {
+            server.publish_mavlink_args(&args);
+        }
+    }
+}
+
+/// Send every new map as OBSTACLE_DISTANCE, so PX4 collision prevention and ArduPilot
+/// proximity avoidance can use the lidar as well
+fn obstacle_distance_system(
+    link: Option<Res<MavlinkLink>>,
+    octree: Res<Octree>,
+    config: Res<ObstacleDistanceConfig>,
+    frame_pose: Res<FramePose>,
+) {
+    let Some(link) = link else {
+        return;
+    };
+    if !octree.is_changed() {
+        return;
+    }
+    let level = frame_pose.0.map_or(Quat::IDENTITY, |pose| obstacle_distance::level_rotation(pose.orientation));
+    let message = obstacle_distance::obstacle_distance_from_octree(&octree, &config, level, link.time_boot_ms() as u64 * 1000);
+    if let Err(e) = link.send(&message) {
+        println!("Error: failed to send OBSTACLE_DISTANCE: {}", e);
+    }
+}
+
+/// Feed due flight log messages into the viewer. Only the newest point frame of each
+/// update is kept, the map is rebuilt from scratch anyway.
+fn playback_system(
+    time: Res<Time>,
+    playback: Option<ResMut<FlightPlayback>>,
+    mut imu: ResMut<ImuData>,
+    mut recorded_path: ResMut<RecordedPath>,
+    mut playback_frame: ResMut<PlaybackFrame>,
+) {
+    let Some(mut playback) = playback else {
+        return;
+    };
+    for entry in playback.advance(time.delta()) {
+        match &entry.event {
+            FlightLogEvent::Points(points) => playback_frame.0 = Some(points.clone()),
+            FlightLogEvent::Imu(message) => {
+                imu.timestamp = message.sensor_timestamp;
+                imu.gyro_x = message.angular_velocity.x as f32;
+                imu.gyro_y = message.angular_velocity.y as f32;
+                imu.gyro_z = message.angular_velocity.z as f32;
+                imu.acc_x = message.linear_acceleration.x as f32;
+                imu.acc_y = message.linear_acceleration.y as f32;
+                imu.acc_z = message.linear_acceleration.z as f32;
+            }
+            FlightLogEvent::ApfPath(points) => recorded_path.0 = points.clone(),
+            FlightLogEvent::Pose(_) | FlightLogEvent::MavlinkArgs(_) => {}
+        }
+    }
+}
+
+/// Respawn the voxel cubes whenever the map was rebuilt
+fn octree_render_system(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut velocity: ResMut<VelocityVector>,
-    mut path: ResMut<Path>,
+    octree: Res<Octree>,
     octree_config: Res<OctreeConfig>,
-    apf_config: Res<ApfConfig>,
     query: Query<Entity, With<OctreeEntity>>,
 ) {
+    if !octree.is_changed() {
+        return;
+    }
     for entity in query.iter() {
         commands.entity(entity).despawn();
     }
-    let boundary = octree_config.boundary;
-    let max_depth = octree_config.max_depth;
-    let voxel_size = octree_config.voxel_size;
-    let frame_integration_time = octree_config.frame_integration_time;
-    let mut octree = creat_octree::creat_octree_from_udp(boundary, max_depth, voxel_size, frame_integration_time);
-
-    octree.optimize();
 
+    let boundary = octree_config.boundary;
     let leaves = octree.octree_to_map();
     for (depth, group) in leaves {
         let grouped_pixel_points = point_divider::divide_points(group);
         let cube_mesh = meshes.add(Mesh::from(
             Cuboid::new(
-                get_size(10.0, depth),
-                get_size(10.0, depth),
-                get_size(10.0, depth)
+                get_size(boundary, depth),
+                get_size(boundary, depth),
+                get_size(boundary, depth)
             )
         ));
 
@@ -334,42 +998,44 @@ fn octree_update_system(
             }
         }
     };
+}
 
-    // APF palnning
-    let start = Point3::new(0.0, 0.0, 0.0);
-    let goal_mid360 = (5.0, 0.0, 0.0);
-    let goal = Point3::new(goal_mid360.0, goal_mid360.1, goal_mid360.2);
-
-    let config = ApfConfig {
-        k_att: apf_config.k_att,
-        k_rep: apf_config.k_rep,
-        d0: apf_config.d0,
-        epsilon: apf_config.epsilon,
-        max_steps: apf_config.max_steps,
-        step_size: apf_config.step_size,
-    };
-
-    let _warn_trigger_distance = apf_config.d0;
-
-    let apf_path = apf::apf_plan(start, goal, &octree, config);
-    let vec = match apf_path {
-        Ok(path) => {
-            path
-        }
-        Err(e) => {
-            println!("Error: {:?}", e);
-            Vec::new()
-        }
+/// Push whatever changed this update to Foxglove clients
+fn foxglove_publish_system(
+    server: Option<Res<FoxgloveServer>>,
+    octree: Res<Octree>,
+    imu: Res<ImuData>,
+    path: Res<Path>,
+    velocity: Res<VelocityVector>,
+) {
+    let Some(server) = server else {
+        return;
     };
-    path.0 = vec;
-
-    velocity.0 = Vec3::ZERO;
+    if octree.is_changed() && server.has_subscribers(server.points_channel()) {
+        server.publish_points(&octree_map_to_points(&octree.octree_to_map()));
+    }
+    if imu.is_changed() {
+        server.publish_imu(&imu);
+    }
+    if path.is_changed() {
+        server.publish_path(&path.0);
+    }
+    if velocity.is_changed() {
+        server.publish_velocity(velocity.0);
+    }
 }
 
 fn draw_gizmos(
     mut gizmos: Gizmos,
     velocity: Res<VelocityVector>,
     path: Res<Path>,
+    recorded_path: Res<RecordedPath>,
+    planned: Res<PlannedTrajectory>,
+    prediction: Res<CollisionPrediction>,
+    goals: Res<GoalQueue>,
+    frame_pose: Res<FramePose>,
+    invalid_segment: Res<InvalidPathSegment>,
+    trail: Res<BreadcrumbTrail>,
 ) {
     use std::f32::consts::PI;
     gizmos.line(
@@ -395,6 +1061,76 @@ fn draw_gizmos(
             );
         }
     }
+    // Segment the map blocks, drawn over the path with a marker at each end
+    if let Some(segment) = &invalid_segment.0 {
+        let (x, y, z) = mid360_to_bevy(segment.from.x, segment.from.y, segment.from.z);
+        let (x1, y1, z1) = mid360_to_bevy(segment.to.x, segment.to.y, segment.to.z);
+        let color = Color::srgb_u8(255, 0, 0);
+        gizmos.line(Vec3::new(x, y, z), Vec3::new(x1, y1, z1), color);
+        gizmos.sphere(Isometry3d::from_translation(Vec3::new(x, y, z)), 0.05, color);
+        gizmos.sphere(Isometry3d::from_translation(Vec3::new(x1, y1, z1)), 0.05, color);
+    }
+    // Smoothed trajectory, sampled every 0.1 s
+    if let Some(trajectory) = &planned.trajectory {
+        for pair in trajectory.samples(0.1).windows(2) {
+            let [x, y, z] = pair[0].1.position;
+            let [x1, y1, z1] = pair[1].1.position;
+            let (x, y, z) = mid360_to_bevy(x, y, z);
+            let (x1, y1, z1) = mid360_to_bevy(x1, y1, z1);
+            gizmos.line(
+                Vec3::new(x, y, z),
+                Vec3::new(x1, y1, z1),
+                Color::srgb_u8(255, 0, 255),
+            );
+        }
+    }
+    // The path that was planned during the recorded flight
+    for segment in recorded_path.0.windows(2) {
+        let (x, y, z) = mid360_to_bevy(segment[0].x, segment[0].y, segment[0].z);
+        let (x1, y1, z1) = mid360_to_bevy(segment[1].x, segment[1].y, segment[1].z);
+        gizmos.line(
+            Vec3::new(x, y, z),
+            Vec3::new(x1, y1, z1),
+            Color::srgb_u8(0, 128, 255),
+        );
+    }
+    // First leaf the vehicle would hit, redder the sooner
+    if let Some([x, y, z]) = prediction.hit_voxel {
+        let color = match prediction.severity {
+            CollisionSeverity::Critical => Color::srgb_u8(255, 0, 0),
+            CollisionSeverity::Warning => Color::srgb_u8(255, 128, 0),
+            _ => Color::srgb_u8(255, 255, 0),
+        };
+        let (x, y, z) = mid360_to_bevy(x, y, z);
+        gizmos.sphere(Isometry3d::from_translation(Vec3::new(x, y, z)), 0.1, color);
+    }
+    // Active goal with its acceptance radius, then the queued waypoints
+    let pose = frame_pose.0.map(|pose| (pose.position, pose.orientation));
+    // Breadcrumb trail the vehicle backs off along
+    if let Some((position, orientation)) = pose {
+        let crumbs: Vec<Vec3> = trail
+            .crumbs()
+            .map(|crumb| {
+                let point = goal::local_ned_to_mid360(*crumb, position, orientation);
+                let (x, y, z) = mid360_to_bevy(point.x, point.y, point.z);
+                Vec3::new(x, y, z)
+            })
+            .collect();
+        gizmos.linestrip(crumbs, Color::srgb_u8(255, 160, 0));
+    }
+    let waypoints = goals.active().into_iter().chain(goals.pending());
+    for (i, goal) in waypoints.enumerate() {
+        let Some(position) = goal.to_mid360(pose) else {
+            continue;
+        };
+        let (x, y, z) = mid360_to_bevy(position.x, position.y, position.z);
+        let (radius, color) = if i == 0 {
+            (goals.acceptance_radius(goal).max(0.05), Color::srgb_u8(0, 255, 255))
+        } else {
+            (0.05, Color::srgb_u8(255, 255, 255))
+        };
+        gizmos.sphere(Isometry3d::from_translation(Vec3::new(x, y, z)), radius, color);
+    }
 }
 
 fn get_size(boundary: f32, max_depth: u32) -> f32 {
@@ -406,13 +1142,29 @@ fn get_size(boundary: f32, max_depth: u32) -> f32 {
 }
 
 fn update_imu(
+    mut imu: ResMut<ImuData>,
+    recorder: Option<ResMut<FlightRecorder>>,
+) {
+    let socket_imu = UdpSocket::bind("0.0.0.0:56401").expect("Port bind failed");
+    let imu_data = udp_reader::read_imu(&socket_imu).unwrap();
+    if let Some(mut recorder) = recorder {
+        if let Err(e) = recorder.record_imu(&imu_data) {
+            println!("Error: failed to record IMU sample: {}", e);
+        }
+    }
+    *imu = imu_data;
+}
+
+fn imu_text_system(
+    imu_data: Res<ImuData>,
     mut param_set: ParamSet<(
         Query<&mut Text, With<IMUEntityGyro>>,
         Query<&mut Text, With<IMUEntityAcc>>,
     )>,
 ) {
-    let socket_imu = UdpSocket::bind("0.0.0.0:56401").expect("Port bind failed");
-    let imu_data = udp_reader::read_imu(&socket_imu).unwrap();
+    if !imu_data.is_changed() {
+        return;
+    }
 
     for mut text in param_set.p0().iter_mut() {
         **text = format!("Gyro: Rad/s\nx:{:6.2}, y:{:6.2}, Z:{:6.2}", imu_data.gyro_x, imu_data.gyro_y, imu_data.gyro_z);
@@ -0,0 +1,392 @@
+#![allow(dead_code)]
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{BufRead, Error, ErrorKind, Read, Write};
+
+/// Appended to the client key before hashing, RFC 6455 section 1.3
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Client messages are small JSON requests, anything bigger is treated as garbage
+const MAX_MESSAGE_SIZE: u64 = 1 << 20;
+/// Request line and headers of the upgrade request together, browsers send well under 4 KiB
+const MAX_HANDSHAKE_SIZE: u64 = 16 * 1024;
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeRequest {
+    pub key: String,
+    /// Values of Sec-WebSocket-Protocol, in the order the client offered them
+    pub protocols: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// Read the HTTP upgrade request of a WebSocket client
+pub fn read_handshake<R: BufRead>(reader: &mut R) -> std::io::Result<HandshakeRequest> {
+    let mut key = None;
+    let mut upgrade = false;
+    let mut protocols = Vec::new();
+
+    let mut line = String::new();
+    let mut remaining = MAX_HANDSHAKE_SIZE;
+    read_handshake_line(reader, &mut line, &mut remaining)?;
+    if !line.starts_with("GET ") {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket handshake is not a GET request"));
+    }
+    loop {
+        if read_handshake_line(reader, &mut line, &mut remaining)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "WebSocket handshake ended early"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => key = Some(value.to_string()),
+            "sec-websocket-protocol" => {
+                protocols.extend(value.split(',').map(|protocol| protocol.trim().to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    if !upgrade {
+        return Err(Error::new(ErrorKind::InvalidData, "HTTP request is not a WebSocket upgrade"));
+    }
+    let key = key.ok_or_else(|| Error::new(ErrorKind::InvalidData, "WebSocket handshake has no key"))?;
+    Ok(HandshakeRequest { key, protocols })
+}
+
+/// `read_line` that fails instead of buffering more than `remaining` bytes,
+/// so a client cannot grow the request without ever sending a newline
+fn read_handshake_line<R: BufRead>(reader: &mut R, line: &mut String, remaining: &mut u64) -> std::io::Result<usize> {
+    line.clear();
+    let read = reader.by_ref().take(*remaining).read_line(line)?;
+    *remaining -= read as u64;
+    if *remaining == 0 && !line.ends_with('\n') {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("WebSocket handshake is larger than {} bytes", MAX_HANDSHAKE_SIZE),
+        ));
+    }
+    Ok(read)
+}
+
+pub fn write_handshake_response<W: Write>(writer: &mut W, key: &str, protocol: Option<&str>) -> std::io::Result<()> {
+    write!(writer, "HTTP/1.1 101 Switching Protocols\r\n")?;
+    write!(writer, "Upgrade: websocket\r\n")?;
+    write!(writer, "Connection: Upgrade\r\n")?;
+    write!(writer, "Sec-WebSocket-Accept: {}\r\n", accept_key(key))?;
+    if let Some(protocol) = protocol {
+        write!(writer, "Sec-WebSocket-Protocol: {}\r\n", protocol)?;
+    }
+    write!(writer, "\r\n")?;
+    writer.flush()
+}
+
+pub fn accept_key(key: &str) -> String {
+    BASE64.encode(sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+/// Header of a final, unmasked server frame. The payload is written by the caller.
+pub fn write_frame_header<W: Write>(writer: &mut W, opcode: u8, length: u64) -> std::io::Result<()> {
+    writer.write_u8(0x80 | opcode)?;
+    if length < 126 {
+        writer.write_u8(length as u8)
+    } else if length <= u16::MAX as u64 {
+        writer.write_u8(126)?;
+        writer.write_u16::<BigEndian>(length as u16)
+    } else {
+        writer.write_u8(127)?;
+        writer.write_u64::<BigEndian>(length)
+    }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    write_frame_header(writer, opcode, payload.len() as u64)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Read one frame and return (fin, opcode, unmasked payload)
+pub fn read_frame<R: Read>(reader: &mut R) -> std::io::Result<(bool, u8, Vec<u8>)> {
+    let first = reader.read_u8()?;
+    let second = reader.read_u8()?;
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0F;
+    let masked = second & 0x80 != 0;
+    let length = match second & 0x7F {
+        126 => reader.read_u16::<BigEndian>()? as u64,
+        127 => reader.read_u64::<BigEndian>()?,
+        length => length as u64,
+    };
+    if length > MAX_MESSAGE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, format!("WebSocket frame of {} bytes is too large", length)));
+    }
+
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok((fin, opcode, payload))
+}
+
+/// Reads messages from a client stream, joining fragmented text and binary frames.
+/// Control frames may arrive between fragments; they are returned as they come and the
+/// fragments read so far are kept for the next call.
+pub struct MessageReader<R: Read> {
+    reader: R,
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, fragments: None }
+    }
+
+    pub fn read_message(&mut self) -> std::io::Result<Message> {
+        loop {
+            let (fin, opcode, payload) = read_frame(&mut self.reader)?;
+            match opcode {
+                OPCODE_CLOSE => return Ok(Message::Close),
+                OPCODE_PING => return Ok(Message::Ping(payload)),
+                OPCODE_PONG => return Ok(Message::Pong(payload)),
+                OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_none() => self.fragments = Some((opcode, payload)),
+                OPCODE_CONTINUATION if self.fragments.is_some() => {
+                    let (_, data) = self.fragments.as_mut().unwrap();
+                    data.extend_from_slice(&payload);
+                    if data.len() as u64 > MAX_MESSAGE_SIZE {
+                        return Err(Error::new(ErrorKind::InvalidData, "Fragmented WebSocket message is too large"));
+                    }
+                }
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected WebSocket opcode {}", opcode))),
+            }
+
+            if fin {
+                let (opcode, data) = self.fragments.take().unwrap();
+                return if opcode == OPCODE_TEXT {
+                    String::from_utf8(data)
+                        .map(Message::Text)
+                        .map_err(|_| Error::new(ErrorKind::InvalidData, "WebSocket text message is not UTF-8"))
+                } else {
+                    Ok(Message::Binary(data))
+                };
+            }
+        }
+    }
+}
+
+/// SHA-1 for the handshake only, it is not used for anything security related
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, value) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client frame as browsers send it: masked, with the given fin bit
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.push(if fin { 0x80 } else { 0 } | opcode);
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn sha1_matches_known_digests() {
+        let hex = |digest: [u8; 20]| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Two blocks: the padding does not fit after 56 bytes
+        assert_eq!(
+            hex(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn reads_the_rfc_handshake_and_answers_it() {
+        let request = "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nOrigin: http://example.com\r\n\
+Sec-WebSocket-Protocol: chat, foxglove.websocket.v1\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let handshake = read_handshake(&mut request.as_bytes()).unwrap();
+        assert_eq!(handshake.key, "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(handshake.protocols, vec!["chat", "foxglove.websocket.v1"]);
+
+        let mut response = Vec::new();
+        write_handshake_response(&mut response, &handshake.key, Some("foxglove.websocket.v1")).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Protocol: foxglove.websocket.v1\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn rejects_bad_and_oversized_handshakes() {
+        let no_upgrade = "GET / HTTP/1.1\r\nSec-WebSocket-Key: abc\r\n\r\n";
+        assert!(read_handshake(&mut no_upgrade.as_bytes()).is_err());
+        let no_key = "GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+        assert!(read_handshake(&mut no_key.as_bytes()).is_err());
+        let post = "POST / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: abc\r\n\r\n";
+        assert!(read_handshake(&mut post.as_bytes()).is_err());
+
+        let endless_header = format!("GET / HTTP/1.1\r\nX-Padding: {}", "a".repeat(MAX_HANDSHAKE_SIZE as usize));
+        let error = read_handshake(&mut endless_header.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        let many_headers = format!("GET / HTTP/1.1\r\n{}", "X-Padding: a\r\n".repeat(MAX_HANDSHAKE_SIZE as usize / 10));
+        assert!(read_handshake(&mut many_headers.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn frames_round_trip_at_every_length_encoding() {
+        for length in [0, 5, 125, 126, 1000, 65535, 65536, 200_000] {
+            let payload: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+            let mut buffer = Vec::new();
+            write_frame(&mut buffer, OPCODE_BINARY, &payload).unwrap();
+            let header_length = match length {
+                0..=125 => 2,
+                126..=65535 => 4,
+                _ => 10,
+            };
+            assert_eq!(buffer.len(), header_length + length);
+            assert_eq!(buffer[1] & 0x80, 0, "server frames are not masked");
+
+            let (fin, opcode, read) = read_frame(&mut buffer.as_slice()).unwrap();
+            assert!(fin);
+            assert_eq!(opcode, OPCODE_BINARY);
+            assert_eq!(read, payload);
+
+            let masked = client_frame(true, OPCODE_BINARY, &payload, [0x12, 0x34, 0x56, 0x78]);
+            assert_eq!(read_frame(&mut masked.as_slice()).unwrap(), (true, OPCODE_BINARY, payload));
+        }
+    }
+
+    #[test]
+    fn reads_the_rfc_masked_and_fragmented_examples() {
+        // RFC 6455 section 5.7
+        let masked = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(MessageReader::new(&masked[..]).read_message().unwrap(), Message::Text("Hello".to_string()));
+        let fragmented = [0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f];
+        assert_eq!(MessageReader::new(&fragmented[..]).read_message().unwrap(), Message::Text("Hello".to_string()));
+    }
+
+    #[test]
+    fn control_frames_between_fragments_keep_the_message() {
+        let mut stream = client_frame(false, OPCODE_BINARY, b"abc", [1, 2, 3, 4]);
+        stream.extend(client_frame(false, OPCODE_CONTINUATION, b"def", [5, 6, 7, 8]));
+        stream.extend(client_frame(true, OPCODE_PING, b"ping", [9, 10, 11, 12]));
+        stream.extend(client_frame(true, OPCODE_CONTINUATION, b"ghi", [13, 14, 15, 16]));
+        stream.extend(client_frame(true, OPCODE_CLOSE, b"", [0; 4]));
+
+        let mut messages = MessageReader::new(stream.as_slice());
+        assert_eq!(messages.read_message().unwrap(), Message::Ping(b"ping".to_vec()));
+        assert_eq!(messages.read_message().unwrap(), Message::Binary(b"abcdefghi".to_vec()));
+        assert_eq!(messages.read_message().unwrap(), Message::Close);
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let continuation_first = client_frame(true, OPCODE_CONTINUATION, b"x", [0; 4]);
+        assert!(MessageReader::new(continuation_first.as_slice()).read_message().is_err());
+
+        let mut interleaved = client_frame(false, OPCODE_TEXT, b"a", [0; 4]);
+        interleaved.extend(client_frame(true, OPCODE_TEXT, b"b", [0; 4]));
+        assert!(MessageReader::new(interleaved.as_slice()).read_message().is_err());
+
+        let invalid_utf8 = client_frame(true, OPCODE_TEXT, &[0xff, 0xfe], [0; 4]);
+        assert!(MessageReader::new(invalid_utf8.as_slice()).read_message().is_err());
+
+        let mut too_large = vec![0x82, 127];
+        too_large.extend_from_slice(&(MAX_MESSAGE_SIZE + 1).to_be_bytes());
+        assert_eq!(read_frame(&mut too_large.as_slice()).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let truncated = &client_frame(true, OPCODE_TEXT, b"Hello", [0; 4])[..8];
+        assert!(read_frame(&mut &truncated[..]).is_err());
+    }
+}